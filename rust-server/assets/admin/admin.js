@@ -0,0 +1,110 @@
+// Gravity Gallery admin console: polls /api/v1/admin/overview and renders it.
+(() => {
+  const API = '/api/v1';
+  const REFRESH_MS = 5000;
+
+  const escape = (value) =>
+    String(value ?? '').replace(/[&<>"']/g, (c) => ({ '&': '&amp;', '<': '&lt;', '>': '&gt;', '"': '&quot;', "'": '&#39;' }[c]));
+
+  const time = (secs) => (secs ? new Date(secs * 1000).toLocaleString() : '');
+
+  const bytes = (n) => {
+    if (n == null) return '';
+    const units = ['B', 'KiB', 'MiB', 'GiB', 'TiB'];
+    let i = 0;
+    while (n >= 1024 && i < units.length - 1) { n /= 1024; i++; }
+    return `${n.toFixed(i ? 1 : 0)} ${units[i]}`;
+  };
+
+  const table = (id, headers, rows) => {
+    const el = document.getElementById(id);
+    if (!rows.length) {
+      el.innerHTML = `<tr><td class="empty">None</td></tr>`;
+      return;
+    }
+    const head = headers ? `<tr>${headers.map((h) => `<th>${escape(h)}</th>`).join('')}</tr>` : '';
+    el.innerHTML = head + rows.map((cells) => `<tr>${cells.join('')}</tr>`).join('');
+  };
+
+  const td = (value, cls) => `<td${cls ? ` class="${cls}"` : ''}>${escape(value)}</td>`;
+
+  const call = async (method, path) => {
+    const res = await fetch(API + path, { method });
+    if (!res.ok) alert(`${method} ${path} failed: ${res.status}`);
+    refresh();
+  };
+
+  const jobRow = (job) => [
+    td(job.kind),
+    td(job.status, `status-${job.status}`),
+    td(job.progress != null ? `${Math.round(job.progress * 100)}%` : '', 'num'),
+    td(time(job.created_at)),
+    td(job.error || (job.result ? JSON.stringify(job.result) : '')),
+    job.status === 'running'
+      ? `<td><button class="small" data-cancel="${escape(job.id)}">Cancel</button></td>`
+      : '<td></td>',
+  ];
+
+  const render = (data) => {
+    const scan = data.scan;
+    document.getElementById('scan').innerHTML = scan.last
+      ? `<table>${[
+          ['Last scan', time(scan.last.created_at)],
+          ['Status', scan.last.status],
+          ['Finished', time(scan.last.finished_at)],
+          ['Running jobs', scan.running],
+        ].map(([k, v]) => `<tr><th>${escape(k)}</th>${td(v)}</tr>`).join('')}</table>`
+      : '<p class="empty">No scan has run yet.</p>';
+
+    table('config', null, Object.entries(data.config).map(([k, v]) => [`<th>${escape(k)}</th>`, td(JSON.stringify(v))]));
+
+    const s = data.storage;
+    table('storage', null, [
+      ['Images', s.images],
+      ['Library size', bytes(s.library_bytes)],
+      ['Database', bytes(s.database_bytes)],
+      ['Cache', bytes(s.cache_bytes)],
+      ['Trash', bytes(s.trash_bytes)],
+    ].map(([k, v]) => [`<th>${escape(k)}</th>`, td(v, 'num')]));
+
+    table('sessions', ['Client', 'Playlist', 'Sort', 'Paths', 'Now showing', 'Since'], data.sessions.map((s) => [
+      td(s.client_ip),
+      td(s.playlist_len, 'num'),
+      td(s.sort || ''),
+      td((s.paths || []).join(', ')),
+      td(s.now_showing ? s.now_showing.path : ''),
+      td(s.now_showing ? time(s.now_showing.at) : ''),
+    ]));
+
+    table('jobs', ['Kind', 'Status', 'Progress', 'Created', 'Result', ''], data.jobs.map(jobRow));
+
+    table('errors', ['Time', 'Level', 'Target', 'Message'], data.errors.map((e) => [
+      td(time(e.at)),
+      td(e.level, `level-${e.level}`),
+      td(e.target),
+      td(e.message),
+    ]));
+
+    document.getElementById('updated').textContent = `Updated ${new Date().toLocaleTimeString()}`;
+  };
+
+  const refresh = async () => {
+    try {
+      const res = await fetch(`${API}/admin/overview`);
+      if (res.ok) render(await res.json());
+    } catch (err) {
+      document.getElementById('updated').textContent = `Offline: ${err}`;
+    }
+  };
+
+  document.getElementById('scan-start').onclick = () => call('POST', '/scan');
+  document.getElementById('scan-cancel').onclick = () => call('POST', '/scan/cancel');
+  document.getElementById('toggle-parent').onclick = () => call('POST', '/runtime-config/toggle');
+  document.getElementById('jobs').onclick = (event) => {
+    const id = event.target.dataset && event.target.dataset.cancel;
+    if (id) call('DELETE', `/jobs/${encodeURIComponent(id)}`);
+  };
+
+  refresh();
+  setInterval(refresh, REFRESH_MS);
+})();
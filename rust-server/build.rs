@@ -0,0 +1,11 @@
+fn main() {
+    // gRPC 的消息与服务代码由 proto/gallery.proto 生成 (使用内置的 protoc，无需系统安装)
+    #[cfg(feature = "grpc")]
+    {
+        std::env::set_var("PROTOC", protoc_bin_vendored::protoc_bin_path().expect("vendored protoc"));
+        tonic_build::configure()
+            .build_client(false)
+            .compile_protos(&["proto/gallery.proto"], &["proto"])
+            .expect("failed to compile proto/gallery.proto");
+    }
+}
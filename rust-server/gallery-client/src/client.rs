@@ -0,0 +1,176 @@
+//! 基于 reqwest 的类型化客户端
+
+use std::fmt;
+
+use reqwest::{Method, RequestBuilder, Url};
+use serde::{de::DeserializeOwned, Deserialize};
+
+use crate::types::{
+    BrowseResponse, CapabilitiesResponse, ChunkedPlaylistResponse, ImageInfoResponse, PlaylistRequest, QueryRequest, QueryResponse,
+    RestorePlaylistRequest, RestorePlaylistResponse,
+};
+
+#[derive(Debug)]
+pub enum Error {
+    /// 服务器地址无法解析
+    InvalidUrl(String),
+    /// 连接失败、超时或响应无法解析
+    Http(reqwest::Error),
+    /// 服务器返回的错误 (`detail` 为响应中的说明)
+    Api { status: u16, detail: String },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::InvalidUrl(url) => write!(f, "invalid server URL {:?}", url),
+            Error::Http(err) => write!(f, "request failed: {}", err),
+            Error::Api { status, detail } => write!(f, "server returned {}: {}", status, detail),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Http(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(err: reqwest::Error) -> Self {
+        Error::Http(err)
+    }
+}
+
+/// 错误响应体：多数接口为 `detail`，文件接口为 `message`
+#[derive(Deserialize)]
+struct ErrorBody {
+    detail: Option<serde_json::Value>,
+    message: Option<String>,
+}
+
+/// 播放列表接口在设置 `chunk_size` 时返回对象，否则返回数组
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum PlaylistBody {
+    Paths(Vec<String>),
+    Chunked(ChunkedPlaylistResponse),
+}
+
+#[derive(Clone, Debug)]
+pub struct GalleryClient {
+    base: Url,
+    http: reqwest::Client,
+}
+
+impl GalleryClient {
+    /// `base_url` 为服务器根地址，如 `http://192.168.1.10:4860`
+    pub fn new(base_url: &str) -> Result<Self, Error> {
+        Self::with_http_client(base_url, reqwest::Client::new())
+    }
+
+    /// 使用自定义的 reqwest 客户端 (超时、证书等)
+    pub fn with_http_client(base_url: &str, http: reqwest::Client) -> Result<Self, Error> {
+        let mut base = Url::parse(base_url).map_err(|_| Error::InvalidUrl(base_url.to_string()))?;
+        if !base.path().ends_with('/') {
+            base.set_path(&format!("{}/", base.path()));
+        }
+        Ok(Self { base, http })
+    }
+
+    fn request(&self, method: Method, endpoint: &str) -> RequestBuilder {
+        let url = self.base.join(endpoint).expect("endpoint paths are relative");
+        self.http.request(method, url)
+    }
+
+    async fn send<T: DeserializeOwned>(&self, request: RequestBuilder) -> Result<T, Error> {
+        Ok(check(request.send().await?).await?.json().await?)
+    }
+
+    /// 生成播放列表 (同时成为本机的会话播放列表)；设置了 `chunk_size` 时只返回首批条目
+    pub async fn playlist(&self, request: &PlaylistRequest) -> Result<Vec<String>, Error> {
+        let body: PlaylistBody = self.send(self.request(Method::POST, "api/v1/playlist").json(request)).await?;
+        Ok(match body {
+            PlaylistBody::Paths(paths) => paths,
+            PlaylistBody::Chunked(chunked) => chunked.playlist,
+        })
+    }
+
+    /// 用客户端保存的列表恢复会话
+    pub async fn restore_playlist(&self, request: &RestorePlaylistRequest) -> Result<RestorePlaylistResponse, Error> {
+        self.send(self.request(Method::POST, "api/v1/restore-playlist").json(request)).await
+    }
+
+    /// 列出文件夹 (空字符串为根目录)
+    pub async fn browse(&self, path: &str) -> Result<BrowseResponse, Error> {
+        self.send(self.request(Method::GET, "api/v1/browse").query(&[("path", path)])).await
+    }
+
+    pub async fn image_info(&self, path: &str) -> Result<ImageInfoResponse, Error> {
+        self.send(self.request(Method::GET, "api/v1/info").query(&[("path", path)])).await
+    }
+
+    /// 结构化过滤查询
+    pub async fn query(&self, request: &QueryRequest) -> Result<QueryResponse, Error> {
+        self.send(self.request(Method::POST, "api/v1/query").json(request)).await
+    }
+
+    /// 服务器版本、启用的功能与各项上限
+    pub async fn capabilities(&self) -> Result<CapabilitiesResponse, Error> {
+        self.send(self.request(Method::GET, "api/v1/capabilities")).await
+    }
+
+    /// 图片原文件的地址 (可直接交给图片解码器或浏览器)
+    pub fn file_url(&self, path: &str) -> String {
+        format!("{}api/v1/file?path={}", self.base, urlencoding::encode(path))
+    }
+
+    /// 下载图片原文件
+    pub async fn file(&self, path: &str) -> Result<Vec<u8>, Error> {
+        let response = check(self.request(Method::GET, "api/v1/file").query(&[("path", path)]).send().await?).await?;
+        Ok(response.bytes().await?.to_vec())
+    }
+
+    /// 按稳定 ID 下载 ROOT_DIR 之外的文件 (`file_id` 字段)
+    pub async fn file_by_id(&self, file_id: &str) -> Result<Vec<u8>, Error> {
+        let endpoint = format!("api/v1/file/{}", urlencoding::encode(file_id));
+        let response = check(self.request(Method::GET, &endpoint).send().await?).await?;
+        Ok(response.bytes().await?.to_vec())
+    }
+}
+
+/// 把非 2xx 响应转换为 `Error::Api`
+async fn check(response: reqwest::Response) -> Result<reqwest::Response, Error> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+    let text = response.text().await.unwrap_or_default();
+    let detail = match serde_json::from_str::<ErrorBody>(&text) {
+        Ok(ErrorBody { detail: Some(serde_json::Value::String(detail)), .. }) => detail,
+        Ok(ErrorBody { detail: Some(serde_json::Value::Array(errors)), .. }) => describe_field_errors(&errors),
+        Ok(ErrorBody { detail: Some(detail), .. }) => detail.to_string(),
+        Ok(ErrorBody { message: Some(message), .. }) => message,
+        _ => text,
+    };
+    Err(Error::Api { status: status.as_u16(), detail })
+}
+
+/// 422 响应的 `detail` 为字段错误列表 (`loc` + `msg`)，拼成 `sort: Unknown value ...; tags.0: ...`
+fn describe_field_errors(errors: &[serde_json::Value]) -> String {
+    errors
+        .iter()
+        .map(|error| {
+            let field: Vec<String> = error["loc"]
+                .as_array()
+                .map(|loc| loc.iter().skip(1).map(|part| part.as_str().map_or_else(|| part.to_string(), str::to_string)).collect())
+                .unwrap_or_default();
+            let msg = error["msg"].as_str().unwrap_or_default();
+            if field.is_empty() { msg.to_string() } else { format!("{}: {}", field.join("."), msg) }
+        })
+        .collect::<Vec<_>>()
+        .join("; ")
+}
@@ -0,0 +1,27 @@
+//! Gravity Gallery 的接口类型与 HTTP 客户端
+//!
+//! `types` 中的请求/响应类型由服务器直接使用，是接口格式的唯一来源；
+//! `client` 特性 (默认启用) 提供基于 reqwest 的类型化客户端，供 Rust 编写的相框客户端与集成测试使用。
+//!
+//! ```no_run
+//! # async fn demo() -> Result<(), gallery_client::Error> {
+//! use gallery_client::{GalleryClient, PlaylistRequest};
+//!
+//! let client = GalleryClient::new("http://192.168.1.10:4860")?;
+//! let playlist = client
+//!     .playlist(&PlaylistRequest { paths: vec!["holidays".into()], sort: Some("date".into()), ..Default::default() })
+//!     .await?;
+//! let first = client.file(&playlist[0]).await?;
+//! # Ok(())
+//! # }
+//! ```
+
+pub mod types;
+
+pub use types::*;
+
+#[cfg(feature = "client")]
+mod client;
+
+#[cfg(feature = "client")]
+pub use client::{Error, GalleryClient};
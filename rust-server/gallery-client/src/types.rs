@@ -0,0 +1,629 @@
+//! 接口的请求与响应类型 (服务器与客户端共用)
+
+use serde::{Deserialize, Serialize};
+
+pub fn default_sort() -> String {
+    "shuffle".to_string()
+}
+
+pub fn default_orientation() -> String {
+    "Both".to_string()
+}
+
+pub fn default_direction() -> String {
+    "forward".to_string()
+}
+
+pub fn default_query_sort() -> String {
+    "name".to_string()
+}
+
+// --- 播放列表 ---
+
+/// 接口: POST /api/playlist
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct PlaylistRequest {
+    /// 至少一个文件夹 (`.` 为整个图库)，`scheduled` 或 `mix` 请求可以为空
+    #[serde(default)]
+    pub paths: Vec<String>,
+    /// 省略时使用所请求文件夹的默认值 (`PATCH /api/folder`)，文件夹也没有设置时为 `shuffle`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sort: Option<String>,
+    /// 省略时同 `sort`，最终默认为 `Both`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub orientation: Option<String>,
+    #[serde(default = "default_direction")]
+    pub direction: String,
+    pub current_path: Option<String>,
+    #[serde(default)]
+    pub interleave: bool,
+    pub max_per_folder: Option<usize>,
+    /// 设置后先返回前 N 项，完整列表在后台写入会话
+    pub chunk_size: Option<usize>,
+    /// 名称排序使用的语言区域 (如 "zh"、"de")，需启用 `icu` 特性；为空时使用自然排序
+    pub collation: Option<String>,
+    /// 标签过滤：每个标签匹配其整棵子树 (如 "places/japan" 包含 "places/japan/kyoto")，多个标签需同时满足
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// 人物过滤 (人脸聚类 ID)，多个人物需同时出现
+    #[serde(default)]
+    pub people: Vec<i64>,
+    /// 连拍/近似重复折叠：同一文件夹内几秒内拍摄或感知哈希几乎相同的图片只保留一张
+    #[serde(default)]
+    pub collapse_bursts: bool,
+    /// 应用服务器端时间表规则 (见 `/api/schedules`)，规则切换时会话播放列表自动重新生成
+    #[serde(default)]
+    pub scheduled: bool,
+    /// 加权混合多个智能相册：非空时忽略 `paths`、`tags`、`people` 与排序，按权重比例从各来源随机抽样并交错，
+    /// 每次请求重新抽样
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub mix: Vec<MixSource>,
+    /// 随机排序时的间隔约束 (`folder` 或 `date`)：同一文件夹/同一天的图片之间至少隔开 `spread_distance` 张
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub spread_by: Option<String>,
+    /// 省略时为 3
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub spread_distance: Option<usize>,
+    /// 最近 N 天内加入索引的图片排在最前 (保持各自的排序)，之后才是其余图片
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub boost_recent_days: Option<u32>,
+    /// 只要全景图 (长边至少是短边的 2 倍)，忽略方向过滤
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub panoramas_only: bool,
+    /// `Landscape` / `Portrait` 默认排除全景图，设为 true 时全景图按宽高照常归类
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub include_panoramas: bool,
+}
+
+/// 加权混合的一个来源 (如 70 份"家人收藏"、30 份"本月新增")
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct MixSource {
+    /// 只用于显示
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// 相对权重
+    pub weight: f64,
+    /// 同 `POST /api/query` 的过滤条件
+    #[serde(default)]
+    pub filter: QueryFilter,
+}
+
+impl Default for PlaylistRequest {
+    /// 整个图库，随机顺序
+    fn default() -> Self {
+        Self {
+            paths: vec![".".to_string()],
+            sort: None,
+            orientation: None,
+            direction: default_direction(),
+            current_path: None,
+            interleave: false,
+            max_per_folder: None,
+            chunk_size: None,
+            collation: None,
+            tags: Vec::new(),
+            people: Vec::new(),
+            collapse_bursts: false,
+            scheduled: false,
+            mix: Vec::new(),
+            spread_by: None,
+            spread_distance: None,
+            boost_recent_days: None,
+            panoramas_only: false,
+            include_panoramas: false,
+        }
+    }
+}
+
+impl PlaylistRequest {
+    /// 实际使用的排序模式
+    pub fn sort_mode(&self) -> String {
+        self.sort.clone().unwrap_or_else(default_sort)
+    }
+
+    /// 实际使用的方向过滤
+    pub fn orientation_filter(&self) -> String {
+        self.orientation.clone().unwrap_or_else(default_orientation)
+    }
+}
+
+/// 会话中记录的播放列表生成条件
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PlaylistCriteria {
+    pub sort: String,
+    pub direction: String,
+    pub orientation: String,
+    pub paths: Vec<String>,
+    #[serde(default)]
+    pub interleave: bool,
+    #[serde(default)]
+    pub max_per_folder: Option<usize>,
+    #[serde(default)]
+    pub collation: Option<String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(default)]
+    pub people: Vec<i64>,
+    #[serde(default)]
+    pub collapse_bursts: bool,
+    #[serde(default)]
+    pub scheduled: bool,
+    /// 生成时生效的时间表规则
+    #[serde(default)]
+    pub schedule_rule: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub mix: Vec<MixSource>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub spread_by: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub spread_distance: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub boost_recent_days: Option<u32>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub panoramas_only: bool,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub include_panoramas: bool,
+}
+
+/// 会话播放列表的生成状态 (分块模式下完整列表在后台生成)
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum GenerationStatus {
+    Pending,
+    Complete,
+}
+
+/// 图片索引的新鲜度：启动扫描期间直接使用已有索引作答，不等待扫描完成
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum IndexFreshness {
+    /// 最近一次全量扫描已完成
+    #[default]
+    Fresh,
+    /// 启动扫描进行中，结果来自上次运行留下的索引，可能缺少新文件或包含已删除的文件
+    Stale,
+    /// 首次扫描进行中 (此前没有索引)，结果只包含已写入的部分
+    Building,
+}
+
+impl IndexFreshness {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            IndexFreshness::Fresh => "fresh",
+            IndexFreshness::Stale => "stale",
+            IndexFreshness::Building => "building",
+        }
+    }
+}
+
+/// 设置了 `chunk_size` 时 POST /api/playlist 的响应
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ChunkedPlaylistResponse {
+    pub playlist: Vec<String>,
+    pub generation_status: GenerationStatus,
+    #[serde(default)]
+    pub index_freshness: IndexFreshness,
+    /// 所请求文件夹的默认播放间隔 (秒)；纯数组响应改用 `x-slideshow-interval` 响应头
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub interval_secs: Option<u32>,
+    /// `playlist` 的稳定哈希 (见 `RestorePlaylistRequest::playlist_hash`)；纯数组响应改用 `x-playlist-hash` 响应头
+    #[serde(default)]
+    pub playlist_hash: String,
+}
+
+/// 接口: POST /api/restore-playlist
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RestorePlaylistRequest {
+    pub playlist: Vec<String>,
+    #[serde(default)]
+    pub current_index: usize,
+    pub criteria: Option<PlaylistCriteria>,
+    #[serde(default)]
+    pub validate: RestoreValidation,
+    /// 客户端保存列表时记下的哈希 (来自播放列表或会话响应)。服务器端会话已不是这份列表时返回 409，
+    /// 客户端应改为重新获取 `/api/session-playlist`，而不是用旧下标覆盖新列表
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub playlist_hash: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RestorePlaylistResponse {
+    /// 固定为 `restored`
+    pub status: String,
+    pub valid_count: usize,
+    pub original_count: usize,
+    pub current_index: usize,
+    pub validation: RestoreValidation,
+    pub playlist: Vec<String>,
+    /// 恢复后会话播放列表的哈希
+    #[serde(default)]
+    pub playlist_hash: String,
+}
+
+/// 恢复播放列表时的路径校验方式
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RestoreValidation {
+    /// 只保留索引中存在的路径 (批量 SQL 查询，适合大列表/NFS)
+    Index,
+    /// 逐个检查文件系统 (默认，最严格但最慢)
+    #[default]
+    Fs,
+    /// 只做路径规范化与权限检查
+    None,
+    /// 立即接受，随后在后台逐个检查文件系统，失效路径通过 /api/events 通知
+    Background,
+}
+
+// --- 浏览与元数据 ---
+
+/// 文件夹的展示信息，与目录名无关；路径键为相对 ROOT_DIR 的文件夹 (根目录为空字符串)
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[cfg_attr(feature = "sqlx", derive(sqlx::FromRow))]
+pub struct FolderMeta {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// 置顶封面 (图片路径键)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cover: Option<String>,
+    /// 默认排序模式 (同 `PlaylistRequest::sort`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sort: Option<String>,
+    /// 默认方向过滤 (同 `PlaylistRequest::orientation`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub orientation: Option<String>,
+    /// 默认播放间隔 (秒)，随播放列表响应返回给客户端
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub interval_secs: Option<u32>,
+    /// 冷存储：原片所在的磁盘可能离线，索引与预览照常可用，请求原片时返回 453 而不是 404
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub archived: bool,
+    /// 原片存放位置的说明 (如 "蓝色移动硬盘 #3")，随 453 响应作为提示返回
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub archive_note: Option<String>,
+}
+
+impl FolderMeta {
+    pub fn is_empty(&self) -> bool {
+        self.title.is_none()
+            && self.description.is_none()
+            && self.cover.is_none()
+            && self.sort.is_none()
+            && self.orientation.is_none()
+            && self.interval_secs.is_none()
+            && !self.archived
+            && self.archive_note.is_none()
+    }
+}
+
+/// 接口: GET /api/browse
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BrowseResponse {
+    #[serde(rename = "currentPath")]
+    pub current_path: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub meta: Option<FolderMeta>,
+    pub items: Vec<BrowseItem>,
+    #[serde(default)]
+    pub index_freshness: IndexFreshness,
+    /// 文件夹位于离线的归档文件夹中，列表来自索引
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub offline: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BrowseItem {
+    pub name: String,
+    pub path: String,
+    /// `folder` 或 `file`
+    #[serde(rename = "type")]
+    pub item_type: String,
+    /// 文件夹的标题、封面等 (见 `PATCH /api/folder`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub meta: Option<FolderMeta>,
+    /// ROOT_DIR 之外的文件的稳定 ID (`GET /api/file/{id}`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file_id: Option<String>,
+}
+
+/// 人工编辑的标题、说明与评分 (幻灯片用它代替文件名)
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[cfg_attr(feature = "sqlx", derive(sqlx::FromRow))]
+pub struct ImageCaption {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub caption: Option<String>,
+    /// 星级 1–5
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rating: Option<i64>,
+}
+
+impl ImageCaption {
+    pub fn is_empty(&self) -> bool {
+        self.title.is_none() && self.caption.is_none() && self.rating.is_none()
+    }
+}
+
+/// 非破坏性编辑 (PUT /api/edits)：渲染输出时按 旋转 → 裁剪 → 曝光 的顺序应用，原图不变
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ImageEdit {
+    /// 裁剪区域，取值 0–1 的比例坐标 (相对旋转后的图片)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub crop: Option<CropRect>,
+    /// 顺时针旋转角度 (-180–180)；非直角时自动裁掉旋转产生的空角
+    #[serde(default)]
+    pub rotation: f32,
+    /// 曝光补偿 (EV)
+    #[serde(default)]
+    pub exposure: f32,
+}
+
+impl ImageEdit {
+    pub fn is_empty(&self) -> bool {
+        self.crop.is_none() && self.rotation == 0.0 && self.exposure == 0.0
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct CropRect {
+    pub x: f32,
+    pub y: f32,
+    pub w: f32,
+    pub h: f32,
+}
+
+/// 接口: GET /api/info (以及 POST /api/info/batch 的条目)
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ImageInfoResponse {
+    pub path: String,
+    /// ROOT_DIR 之外的文件的稳定 ID (`GET /api/file/{id}`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file_id: Option<String>,
+    pub width: u32,
+    pub height: u32,
+    pub orientation: String,
+    /// 长宽比超过 2:1 的全景图 (可用 /api/tiles 滚动浏览)
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub panorama: bool,
+    pub mtime: f64,
+    /// 首次加入索引的时间 (批量复制的文件 mtime 都是复制日期，这个才反映"最近新增")
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub added_at: Option<f64>,
+    /// 内嵌 ICC 配置文件的描述 (如 "Display P3")
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub color_profile: Option<String>,
+    /// 带 HDR 增益图 (Ultra HDR)；不支持 HDR 的客户端应使用 /api/resize 的 SDR 版本
+    #[serde(default)]
+    pub hdr_gain_map: bool,
+    pub size: i64,
+    pub mime: String,
+    pub hash: Option<String>,
+    pub tags: Vec<String>,
+    /// 自动标签 (ONNX) 给出的建议，尚未确认
+    pub suggested_tags: Vec<SuggestedTag>,
+    /// 同名伴生文件 (Live Photo 视频、RAW / HEIC 原片)，可通过 /api/file 获取
+    pub companions: Vec<Companion>,
+    /// 人工编辑的标题与说明 (PATCH /api/info)
+    #[serde(flatten)]
+    pub caption: ImageCaption,
+    /// 渲染时应用的非破坏性编辑 (PUT /api/edits)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub edit: Option<ImageEdit>,
+    /// 原片备份状态 (服务器开启了 S3 备份时给出)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub backup: Option<BackupStatus>,
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum BackupState {
+    /// 当前版本已上传
+    BackedUp,
+    /// 新文件或修改过的文件，等待下一轮上传
+    Pending,
+    /// 最近一次上传失败，下一轮重试
+    Failed,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BackupStatus {
+    pub state: BackupState,
+    /// 最近一次成功上传的时间 (可能是旧版本)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub uploaded_at: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Companion {
+    pub path: String,
+    pub kind: String,
+    pub mime: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SuggestedTag {
+    pub tag: String,
+    pub score: f64,
+}
+
+// --- 组合查询 ---
+
+/// 结构化过滤条件；所有条件需同时满足，空字段表示不限
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct QueryFilter {
+    /// 文件夹前缀 (多个取并集)，为空时为整个 ROOT_DIR
+    pub paths: Vec<String>,
+    /// 同 `PlaylistRequest::tags`：匹配整棵子树，多个需同时满足
+    pub tags: Vec<String>,
+    pub people: Vec<i64>,
+    pub min_rating: Option<i64>,
+    pub max_rating: Option<i64>,
+    /// `YYYY-MM-DD` 或 RFC 3339；按拍摄时间 (缺失时用修改时间) 比较，`date_to` 为日期时包含当天
+    pub date_from: Option<String>,
+    pub date_to: Option<String>,
+    /// `Landscape` / `Portrait` / `Both`
+    pub orientation: Option<String>,
+    /// 同 `PlaylistRequest::panoramas_only`
+    pub panoramas_only: bool,
+    /// 同 `PlaylistRequest::include_panoramas`
+    pub include_panoramas: bool,
+    /// `still` (无 Live Photo 视频)、`live`、`raw` (有 RAW 原片)
+    pub kind: Option<String>,
+    /// 匹配路径、标题、说明与标签 (不区分大小写)
+    pub text: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum QueryOutput {
+    /// 带元数据的分页结果 (同浏览视图)
+    #[default]
+    Items,
+    /// 只有路径的有序列表；`session: true` 时整份列表同时成为该客户端的会话播放列表
+    Playlist,
+}
+
+/// 接口: POST /api/query
+#[derive(Debug, Serialize, Deserialize)]
+pub struct QueryRequest {
+    #[serde(default)]
+    pub filter: QueryFilter,
+    /// 默认按名称 (随机排序在分页时没有稳定顺序)
+    #[serde(default = "default_query_sort")]
+    pub sort: String,
+    #[serde(default = "default_direction")]
+    pub direction: String,
+    #[serde(default)]
+    pub output: QueryOutput,
+    #[serde(default)]
+    pub offset: usize,
+    pub limit: Option<usize>,
+    #[serde(default)]
+    pub session: bool,
+}
+
+impl Default for QueryRequest {
+    fn default() -> Self {
+        Self {
+            filter: QueryFilter::default(),
+            sort: default_query_sort(),
+            direction: default_direction(),
+            output: QueryOutput::default(),
+            offset: 0,
+            limit: None,
+            session: false,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct QueryResponse {
+    pub total: usize,
+    pub offset: usize,
+    pub limit: usize,
+    /// `output: items` 时给出
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub items: Option<Vec<QueryItem>>,
+    /// `output: playlist` 时给出
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub playlist: Option<Vec<String>>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct QueryItem {
+    pub path: String,
+    pub width: u32,
+    pub height: u32,
+    pub orientation: String,
+    pub mtime: f64,
+    pub size: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub taken_at: Option<f64>,
+    /// ROOT_DIR 之外的文件的稳定 ID (`GET /api/file/{id}`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file_id: Option<String>,
+    #[serde(flatten)]
+    pub caption: ImageCaption,
+}
+
+// --- 能力发现 ---
+
+/// GET /api/capabilities 的响应：客户端据此调整界面，而不必探测接口、解析 404
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CapabilitiesResponse {
+    pub version: String,
+    /// 编译时启用的 cargo feature
+    pub features: Vec<String>,
+    /// 当前实际可用的功能 (编译了对应 feature 且运行时已配置)
+    pub capabilities: Capabilities,
+    /// 播放列表与查询接受的 `sort` 取值
+    pub sort_modes: Vec<String>,
+    pub formats: SupportedFormats,
+    pub limits: Limits,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Capabilities {
+    /// 直接显示 HEIC / HEIF (为 false 时这些文件只作为伴生原片提供下载)
+    pub heic: bool,
+    /// 独立的视频条目
+    pub video: bool,
+    /// Live Photo 伴生视频
+    pub live_photos: bool,
+    /// 人脸检测与 /api/people
+    pub faces: bool,
+    /// ONNX 自动标签
+    pub autotag: bool,
+    /// 文本搜图 (/api/search/semantic)
+    pub semantic_search: bool,
+    /// DLNA / UPnP 媒体服务器
+    pub dlna: bool,
+    /// 投屏到 Chromecast
+    pub cast: bool,
+    /// 邮件摘要
+    pub email_digests: bool,
+    /// 新图片推送通知
+    pub notifications: bool,
+    /// 按语言区域排序名称 (ICU)
+    pub locale_collation: bool,
+    /// 原片备份到 S3 (/api/backup)
+    pub backup: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SupportedFormats {
+    /// 会被索引并显示的图片扩展名
+    pub images: Vec<String>,
+    /// 同名伴生文件的扩展名及类型
+    pub companions: Vec<CompanionFormat>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CompanionFormat {
+    pub extension: String,
+    pub kind: String,
+}
+
+/// 各接口的数量与长度上限 (超出时返回 400)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Limits {
+    pub query_max_limit: usize,
+    pub info_batch_max: usize,
+    pub collage_max_images: usize,
+    pub contact_sheet_max_images: usize,
+    /// 缩放、拼贴与设备配置的最大边长 (像素)
+    pub render_max_side: u32,
+    pub image_title_max_chars: usize,
+    pub image_caption_max_chars: usize,
+    pub folder_title_max_chars: usize,
+    pub folder_description_max_chars: usize,
+    pub share_comment_max_chars: usize,
+    pub idempotency_max_request_bytes: usize,
+}
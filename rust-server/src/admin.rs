@@ -0,0 +1,197 @@
+//! 可选的内置管理页 (cargo feature `admin-ui`)
+//!
+//! - `GET /admin`: 单页控制台，静态资源编译进二进制，无需另外部署前端
+//! - `GET /api/v1/admin/overview`: 控制台使用的汇总数据：扫描任务、会话、存储统计、运行时配置与最近的错误日志
+//!
+//! 页面上的操作直接调用现有接口 (`/api/v1/scan`、`/api/v1/jobs/:id`、`/api/v1/runtime-config/toggle`)。
+
+use std::{
+    collections::VecDeque,
+    sync::{atomic::Ordering, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use axum::{
+    extract::State,
+    http::{header, StatusCode},
+    response::{Html, IntoResponse, Response},
+    routing::get,
+    Json, Router,
+};
+use serde::Serialize;
+use tracing_subscriber::{filter::LevelFilter, layer::Context, registry::LookupSpan, Layer};
+
+use crate::{
+    diagnostics::{database_size, dir_size},
+    AppState, JobRecord, CACHE_DIR_NAME, INTERNAL_PATH_SQL_FILTER, PRESENT_SQL_FILTER, TRASH_DIR_NAME,
+};
+
+const INDEX_HTML: &str = include_str!("../assets/admin/index.html");
+const ADMIN_JS: &str = include_str!("../assets/admin/admin.js");
+const ADMIN_CSS: &str = include_str!("../assets/admin/admin.css");
+
+/// 保留的最近错误/警告条数
+const MAX_RECENT_ERRORS: usize = 100;
+
+static RECENT_ERRORS: Mutex<VecDeque<LogEntry>> = Mutex::new(VecDeque::new());
+
+pub fn router(state: AppState) -> Router {
+    Router::new()
+        .route("/admin", get(index))
+        .route("/admin/assets/:name", get(asset))
+        .route("/api/v1/admin/overview", get(overview))
+        .with_state(state)
+}
+
+async fn index() -> Html<&'static str> {
+    Html(INDEX_HTML)
+}
+
+async fn asset(axum::extract::Path(name): axum::extract::Path<String>) -> Response {
+    let (body, mime) = match name.as_str() {
+        "admin.js" => (ADMIN_JS, "text/javascript; charset=utf-8"),
+        "admin.css" => (ADMIN_CSS, "text/css; charset=utf-8"),
+        _ => return StatusCode::NOT_FOUND.into_response(),
+    };
+    ([(header::CONTENT_TYPE, mime), (header::CACHE_CONTROL, "no-cache")], body).into_response()
+}
+
+// --- 最近的错误日志 ---
+
+#[derive(Clone, Serialize)]
+struct LogEntry {
+    at: f64,
+    level: String,
+    target: String,
+    message: String,
+}
+
+/// 收集 WARN 及以上级别日志的 tracing 层 (不受 `RUST_LOG` 影响)
+pub fn error_log_layer<S>() -> impl Layer<S>
+where
+    S: tracing::Subscriber + for<'span> LookupSpan<'span>,
+{
+    ErrorLog.with_filter(LevelFilter::WARN)
+}
+
+struct ErrorLog;
+
+impl<S: tracing::Subscriber> Layer<S> for ErrorLog {
+    fn on_event(&self, event: &tracing::Event<'_>, _ctx: Context<'_, S>) {
+        let mut visitor = MessageVisitor(String::new());
+        event.record(&mut visitor);
+        let entry = LogEntry {
+            at: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs_f64(),
+            level: event.metadata().level().to_string(),
+            target: event.metadata().target().to_string(),
+            message: visitor.0,
+        };
+        if let Ok(mut entries) = RECENT_ERRORS.lock() {
+            if entries.len() >= MAX_RECENT_ERRORS {
+                entries.pop_front();
+            }
+            entries.push_back(entry);
+        }
+    }
+}
+
+/// 拼接 `message` 与其余字段 (`key=value`)
+struct MessageVisitor(String);
+
+impl tracing::field::Visit for MessageVisitor {
+    fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+        if !self.0.is_empty() {
+            self.0.push(' ');
+        }
+        if field.name() == "message" {
+            self.0.push_str(&format!("{:?}", value));
+        } else {
+            self.0.push_str(&format!("{}={:?}", field.name(), value));
+        }
+    }
+}
+
+// --- 汇总数据 ---
+
+#[derive(Serialize)]
+struct SessionSummary {
+    client_ip: String,
+    playlist_len: usize,
+    sort: Option<String>,
+    paths: Vec<String>,
+    now_showing: Option<crate::NowShowing>,
+}
+
+/// 接口: GET /api/v1/admin/overview
+async fn overview(State(state): State<AppState>) -> Json<serde_json::Value> {
+    let last_scan: Option<JobRecord> =
+        sqlx::query_as("SELECT * FROM jobs WHERE kind = 'scan' ORDER BY created_at DESC LIMIT 1")
+            .fetch_optional(&state.db)
+            .await
+            .ok()
+            .flatten();
+    let jobs: Vec<JobRecord> = sqlx::query_as("SELECT * FROM jobs ORDER BY created_at DESC LIMIT 20")
+        .fetch_all(&state.db)
+        .await
+        .unwrap_or_default();
+    let running = state.jobs.lock().map(|jobs| jobs.len()).unwrap_or(0);
+
+    let now_showing = state.now_showing.read().await.clone();
+    let mut sessions: Vec<SessionSummary> = state
+        .user_sessions
+        .read()
+        .await
+        .iter()
+        .map(|(ip, session)| SessionSummary {
+            client_ip: ip.clone(),
+            playlist_len: session.playlist.len(),
+            sort: session.criteria.as_ref().map(|c| c.sort.clone()),
+            paths: session.criteria.as_ref().map(|c| c.paths.clone()).unwrap_or_default(),
+            now_showing: now_showing.get(ip).cloned(),
+        })
+        .collect();
+    sessions.sort_by(|a, b| a.client_ip.cmp(&b.client_ip));
+
+    let (images, library_bytes): (i64, i64) = sqlx::query_as(&format!(
+        "SELECT COUNT(*), COALESCE(SUM(size), 0) FROM images WHERE {} AND {}",
+        PRESENT_SQL_FILTER, INTERNAL_PATH_SQL_FILTER
+    ))
+    .fetch_one(&state.db)
+    .await
+    .unwrap_or((0, 0));
+    let root_dir = state.root_dir.clone();
+    let (database_bytes, cache_bytes, trash_bytes) = tokio::task::spawn_blocking(move || {
+        (database_size(&root_dir), dir_size(&root_dir.join(CACHE_DIR_NAME)), dir_size(&root_dir.join(TRASH_DIR_NAME)))
+    })
+    .await
+    .unwrap_or_default();
+
+    let bandwidth_kbps = state.bandwidth.read().unwrap().as_ref().map(|limiter| limiter.rate / 1024.0);
+    let dark_hours = state.dark_hours.read().unwrap().clone();
+    let errors: Vec<LogEntry> =
+        RECENT_ERRORS.lock().map(|entries| entries.iter().rev().cloned().collect()).unwrap_or_default();
+
+    Json(serde_json::json!({
+        "scan": { "last": last_scan, "running": running },
+        "jobs": jobs,
+        "sessions": sessions,
+        "storage": {
+            "images": images,
+            "library_bytes": library_bytes,
+            "database_bytes": database_bytes,
+            "cache_bytes": cache_bytes,
+            "trash_bytes": trash_bytes,
+        },
+        "config": {
+            "root_dir": state.root_dir.display().to_string(),
+            "allow_parent_dir_access": *state.allow_parent_dir_access.read().await,
+            "follow_symlinks": state.follow_symlinks,
+            "external_files_by_id_only": state.external_files_by_id_only,
+            "bandwidth_limit_kbps": bandwidth_kbps,
+            "dark_hours": *dark_hours,
+            "access_log_days": state.access_log_retention_days.load(Ordering::Relaxed),
+            "config_file": std::env::var("GALLERY_CONFIG").ok(),
+        },
+        "errors": errors,
+    }))
+}
@@ -0,0 +1,245 @@
+//! 可选的 ONNX 自动标签 (cargo feature `onnx`)
+//!
+//! 模型通过环境变量配置：
+//! - `GALLERY_ONNX_MODEL`: 模型文件路径 (未设置时不启用)
+//! - `GALLERY_ONNX_LABELS`: 标签文件，每行一个，顺序与分类输出一致 (可选)
+//! - `GALLERY_ONNX_INPUT_SIZE`: 输入边长，默认 224
+//! - `GALLERY_ONNX_EMBEDDING_OUTPUT` / `GALLERY_ONNX_LOGITS_OUTPUT`: 输出名，默认都取第一个输出
+//! - `GALLERY_AUTOTAG_THRESHOLD` / `GALLERY_AUTOTAG_TOP_K`: 建议标签的最低分与数量，默认 0.3 / 5
+//!
+//! 语义搜索另需与图片模型同一向量空间的文本模型 (如 CLIP 文本塔)：
+//! - `GALLERY_ONNX_TEXT_MODEL`: 文本模型路径
+//! - `GALLERY_ONNX_TOKENIZER`: HuggingFace `tokenizer.json`
+//! - `GALLERY_ONNX_TEXT_CONTEXT`: 输入序列长度，默认 77
+//! - `GALLERY_ONNX_TEXT_OUTPUT`: 输出名，默认取第一个输出
+//!
+//! onnxruntime 动态库由 `ORT_DYLIB_PATH` 指定。
+
+use std::{path::Path, sync::Mutex};
+
+use anyhow::{anyhow, Context, Result};
+use image::imageops::FilterType;
+use ort::{session::Session, value::Tensor};
+use tokenizers::Tokenizer;
+
+/// ImageNet 归一化参数 (MobileNet / CLIP 等常见视觉模型通用)
+const MEAN: [f32; 3] = [0.485, 0.456, 0.406];
+const STD: [f32; 3] = [0.229, 0.224, 0.225];
+
+pub struct AutoTagger {
+    session: Mutex<Session>,
+    labels: Vec<String>,
+    input_size: u32,
+    embedding_output: Option<String>,
+    logits_output: Option<String>,
+    threshold: f32,
+    top_k: usize,
+}
+
+/// 单张图片的分析结果
+pub struct Analysis {
+    /// L2 归一化后的图片向量
+    pub embedding: Vec<f32>,
+    /// (标签, 置信度)，按置信度降序
+    pub tags: Vec<(String, f32)>,
+}
+
+impl AutoTagger {
+    /// 未配置模型时返回 `Ok(None)`
+    pub fn from_env() -> Result<Option<Self>> {
+        let Some(model_path) = crate::config_var("GALLERY_ONNX_MODEL").ok().filter(|v| !v.trim().is_empty()) else {
+            return Ok(None);
+        };
+
+        let labels = match crate::config_var("GALLERY_ONNX_LABELS").ok().filter(|v| !v.trim().is_empty()) {
+            Some(labels_path) => std::fs::read_to_string(&labels_path)
+                .with_context(|| format!("failed to read labels file {}", labels_path))?
+                .lines()
+                .map(|l| l.trim().to_string())
+                .filter(|l| !l.is_empty())
+                .collect(),
+            None => Vec::new(),
+        };
+
+        let session = Session::builder()?
+            .with_intra_threads(2)?
+            .commit_from_file(&model_path)
+            .with_context(|| format!("failed to load ONNX model {}", model_path))?;
+
+        Ok(Some(Self {
+            session: Mutex::new(session),
+            labels,
+            input_size: env_parse("GALLERY_ONNX_INPUT_SIZE").unwrap_or(224),
+            embedding_output: crate::config_var("GALLERY_ONNX_EMBEDDING_OUTPUT").ok(),
+            logits_output: crate::config_var("GALLERY_ONNX_LOGITS_OUTPUT").ok(),
+            threshold: env_parse("GALLERY_AUTOTAG_THRESHOLD").unwrap_or(0.3),
+            top_k: env_parse("GALLERY_AUTOTAG_TOP_K").unwrap_or(5),
+        }))
+    }
+
+    /// 读取图片并推理 (阻塞调用，应放在 spawn_blocking 中)
+    pub fn analyze(&self, full_path: &Path) -> Result<Analysis> {
+        let size = self.input_size;
+        let rgb = image::open(full_path)?
+            .resize_exact(size, size, FilterType::Triangle)
+            .to_rgb8();
+
+        // HWC u8 -> NCHW f32
+        let plane = (size * size) as usize;
+        let mut input = vec![0f32; plane * 3];
+        for (i, pixel) in rgb.pixels().enumerate() {
+            for c in 0..3 {
+                input[c * plane + i] = (pixel[c] as f32 / 255.0 - MEAN[c]) / STD[c];
+            }
+        }
+        let tensor = Tensor::from_array(([1usize, 3, size as usize, size as usize], input))?;
+
+        let mut session = self.session.lock().map_err(|_| anyhow!("ONNX session poisoned"))?;
+        let outputs = session.run(ort::inputs![tensor])?;
+
+        let extract = |name: &Option<String>| -> Result<Vec<f32>> {
+            let value = match name {
+                Some(name) => outputs.get(name).ok_or_else(|| anyhow!("model has no output {}", name))?,
+                None => &outputs[0],
+            };
+            Ok(value.try_extract_tensor::<f32>()?.1.to_vec())
+        };
+
+        let embedding = l2_normalize(extract(&self.embedding_output)?);
+        let tags = if self.labels.is_empty() {
+            Vec::new()
+        } else {
+            let logits = extract(&self.logits_output)?;
+            if logits.len() != self.labels.len() {
+                return Err(anyhow!(
+                    "logits length {} does not match {} labels",
+                    logits.len(),
+                    self.labels.len()
+                ));
+            }
+            self.top_tags(&softmax(&logits))
+        };
+
+        Ok(Analysis { embedding, tags })
+    }
+
+    fn top_tags(&self, scores: &[f32]) -> Vec<(String, f32)> {
+        let mut ranked: Vec<(usize, f32)> = scores.iter().copied().enumerate().collect();
+        ranked.sort_by(|a, b| b.1.total_cmp(&a.1));
+        ranked
+            .into_iter()
+            .take(self.top_k)
+            .filter(|(_, score)| *score >= self.threshold)
+            .map(|(idx, score)| (self.labels[idx].clone(), score))
+            .collect()
+    }
+}
+
+/// 把查询文本编码为与图片向量可比较的向量
+pub struct TextEncoder {
+    session: Mutex<Session>,
+    tokenizer: Tokenizer,
+    context_length: usize,
+    output: Option<String>,
+}
+
+impl TextEncoder {
+    /// 未配置文本模型时返回 `Ok(None)`
+    pub fn from_env() -> Result<Option<Self>> {
+        let Some(model_path) = crate::config_var("GALLERY_ONNX_TEXT_MODEL").ok().filter(|v| !v.trim().is_empty()) else {
+            return Ok(None);
+        };
+        let tokenizer_path = crate::config_var("GALLERY_ONNX_TOKENIZER")
+            .context("GALLERY_ONNX_TOKENIZER is required together with GALLERY_ONNX_TEXT_MODEL")?;
+        let tokenizer = Tokenizer::from_file(&tokenizer_path)
+            .map_err(|err| anyhow!("failed to load tokenizer {}: {}", tokenizer_path, err))?;
+
+        let session = Session::builder()?
+            .with_intra_threads(2)?
+            .commit_from_file(&model_path)
+            .with_context(|| format!("failed to load ONNX text model {}", model_path))?;
+
+        Ok(Some(Self {
+            session: Mutex::new(session),
+            tokenizer,
+            context_length: env_parse("GALLERY_ONNX_TEXT_CONTEXT").unwrap_or(77),
+            output: crate::config_var("GALLERY_ONNX_TEXT_OUTPUT").ok(),
+        }))
+    }
+
+    /// 阻塞调用，应放在 spawn_blocking 中
+    pub fn embed(&self, text: &str) -> Result<Vec<f32>> {
+        let encoding = self
+            .tokenizer
+            .encode(text, true)
+            .map_err(|err| anyhow!("tokenization failed: {}", err))?;
+
+        // 截断/补零到固定长度
+        let len = self.context_length;
+        let mut ids = vec![0i64; len];
+        let mut mask = vec![0i64; len];
+        for (i, (id, m)) in encoding
+            .get_ids()
+            .iter()
+            .zip(encoding.get_attention_mask())
+            .take(len)
+            .enumerate()
+        {
+            ids[i] = *id as i64;
+            mask[i] = *m as i64;
+        }
+
+        let mut session = self.session.lock().map_err(|_| anyhow!("ONNX session poisoned"))?;
+        let ids = Tensor::from_array(([1usize, len], ids))?;
+        // 部分导出的文本模型只接受 input_ids
+        let outputs = if session.inputs.len() > 1 {
+            session.run(ort::inputs![ids, Tensor::from_array(([1usize, len], mask))?])?
+        } else {
+            session.run(ort::inputs![ids])?
+        };
+
+        let value = match &self.output {
+            Some(name) => outputs.get(name).ok_or_else(|| anyhow!("model has no output {}", name))?,
+            None => &outputs[0],
+        };
+        Ok(l2_normalize(value.try_extract_tensor::<f32>()?.1.to_vec()))
+    }
+}
+
+pub(crate) fn env_parse<T: std::str::FromStr>(key: &str) -> Option<T> {
+    crate::config_var(key).ok().and_then(|v| v.trim().parse().ok())
+}
+
+fn softmax(logits: &[f32]) -> Vec<f32> {
+    let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let exps: Vec<f32> = logits.iter().map(|v| (v - max).exp()).collect();
+    let sum: f32 = exps.iter().sum();
+    exps.into_iter().map(|v| v / sum).collect()
+}
+
+pub(crate) fn l2_normalize(mut v: Vec<f32>) -> Vec<f32> {
+    let norm = v.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm > 0.0 {
+        v.iter_mut().for_each(|x| *x /= norm);
+    }
+    v
+}
+
+/// 向量按小端 f32 序列化存入 BLOB 列
+pub fn embedding_to_blob(embedding: &[f32]) -> Vec<u8> {
+    embedding.iter().flat_map(|v| v.to_le_bytes()).collect()
+}
+
+/// (路径, 图片向量) 列表，供暴力近邻搜索
+pub type EmbeddingIndex = Vec<(String, Vec<f32>)>;
+
+pub fn blob_to_embedding(blob: &[u8]) -> Vec<f32> {
+    blob.chunks_exact(4)
+        .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .collect()
+}
+
+/// 两个已归一化向量的余弦相似度
+pub fn cosine(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b).map(|(x, y)| x * y).sum()
+}
@@ -0,0 +1,481 @@
+//! 可选的 Google Cast 投屏 (cargo feature `cast`)
+//!
+//! - 通过 mDNS (`_googlecast._tcp`) 持续发现局域网内的 Cast 设备
+//! - 以最小化的 CASTV2 发送端实现 (TLS + protobuf `CastMessage`) 启动默认媒体接收器，
+//!   逐张推送当前会话播放列表中的图片
+//! - `GALLERY_CAST_INTERVAL_SECS`: 默认切换间隔，默认 10 秒
+//!
+//! 推送的是 `/api/cast/frame` 转码后的 JPEG 地址，设备需能访问本服务 (见 `GALLERY_PUBLIC_URL`)。
+
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::{anyhow, Context, Result};
+use mdns_sd::{ServiceDaemon, ServiceEvent};
+use serde::Serialize;
+use serde_json::{json, Value};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+    sync::{mpsc, Mutex, RwLock},
+};
+use tokio_rustls::{
+    rustls::{
+        self,
+        client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+        crypto::CryptoProvider,
+        pki_types::{CertificateDer, ServerName, UnixTime},
+        DigitallySignedStruct, SignatureScheme,
+    },
+    TlsConnector,
+};
+
+const SERVICE_TYPE: &str = "_googlecast._tcp.local.";
+/// Default Media Receiver
+const MEDIA_RECEIVER_APP_ID: &str = "CC1AD845";
+const SENDER_ID: &str = "sender-0";
+const RECEIVER_ID: &str = "receiver-0";
+const NS_CONNECTION: &str = "urn:x-cast:com.google.cast.tp.connection";
+const NS_HEARTBEAT: &str = "urn:x-cast:com.google.cast.tp.heartbeat";
+const NS_RECEIVER: &str = "urn:x-cast:com.google.cast.receiver";
+const NS_MEDIA: &str = "urn:x-cast:com.google.cast.media";
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
+const LAUNCH_TIMEOUT: Duration = Duration::from_secs(20);
+/// 单条消息上限 (协议规定 64KiB)
+const MAX_MESSAGE_SIZE: usize = 64 * 1024;
+
+#[derive(Clone, Debug, Serialize)]
+pub struct CastDevice {
+    pub id: String,
+    pub name: String,
+    pub model: Option<String>,
+    pub address: IpAddr,
+    pub port: u16,
+    /// 是否有本服务发起的投屏会话
+    pub casting: bool,
+    #[serde(skip)]
+    fullname: String,
+}
+
+pub enum CastCommand {
+    Next,
+    Stop,
+}
+
+#[derive(Default)]
+pub struct CastManager {
+    devices: RwLock<HashMap<String, CastDevice>>,
+    sessions: Mutex<HashMap<String, mpsc::Sender<CastCommand>>>,
+}
+
+impl CastManager {
+    /// 后台持续浏览 mDNS，维护设备表
+    pub fn spawn_discovery(self: &Arc<Self>) {
+        let daemon = match ServiceDaemon::new() {
+            Ok(daemon) => daemon,
+            Err(err) => {
+                tracing::error!("⚠️ Cast discovery disabled: {}", err);
+                return;
+            }
+        };
+        let receiver = match daemon.browse(SERVICE_TYPE) {
+            Ok(receiver) => receiver,
+            Err(err) => {
+                tracing::error!("⚠️ Cast discovery disabled: {}", err);
+                return;
+            }
+        };
+
+        let manager = self.clone();
+        tokio::spawn(async move {
+            // daemon 需在任务内保持存活
+            let _daemon = daemon;
+            while let Ok(event) = receiver.recv_async().await {
+                match event {
+                    ServiceEvent::ServiceResolved(info) => {
+                        let Some(address) = info.get_addresses().iter().find(|a| a.is_ipv4()).copied() else {
+                            continue;
+                        };
+                        let id = info
+                            .get_property_val_str("id")
+                            .map(str::to_string)
+                            .unwrap_or_else(|| info.get_fullname().to_string());
+                        let device = CastDevice {
+                            name: info
+                                .get_property_val_str("fn")
+                                .map(str::to_string)
+                                .unwrap_or_else(|| id.clone()),
+                            model: info.get_property_val_str("md").map(str::to_string),
+                            address,
+                            port: info.get_port(),
+                            casting: false,
+                            fullname: info.get_fullname().to_string(),
+                            id: id.clone(),
+                        };
+                        tracing::info!("📺 Cast device found: {} ({})", device.name, address);
+                        manager.devices.write().await.insert(id, device);
+                    }
+                    ServiceEvent::ServiceRemoved(_, fullname) => {
+                        manager.devices.write().await.retain(|_, d| d.fullname != fullname);
+                    }
+                    _ => {}
+                }
+            }
+        });
+    }
+
+    pub async fn devices(&self) -> Vec<CastDevice> {
+        let sessions = self.sessions.lock().await;
+        let mut devices: Vec<CastDevice> = self
+            .devices
+            .read()
+            .await
+            .values()
+            .cloned()
+            .map(|mut d| {
+                d.casting = sessions.get(&d.id).is_some_and(|tx| !tx.is_closed());
+                d
+            })
+            .collect();
+        devices.sort_by(|a, b| a.name.cmp(&b.name));
+        devices
+    }
+
+    /// 按设备 ID 或名称 (忽略大小写) 查找
+    pub async fn find(&self, key: &str) -> Option<CastDevice> {
+        let devices = self.devices.read().await;
+        devices
+            .get(key)
+            .or_else(|| devices.values().find(|d| d.name.eq_ignore_ascii_case(key)))
+            .cloned()
+    }
+
+    /// 在设备上开始播放 `urls` (从 `start` 开始，每 `interval` 切换一张)；已有会话则先停止
+    pub async fn start(self: &Arc<Self>, device: CastDevice, urls: Vec<String>, start: usize, interval: Duration) {
+        let (tx, rx) = mpsc::channel(8);
+        if let Some(previous) = self.sessions.lock().await.insert(device.id.clone(), tx.clone()) {
+            let _ = previous.send(CastCommand::Stop).await;
+        }
+
+        let manager = self.clone();
+        tokio::spawn(async move {
+            let id = device.id.clone();
+            if let Err(err) = run_session(&device, &urls, start, interval, rx).await {
+                tracing::warn!("⚠️ Cast session on {} ended: {:#}", device.name, err);
+            }
+            let mut sessions = manager.sessions.lock().await;
+            if sessions.get(&id).is_some_and(|current| current.same_channel(&tx)) {
+                sessions.remove(&id);
+            }
+        });
+    }
+
+    /// 向正在进行的会话发送命令；没有会话时返回 false
+    pub async fn command(&self, device_id: &str, command: CastCommand) -> bool {
+        let sender = self.sessions.lock().await.get(device_id).cloned();
+        match sender {
+            Some(sender) => sender.send(command).await.is_ok(),
+            None => false,
+        }
+    }
+}
+
+async fn run_session(
+    device: &CastDevice,
+    urls: &[String],
+    start: usize,
+    interval: Duration,
+    mut commands: mpsc::Receiver<CastCommand>,
+) -> Result<()> {
+    if urls.is_empty() {
+        return Err(anyhow!("empty playlist"));
+    }
+    let stream = connect(SocketAddr::new(device.address, device.port)).await?;
+    let (mut reader, mut writer) = tokio::io::split(stream);
+
+    // 读取放在独立任务里 (read_exact 不可安全取消)
+    let (incoming_tx, mut incoming) = mpsc::channel::<CastMessage>(32);
+    tokio::spawn(async move {
+        while let Ok(message) = read_message(&mut reader).await {
+            if incoming_tx.send(message).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut request_id = 0u64;
+    let mut next_request_id = || {
+        request_id += 1;
+        request_id
+    };
+
+    send(&mut writer, RECEIVER_ID, NS_CONNECTION, json!({ "type": "CONNECT" })).await?;
+    send(
+        &mut writer,
+        RECEIVER_ID,
+        NS_RECEIVER,
+        json!({ "type": "LAUNCH", "appId": MEDIA_RECEIVER_APP_ID, "requestId": next_request_id() }),
+    )
+    .await?;
+
+    // 等待接收器应用启动，拿到 transportId / sessionId
+    let (transport_id, session_id) = tokio::time::timeout(LAUNCH_TIMEOUT, async {
+        while let Some(message) = incoming.recv().await {
+            let payload = message.json();
+            if message.namespace == NS_HEARTBEAT && payload["type"] == "PING" {
+                send(&mut writer, &message.source_id, NS_HEARTBEAT, json!({ "type": "PONG" })).await?;
+                continue;
+            }
+            if payload["type"] == "LAUNCH_ERROR" {
+                return Err(anyhow!("receiver refused to launch: {}", payload));
+            }
+            if let Some(app) = media_app(&payload) {
+                return Ok(app);
+            }
+        }
+        Err(anyhow!("connection closed during launch"))
+    })
+    .await
+    .context("timed out waiting for the media receiver")??;
+
+    send(&mut writer, &transport_id, NS_CONNECTION, json!({ "type": "CONNECT" })).await?;
+    tracing::info!("📺 Casting {} images to {}", urls.len(), device.name);
+
+    let mut index = start % urls.len();
+    load_image(&mut writer, &transport_id, &urls[index], next_request_id()).await?;
+
+    let mut slide_ticker = tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+    let mut heartbeat = tokio::time::interval(HEARTBEAT_INTERVAL);
+    loop {
+        tokio::select! {
+            command = commands.recv() => match command {
+                Some(CastCommand::Next) => {
+                    index = (index + 1) % urls.len();
+                    load_image(&mut writer, &transport_id, &urls[index], next_request_id()).await?;
+                    slide_ticker.reset();
+                }
+                Some(CastCommand::Stop) | None => {
+                    send(
+                        &mut writer,
+                        RECEIVER_ID,
+                        NS_RECEIVER,
+                        json!({ "type": "STOP", "sessionId": session_id, "requestId": next_request_id() }),
+                    )
+                    .await?;
+                    tracing::info!("📺 Cast session on {} stopped", device.name);
+                    return Ok(());
+                }
+            },
+            _ = slide_ticker.tick() => {
+                index = (index + 1) % urls.len();
+                load_image(&mut writer, &transport_id, &urls[index], next_request_id()).await?;
+            }
+            _ = heartbeat.tick() => {
+                send(&mut writer, RECEIVER_ID, NS_HEARTBEAT, json!({ "type": "PING" })).await?;
+            }
+            message = incoming.recv() => {
+                let Some(message) = message else {
+                    return Err(anyhow!("connection lost"));
+                };
+                let payload = message.json();
+                match (message.namespace.as_str(), payload["type"].as_str()) {
+                    (NS_HEARTBEAT, Some("PING")) => {
+                        send(&mut writer, &message.source_id, NS_HEARTBEAT, json!({ "type": "PONG" })).await?;
+                    }
+                    (NS_CONNECTION, Some("CLOSE")) if message.source_id == transport_id => {
+                        return Err(anyhow!("receiver closed the session"));
+                    }
+                    // 其他发送端接管或用户在设备上退出
+                    (NS_RECEIVER, Some("RECEIVER_STATUS")) if media_app(&payload).is_none() => {
+                        return Err(anyhow!("media receiver is no longer running"));
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+}
+
+/// 从 RECEIVER_STATUS 中取出默认媒体接收器的 (transportId, sessionId)
+fn media_app(payload: &Value) -> Option<(String, String)> {
+    payload["status"]["applications"]
+        .as_array()?
+        .iter()
+        .find(|app| app["appId"] == MEDIA_RECEIVER_APP_ID)
+        .and_then(|app| Some((app["transportId"].as_str()?.to_string(), app["sessionId"].as_str()?.to_string())))
+}
+
+async fn load_image<W: AsyncWrite + Unpin>(writer: &mut W, transport_id: &str, url: &str, request_id: u64) -> Result<()> {
+    send(
+        writer,
+        transport_id,
+        NS_MEDIA,
+        json!({
+            "type": "LOAD",
+            "requestId": request_id,
+            "autoplay": true,
+            "media": { "contentId": url, "contentType": "image/jpeg", "streamType": "NONE" },
+        }),
+    )
+    .await
+}
+
+// --- 传输层 ---
+
+async fn connect(addr: SocketAddr) -> Result<tokio_rustls::client::TlsStream<TcpStream>> {
+    let provider = Arc::new(rustls::crypto::aws_lc_rs::default_provider());
+    let config = rustls::ClientConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(AcceptDeviceCertificate(provider)))
+        .with_no_client_auth();
+    let tcp = tokio::time::timeout(Duration::from_secs(5), TcpStream::connect(addr))
+        .await
+        .context("connection timed out")??;
+    let server_name = ServerName::IpAddress(addr.ip().into());
+    Ok(TlsConnector::from(Arc::new(config)).connect(server_name, tcp).await?)
+}
+
+/// Cast 设备使用自签名证书，只校验握手签名本身
+#[derive(Debug)]
+struct AcceptDeviceCertificate(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for AcceptDeviceCertificate {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+/// `CastMessage` 中实际用到的字段 (payload 固定为 UTF-8 字符串)
+struct CastMessage {
+    source_id: String,
+    namespace: String,
+    payload: String,
+}
+
+impl CastMessage {
+    fn json(&self) -> Value {
+        serde_json::from_str(&self.payload).unwrap_or(Value::Null)
+    }
+}
+
+async fn send<W: AsyncWrite + Unpin>(writer: &mut W, destination: &str, namespace: &str, payload: Value) -> Result<()> {
+    let body = encode_message(SENDER_ID, destination, namespace, &payload.to_string());
+    writer.write_all(&(body.len() as u32).to_be_bytes()).await?;
+    writer.write_all(&body).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+async fn read_message<R: AsyncRead + Unpin>(reader: &mut R) -> Result<CastMessage> {
+    let len = reader.read_u32().await? as usize;
+    if len > MAX_MESSAGE_SIZE {
+        return Err(anyhow!("oversized cast message ({} bytes)", len));
+    }
+    let mut body = vec![0u8; len];
+    reader.read_exact(&mut body).await?;
+    decode_message(&body)
+}
+
+/// protobuf 编码：protocol_version=1, source_id=2, destination_id=3, namespace=4, payload_type=5, payload_utf8=6
+fn encode_message(source: &str, destination: &str, namespace: &str, payload: &str) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(payload.len() + 128);
+    buf.extend_from_slice(&[0x08, 0x00]); // CASTV2_1_0
+    for (tag, value) in [(0x12u8, source), (0x1a, destination), (0x22, namespace)] {
+        buf.push(tag);
+        put_varint(&mut buf, value.len() as u64);
+        buf.extend_from_slice(value.as_bytes());
+    }
+    buf.extend_from_slice(&[0x28, 0x00]); // STRING
+    buf.push(0x32);
+    put_varint(&mut buf, payload.len() as u64);
+    buf.extend_from_slice(payload.as_bytes());
+    buf
+}
+
+fn decode_message(mut buf: &[u8]) -> Result<CastMessage> {
+    let mut message = CastMessage {
+        source_id: String::new(),
+        namespace: String::new(),
+        payload: String::new(),
+    };
+    while !buf.is_empty() {
+        let key = take_varint(&mut buf)?;
+        match key & 0x7 {
+            0 => {
+                take_varint(&mut buf)?;
+            }
+            2 => {
+                let len = take_varint(&mut buf)? as usize;
+                if len > buf.len() {
+                    return Err(anyhow!("truncated cast message"));
+                }
+                let (value, rest) = buf.split_at(len);
+                buf = rest;
+                let text = || String::from_utf8_lossy(value).into_owned();
+                match key >> 3 {
+                    2 => message.source_id = text(),
+                    4 => message.namespace = text(),
+                    6 => message.payload = text(),
+                    _ => {}
+                }
+            }
+            wire => return Err(anyhow!("unsupported protobuf wire type {}", wire)),
+        }
+    }
+    Ok(message)
+}
+
+fn put_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+fn take_varint(buf: &mut &[u8]) -> Result<u64> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let (&byte, rest) = buf.split_first().ok_or_else(|| anyhow!("truncated varint"))?;
+        *buf = rest;
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(anyhow!("varint too long"))
+}
@@ -0,0 +1,521 @@
+//! 渲染时的色彩空间与元数据处理
+//!
+//! 广色域照片 (Display P3、Adobe RGB 等) 的像素值只有配合内嵌的 ICC 配置文件才有意义，
+//! 直接重新编码而丢掉配置文件会显得发灰。渲染结果有两种处理方式 (`/api/resize?metadata=`)：
+//! - `strip` (默认，分享与设备配置固定使用)：像素按 ICC 配置文件转换到 sRGB，不输出 EXIF / ICC
+//! - `keep`：像素保持原色彩空间，JPEG 输出原样嵌入 ICC 配置文件与 EXIF
+//!
+//! 只支持矩阵/TRC 型 RGB 配置文件 (相机与手机生成的基本都是)；LUT 型或无法解析的配置文件按 sRGB 处理。
+//!
+//! Ultra HDR / ISO 21496-1 JPEG 在主图之后附带一张增益图。渲染结果总是 SDR：主图本身是 SDR 时直接使用主图，
+//! 主图是 HDR (`hdrgm:BaseRenditionIsHDR="True"`) 时按增益图把主图映射到 SDR。需要 HDR 效果的客户端用 `/api/file` 取原图。
+
+use std::{io::BufReader, path::Path};
+
+/// 渲染结果中的 EXIF / ICC 处理方式
+#[derive(Clone, Copy, Debug, Default, serde::Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum MetadataMode {
+    #[default]
+    Strip,
+    Keep,
+}
+
+/// 原图内嵌的 ICC 配置文件 (JPEG APP2 / PNG iCCP)
+pub fn read_icc_profile(full_path: &Path) -> Option<Vec<u8>> {
+    use image::ImageDecoder;
+
+    let ext = full_path.extension()?.to_str()?.to_ascii_lowercase();
+    let reader = BufReader::new(std::fs::File::open(full_path).ok()?);
+    match ext.as_str() {
+        "jpg" | "jpeg" => image::codecs::jpeg::JpegDecoder::new(reader).ok()?.icc_profile(),
+        "png" => image::codecs::png::PngDecoder::new(reader).ok()?.icc_profile(),
+        _ => None,
+    }
+}
+
+/// 原图的 EXIF 原始数据 (TIFF 结构，不含 `Exif\0\0` 前缀)，仅限 JPEG
+pub fn read_exif_block(full_path: &Path) -> Option<Vec<u8>> {
+    let ext = full_path.extension()?.to_str()?.to_ascii_lowercase();
+    if !matches!(ext.as_str(), "jpg" | "jpeg") {
+        return None;
+    }
+    let mut reader = BufReader::new(std::fs::File::open(full_path).ok()?);
+    exif::get_exif_attr_from_jpeg(&mut reader).ok()
+}
+
+/// 扫描后记录的色彩信息
+pub struct ColorInfo {
+    /// 内嵌 ICC 配置文件的描述 (如 "Display P3")；没有配置文件时为 None
+    pub profile: Option<String>,
+    /// 是否带 HDR 增益图 (目前只识别 JPEG)
+    pub hdr_gain_map: bool,
+}
+
+/// 检测内嵌 ICC 配置文件与 HDR 增益图，只读取文件头部
+pub fn inspect(full_path: &Path) -> ColorInfo {
+    let profile = read_icc_profile(full_path).map(|icc| icc_description(&icc).unwrap_or_else(|| "Unnamed ICC profile".to_string()));
+    let hdr_gain_map = is_jpeg(full_path) && read_head(full_path).is_some_and(|head| has_gain_map(&head));
+    ColorInfo { profile, hdr_gain_map }
+}
+
+/// ICC 配置文件的描述文字 (`desc` 标签，v2 的 ASCII 或 v4 的 `mluc` 第一条记录)
+pub fn icc_description(profile: &[u8]) -> Option<String> {
+    let tag = icc_tag(profile, b"desc")?;
+    let text = match tag.get(..4)? {
+        b"desc" => {
+            let len = be_u32(tag, 8)? as usize;
+            String::from_utf8_lossy(tag.get(12..12usize.checked_add(len)?)?).into_owned()
+        }
+        b"mluc" => {
+            let len = be_u32(tag, 20)? as usize;
+            let offset = be_u32(tag, 24)? as usize;
+            let units: Vec<u16> = tag
+                .get(offset..offset.checked_add(len)?)?
+                .chunks_exact(2)
+                .map(|c| u16::from_be_bytes([c[0], c[1]]))
+                .collect();
+            String::from_utf16_lossy(&units)
+        }
+        _ => return None,
+    };
+    let text = text.trim_matches('\0').trim();
+    (!text.is_empty()).then(|| text.to_string())
+}
+
+fn is_jpeg(full_path: &Path) -> bool {
+    full_path
+        .extension()
+        .and_then(|e| e.to_str())
+        .is_some_and(|e| matches!(e.to_ascii_lowercase().as_str(), "jpg" | "jpeg"))
+}
+
+/// JPEG 主图 SOS 之前的全部标记段：逐段读取，到 SOS 即停止，不读入熵编码数据 (畸形文件最多读 1 MiB)
+fn read_head(full_path: &Path) -> Option<Vec<u8>> {
+    use std::io::Read;
+
+    fn read_u8(reader: &mut impl Read) -> Option<u8> {
+        let mut b = [0u8; 1];
+        reader.read_exact(&mut b).ok().map(|_| b[0])
+    }
+
+    let mut reader = BufReader::new(std::fs::File::open(full_path).ok()?).take(1 << 20);
+    let mut head = vec![read_u8(&mut reader)?, read_u8(&mut reader)?];
+    if head != [0xFF, 0xD8] {
+        return None;
+    }
+    while let Some(byte) = read_u8(&mut reader) {
+        head.push(byte);
+        if byte != 0xFF {
+            break;
+        }
+        // 标记前可以有任意个 0xFF 填充字节
+        let mut marker = 0xFF;
+        while marker == 0xFF {
+            let Some(next) = read_u8(&mut reader) else { return Some(head) };
+            head.push(next);
+            marker = next;
+        }
+        match marker {
+            0xDA | 0xD9 => break,
+            0x01 | 0xD0..=0xD7 => continue,
+            _ => {}
+        }
+        let (Some(hi), Some(lo)) = (read_u8(&mut reader), read_u8(&mut reader)) else { break };
+        head.extend_from_slice(&[hi, lo]);
+        let len = u16::from_be_bytes([hi, lo]).saturating_sub(2);
+        if (&mut reader).take(len as u64).read_to_end(&mut head).ok()? < len as usize {
+            break;
+        }
+    }
+    Some(head)
+}
+
+const XMP_HEADER: &[u8] = b"http://ns.adobe.com/xap/1.0/\0";
+const GAIN_MAP_NAMESPACE: &str = "http://ns.adobe.com/hdr-gain-map/1.0/";
+const ISO_GAIN_MAP_URN: &[u8] = b"urn:iso:std:iso:ts:21496:-1\0";
+
+/// JPEG 在 SOS 之前的标记段 (标记, 内容)，以及 SOS 的位置
+fn jpeg_header_segments(jpeg: &[u8]) -> (Vec<(u8, &[u8])>, usize) {
+    let mut segments = Vec::new();
+    if jpeg.get(..2) != Some(&[0xFF, 0xD8][..]) {
+        return (segments, 0);
+    }
+    let mut at = 2;
+    while at + 4 <= jpeg.len() && jpeg[at] == 0xFF {
+        let marker = jpeg[at + 1];
+        if marker == 0xFF {
+            at += 1;
+            continue;
+        }
+        if marker == 0xDA || marker == 0xD9 {
+            break;
+        }
+        let Some(len) = be_u16(jpeg, at + 2) else { break };
+        let end = (at + 2 + len as usize).min(jpeg.len());
+        segments.push((marker, &jpeg[(at + 4).min(end)..end]));
+        at = end;
+    }
+    (segments, at)
+}
+
+fn jpeg_xmp(segments: &[(u8, &[u8])]) -> Option<String> {
+    segments.iter().find_map(|(marker, data)| {
+        let xmp = data.strip_prefix(XMP_HEADER).filter(|_| *marker == 0xE1)?;
+        Some(String::from_utf8_lossy(xmp).into_owned())
+    })
+}
+
+/// 主图声明了增益图：XMP 中的 hdrgm 命名空间 (Ultra HDR) 或 ISO 21496-1 的 APP2 段
+fn has_gain_map(jpeg: &[u8]) -> bool {
+    let (segments, _) = jpeg_header_segments(jpeg);
+    jpeg_xmp(&segments).is_some_and(|xmp| xmp.contains(GAIN_MAP_NAMESPACE))
+        || segments.iter().any(|(marker, data)| *marker == 0xE2 && data.starts_with(ISO_GAIN_MAP_URN))
+}
+
+/// 主图之后的第一张 JPEG (增益图)。熵编码数据中的 0xFF 后只会跟 0x00 或 RST 标记，第一个 EOI 即主图结束
+fn secondary_jpeg(jpeg: &[u8]) -> Option<&[u8]> {
+    let (_, sos) = jpeg_header_segments(jpeg);
+    let eoi = sos + jpeg.get(sos..)?.windows(2).position(|w| w == [0xFF, 0xD9])? + 2;
+    let start = eoi + jpeg.get(eoi..)?.windows(3).position(|w| w == [0xFF, 0xD8, 0xFF])?;
+    Some(&jpeg[start..])
+}
+
+/// 读取 XMP 属性值，支持属性写法与元素写法 (多通道的 `rdf:Seq` 取第一个值)
+fn xmp_value<'a>(xmp: &'a str, name: &str) -> Option<&'a str> {
+    if let Some(start) = xmp.find(&format!("{}=\"", name)) {
+        let rest = &xmp[start + name.len() + 2..];
+        return Some(&rest[..rest.find('"')?]);
+    }
+    let start = xmp.find(&format!("<{}>", name))? + name.len() + 2;
+    let mut rest = &xmp[start..];
+    if let Some(li) = rest.trim_start().strip_prefix("<rdf:Seq>") {
+        rest = &li.trim_start()[li.trim_start().find('>')? + 1..];
+    }
+    Some(rest[..rest.find('<')?].trim())
+}
+
+/// 增益图参数 (log2 空间)，默认值见 Adobe 增益图规范
+struct GainMapParams {
+    min: f32,
+    max: f32,
+    gamma: f32,
+    offset_sdr: f32,
+    offset_hdr: f32,
+    base_is_hdr: bool,
+}
+
+impl GainMapParams {
+    fn from_xmp(xmp: &str) -> Option<Self> {
+        let number = |name: &str, default: f32| xmp_value(xmp, name).and_then(|v| v.parse().ok()).unwrap_or(default);
+        Some(Self {
+            min: number("hdrgm:GainMapMin", 0.0),
+            max: xmp_value(xmp, "hdrgm:GainMapMax")?.parse().ok()?,
+            gamma: number("hdrgm:Gamma", 1.0).max(0.01),
+            offset_sdr: number("hdrgm:OffsetSDR", 1.0 / 64.0),
+            offset_hdr: number("hdrgm:OffsetHDR", 1.0 / 64.0),
+            base_is_hdr: xmp_value(xmp, "hdrgm:BaseRenditionIsHDR").is_some_and(|v| v.eq_ignore_ascii_case("true")),
+        })
+    }
+}
+
+/// 主图为 HDR 的增益图 JPEG：渲染前需要先映射到 SDR
+pub struct HdrBase {
+    params: GainMapParams,
+    map: image::RgbImage,
+}
+
+impl HdrBase {
+    /// 只有带增益图且主图为 HDR 时返回 Some；主图是 SDR 的 Ultra HDR 直接按普通 JPEG 渲染
+    pub fn read(full_path: &Path) -> Option<Self> {
+        if !is_jpeg(full_path) || !has_gain_map(&read_head(full_path)?) {
+            return None;
+        }
+        Self::parse(&std::fs::read(full_path).ok()?)
+    }
+
+    fn parse(jpeg: &[u8]) -> Option<Self> {
+        let gain_map = secondary_jpeg(jpeg)?;
+        // Ultra HDR 把参数写在增益图自己的 XMP 中，少数写入器写在主图
+        let (segments, _) = jpeg_header_segments(gain_map);
+        let params = jpeg_xmp(&segments)
+            .and_then(|xmp| GainMapParams::from_xmp(&xmp))
+            .or_else(|| GainMapParams::from_xmp(&jpeg_xmp(&jpeg_header_segments(jpeg).0)?))?;
+        if !params.base_is_hdr {
+            return None;
+        }
+        let map = image::load_from_memory_with_format(gain_map, image::ImageFormat::Jpeg).ok()?.to_rgb8();
+        Some(Self { params, map })
+    }
+
+    /// 满权重应用增益图：SDR = (HDR + offset_hdr) × 2^G − offset_sdr，G 在 [min, max] 之间插值
+    pub fn to_sdr(&self, mut base: image::RgbImage) -> image::RgbImage {
+        let GainMapParams { min, max, gamma, offset_sdr, offset_hdr, .. } = self.params;
+        let map = image::imageops::resize(&self.map, base.width(), base.height(), image::imageops::FilterType::Triangle);
+        let decode: Vec<f32> = (0..256).map(|i| srgb_to_linear(i as f32 / 255.0)).collect();
+        let gain: Vec<f32> = (0..256)
+            .map(|i| {
+                let t = (i as f32 / 255.0).powf(1.0 / gamma);
+                (min + (max - min) * t).exp2()
+            })
+            .collect();
+        for (pixel, factor) in base.pixels_mut().zip(map.pixels()) {
+            for channel in 0..3 {
+                let linear = (decode[pixel[channel] as usize] + offset_hdr) * gain[factor[channel] as usize] - offset_sdr;
+                pixel.0[channel] = (linear_to_srgb(linear.clamp(0.0, 1.0)) * 255.0).round() as u8;
+            }
+        }
+        base
+    }
+}
+
+/// 曝光补偿 (EV)，在线性光中按 2^EV 缩放，超出的高光截断
+pub fn adjust_exposure(image: &mut image::RgbImage, ev: f32) {
+    let gain = ev.exp2();
+    let lut: Vec<u8> = (0..256)
+        .map(|i| (linear_to_srgb((srgb_to_linear(i as f32 / 255.0) * gain).min(1.0)) * 255.0).round() as u8)
+        .collect();
+    for pixel in image.pixels_mut() {
+        for c in pixel.0.iter_mut() {
+            *c = lut[*c as usize];
+        }
+    }
+}
+
+/// 单通道的色调响应曲线 (编码值 → 线性光)
+enum Curve {
+    Gamma(f32),
+    Table(Vec<f32>),
+    /// ICC `para` 曲线：[g, a, b, c, d, e, f]
+    Parametric(u16, [f32; 7]),
+}
+
+impl Curve {
+    fn eval(&self, x: f32) -> f32 {
+        match self {
+            Curve::Gamma(g) => x.powf(*g),
+            Curve::Table(table) => {
+                let pos = x.clamp(0.0, 1.0) * (table.len() - 1) as f32;
+                let i = (pos.floor() as usize).min(table.len() - 2);
+                let t = pos - i as f32;
+                table[i] * (1.0 - t) + table[i + 1] * t
+            }
+            Curve::Parametric(kind, [g, a, b, c, d, e, f]) => match kind {
+                0 => x.powf(*g),
+                1 if x >= -b / a => (a * x + b).powf(*g),
+                1 => 0.0,
+                2 if x >= -b / a => (a * x + b).powf(*g) + c,
+                2 => *c,
+                3 if x >= *d => (a * x + b).powf(*g),
+                3 => c * x,
+                _ if x >= *d => (a * x + b).powf(*g) + e,
+                _ => c * x + f,
+            },
+        }
+    }
+}
+
+/// sRGB 编码值 → 线性光
+fn srgb_to_linear(x: f32) -> f32 {
+    if x <= 0.04045 {
+        x / 12.92
+    } else {
+        ((x + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn linear_to_srgb(x: f32) -> f32 {
+    if x <= 0.003_130_8 {
+        x * 12.92
+    } else {
+        1.055 * x.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+/// PCS (D50 XYZ) → 线性 sRGB，即 Bradford 适配到 D50 的 sRGB 矩阵的逆
+const XYZ_D50_TO_SRGB: [[f32; 3]; 3] = [
+    [3.133_856, -1.616_867, -0.490_615],
+    [-0.978_768, 1.916_142, 0.033_454],
+    [0.071_945, -0.228_991, 1.405_243],
+];
+
+fn be_u16(data: &[u8], at: usize) -> Option<u16> {
+    Some(u16::from_be_bytes(data.get(at..at + 2)?.try_into().ok()?))
+}
+
+fn be_u32(data: &[u8], at: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(data.get(at..at + 4)?.try_into().ok()?))
+}
+
+fn s15_fixed16(data: &[u8], at: usize) -> Option<f32> {
+    Some(i32::from_be_bytes(data.get(at..at + 4)?.try_into().ok()?) as f32 / 65536.0)
+}
+
+/// 按签名查找标签数据
+fn icc_tag<'a>(profile: &'a [u8], signature: &[u8; 4]) -> Option<&'a [u8]> {
+    let count = be_u32(profile, 128)? as usize;
+    (0..count.min(256)).find_map(|i| {
+        let entry = 132 + i * 12;
+        if profile.get(entry..entry + 4)? != signature {
+            return None;
+        }
+        let offset = be_u32(profile, entry + 4)? as usize;
+        let size = be_u32(profile, entry + 8)? as usize;
+        profile.get(offset..offset.checked_add(size)?)
+    })
+}
+
+fn parse_xyz(tag: &[u8]) -> Option<[f32; 3]> {
+    if tag.get(..4)? != b"XYZ " {
+        return None;
+    }
+    Some([s15_fixed16(tag, 8)?, s15_fixed16(tag, 12)?, s15_fixed16(tag, 16)?])
+}
+
+fn parse_curve(tag: &[u8]) -> Option<Curve> {
+    match tag.get(..4)? {
+        b"curv" => match be_u32(tag, 8)? {
+            0 => Some(Curve::Gamma(1.0)),
+            1 => Some(Curve::Gamma(be_u16(tag, 12)? as f32 / 256.0)),
+            n => {
+                let table = (0..n as usize)
+                    .map(|i| be_u16(tag, 12 + i * 2).map(|v| v as f32 / 65535.0))
+                    .collect::<Option<Vec<f32>>>()?;
+                Some(Curve::Table(table))
+            }
+        },
+        b"para" => {
+            let kind = be_u16(tag, 8)?;
+            let count = match kind {
+                0 => 1,
+                1 => 3,
+                2 => 4,
+                3 => 5,
+                4 => 7,
+                _ => return None,
+            };
+            let mut params = [0.0; 7];
+            for (i, param) in params.iter_mut().take(count).enumerate() {
+                *param = s15_fixed16(tag, 12 + i * 4)?;
+            }
+            Some(Curve::Parametric(kind, params))
+        }
+        _ => None,
+    }
+}
+
+/// 矩阵/TRC 型 RGB 配置文件到 sRGB 的转换 (查表实现)
+pub struct SrgbTransform {
+    /// 每通道 8 位编码值 → 线性光
+    decode: [[f32; 256]; 3],
+    matrix: [[f32; 3]; 3],
+    /// 线性光 (0~1 等分 4096 份) → sRGB 8 位编码值
+    encode: Vec<u8>,
+}
+
+impl SrgbTransform {
+    /// 解析 ICC 配置文件；不支持的类型或本身就是 sRGB 时返回 None (无需转换)
+    pub fn from_icc(profile: &[u8]) -> Option<Self> {
+        if profile.get(16..20)? != b"RGB " {
+            return None;
+        }
+        let columns = [
+            parse_xyz(icc_tag(profile, b"rXYZ")?)?,
+            parse_xyz(icc_tag(profile, b"gXYZ")?)?,
+            parse_xyz(icc_tag(profile, b"bXYZ")?)?,
+        ];
+        let curves = [
+            parse_curve(icc_tag(profile, b"rTRC")?)?,
+            parse_curve(icc_tag(profile, b"gTRC")?)?,
+            parse_curve(icc_tag(profile, b"bTRC")?)?,
+        ];
+
+        let mut matrix = [[0.0f32; 3]; 3];
+        for (row, out) in matrix.iter_mut().enumerate() {
+            for (col, value) in out.iter_mut().enumerate() {
+                *value = (0..3).map(|k| XYZ_D50_TO_SRGB[row][k] * columns[col][k]).sum();
+            }
+        }
+        let mut decode = [[0.0f32; 256]; 3];
+        for (channel, curve) in curves.iter().enumerate() {
+            for (i, value) in decode[channel].iter_mut().enumerate() {
+                *value = curve.eval(i as f32 / 255.0);
+            }
+        }
+
+        let identity_matrix = matrix
+            .iter()
+            .enumerate()
+            .all(|(row, values)| values.iter().enumerate().all(|(col, v)| (v - if row == col { 1.0 } else { 0.0 }).abs() < 0.01));
+        let srgb_curves = decode
+            .iter()
+            .all(|table| table.iter().enumerate().all(|(i, v)| (v - srgb_to_linear(i as f32 / 255.0)).abs() < 0.005));
+        if identity_matrix && srgb_curves {
+            return None;
+        }
+
+        let encode = (0..4096)
+            .map(|i| (linear_to_srgb(i as f32 / 4095.0) * 255.0).round() as u8)
+            .collect();
+        Some(Self { decode, matrix, encode })
+    }
+
+    /// 就地转换，超出 sRGB 色域的颜色截断
+    pub fn apply(&self, image: &mut image::RgbImage) {
+        for pixel in image.pixels_mut() {
+            let linear = [
+                self.decode[0][pixel[0] as usize],
+                self.decode[1][pixel[1] as usize],
+                self.decode[2][pixel[2] as usize],
+            ];
+            for (channel, row) in self.matrix.iter().enumerate() {
+                let value = row[0] * linear[0] + row[1] * linear[1] + row[2] * linear[2];
+                pixel.0[channel] = self.encode[(value.clamp(0.0, 1.0) * 4095.0).round() as usize];
+            }
+        }
+    }
+}
+
+/// ICC 配置文件每个 APP2 段最多携带的字节数
+const ICC_CHUNK_BYTES: usize = 65519;
+
+/// 把 EXIF 与 ICC 配置文件作为 APP1 / APP2 段插入编码好的 JPEG (JFIF 段之后)
+pub fn embed_jpeg_metadata(jpeg: Vec<u8>, exif: Option<&[u8]>, icc: Option<&[u8]>) -> Vec<u8> {
+    if jpeg.get(..2) != Some(&[0xFF, 0xD8][..]) {
+        return jpeg;
+    }
+    let mut insert_at = 2;
+    if jpeg.get(2..4) == Some(&[0xFF, 0xE0][..]) {
+        if let Some(len) = be_u16(&jpeg, 4) {
+            insert_at = (4 + len as usize).min(jpeg.len());
+        }
+    }
+
+    let mut segments = Vec::new();
+    let mut push_segment = |marker: u8, parts: &[&[u8]]| {
+        let len: usize = parts.iter().map(|p| p.len()).sum::<usize>() + 2;
+        segments.extend_from_slice(&[0xFF, marker]);
+        segments.extend_from_slice(&(len as u16).to_be_bytes());
+        for part in parts {
+            segments.extend_from_slice(part);
+        }
+    };
+    // 单个段最长 65535 字节，放不下的 EXIF (通常是超大缩略图) 直接丢弃
+    if let Some(exif) = exif.filter(|e| e.len() + 8 <= u16::MAX as usize) {
+        push_segment(0xE1, &[b"Exif\0\0", exif]);
+    }
+    if let Some(icc) = icc {
+        let chunks: Vec<&[u8]> = icc.chunks(ICC_CHUNK_BYTES).collect();
+        if chunks.len() < 256 {
+            for (i, chunk) in chunks.iter().enumerate() {
+                push_segment(0xE2, &[b"ICC_PROFILE\0", &[(i + 1) as u8, chunks.len() as u8], chunk]);
+            }
+        }
+    }
+
+    let mut out = Vec::with_capacity(jpeg.len() + segments.len());
+    out.extend_from_slice(&jpeg[..insert_at]);
+    out.extend_from_slice(&segments);
+    out.extend_from_slice(&jpeg[insert_at..]);
+    out
+}
@@ -0,0 +1,167 @@
+//! 自检接口 `GET /api/admin/diagnostics`：汇总排查问题常用的信息，便于附在问题报告里
+//!
+//! - 图库根目录是否存在、可读、可写，所在磁盘的剩余空间
+//! - 数据库文件大小、`PRAGMA integrity_check` 与外键检查结果
+//! - 缩略图缓存大小
+//! - 时钟偏差：与客户端 (`Date` 请求头或 `?client_time=` Unix 秒) 比较，并检查数据库中是否有"未来"的时间戳
+//! - 版本、目标平台与启用的 cargo feature
+
+use std::{
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use axum::{
+    extract::{Query, State},
+    http::{header, HeaderMap},
+    Json,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{enabled_features, AppState, CACHE_DIR_NAME};
+
+/// 完整性检查最多报告的问题条数
+const INTEGRITY_MAX_ERRORS: i64 = 20;
+
+#[derive(Debug, Deserialize)]
+pub struct DiagnosticsQuery {
+    /// 客户端当前时间 (Unix 秒)，优先于 `Date` 请求头
+    client_time: Option<f64>,
+}
+
+#[derive(Serialize)]
+struct RootDirReport {
+    path: String,
+    exists: bool,
+    readable: bool,
+    writable: bool,
+    available_bytes: Option<u64>,
+    total_bytes: Option<u64>,
+    error: Option<String>,
+}
+
+#[derive(Serialize)]
+struct DatabaseReport {
+    /// 主文件与 WAL / SHM 的总大小
+    size_bytes: u64,
+    /// `ok` 或发现的问题 (最多 20 条)
+    integrity: Vec<String>,
+    foreign_key_violations: i64,
+}
+
+#[derive(Serialize)]
+struct ClockReport {
+    server_time: f64,
+    client_time: Option<f64>,
+    /// 服务器时间减客户端时间 (秒)，正数表示服务器偏快
+    skew_secs: Option<f64>,
+    /// 数据库里最新的时间戳超出当前时间的秒数 (时钟曾被回拨时出现)
+    db_future_secs: Option<f64>,
+}
+
+/// 目录下文件的总字节数 (不存在时为 0)
+pub(crate) fn dir_size(dir: &Path) -> u64 {
+    walkdir::WalkDir::new(dir)
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter_map(|e| e.metadata().ok())
+        .filter(|m| m.is_file())
+        .map(|m| m.len())
+        .sum()
+}
+
+/// 数据库文件 (含 WAL / SHM) 的总字节数
+pub(crate) fn database_size(root_dir: &Path) -> u64 {
+    ["gallery_metadata.db", "gallery_metadata.db-wal", "gallery_metadata.db-shm"]
+        .iter()
+        .filter_map(|name| std::fs::metadata(root_dir.join(name)).ok())
+        .map(|m| m.len())
+        .sum()
+}
+
+/// 根目录的可访问性：能否列目录、能否写入临时文件
+fn check_root_dir(root_dir: &Path) -> RootDirReport {
+    let exists = root_dir.is_dir();
+    let read = std::fs::read_dir(root_dir).map(|_| ());
+    let probe = root_dir.join(format!(".gallery-diagnostics-{}", std::process::id()));
+    let write = std::fs::write(&probe, b"probe").and_then(|_| std::fs::remove_file(&probe));
+    let stats = fs4::statvfs(root_dir);
+    let error = [read.as_ref().err(), write.as_ref().err()]
+        .into_iter()
+        .flatten()
+        .map(|e| e.to_string())
+        .next();
+    RootDirReport {
+        path: root_dir.display().to_string(),
+        exists,
+        readable: read.is_ok(),
+        writable: write.is_ok(),
+        available_bytes: stats.as_ref().ok().map(|s| s.available_space()),
+        total_bytes: stats.as_ref().ok().map(|s| s.total_space()),
+        error,
+    }
+}
+
+/// `Date` 请求头 (RFC 2822 / IMF-fixdate) 转 Unix 秒
+fn client_time_from_headers(headers: &HeaderMap) -> Option<f64> {
+    let date = headers.get(header::DATE)?.to_str().ok()?;
+    chrono::DateTime::parse_from_rfc2822(date).ok().map(|t| t.timestamp() as f64)
+}
+
+/// 接口: GET /api/admin/diagnostics
+pub async fn diagnostics(
+    State(state): State<AppState>,
+    Query(query): Query<DiagnosticsQuery>,
+    headers: HeaderMap,
+) -> Json<serde_json::Value> {
+    let root_dir = state.root_dir.clone();
+    let (root, database_bytes, cache_bytes) = tokio::task::spawn_blocking(move || {
+        (check_root_dir(&root_dir), database_size(&root_dir), dir_size(&root_dir.join(CACHE_DIR_NAME)))
+    })
+    .await
+    .expect("diagnostics task panicked");
+
+    let integrity: Vec<String> = sqlx::query_scalar(&format!("PRAGMA integrity_check({})", INTEGRITY_MAX_ERRORS))
+        .fetch_all(&state.db)
+        .await
+        .unwrap_or_else(|e| vec![format!("integrity_check failed: {}", e)]);
+    let foreign_key_violations: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM pragma_foreign_key_check")
+        .fetch_one(&state.db)
+        .await
+        .unwrap_or(-1);
+
+    let server_time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs_f64();
+    let client_time = query.client_time.or_else(|| client_time_from_headers(&headers));
+    let newest: Option<f64> = sqlx::query_scalar(
+        "SELECT MAX(t) FROM (SELECT MAX(created_at) AS t FROM jobs UNION ALL SELECT MAX(at) FROM access_log
+             UNION ALL SELECT MAX(created_at) FROM playlists)",
+    )
+    .fetch_one(&state.db)
+    .await
+    .unwrap_or(None);
+
+    let decoded_images = state.decoded_images.lock().map(|cache| cache.entries.len()).unwrap_or(0);
+    Json(serde_json::json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "build": {
+            "profile": if cfg!(debug_assertions) { "debug" } else { "release" },
+            "target_os": std::env::consts::OS,
+            "target_arch": std::env::consts::ARCH,
+            "features": enabled_features(),
+        },
+        "root_dir": root,
+        "database": DatabaseReport { size_bytes: database_bytes, integrity, foreign_key_violations },
+        "cache": {
+            "dir": state.root_dir.join(CACHE_DIR_NAME).display().to_string(),
+            "size_bytes": cache_bytes,
+            "decoded_images": decoded_images,
+        },
+        "clock": ClockReport {
+            server_time,
+            client_time,
+            skew_secs: client_time.map(|t| server_time - t),
+            db_future_secs: newest.map(|t| t - server_time).filter(|d| *d > 1.0),
+        },
+        "index_freshness": *state.index_freshness.read().unwrap(),
+    }))
+}
@@ -0,0 +1,168 @@
+//! 可选的人脸检测与特征提取 (cargo feature `onnx`)
+//!
+//! 需要两个模型，均通过环境变量配置：
+//! - `GALLERY_FACE_DETECTOR_MODEL`: UltraFace 风格检测器 (输出 `scores` [1,N,2] 与归一化 `boxes` [1,N,4])
+//! - `GALLERY_FACE_EMBEDDER_MODEL`: ArcFace 风格特征模型 (112x112 输入，输出人脸向量)
+//! - `GALLERY_FACE_INPUT_WIDTH` / `GALLERY_FACE_INPUT_HEIGHT`: 检测器输入尺寸，默认 320x240
+//! - `GALLERY_FACE_MIN_SCORE`: 检测置信度下限，默认 0.7
+
+use std::{path::Path, sync::Mutex};
+
+use anyhow::{anyhow, Context, Result};
+use image::{imageops::FilterType, DynamicImage, RgbImage};
+use ort::{session::Session, value::Tensor};
+
+use crate::autotag::{env_parse, l2_normalize};
+
+const EMBEDDER_INPUT: u32 = 112;
+const NMS_IOU: f32 = 0.3;
+/// 太小的人脸无法得到可靠特征
+const MIN_FACE_PIXELS: f32 = 24.0;
+
+pub struct FaceAnalyzer {
+    detector: Mutex<Session>,
+    embedder: Mutex<Session>,
+    input_width: u32,
+    input_height: u32,
+    min_score: f32,
+}
+
+pub struct DetectedFace {
+    /// 归一化坐标 (x, y, w, h)，相对原图
+    pub bbox: [f32; 4],
+    pub score: f32,
+    pub embedding: Vec<f32>,
+}
+
+impl FaceAnalyzer {
+    /// 未配置检测器时返回 `Ok(None)`
+    pub fn from_env() -> Result<Option<Self>> {
+        let Some(detector_path) = crate::config_var("GALLERY_FACE_DETECTOR_MODEL").ok().filter(|v| !v.trim().is_empty())
+        else {
+            return Ok(None);
+        };
+        let embedder_path = crate::config_var("GALLERY_FACE_EMBEDDER_MODEL")
+            .context("GALLERY_FACE_EMBEDDER_MODEL is required together with GALLERY_FACE_DETECTOR_MODEL")?;
+
+        let load = |path: &str| -> Result<Session> {
+            Session::builder()?
+                .with_intra_threads(2)?
+                .commit_from_file(path)
+                .with_context(|| format!("failed to load ONNX model {}", path))
+        };
+
+        Ok(Some(Self {
+            detector: Mutex::new(load(&detector_path)?),
+            embedder: Mutex::new(load(&embedder_path)?),
+            input_width: env_parse("GALLERY_FACE_INPUT_WIDTH").unwrap_or(320),
+            input_height: env_parse("GALLERY_FACE_INPUT_HEIGHT").unwrap_or(240),
+            min_score: env_parse("GALLERY_FACE_MIN_SCORE").unwrap_or(0.7),
+        }))
+    }
+
+    /// 检测并提取图片中所有人脸 (阻塞调用，应放在 spawn_blocking 中)
+    pub fn analyze(&self, full_path: &Path) -> Result<Vec<DetectedFace>> {
+        let img = image::open(full_path)?;
+        let (img_w, img_h) = (img.width() as f32, img.height() as f32);
+
+        let boxes = self.detect(&img)?;
+        let mut faces = Vec::new();
+        for (bbox, score) in boxes {
+            let [x, y, w, h] = bbox;
+            if w * img_w < MIN_FACE_PIXELS || h * img_h < MIN_FACE_PIXELS {
+                continue;
+            }
+            let crop = img
+                .crop_imm((x * img_w) as u32, (y * img_h) as u32, (w * img_w) as u32, (h * img_h) as u32)
+                .resize_exact(EMBEDDER_INPUT, EMBEDDER_INPUT, FilterType::Triangle)
+                .to_rgb8();
+            faces.push(DetectedFace {
+                bbox,
+                score,
+                embedding: self.embed(&crop)?,
+            });
+        }
+        Ok(faces)
+    }
+
+    fn detect(&self, img: &DynamicImage) -> Result<Vec<([f32; 4], f32)>> {
+        let rgb = img
+            .resize_exact(self.input_width, self.input_height, FilterType::Triangle)
+            .to_rgb8();
+        let input = to_nchw(&rgb, 127.0, 128.0);
+        let tensor = Tensor::from_array((
+            [1usize, 3, self.input_height as usize, self.input_width as usize],
+            input,
+        ))?;
+
+        let mut detector = self.detector.lock().map_err(|_| anyhow!("ONNX session poisoned"))?;
+        let outputs = detector.run(ort::inputs![tensor])?;
+        let scores = outputs
+            .get("scores")
+            .unwrap_or(&outputs[0])
+            .try_extract_tensor::<f32>()?
+            .1;
+        let boxes = outputs
+            .get("boxes")
+            .unwrap_or(&outputs[1])
+            .try_extract_tensor::<f32>()?
+            .1;
+
+        let mut candidates: Vec<([f32; 4], f32)> = scores
+            .chunks_exact(2)
+            .zip(boxes.chunks_exact(4))
+            .filter(|(s, _)| s[1] >= self.min_score)
+            .map(|(s, b)| {
+                let x1 = b[0].clamp(0.0, 1.0);
+                let y1 = b[1].clamp(0.0, 1.0);
+                let x2 = b[2].clamp(0.0, 1.0);
+                let y2 = b[3].clamp(0.0, 1.0);
+                ([x1, y1, (x2 - x1).max(0.0), (y2 - y1).max(0.0)], s[1])
+            })
+            .collect();
+        candidates.sort_by(|a, b| b.1.total_cmp(&a.1));
+
+        // 非极大值抑制
+        let mut kept: Vec<([f32; 4], f32)> = Vec::new();
+        for candidate in candidates {
+            if kept.iter().all(|k| iou(&k.0, &candidate.0) < NMS_IOU) {
+                kept.push(candidate);
+            }
+        }
+        Ok(kept)
+    }
+
+    fn embed(&self, face: &RgbImage) -> Result<Vec<f32>> {
+        let input = to_nchw(face, 127.5, 128.0);
+        let size = EMBEDDER_INPUT as usize;
+        let tensor = Tensor::from_array(([1usize, 3, size, size], input))?;
+        let mut embedder = self.embedder.lock().map_err(|_| anyhow!("ONNX session poisoned"))?;
+        let outputs = embedder.run(ort::inputs![tensor])?;
+        Ok(l2_normalize(outputs[0].try_extract_tensor::<f32>()?.1.to_vec()))
+    }
+}
+
+fn to_nchw(rgb: &RgbImage, mean: f32, scale: f32) -> Vec<f32> {
+    let plane = (rgb.width() * rgb.height()) as usize;
+    let mut input = vec![0f32; plane * 3];
+    for (i, pixel) in rgb.pixels().enumerate() {
+        for c in 0..3 {
+            input[c * plane + i] = (pixel[c] as f32 - mean) / scale;
+        }
+    }
+    input
+}
+
+fn iou(a: &[f32; 4], b: &[f32; 4]) -> f32 {
+    let x1 = a[0].max(b[0]);
+    let y1 = a[1].max(b[1]);
+    let x2 = (a[0] + a[2]).min(b[0] + b[2]);
+    let y2 = (a[1] + a[3]).min(b[1] + b[3]);
+    let inter = (x2 - x1).max(0.0) * (y2 - y1).max(0.0);
+    let union = a[2] * a[3] + b[2] * b[3] - inter;
+    if union > 0.0 {
+        inter / union
+    } else {
+        0.0
+    }
+}
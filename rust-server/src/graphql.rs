@@ -0,0 +1,386 @@
+//! 可选的 GraphQL 接口 (cargo feature `graphql`)
+//!
+//! - `POST /api/graphql`: 执行查询 (只读)
+//! - `GET /api/graphql`: GraphiQL 调试页面
+//!
+//! 覆盖 images / image / folder / albums / tags / sessions，字段与对应的 REST 接口一致；
+//! 标题、标签等附加字段只在被请求时才查询数据库。过滤条件与 `POST /api/query` 共用同一套实现。
+
+use std::{collections::HashSet, sync::OnceLock};
+
+use async_graphql::{
+    http::GraphiQLSource, Context, EmptyMutation, EmptySubscription, InputObject, Object, Result, Schema,
+    SimpleObject,
+};
+use axum::{
+    extract::State,
+    response::{Html, IntoResponse},
+    Json,
+};
+
+use crate::{
+    escape_like_pattern, external_file_id, load_folder_meta, load_image_caption, load_image_tags, normalize_rel_path, parent_folder,
+    resolve_and_authorize, run_image_query, AppState, FolderMeta, FolderRow, FOLDER_META_COLUMNS, GenerationStatus, ImageCaption,
+    ImageMetadata, PathAccessError, QueryFilter, TRASH_DIR_NAME,
+};
+
+type GallerySchema = Schema<QueryRoot, EmptyMutation, EmptySubscription>;
+
+/// 单个查询的嵌套深度与复杂度上限，防止一次请求展开整个图库
+const MAX_DEPTH: usize = 10;
+const MAX_COMPLEXITY: usize = 5000;
+const MAX_PAGE_SIZE: usize = 1000;
+
+fn schema() -> &'static GallerySchema {
+    static SCHEMA: OnceLock<GallerySchema> = OnceLock::new();
+    SCHEMA.get_or_init(|| {
+        Schema::build(QueryRoot, EmptyMutation, EmptySubscription)
+            .limit_depth(MAX_DEPTH)
+            .limit_complexity(MAX_COMPLEXITY)
+            .finish()
+    })
+}
+
+/// 接口: POST /api/graphql
+pub async fn execute(State(state): State<AppState>, Json(request): Json<async_graphql::Request>) -> impl IntoResponse {
+    Json(schema().execute(request.data(state)).await)
+}
+
+/// 接口: GET /api/graphql
+pub async fn graphiql(State(state): State<AppState>) -> impl IntoResponse {
+    Html(GraphiQLSource::build().endpoint(&format!("{}/api/v1/graphql", state.base_path)).finish())
+}
+
+/// 同 `QueryFilter`
+#[derive(InputObject, Default)]
+struct ImageFilter {
+    #[graphql(default)]
+    paths: Vec<String>,
+    #[graphql(default)]
+    tags: Vec<String>,
+    #[graphql(default)]
+    people: Vec<i64>,
+    min_rating: Option<i64>,
+    max_rating: Option<i64>,
+    date_from: Option<String>,
+    date_to: Option<String>,
+    orientation: Option<String>,
+    #[graphql(default)]
+    panoramas_only: bool,
+    #[graphql(default)]
+    include_panoramas: bool,
+    kind: Option<String>,
+    text: Option<String>,
+}
+
+impl From<ImageFilter> for QueryFilter {
+    fn from(f: ImageFilter) -> Self {
+        QueryFilter {
+            paths: f.paths,
+            tags: f.tags,
+            people: f.people,
+            min_rating: f.min_rating,
+            max_rating: f.max_rating,
+            date_from: f.date_from,
+            date_to: f.date_to,
+            orientation: f.orientation,
+            panoramas_only: f.panoramas_only,
+            include_panoramas: f.include_panoramas,
+            kind: f.kind,
+            text: f.text,
+        }
+    }
+}
+
+struct Image(ImageMetadata);
+
+#[Object]
+impl Image {
+    async fn path(&self) -> &str {
+        &self.0.path
+    }
+    async fn folder(&self) -> String {
+        parent_folder(&self.0.path)
+    }
+    /// 原图地址 (相对服务器根)；ROOT_DIR 之外的文件使用稳定 ID
+    async fn url(&self, ctx: &Context<'_>) -> Result<String> {
+        let state = ctx.data::<AppState>()?;
+        Ok(match external_file_id(&state.db, &self.0.path).await {
+            Some(id) => format!("{}/api/v1/file/{}", state.base_path, id),
+            None => format!("{}/api/v1/file?path={}", state.base_path, urlencoding::encode(&self.0.path)),
+        })
+    }
+    async fn width(&self) -> u32 {
+        self.0.width
+    }
+    async fn height(&self) -> u32 {
+        self.0.height
+    }
+    async fn orientation(&self) -> &str {
+        if self.0.is_landscape { "landscape" } else { "portrait" }
+    }
+    async fn panorama(&self) -> bool {
+        self.0.is_panorama
+    }
+    async fn mtime(&self) -> f64 {
+        self.0.mtime
+    }
+    async fn size(&self) -> i64 {
+        self.0.size
+    }
+    async fn taken_at(&self) -> Option<f64> {
+        self.0.taken_at
+    }
+    async fn added_at(&self) -> Option<f64> {
+        self.0.added_at
+    }
+    async fn color_profile(&self) -> Option<&str> {
+        self.0.color_profile.as_deref()
+    }
+    async fn hdr_gain_map(&self) -> Option<bool> {
+        self.0.hdr_gain_map
+    }
+    async fn title(&self, ctx: &Context<'_>) -> Result<Option<String>> {
+        Ok(self.caption_row(ctx).await?.title)
+    }
+    async fn caption(&self, ctx: &Context<'_>) -> Result<Option<String>> {
+        Ok(self.caption_row(ctx).await?.caption)
+    }
+    async fn rating(&self, ctx: &Context<'_>) -> Result<Option<i64>> {
+        Ok(self.caption_row(ctx).await?.rating)
+    }
+    async fn tags(&self, ctx: &Context<'_>) -> Result<Vec<String>> {
+        let state = ctx.data::<AppState>()?;
+        Ok(load_image_tags(&state.db, &self.0.path).await)
+    }
+}
+
+impl Image {
+    async fn caption_row(&self, ctx: &Context<'_>) -> Result<ImageCaption> {
+        let state = ctx.data::<AppState>()?;
+        Ok(load_image_caption(&state.db, &self.0.path).await)
+    }
+}
+
+#[derive(SimpleObject)]
+struct ImagePage {
+    total: usize,
+    offset: usize,
+    items: Vec<Image>,
+}
+
+struct Folder {
+    path: String,
+    meta: Option<FolderMeta>,
+}
+
+#[Object]
+impl Folder {
+    /// 相对 ROOT_DIR，根目录为空字符串
+    async fn path(&self) -> &str {
+        &self.path
+    }
+    async fn name(&self) -> &str {
+        self.path.rsplit('/').next().unwrap_or_default()
+    }
+    async fn title(&self) -> Option<&str> {
+        self.meta.as_ref()?.title.as_deref()
+    }
+    async fn description(&self) -> Option<&str> {
+        self.meta.as_ref()?.description.as_deref()
+    }
+    async fn cover(&self) -> Option<&str> {
+        self.meta.as_ref()?.cover.as_deref()
+    }
+    async fn sort(&self) -> Option<&str> {
+        self.meta.as_ref()?.sort.as_deref()
+    }
+    async fn orientation(&self) -> Option<&str> {
+        self.meta.as_ref()?.orientation.as_deref()
+    }
+    /// 默认播放间隔 (秒)
+    async fn interval_secs(&self) -> Option<u32> {
+        self.meta.as_ref()?.interval_secs
+    }
+    /// 冷存储：原片可能离线
+    async fn archived(&self) -> bool {
+        self.meta.as_ref().is_some_and(|meta| meta.archived)
+    }
+    async fn archive_note(&self) -> Option<&str> {
+        self.meta.as_ref()?.archive_note.as_deref()
+    }
+    /// 直接子文件夹 (按名称)
+    async fn subfolders(&self, ctx: &Context<'_>) -> Result<Vec<Folder>> {
+        let state = ctx.data::<AppState>()?;
+        let allow_parent = *state.allow_parent_dir_access.read().await;
+        let Ok(dir) = resolve_and_authorize(&state.root_dir, &self.path, allow_parent) else {
+            return Ok(Vec::new());
+        };
+        let mut names: Vec<String> = std::fs::read_dir(dir)
+            .map(|entries| {
+                entries
+                    .flatten()
+                    .filter(|e| e.file_type().is_ok_and(|t| t.is_dir()))
+                    .map(|e| e.file_name().to_string_lossy().into_owned())
+                    .filter(|name| !name.starts_with('.') && name != TRASH_DIR_NAME)
+                    .collect()
+            })
+            .unwrap_or_default();
+        names.sort_by(|a, b| natord::compare_ignore_case(a, b));
+        let mut folders = Vec::with_capacity(names.len());
+        for name in names {
+            let path = if self.path.is_empty() { name } else { format!("{}/{}", self.path, name) };
+            let meta = load_folder_meta(state, &path).await;
+            folders.push(Folder { path, meta });
+        }
+        Ok(folders)
+    }
+    /// 文件夹 (含子文件夹) 内的图片，默认按文件夹设置的排序
+    async fn images(
+        &self,
+        ctx: &Context<'_>,
+        sort: Option<String>,
+        #[graphql(default = "forward")] direction: String,
+        #[graphql(default)] offset: usize,
+        #[graphql(default = 100)] limit: usize,
+    ) -> Result<ImagePage> {
+        let filter = ImageFilter { paths: vec![self.path.clone()], ..Default::default() };
+        let sort = sort.or_else(|| self.meta.as_ref()?.sort.clone()).unwrap_or_else(|| "name".to_string());
+        image_page(ctx, filter, &sort, &direction, offset, limit).await
+    }
+}
+
+#[derive(SimpleObject)]
+struct TagCount {
+    tag: String,
+    count: i64,
+}
+
+#[derive(SimpleObject)]
+struct Session {
+    client: String,
+    generation_status: String,
+    playlist_size: usize,
+    /// 最近一次展示的图片
+    now_showing: Option<String>,
+    now_showing_at: Option<f64>,
+    /// 生成该播放列表的条件 (同 `/api/session-status`)
+    criteria: Option<async_graphql::Json<serde_json::Value>>,
+}
+
+async fn image_page(
+    ctx: &Context<'_>,
+    filter: ImageFilter,
+    sort: &str,
+    direction: &str,
+    offset: usize,
+    limit: usize,
+) -> Result<ImagePage> {
+    let state = ctx.data::<AppState>()?;
+    let images = run_image_query(state, &filter.into(), sort, direction, &HashSet::new()).await?;
+    let total = images.len();
+    let items = images.into_iter().skip(offset).take(limit.min(MAX_PAGE_SIZE)).map(Image).collect();
+    Ok(ImagePage { total, offset, items })
+}
+
+struct QueryRoot;
+
+#[Object]
+impl QueryRoot {
+    /// 结构化过滤 + 排序 + 分页 (同 `POST /api/query`)
+    async fn images(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(default)] filter: ImageFilter,
+        #[graphql(default = "name")] sort: String,
+        #[graphql(default = "forward")] direction: String,
+        #[graphql(default)] offset: usize,
+        #[graphql(default = 100)] limit: usize,
+    ) -> Result<ImagePage> {
+        image_page(ctx, filter, &sort, &direction, offset, limit).await
+    }
+
+    async fn image(&self, ctx: &Context<'_>, path: String) -> Result<Option<Image>> {
+        let state = ctx.data::<AppState>()?;
+        let allow_parent = *state.allow_parent_dir_access.read().await;
+        let rel = normalize_rel_path(&path);
+        if resolve_and_authorize(&state.root_dir, &rel, allow_parent) == Err(PathAccessError::Forbidden) {
+            return Err("Access outside ROOT_DIR is disabled".into());
+        }
+        let row = sqlx::query_as::<_, ImageMetadata>("SELECT * FROM images WHERE path = ?")
+            .bind(&rel)
+            .fetch_optional(&state.db)
+            .await?;
+        Ok(row.map(Image))
+    }
+
+    /// 文件夹 (默认根目录)
+    async fn folder(&self, ctx: &Context<'_>, #[graphql(default)] path: String) -> Result<Option<Folder>> {
+        let state = ctx.data::<AppState>()?;
+        let allow_parent = *state.allow_parent_dir_access.read().await;
+        let mut rel = normalize_rel_path(&path);
+        if rel == "." {
+            rel.clear();
+        }
+        match resolve_and_authorize(&state.root_dir, &rel, allow_parent) {
+            Ok(full) if full.is_dir() => {}
+            Err(PathAccessError::Forbidden) => return Err("Access outside ROOT_DIR is disabled".into()),
+            _ => return Ok(None),
+        }
+        let meta = load_folder_meta(state, &rel).await;
+        Ok(Some(Folder { path: rel, meta }))
+    }
+
+    /// 设置了标题、说明、封面或排序的文件夹 (相册)
+    async fn albums(&self, ctx: &Context<'_>) -> Result<Vec<Folder>> {
+        let state = ctx.data::<AppState>()?;
+        let rows: Vec<FolderRow> =
+            sqlx::query_as(&format!("SELECT path, {} FROM folders ORDER BY path", FOLDER_META_COLUMNS))
+                .fetch_all(&state.db)
+                .await?;
+        Ok(rows.into_iter().map(|row| Folder { path: row.path, meta: Some(row.meta) }).collect())
+    }
+
+    /// 标签及其图片数 (按数量从多到少)
+    async fn tags(&self, ctx: &Context<'_>, prefix: Option<String>) -> Result<Vec<TagCount>> {
+        let state = ctx.data::<AppState>()?;
+        let rows: Vec<(String, i64)> = sqlx::query_as(
+            "SELECT tag, COUNT(*) AS n FROM image_tags WHERE tag LIKE ? ESCAPE '\\' GROUP BY tag ORDER BY n DESC, tag",
+        )
+        .bind(format!("{}%", escape_like_pattern(prefix.as_deref().unwrap_or(""))))
+        .fetch_all(&state.db)
+        .await?;
+        Ok(rows.into_iter().map(|(tag, count)| TagCount { tag, count }).collect())
+    }
+
+    /// 当前内存中的客户端会话
+    async fn sessions(&self, ctx: &Context<'_>) -> Result<Vec<Session>> {
+        let state = ctx.data::<AppState>()?;
+        let now_showing = state.now_showing.read().await;
+        let sessions = state.user_sessions.read().await;
+        let mut result: Vec<Session> = sessions
+            .iter()
+            .map(|(client, session)| {
+                let showing = now_showing.get(client);
+                Session {
+                    client: client.clone(),
+                    generation_status: match session.generation_status {
+                        GenerationStatus::Pending => "pending",
+                        GenerationStatus::Complete => "complete",
+                    }
+                    .to_string(),
+                    playlist_size: session.playlist.len(),
+                    now_showing: showing.map(|s| s.path.clone()),
+                    now_showing_at: showing.map(|s| s.at),
+                    criteria: session
+                        .criteria
+                        .as_ref()
+                        .and_then(|c| serde_json::to_value(c).ok())
+                        .map(async_graphql::Json),
+                }
+            })
+            .collect();
+        result.sort_by(|a, b| a.client.cmp(&b.client));
+        Ok(result)
+    }
+}
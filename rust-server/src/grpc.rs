@@ -0,0 +1,206 @@
+//! 可选的 gRPC 接口 (cargo feature `grpc`)
+//!
+//! 服务定义见 `proto/gallery.proto` (`gallery.v1.Gallery`)，与 HTTP 接口共用端口 (gRPC 需 HTTP/2：
+//! 明文时客户端以 prior knowledge 方式连接，TLS 时经 ALPN 协商)。行为与对应的 REST 接口一致，
+//! 面向不便解析 JSON 的相框固件。
+
+// 流与接口签名中的错误类型固定为 tonic::Status
+#![allow(clippy::result_large_err)]
+
+use std::{net::SocketAddr, pin::Pin, time::{SystemTime, UNIX_EPOCH}};
+
+use axum::extract::ConnectInfo;
+use futures::{Stream, StreamExt};
+use tokio_util::io::ReaderStream;
+use tonic::{Request, Response, Status};
+
+use crate::{
+    apply_folder_defaults, authorize_client_path, collect_image_info, generate_playlist, is_image_ext, load_image_edit,
+    path_has_symlink, playlist_for_client, prepare_request_paths, record_image_served, render_image, store_session_playlist, AppState,
+    GenerationStatus, NowShowing,
+    PathAccessError, PlaylistCriteria, PlaylistRequest, RenderSpec, Validate,
+};
+
+mod pb {
+    tonic::include_proto!("gallery.v1");
+}
+
+use pb::gallery_server::{Gallery, GalleryServer};
+
+/// 每个 `FileChunk` 的大小
+const CHUNK_SIZE: usize = 64 * 1024;
+/// 缩放请求允许的最大边长
+const MAX_RENDER_SIDE: u32 = 8192;
+
+/// 挂到 HTTP 路由上的 gRPC 服务
+pub fn router(state: AppState) -> axum::Router {
+    tonic::service::Routes::new(GalleryServer::new(GalleryService { state })).into_axum_router()
+}
+
+struct GalleryService {
+    state: AppState,
+}
+
+fn client_ip<T>(request: &Request<T>) -> String {
+    request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip().to_string())
+        .unwrap_or_default()
+}
+
+fn or_default(value: String, default: &str) -> String {
+    if value.is_empty() { default.to_string() } else { value }
+}
+
+fn status_from_http(status: axum::http::StatusCode, detail: &serde_json::Value) -> Status {
+    let message = detail.get("detail").and_then(|d| d.as_str()).unwrap_or_default().to_string();
+    match status {
+        axum::http::StatusCode::FORBIDDEN => Status::permission_denied(message),
+        axum::http::StatusCode::NOT_FOUND => Status::not_found(message),
+        axum::http::StatusCode::BAD_REQUEST | axum::http::StatusCode::UNPROCESSABLE_ENTITY => {
+            Status::invalid_argument(message)
+        }
+        _ => Status::internal(message),
+    }
+}
+
+type FileStream = Pin<Box<dyn Stream<Item = Result<pb::FileChunk, Status>> + Send>>;
+
+#[tonic::async_trait]
+impl Gallery for GalleryService {
+    async fn get_playlist(
+        &self,
+        request: Request<pb::PlaylistRequest>,
+    ) -> Result<Response<pb::PlaylistResponse>, Status> {
+        let ip = client_ip(&request);
+        let req = request.into_inner();
+        let mut req = PlaylistRequest {
+            paths: if req.paths.is_empty() { vec![".".to_string()] } else { req.paths },
+            // 空字符串表示未指定，可使用文件夹默认值
+            sort: (!req.sort.is_empty()).then_some(req.sort),
+            orientation: (!req.orientation.is_empty()).then_some(req.orientation),
+            direction: or_default(req.direction, "forward"),
+            current_path: req.current_path,
+            collation: self.state.default_collation.clone(),
+            tags: req.tags,
+            ..Default::default()
+        };
+        let errors = req.validate();
+        if !errors.is_empty() {
+            let message: Vec<String> = errors.iter().map(|e| format!("{}: {}", e.loc[1..].join("."), e.msg)).collect();
+            return Err(Status::invalid_argument(message.join("; ")));
+        }
+        let valid_paths = prepare_request_paths(&self.state, &req.paths).await;
+        apply_folder_defaults(&self.state, &mut req, &valid_paths).await;
+        let paths = generate_playlist(&self.state, &req, &valid_paths, &ip).await;
+        let criteria = PlaylistCriteria {
+            sort: req.sort_mode(),
+            orientation: req.orientation_filter(),
+            direction: req.direction,
+            paths: valid_paths,
+            interleave: false,
+            max_per_folder: None,
+            collation: req.collation,
+            tags: req.tags,
+            people: Vec::new(),
+            collapse_bursts: false,
+            scheduled: false,
+            schedule_rule: None,
+            mix: Vec::new(),
+            spread_by: None,
+            spread_distance: None,
+            boost_recent_days: None,
+            panoramas_only: false,
+            include_panoramas: false,
+        };
+        store_session_playlist(&self.state, &ip, paths.clone(), Some(criteria), GenerationStatus::Complete).await;
+        let paths = playlist_for_client(&self.state, paths).await;
+        Ok(Response::new(pb::PlaylistResponse { paths }))
+    }
+
+    async fn get_image_info(&self, request: Request<pb::ImageInfoRequest>) -> Result<Response<pb::ImageInfo>, Status> {
+        let info = collect_image_info(&self.state, &request.into_inner().path)
+            .await
+            .map_err(|(status, axum::Json(detail))| status_from_http(status, &detail))?;
+        Ok(Response::new(pb::ImageInfo {
+            path: info.path,
+            width: info.width,
+            height: info.height,
+            orientation: info.orientation,
+            mtime: info.mtime,
+            size: info.size,
+            mime: info.mime,
+            hash: info.hash,
+            tags: info.tags,
+            title: info.caption.title,
+            caption: info.caption.caption,
+            rating: info.caption.rating,
+        }))
+    }
+
+    type StreamFileStream = FileStream;
+
+    async fn stream_file(&self, request: Request<pb::FileRequest>) -> Result<Response<FileStream>, Status> {
+        let ip = client_ip(&request);
+        let req = request.into_inner();
+        let (rel, full) = match authorize_client_path(&self.state, &req.path).await {
+            Ok((rel, full)) if full.is_file() => (rel, full),
+            Err(PathAccessError::Forbidden) => return Err(Status::permission_denied("Access outside ROOT_DIR is disabled")),
+            _ => return Err(Status::not_found("File not found")),
+        };
+        if !self.state.follow_symlinks && path_has_symlink(&self.state.root_dir, &full) {
+            return Err(Status::not_found("File not found"));
+        }
+        let edit = load_image_edit(&self.state.db, &rel).await;
+        if is_image_ext(&full) {
+            record_image_served(&self.state, &rel);
+            let at = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs_f64();
+            self.state.now_showing.write().await.insert(ip.clone(), NowShowing { path: rel, at });
+        }
+
+        if let (Some(width), Some(height)) = (req.max_width, req.max_height) {
+            if !(1..=MAX_RENDER_SIDE).contains(&width) || !(1..=MAX_RENDER_SIDE).contains(&height) {
+                return Err(Status::invalid_argument(format!("Size must be between 1 and {} pixels", MAX_RENDER_SIDE)));
+            }
+            let (bytes, mime) = tokio::task::spawn_blocking(move || render_image(&full, &RenderSpec { edit, ..RenderSpec::fit_within(width, height) }))
+                .await
+                .ok()
+                .flatten()
+                .ok_or_else(|| Status::failed_precondition("Unreadable image"))?;
+            let total_size = bytes.len() as u64;
+            let chunks: Vec<Result<pb::FileChunk, Status>> = bytes
+                .chunks(CHUNK_SIZE)
+                .enumerate()
+                .map(|(i, data)| {
+                    Ok(pb::FileChunk {
+                        data: data.to_vec(),
+                        mime: if i == 0 { mime.to_string() } else { String::new() },
+                        total_size: if i == 0 { total_size } else { 0 },
+                    })
+                })
+                .collect();
+            return Ok(Response::new(Box::pin(futures::stream::iter(chunks))));
+        }
+
+        let file = tokio::fs::File::open(&full).await.map_err(|e| Status::internal(e.to_string()))?;
+        let total_size = file.metadata().await.map(|m| m.len()).unwrap_or(0);
+        let mime = mime_guess::from_path(&full).first_or_octet_stream().to_string();
+        let limiter = self.state.bandwidth.read().unwrap().clone();
+        let stream = ReaderStream::with_capacity(file, CHUNK_SIZE).enumerate().then(move |(i, chunk)| {
+            let (limiter, ip, mime) = (limiter.clone(), ip.clone(), mime.clone());
+            async move {
+                let data = chunk.map_err(|e| Status::internal(e.to_string()))?;
+                if let Some(limiter) = limiter {
+                    limiter.pace(&ip, data.len()).await;
+                }
+                Ok(pb::FileChunk {
+                    data: data.to_vec(),
+                    mime: if i == 0 { mime } else { String::new() },
+                    total_size: if i == 0 { total_size } else { 0 },
+                })
+            }
+        });
+        Ok(Response::new(Box::pin(stream)))
+    }
+}
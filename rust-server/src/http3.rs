@@ -0,0 +1,78 @@
+//! 可选的 HTTP/3 (QUIC) 监听 (cargo feature `http3`，运行时由 `GALLERY_HTTP3` 开启)
+//!
+//! 与 TLS 监听共用证书与路由，在 UDP 端口 (`GALLERY_HTTP3_PORT`，默认与 TCP 相同) 上接受 QUIC 连接；
+//! TCP 响应附带 `Alt-Svc`，浏览器据此升级。QUIC 的丢包重传按流进行，信号边缘的相框不会因为
+//! 一个丢失的 TCP 分段卡住整张图片。
+
+use std::net::SocketAddr;
+
+use axum::{body::Body, extract::ConnectInfo, http::Request, Router};
+use axum_server::tls_rustls::RustlsConfig;
+use bytes::{Buf, Bytes};
+use futures::StreamExt;
+use tower::ServiceExt;
+
+type RequestResolver = h3::server::RequestResolver<h3_quinn::Connection, Bytes>;
+
+/// 绑定 UDP 端口并在后台接受连接
+pub fn spawn(addr: SocketAddr, tls: &RustlsConfig, app: Router) -> anyhow::Result<()> {
+    let mut crypto = (*tls.get_inner()).clone();
+    crypto.alpn_protocols = vec![b"h3".to_vec()];
+    let crypto = quinn::crypto::rustls::QuicServerConfig::try_from(crypto)?;
+    let endpoint = quinn::Endpoint::server(quinn::ServerConfig::with_crypto(std::sync::Arc::new(crypto)), addr)?;
+    tracing::info!("🚀 HTTP/3 (QUIC) 监听 udp://{}", addr);
+
+    tokio::spawn(async move {
+        while let Some(incoming) = endpoint.accept().await {
+            let app = app.clone();
+            tokio::spawn(async move {
+                if let Err(err) = handle_connection(incoming, app).await {
+                    tracing::debug!("HTTP/3 连接结束: {}", err);
+                }
+            });
+        }
+    });
+    Ok(())
+}
+
+async fn handle_connection(incoming: quinn::Incoming, app: Router) -> anyhow::Result<()> {
+    let conn = incoming.await?;
+    let remote = conn.remote_address();
+    let mut h3_conn = h3::server::builder().build(h3_quinn::Connection::new(conn)).await?;
+    while let Some(resolver) = h3_conn.accept().await? {
+        let app = app.clone();
+        tokio::spawn(async move {
+            if let Err(err) = handle_request(resolver, app, remote).await {
+                tracing::debug!("HTTP/3 请求失败 ({}): {}", remote, err);
+            }
+        });
+    }
+    Ok(())
+}
+
+/// 把一个 HTTP/3 请求流转成 axum 请求，响应体按帧流式写回
+async fn handle_request(resolver: RequestResolver, app: Router, remote: SocketAddr) -> anyhow::Result<()> {
+    let (request, stream) = resolver.resolve_request().await?;
+    let (mut send, recv) = stream.split();
+
+    let body = futures::stream::unfold(Some(recv), |recv| async move {
+        let mut recv = recv?;
+        match recv.recv_data().await {
+            Ok(Some(mut chunk)) => Some((Ok(chunk.copy_to_bytes(chunk.remaining())), Some(recv))),
+            Ok(None) => None,
+            Err(err) => Some((Err(err), None)),
+        }
+    });
+    let (mut parts, ()) = request.into_parts();
+    parts.extensions.insert(ConnectInfo(remote));
+    let response = app.oneshot(Request::from_parts(parts, Body::from_stream(body))).await?;
+
+    let (parts, body) = response.into_parts();
+    send.send_response(axum::http::Response::from_parts(parts, ())).await?;
+    let mut data = body.into_data_stream();
+    while let Some(chunk) = data.next().await {
+        send.send_data(chunk?).await?;
+    }
+    send.finish().await?;
+    Ok(())
+}
@@ -1,13 +1,47 @@
+#[cfg(feature = "onnx")]
+mod autotag;
+#[cfg(feature = "onnx")]
+mod faces;
+#[cfg(feature = "cast")]
+mod cast;
+#[cfg(feature = "mdns")]
+mod mdns;
+#[cfg(feature = "mqtt")]
+mod mqtt;
+#[cfg(feature = "smtp")]
+mod digest;
+#[cfg(feature = "notify")]
+mod notify;
+#[cfg(feature = "graphql")]
+mod graphql;
+#[cfg(feature = "grpc")]
+mod grpc;
+#[cfg(feature = "http3")]
+mod http3;
+#[cfg(feature = "admin-ui")]
+mod admin;
+mod diagnostics;
+mod replication;
+mod backup;
+mod color;
+mod thumbnail_cache;
+
 use anyhow::Result;
 use axum::{
-    extract::{ConnectInfo, Query, State},
-    http::{header, HeaderMap, StatusCode},
-    response::{IntoResponse, Response},
-    routing::{get, post},
+    body::Body,
+    extract::{ConnectInfo, DefaultBodyLimit, Query, Request, State},
+    http::{header, HeaderName, HeaderValue, StatusCode},
+    middleware::{self, Next},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Response,
+    },
+    routing::{get, patch, post, put},
     Json, Router,
 };
-use tower_http::trace::TraceLayer;
-use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
+use tower::ServiceExt;
+use tower_http::{services::ServeFile, trace::TraceLayer};
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, Layer};
 use axum_server::tls_rustls::RustlsConfig;
 use futures::StreamExt;
 use mime_guess::from_path;
@@ -15,66 +49,406 @@ use path_clean::PathClean;
 use pathdiff::diff_paths;
 use rand::seq::SliceRandom;
 use serde::{Deserialize, Serialize};
-use sqlx::{sqlite::SqlitePoolOptions, Pool, Row, Sqlite};
+use sqlx::{
+    sqlite::{SqliteConnectOptions, SqlitePoolOptions},
+    Pool, Row, Sqlite,
+};
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{hash_map::DefaultHasher, BTreeMap, HashMap, HashSet},
+    convert::Infallible,
     env,
+    hash::{Hash, Hasher},
     net::SocketAddr,
     path::{Path, PathBuf},
     sync::Arc,
-    time::{SystemTime, UNIX_EPOCH},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
-use tokio::sync::RwLock;
+use gallery_client::types::{
+    BrowseItem, BrowseResponse, Capabilities, CapabilitiesResponse, ChunkedPlaylistResponse, Companion, CompanionFormat,
+    FolderMeta, GenerationStatus, ImageCaption, ImageEdit, ImageInfoResponse, IndexFreshness, Limits, MixSource, PlaylistCriteria,
+    PlaylistRequest, QueryFilter, QueryItem, QueryOutput, QueryRequest, QueryResponse, RestorePlaylistRequest,
+    RestorePlaylistResponse, RestoreValidation, SuggestedTag, SupportedFormats,
+};
+use tokio::sync::{broadcast, RwLock};
+use unicode_normalization::UnicodeNormalization;
 use tower_http::cors::CorsLayer;
 use walkdir::WalkDir;
 
 // --- 常量与配置 ---
 const ALLOWED_EXTENSIONS: &[&str] = &["jpg", "jpeg", "png", "gif", "webp", "bmp"];
+/// 与图片同名的伴生文件扩展名及类型 (Live Photo 视频、RAW / HEIC 原片)
+const COMPANION_EXTENSIONS: &[(&str, &str)] = &[
+    ("mov", "live_video"),
+    ("mp4", "live_video"),
+    ("heic", "original"),
+    ("heif", "original"),
+    ("dng", "raw"),
+    ("cr2", "raw"),
+    ("cr3", "raw"),
+    ("nef", "raw"),
+    ("arw", "raw"),
+    ("raf", "raw"),
+    ("orf", "raw"),
+    ("rw2", "raw"),
+];
+/// 可通过 SSE `remote_command` 事件转发给客户端的遥控命令
+#[cfg_attr(not(feature = "mqtt"), allow(dead_code))]
+const REMOTE_COMMANDS: &[&str] = &["next", "previous", "pause", "resume"];
+/// ROOT_DIR 下的回收站目录 (扫描时跳过)
+const TRASH_DIR_NAME: &str = ".gallery-trash";
+/// 服务端生成的缓存 (切片等)，扫描时跳过
+const CACHE_DIR_NAME: &str = ".gallery-cache";
+/// 感知哈希汉明距离不超过该值视为近似重复
+const BURST_PHASH_DISTANCE: u32 = 6;
+/// 缩放、拼贴与设备配置允许的最大边长
+const RENDER_MAX_SIDE: u32 = 8192;
+/// 可选的 cargo feature (诊断与能力发现接口报告其中已启用的)
+const CARGO_FEATURES: &[(&str, bool)] = &[
+    ("icu", cfg!(feature = "icu")),
+    ("onnx", cfg!(feature = "onnx")),
+    ("mqtt", cfg!(feature = "mqtt")),
+    ("mdns", cfg!(feature = "mdns")),
+    ("cast", cfg!(feature = "cast")),
+    ("smtp", cfg!(feature = "smtp")),
+    ("notify", cfg!(feature = "notify")),
+    ("graphql", cfg!(feature = "graphql")),
+    ("grpc", cfg!(feature = "grpc")),
+    ("http3", cfg!(feature = "http3")),
+    ("admin-ui", cfg!(feature = "admin-ui")),
+    ("replication", cfg!(feature = "replication")),
+    ("backup", cfg!(feature = "backup")),
+];
+/// 注册到 SQLite 的自然排序规则名 (与 natord::compare_ignore_case 一致)
+const NATURAL_COLLATION: &str = "NATURAL_NOCASE";
 
 #[derive(Clone)]
 struct AppState {
     db: Pool<Sqlite>,
     root_dir: Arc<PathBuf>,
     allow_parent_dir_access: Arc<RwLock<bool>>,
-    external_synced_paths_this_boot: Arc<RwLock<HashSet<String>>>,
+    /// ROOT_DIR 之外的路径的按需同步 (`GALLERY_EXTERNAL_RESYNC_SECS` 控制多久后重新同步)
+    external_syncs: Arc<SyncTracker>,
+    /// 已索引的 ROOT_DIR 内文件夹被请求时的重新同步 (`GALLERY_FOLDER_RESYNC_SECS`，未设置时只依赖全量扫描)
+    folder_syncs: Arc<SyncTracker>,
     user_sessions: Arc<RwLock<HashMap<String, UserSessionData>>>,
-    log_api_file_requests: bool,
+    /// `/api/file` 访问日志的缓冲与保留天数 (`GALLERY_ACCESS_LOG_DAYS`，默认 0 即关闭；可热加载)
+    access_log: Arc<std::sync::Mutex<Vec<AccessRecord>>>,
+    access_log_retention_days: Arc<std::sync::atomic::AtomicU64>,
+    /// 每个客户端的文件传输带宽上限 (未配置时不限速；可热加载)
+    bandwidth: Arc<std::sync::RwLock<Option<Arc<BandwidthLimiter>>>>,
+    /// 各接口拒绝客户端直接传来的 ROOT_DIR 之外的路径，只接受 `id:{file_id}` 引用或 `/api/file/{id}`；
+    /// 播放列表中的外部路径也改为下发 ID 引用 (`GALLERY_EXTERNAL_FILES_BY_ID`，见 `client_path_key`)
+    external_files_by_id_only: bool,
+    follow_symlinks: bool,
+    default_collation: Option<String>,
+    playlist_cache: Arc<RwLock<HashMap<u64, CachedPlaylist>>>,
+    playlist_cache_ttl: Duration,
+    events: broadcast::Sender<ServerEvent>,
+    /// 待写入的展示统计 (路径 -> (次数, 最后展示时间))，由后台任务定期批量落库
+    serve_stats: Arc<std::sync::Mutex<HashMap<String, (i64, f64)>>>,
+    /// 展示统计的采样率 (0~1)，计数按 1/采样率 放大
+    analytics_sample_rate: f64,
+    /// 每个客户端 IP 最近一次 GET 的图片
+    now_showing: Arc<RwLock<HashMap<String, NowShowing>>>,
+    /// 未使用的一次性配对令牌 -> 签发时间
+    pairing_tokens: Arc<std::sync::Mutex<HashMap<String, Instant>>>,
+    pairing_token_ttl: Duration,
+    /// 分享密码输错的次数 (按客户端 IP)，用于退避
+    share_unlock_throttle: Arc<std::sync::Mutex<UnlockThrottle>>,
+    /// 同时进行的分享密码校验 (Argon2) 数量上限
+    share_unlock_verifies: Arc<tokio::sync::Semaphore>,
+    /// 全局熄屏时段 (`GALLERY_DARK_HOURS`，可热加载)
+    dark_hours: Arc<std::sync::RwLock<Arc<Vec<ScheduleWindow>>>>,
+    /// 最近解码的原图 (IIIF / 切片共用)
+    decoded_images: Arc<std::sync::Mutex<DecodedImageCache>>,
+    /// 缩略图磁盘缓存的命中统计
+    thumbnail_cache: Arc<thumbnail_cache::CacheStats>,
+    /// 运行中的后台任务 -> 取消令牌
+    jobs: Arc<std::sync::Mutex<HashMap<String, tokio_util::sync::CancellationToken>>>,
+    /// 启动扫描完成前为 stale / building，播放列表与浏览接口照常用已有索引作答并在响应中标明
+    index_freshness: Arc<std::sync::RwLock<IndexFreshness>>,
+    /// 本图库的配置来源 (热加载时重新读取)
+    settings: Settings,
+    /// 本图库对外的 URL 前缀：默认图库为空，profile 为 `/g/{name}`
+    base_path: String,
+    #[cfg(feature = "onnx")]
+    autotagger: Option<Arc<autotag::AutoTagger>>,
+    #[cfg(feature = "onnx")]
+    text_encoder: Option<Arc<autotag::TextEncoder>>,
+    /// 语义搜索用的内存向量表 (首次查询时加载，自动标签完成后失效)
+    #[cfg(feature = "onnx")]
+    embedding_index: Arc<RwLock<Option<Arc<autotag::EmbeddingIndex>>>>,
+    #[cfg(feature = "onnx")]
+    face_analyzer: Option<Arc<faces::FaceAnalyzer>>,
+    #[cfg(feature = "cast")]
+    cast: Arc<cast::CastManager>,
+    #[cfg(feature = "smtp")]
+    mailer: Option<Arc<digest::Mailer>>,
+    #[cfg(feature = "notify")]
+    notifier: Option<Arc<notify::Notifier>>,
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
-struct PlaylistCriteria {
-    sort: String,
-    direction: String,
-    orientation: String,
-    paths: Vec<String>,
+/// 按键合并的按需同步 (single-flight)：同一个键同时只有一次同步在执行，
+/// 并发的请求等待它完成后直接复用结果；完成时间超过 `max_age` 后允许再次同步
+struct SyncTracker {
+    slots: std::sync::Mutex<HashMap<String, Arc<tokio::sync::Mutex<Option<Instant>>>>>,
+    /// 为空时每个键只同步一次 (直到重启)
+    max_age: Option<Duration>,
+}
+
+impl SyncTracker {
+    fn new(max_age: Option<Duration>) -> Self {
+        Self { slots: std::sync::Mutex::new(HashMap::new()), max_age }
+    }
+
+    /// 键尚未同步或已过期时执行 `sync`，返回是否执行了
+    async fn run_if_stale<F, Fut>(&self, key: &str, sync: F) -> bool
+    where
+        F: FnOnce() -> Fut,
+        Fut: std::future::Future<Output = ()>,
+    {
+        let slot = self.slots.lock().unwrap().entry(key.to_string()).or_default().clone();
+        let mut synced_at = slot.lock().await;
+        let fresh = synced_at.is_some_and(|at| self.max_age.is_none_or(|max_age| at.elapsed() < max_age));
+        if fresh {
+            return false;
+        }
+        sync().await;
+        *synced_at = Some(Instant::now());
+        true
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+struct NowShowing {
+    path: String,
+    at: f64,
+}
+
+/// 近期播放列表请求的结果缓存
+#[derive(Clone)]
+struct CachedPlaylist {
+    paths: Arc<Vec<String>>,
+    cached_at: Instant,
+}
+
+/// HTTP 连接参数 (两个监听模式共用：TLS 经 ALPN 协商 h2，明文时 h2 需 prior knowledge)
+#[derive(Clone, Debug)]
+struct HttpTuning {
+    /// TLS 是否通告 h2 (`GALLERY_HTTP2`，默认开启；个别反向代理处理 h2 有问题时可关闭)
+    http2: bool,
+    /// 每个 HTTP/2 连接的最大并发流 (`GALLERY_HTTP2_MAX_STREAMS`，未设置时用 hyper 默认值 200)
+    max_concurrent_streams: Option<u32>,
+    /// HTTP/2 PING 保活间隔 (`GALLERY_HTTP2_KEEPALIVE_SECS`，未设置时不发送)
+    keep_alive_interval: Option<Duration>,
+    /// PING 未获应答时关闭连接的等待时长 (`GALLERY_HTTP2_KEEPALIVE_TIMEOUT_SECS`，默认 20 秒)
+    keep_alive_timeout: Duration,
+    /// HTTP/1.1 是否复用连接 (`GALLERY_HTTP1_KEEPALIVE`，默认开启)
+    http1_keep_alive: bool,
+}
+
+impl HttpTuning {
+    fn from_env() -> Self {
+        let enabled_unless_off = |name: &str| config_var(name).is_err() || env_flag_enabled(name);
+        Self {
+            http2: enabled_unless_off("GALLERY_HTTP2"),
+            max_concurrent_streams: config_var("GALLERY_HTTP2_MAX_STREAMS")
+                .ok()
+                .and_then(|v| v.trim().parse::<u32>().ok())
+                .filter(|n| *n > 0),
+            keep_alive_interval: env_duration_secs("GALLERY_HTTP2_KEEPALIVE_SECS"),
+            keep_alive_timeout: env_duration_secs("GALLERY_HTTP2_KEEPALIVE_TIMEOUT_SECS")
+                .unwrap_or(Duration::from_secs(20)),
+            http1_keep_alive: enabled_unless_off("GALLERY_HTTP1_KEEPALIVE"),
+        }
+    }
+
+    fn apply(&self, builder: &mut hyper_util::server::conn::auto::Builder<hyper_util::rt::TokioExecutor>) {
+        builder.http1().keep_alive(self.http1_keep_alive);
+        let mut http2 = builder.http2();
+        // 保活 PING 依赖计时器
+        http2.timer(hyper_util::rt::TokioTimer::new());
+        if let Some(max) = self.max_concurrent_streams {
+            http2.max_concurrent_streams(max);
+        }
+        if let Some(interval) = self.keep_alive_interval {
+            http2.keep_alive_interval(interval).keep_alive_timeout(self.keep_alive_timeout);
+        }
+    }
+}
+
+/// 安全加固响应头 (每项都可通过环境变量覆盖或关闭)
+#[derive(Clone, Debug)]
+struct SecurityHeaders {
+    content_type_options: Option<HeaderValue>,
+    content_security_policy: Option<HeaderValue>,
+    referrer_policy: Option<HeaderValue>,
+    cross_origin_resource_policy: Option<HeaderValue>,
+}
+
+impl SecurityHeaders {
+    fn from_env() -> Self {
+        if config_var("GALLERY_SECURITY_HEADERS").is_ok() && !env_flag_enabled("GALLERY_SECURITY_HEADERS") {
+            return Self {
+                content_type_options: None,
+                content_security_policy: None,
+                referrer_policy: None,
+                cross_origin_resource_policy: None,
+            };
+        }
+        Self {
+            content_type_options: env_header_value("GALLERY_HEADER_X_CONTENT_TYPE_OPTIONS", "nosniff"),
+            content_security_policy: env_header_value(
+                "GALLERY_HEADER_CSP",
+                "default-src 'none'; img-src 'self' data:; style-src 'unsafe-inline'; sandbox",
+            ),
+            referrer_policy: env_header_value("GALLERY_HEADER_REFERRER_POLICY", "no-referrer"),
+            // 前端与 API 通常不同源 (CORS 全开)，默认允许跨源加载图片
+            cross_origin_resource_policy: env_header_value("GALLERY_HEADER_CORP", "cross-origin"),
+        }
+    }
+}
+
+/// 未带版本号的 `/api/...` 路由被标记为弃用的日期 (新客户端应使用 `/api/v1/...`)
+const LEGACY_API_DEPRECATED_ON: &str = "2026-10-16";
+
+/// 旧版 (未带版本号) API 路由的弃用策略
+///
+/// 旧路由照常工作，但响应附带 `Deprecation` / `Sunset` / `Link` / `Warning` 头，
+/// 方便相框客户端的开发者在接口出现破坏性变更之前迁移到 `/api/v1`。
+/// `GALLERY_API_SUNSET` (`YYYY-MM-DD` 或 RFC 3339) 设置停用日期，过期后旧路由返回 410；
+/// `GALLERY_API_LEGACY_ROUTES=false` 立即停用旧路由。
+#[derive(Debug)]
+struct ApiDeprecation {
+    deprecation: HeaderValue,
+    sunset: Option<(f64, HeaderValue)>,
+    legacy_enabled: bool,
+    /// 已提示过的客户端 IP -> 提示时间 (每个客户端每天最多记一次日志，条目数有上限)
+    warned_clients: std::sync::Mutex<HashMap<String, Instant>>,
+}
+
+/// 同一客户端再次提示的间隔，以及记住的客户端数上限
+const LEGACY_WARN_INTERVAL: Duration = Duration::from_secs(86400);
+const LEGACY_WARNED_MAX: usize = 1024;
+
+impl ApiDeprecation {
+    fn from_env() -> Self {
+        let deprecated_at = parse_query_date(LEGACY_API_DEPRECATED_ON, false).unwrap_or(0.0);
+        let sunset = config_var("GALLERY_API_SUNSET").ok().and_then(|raw| match parse_query_date(&raw, false) {
+            Some(at) => {
+                let date = chrono::DateTime::from_timestamp(at as i64, 0)?;
+                let value = HeaderValue::from_str(&date.format("%a, %d %b %Y %H:%M:%S GMT").to_string()).ok()?;
+                Some((at, value))
+            }
+            None => {
+                tracing::warn!("⚠️ GALLERY_API_SUNSET 无法解析: {:?} (应为 YYYY-MM-DD 或 RFC 3339)", raw);
+                None
+            }
+        });
+        Self {
+            deprecation: HeaderValue::from_str(&format!("@{}", deprecated_at as i64)).unwrap(),
+            sunset,
+            legacy_enabled: config_var("GALLERY_API_LEGACY_ROUTES").is_err() || env_flag_enabled("GALLERY_API_LEGACY_ROUTES"),
+            warned_clients: std::sync::Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// 该客户端是否需要 (再次) 记录迁移提示
+    fn should_warn(&self, client: &str) -> bool {
+        let mut warned = self.warned_clients.lock().unwrap();
+        if warned.get(client).is_some_and(|at| at.elapsed() < LEGACY_WARN_INTERVAL) {
+            return false;
+        }
+        if warned.len() >= LEGACY_WARNED_MAX {
+            warned.retain(|_, at| at.elapsed() < LEGACY_WARN_INTERVAL);
+            // 仍然全是近期的客户端时丢掉最早的一个
+            if warned.len() >= LEGACY_WARNED_MAX {
+                if let Some(oldest) = warned.iter().min_by_key(|(_, at)| **at).map(|(c, _)| c.clone()) {
+                    warned.remove(&oldest);
+                }
+            }
+        }
+        warned.insert(client.to_string(), Instant::now());
+        true
+    }
+
+    fn is_sunset(&self) -> bool {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs_f64();
+        !self.legacy_enabled || self.sunset.as_ref().is_some_and(|(at, _)| now >= *at)
+    }
 }
 
 #[derive(Clone, Debug)]
 struct UserSessionData {
     playlist: Vec<String>,
     criteria: Option<PlaylistCriteria>,
+    generation_status: GenerationStatus,
+    /// 每次替换会话时分配的代号；后台任务写回前据此确认会话没有被别的请求替换
+    generation: u64,
+    /// 从休眠中唤醒的会话：客户端结束会话时留下的进度，直到下一次生成或恢复播放列表
+    resume: Option<SessionResume>,
+}
+
+/// 客户端结束会话 (`POST /api/session/end`) 时保存的进度
+#[derive(Clone, Debug, Serialize)]
+struct SessionResume {
+    current_index: Option<usize>,
+    settings: Option<serde_json::Value>,
+    ended_at: f64,
 }
 
 // --- 数据模型 ---
 
 #[derive(Debug, Deserialize)]
-struct PlaylistRequest {
+struct BlocklistRequest {
     paths: Vec<String>,
-    #[serde(default = "default_sort")]
-    sort: String,
-    #[serde(default = "default_orientation")]
-    orientation: String,
-    #[serde(default = "default_direction")]
-    direction: String,
-    current_path: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct BlocklistResponse {
+    paths: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum TagAction {
+    Add,
+    Remove,
 }
 
 #[derive(Debug, Deserialize)]
-struct RestorePlaylistRequest {
-    playlist: Vec<String>,
+struct BulkTagRequest {
+    tag: String,
+    action: TagAction,
+    /// 文件夹前缀：作用于该目录下 (递归) 所有已索引图片
     #[serde(default)]
-    current_index: usize,
-    criteria: Option<PlaylistCriteria>,
+    folder: Option<String>,
+    /// 显式路径列表，可与 folder 同时使用
+    #[serde(default)]
+    paths: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct BulkTagResponse {
+    tag: String,
+    action: TagAction,
+    matched: u64,
+    affected: u64,
+}
+
+#[derive(Debug, Deserialize)]
+struct TagSuggestQuery {
+    #[serde(default)]
+    q: String,
+    limit: Option<usize>,
+}
+
+#[derive(Debug, Serialize)]
+struct TagSuggestion {
+    tag: String,
+    /// 直接带有该标签的图片数
+    count: i64,
+    has_children: bool,
 }
 
 #[derive(Debug, Deserialize)]
@@ -93,26 +467,38 @@ struct FileQuery {
     path: String,
 }
 
-#[derive(Debug, Serialize)]
-struct BrowseItem {
-    name: String,
-    path: String,
-    #[serde(rename = "type")]
-    item_type: String,
-}
-
-#[derive(Debug, Serialize)]
-struct BrowseResponse {
-    #[serde(rename = "currentPath")]
-    current_path: String,
-    items: Vec<BrowseItem>,
-}
-
 #[derive(Debug, Serialize)]
 struct SessionStatusResponse {
     has_session: bool,
     source: Option<String>,
     playlist_size: usize,
+    playlist_hash: Option<String>,
+    generation_status: GenerationStatus,
+    index_freshness: IndexFreshness,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    resume: Option<SessionResume>,
+    #[serde(flatten)]
+    sleep: SleepHint,
+}
+
+/// 熄屏时段提示：`sleep_until` 仅在当前处于熄屏时段时给出 (Unix 秒)
+#[derive(Clone, Debug, Serialize)]
+struct SleepHint {
+    dark_hours: Arc<Vec<ScheduleWindow>>,
+    sleep_until: Option<i64>,
+}
+
+impl SleepHint {
+    fn new(dark_hours: Arc<Vec<ScheduleWindow>>) -> Self {
+        let now = chrono::Local::now();
+        let in_dark = |t: &chrono::DateTime<chrono::Local>| dark_hours.iter().any(|w| w.contains(t));
+        let sleep_until = if in_dark(&now) {
+            next_schedule_change(now, in_dark).map(|t| t.timestamp())
+        } else {
+            None
+        };
+        Self { dark_hours, sleep_until }
+    }
 }
 
 #[derive(Debug, Serialize)]
@@ -121,7 +507,14 @@ struct SessionPlaylistResponse {
     source: Option<String>,
     playlist_size: usize,
     playlist: Vec<String>,
+    playlist_hash: Option<String>,
     criteria: Option<PlaylistCriteria>,
+    generation_status: GenerationStatus,
+    index_freshness: IndexFreshness,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    resume: Option<SessionResume>,
+    #[serde(flatten)]
+    sleep: SleepHint,
 }
 
 #[derive(sqlx::FromRow, Clone, Debug)]
@@ -131,35 +524,282 @@ struct ImageMetadata {
     width: u32,
     height: u32,
     is_landscape: bool,
+    size: i64,
+    /// EXIF 拍摄时间 (按 UTC 解释的秒数，仅用于比较间隔)
+    taken_at: Option<f64>,
+    /// 64 位差值感知哈希 (十六进制)；空字符串表示无法计算
+    phash: Option<String>,
+    /// 首次加入索引的时间，之后的重新扫描与移动都保留 (写入时由数据库行决定，扫描结果中为空)
+    added_at: Option<f64>,
+    /// 内嵌 ICC 配置文件的描述
+    color_profile: Option<String>,
+    /// 是否带 HDR 增益图；NULL 表示还没检测过
+    hdr_gain_map: Option<bool>,
+    /// 长宽比达到 `PANORAMA_MIN_ASPECT`
+    is_panorama: bool,
+}
+
+/// 长边至少是短边的这么多倍时视为全景图
+const PANORAMA_MIN_ASPECT: f64 = 2.0;
+
+fn is_panorama(width: u32, height: u32) -> bool {
+    width.min(height) > 0 && width.max(height) as f64 >= width.min(height) as f64 * PANORAMA_MIN_ASPECT
 }
 
-fn default_sort() -> String { "shuffle".to_string() }
-fn default_orientation() -> String { "Both".to_string() }
-fn default_direction() -> String { "forward".to_string() }
 
 fn path_to_rel_string(root_dir: &Path, full_path: &Path) -> String {
-    diff_paths(full_path, root_dir)
-        .unwrap_or_else(|| PathBuf::from(""))
-        .to_string_lossy()
-        .replace('\\', "/")
+    db_path_key(root_dir, full_path).unwrap_or_default()
 }
 
 // --- 辅助函数 ---
 
+/// 计算写入数据库的路径键：与 ROOT_DIR 同盘时为相对路径 (可能以 `../` 开头)，
+/// Windows 下跨盘符/UNC 共享时为绝对路径键 (`D:/Wallpapers/a.jpg`、`//nas/photos/a.jpg`)
+fn db_path_key(root_dir: &Path, full_path: &Path) -> Option<String> {
+    let root = strip_verbatim_prefix(root_dir);
+    let full = strip_verbatim_prefix(full_path);
+    if cfg!(windows) && !same_path_prefix(&root, &full) {
+        return absolute_path_key(&full.to_string_lossy()).map(|key| to_nfc(&key));
+    }
+    diff_paths(&full, &root).map(|rel| to_nfc(&rel.to_string_lossy().replace('\\', "/")))
+}
+
+/// 统一使用 NFC 形式 (macOS 同步来的文件名常为 NFD)，保证路径键可比较
+fn to_nfc(value: &str) -> String {
+    value.nfc().collect()
+}
+
+/// 路径在磁盘上找不到时，逐级按 NFC 比较目录项，找回实际存储为其他
+/// Unicode 形式 (如 NFD) 的文件
+fn find_unicode_variant(full_path: &Path) -> Option<PathBuf> {
+    let mut resolved = PathBuf::new();
+    for component in full_path.components() {
+        let candidate = resolved.join(component);
+        if candidate.symlink_metadata().is_ok() {
+            resolved = candidate;
+            continue;
+        }
+        let wanted = to_nfc(&component.as_os_str().to_string_lossy());
+        let entry = std::fs::read_dir(&resolved)
+            .ok()?
+            .flatten()
+            .find(|e| to_nfc(&e.file_name().to_string_lossy()) == wanted)?;
+        resolved.push(entry.file_name());
+    }
+    Some(resolved)
+}
+
+/// 去掉 Windows verbatim 前缀：`\\?\C:\x` → `C:\x`，`\\?\UNC\nas\share` → `\\nas\share`
+fn strip_verbatim_prefix(path: &Path) -> PathBuf {
+    let raw = path.to_string_lossy();
+    if let Some(rest) = raw.strip_prefix(r"\\?\UNC\") {
+        PathBuf::from(format!(r"\\{}", rest))
+    } else if let Some(rest) = raw.strip_prefix(r"\\?\") {
+        PathBuf::from(rest)
+    } else {
+        path.to_path_buf()
+    }
+}
+
+/// 两个路径的盘符 / UNC 前缀是否相同 (不区分大小写)
+fn same_path_prefix(a: &Path, b: &Path) -> bool {
+    let prefix_of = |p: &Path| match p.components().next() {
+        Some(std::path::Component::Prefix(prefix)) => {
+            Some(prefix.as_os_str().to_string_lossy().to_ascii_uppercase())
+        }
+        _ => None,
+    };
+    prefix_of(a) == prefix_of(b)
+}
+
+/// 把 Windows 绝对路径 (盘符、UNC、verbatim) 规范化为稳定的路径键；
+/// 不是这类路径时返回 None。盘符只在 Windows 上识别 (其他系统上 `x:` 是普通的目录名)
+fn absolute_path_key(raw: &str) -> Option<String> {
+    let slashed = raw.trim().replace('\\', "/");
+    let unprefixed = if let Some(rest) = slashed.strip_prefix("//?/UNC/") {
+        format!("//{}", rest)
+    } else if let Some(rest) = slashed.strip_prefix("//?/") {
+        rest.to_string()
+    } else {
+        slashed
+    };
+
+    if let Some(unc) = unprefixed.strip_prefix("//") {
+        let unc = unc.trim_end_matches('/');
+        if unc.is_empty() || unc.starts_with('/') {
+            return None;
+        }
+        return Some(format!("//{}", unc));
+    }
+
+    let bytes = unprefixed.as_bytes();
+    if cfg!(windows) && bytes.len() >= 2 && bytes[0].is_ascii_alphabetic() && bytes[1] == b':' {
+        let rest = unprefixed[2..].trim_start_matches('/').trim_end_matches('/');
+        let drive = (bytes[0] as char).to_ascii_uppercase();
+        return Some(format!("{}:/{}", drive, rest));
+    }
+    None
+}
+
+/// 数据库路径键是否指向 ROOT_DIR 之外 (上级目录或其他盘符/共享)
+fn is_external_key(key: &str) -> bool {
+    key == ".." || key.starts_with("../") || absolute_path_key(key).is_some()
+}
+
+/// ROOT_DIR 之外的文件的稳定 ID (首次遇到时分配并持久化)，客户端可通过 `/api/file/{id}` 获取文件，
+/// 无需传输 `../` 或绝对路径；ROOT_DIR 内的路径返回 None
+async fn external_file_id<'e, E>(executor: E, path: &str) -> Option<String>
+where
+    E: sqlx::Executor<'e, Database = Sqlite> + Copy,
+{
+    if !is_external_key(path) {
+        return None;
+    }
+    sqlx::query("INSERT OR IGNORE INTO external_files (id, path) VALUES (?, ?)")
+        .bind(random_token(22))
+        .bind(path)
+        .execute(executor)
+        .await
+        .ok()?;
+    sqlx::query_scalar("SELECT id FROM external_files WHERE path = ?")
+        .bind(path)
+        .fetch_optional(executor)
+        .await
+        .ok()
+        .flatten()
+}
+
+/// 客户端用 `id:{file_id}` 代替 ROOT_DIR 之外的路径 (播放列表在按 ID 模式下就这样下发)
+const FILE_ID_REF_PREFIX: &str = "id:";
+
+/// 客户端传入的路径统一经过这里：展开 `id:{file_id}` 引用；开启 `GALLERY_EXTERNAL_FILES_BY_ID` 时
+/// 拒绝直接传来的 `../` 或绝对路径。之后仍需 `resolve_and_authorize` 做权限检查
+async fn client_path_key(state: &AppState, raw_path: &str) -> Result<String, PathAccessError> {
+    if let Some(id) = raw_path.trim().strip_prefix(FILE_ID_REF_PREFIX) {
+        return sqlx::query_scalar("SELECT path FROM external_files WHERE id = ?")
+            .bind(id)
+            .fetch_optional(&state.db)
+            .await
+            .ok()
+            .flatten()
+            .ok_or(PathAccessError::NotFound);
+    }
+    let rel = normalize_rel_path(raw_path);
+    if state.external_files_by_id_only && is_external_key(&rel) {
+        return Err(PathAccessError::Forbidden);
+    }
+    Ok(rel)
+}
+
+/// `client_path_key` + `resolve_and_authorize`，返回 (路径键, 完整路径)
+async fn authorize_client_path(state: &AppState, raw_path: &str) -> Result<(String, PathBuf), PathAccessError> {
+    let rel = client_path_key(state, raw_path).await?;
+    let allow_parent = *state.allow_parent_dir_access.read().await;
+    let full = resolve_and_authorize(&state.root_dir, &rel, allow_parent)?;
+    Ok((rel, full))
+}
+
+/// 按 ID 模式下把播放列表中 ROOT_DIR 之外的路径换成 `id:{file_id}` 引用，客户端原样传回各接口即可
+async fn playlist_for_client(state: &AppState, playlist: Vec<String>) -> Vec<String> {
+    if !state.external_files_by_id_only || !playlist.iter().any(|p| is_external_key(p)) {
+        return playlist;
+    }
+    let mut out = Vec::with_capacity(playlist.len());
+    for path in playlist {
+        if !is_external_key(&path) {
+            out.push(path);
+        } else if let Some(id) = external_file_id(&state.db, &path).await {
+            out.push(format!("{}{}", FILE_ID_REF_PREFIX, id));
+        }
+    }
+    out
+}
+
+/// SQL 条件：排除 ROOT_DIR 之外的路径键 (与 is_external_key 对应)
+#[cfg(windows)]
+const INTERNAL_PATH_SQL_FILTER: &str = "path NOT LIKE '../%' AND path NOT LIKE '_:/%' AND path NOT LIKE '//%'";
+#[cfg(not(windows))]
+const INTERNAL_PATH_SQL_FILTER: &str = "path NOT LIKE '../%' AND path NOT LIKE '//%'";
+
+/// SQL 条件：排除扫描时找不到文件的图片 (墓碑记录，宽限期内保留标签、评分等数据)
+const PRESENT_SQL_FILTER: &str = "missing_since IS NULL";
+
 fn normalize_rel_path(path: &str) -> String {
-    path.replace('\\', "/")
-        .trim()
-        .trim_start_matches('/')
-        .trim_end_matches('/')
-        .replace("/./", "/")
+    if cfg!(windows) {
+        if let Some(key) = absolute_path_key(path) {
+            return to_nfc(&key);
+        }
+    }
+    to_nfc(
+        path.replace('\\', "/")
+            .trim()
+            .trim_start_matches('/')
+            .trim_end_matches('/')
+            .replace("/./", "/")
+            .as_str(),
+    )
+}
+
+/// 字符串中是否含有 `%XX` 形式的百分号转义
+fn looks_percent_encoded(path: &str) -> bool {
+    path.as_bytes()
+        .windows(3)
+        .any(|w| w[0] == b'%' && w[1].is_ascii_hexdigit() && w[2].is_ascii_hexdigit())
+}
+
+/// 路径参数的编码约定 (所有接口一致)：
+/// - 查询参数 (`/api/file?path=`、`/api/browse?path=` 等) 由 `Query` 提取器按 `application/x-www-form-urlencoded`
+///   解码且只解码一次。`#`、`?`、`&`、`+`、`%` 与非 ASCII 字符须编码 (如 `encodeURIComponent`)，未编码的 `+` 视为空格
+/// - JSON 请求体 (播放列表等) 中的路径是原始 UTF-8 字符串，不做任何编码
+///
+/// 服务器不会再次解码：解码后仍含 `%XX` 且不存在该文件或文件夹时视为重复编码，返回 400 而不是猜测原意
+fn reject_double_encoded(root_dir: &Path, rel: &str) -> Result<(), (StatusCode, Json<serde_json::Value>)> {
+    if !looks_percent_encoded(rel) || resolve_full_path(root_dir, rel).exists() {
+        return Ok(());
+    }
+    Err((
+        StatusCode::BAD_REQUEST,
+        Json(serde_json::json!({
+            "detail": format!("Path {:?} appears to be percent-encoded more than once; encode it exactly once", rel)
+        })),
+    ))
 }
 
 fn resolve_full_path(root_dir: &Path, rel_path: &str) -> PathBuf {
+    // 绝对路径键 (盘符/UNC) 直接还原为系统路径，join 会以其替换 ROOT_DIR
+    if cfg!(windows) && absolute_path_key(rel_path).is_some() {
+        return PathBuf::from(rel_path.replace('/', "\\")).clean();
+    }
     root_dir.join(rel_path).clean()
 }
 
+/// 读取可选的响应头配置：未设置时使用默认值，设置为空字符串则关闭该响应头
+fn env_header_value(name: &str, default: &str) -> Option<HeaderValue> {
+    let raw = config_var(name).unwrap_or_else(|_| default.to_string());
+    let trimmed = raw.trim();
+    if trimmed.is_empty() {
+        return None;
+    }
+    match HeaderValue::from_str(trimmed) {
+        Ok(v) => Some(v),
+        Err(_) => {
+            tracing::error!("⚠️ {} 的值不是合法的响应头，已忽略: {}", name, trimmed);
+            None
+        }
+    }
+}
+
+/// 以秒为单位的可选时长，未设置或为 0 时为空
+fn env_duration_secs(name: &str) -> Option<Duration> {
+    config_var(name)
+        .ok()
+        .and_then(|v| v.trim().parse::<u64>().ok())
+        .filter(|secs| *secs > 0)
+        .map(Duration::from_secs)
+}
+
 fn env_flag_enabled(name: &str) -> bool {
-    env::var(name)
+    config_var(name)
         .map(|v| {
             matches!(
                 v.trim().to_ascii_lowercase().as_str(),
@@ -173,6 +813,43 @@ fn is_under_root(root_dir: &Path, full_path: &Path) -> bool {
     full_path.starts_with(root_dir)
 }
 
+/// 路径校验失败的原因
+#[derive(Debug, PartialEq, Eq)]
+enum PathAccessError {
+    /// 位于 ROOT_DIR 之外 (含经由符号链接逃逸) 且未开启上级目录访问
+    Forbidden,
+    /// 路径不存在或无法解析
+    NotFound,
+}
+
+/// 解析相对路径并做权限校验，所有处理函数统一经过这里。
+///
+/// 先做字面检查 (拦截 `..` 穿越)，再用 `canonicalize` 解析符号链接与
+/// Windows UNC/verbatim 前缀，确认真实位置仍在 ROOT_DIR 之下。
+/// 返回字面清洗后的完整路径，保证数据库中的相对路径键不随链接目标变化。
+fn resolve_and_authorize(root_dir: &Path, rel_path: &str, allow_parent: bool) -> Result<PathBuf, PathAccessError> {
+    let mut full = resolve_full_path(root_dir, rel_path);
+    if !allow_parent && !is_under_root(root_dir, &full) {
+        return Err(PathAccessError::Forbidden);
+    }
+
+    // 路径键是 NFC，磁盘上可能是 NFD：找不到时尝试按 Unicode 等价匹配
+    if !full.exists() {
+        full = find_unicode_variant(&full).ok_or(PathAccessError::NotFound)?;
+    }
+    if allow_parent {
+        return Ok(full);
+    }
+
+    let canonical = std::fs::canonicalize(&full).map_err(|_| PathAccessError::NotFound)?;
+    let canonical_root = std::fs::canonicalize(root_dir).unwrap_or_else(|_| root_dir.to_path_buf());
+    if !canonical.starts_with(&canonical_root) {
+        return Err(PathAccessError::Forbidden);
+    }
+
+    Ok(full)
+}
+
 fn is_image_ext(path: &Path) -> bool {
     path.extension()
         .and_then(|e| e.to_str())
@@ -180,10 +857,85 @@ fn is_image_ext(path: &Path) -> bool {
         .unwrap_or(false)
 }
 
+/// 递归列出目录下的图片文件。follow_symlinks 开启时跟随符号链接，
+/// 由 walkdir 检测链接成环并跳过 (记录日志)
+fn walk_image_files(dir: &Path, follow_symlinks: bool) -> impl Iterator<Item = walkdir::DirEntry> {
+    WalkDir::new(dir)
+        .follow_links(follow_symlinks)
+        .into_iter()
+        .filter_entry(|e| e.file_name() != TRASH_DIR_NAME && e.file_name() != CACHE_DIR_NAME)
+        .filter_map(|e| match e {
+            Ok(entry) => Some(entry),
+            Err(err) => {
+                if let Some(ancestor) = err.loop_ancestor() {
+                    tracing::warn!("🔁 符号链接成环，已跳过: {:?} -> {}", err.path(), ancestor.display());
+                }
+                None
+            }
+        })
+        .filter(|entry| entry.file_type().is_file() && is_image_ext(entry.path()))
+}
+
+/// ROOT_DIR 与目标之间是否经过符号链接 (不跟随链接时用于拒绝访问)
+fn path_has_symlink(root_dir: &Path, full_path: &Path) -> bool {
+    let Ok(rel) = full_path.strip_prefix(root_dir) else {
+        return full_path
+            .symlink_metadata()
+            .map(|m| m.file_type().is_symlink())
+            .unwrap_or(false);
+    };
+    let mut current = root_dir.to_path_buf();
+    for component in rel.components() {
+        current.push(component);
+        if current
+            .symlink_metadata()
+            .map(|m| m.file_type().is_symlink())
+            .unwrap_or(false)
+        {
+            return true;
+        }
+    }
+    false
+}
+
 fn escape_like_pattern(value: &str) -> String {
     value.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_")
 }
 
+/// 规范化层级标签：去掉各级首尾空白与空层级 (" people / alice/" -> "people/alice")
+fn normalize_tag(raw: &str) -> Option<String> {
+    let segments: Vec<&str> = raw.split('/').map(str::trim).filter(|s| !s.is_empty()).collect();
+    if segments.is_empty() {
+        None
+    } else {
+        Some(segments.join("/"))
+    }
+}
+
+/// 为播放列表查询追加标签子树过滤，返回需要按顺序绑定的参数
+fn push_tag_filters(query_builder: &mut String, tags: &[String]) -> Vec<String> {
+    let mut binds = Vec::new();
+    for tag in tags.iter().filter_map(|t| normalize_tag(t)) {
+        query_builder.push_str(
+            " AND path IN (SELECT path FROM image_tags WHERE tag = ? COLLATE NOCASE OR tag LIKE ? ESCAPE '\\')",
+        );
+        let subtree_pattern = format!("{}/%", escape_like_pattern(&tag));
+        binds.push(tag);
+        binds.push(subtree_pattern);
+    }
+    binds
+}
+
+/// 为播放列表查询追加人物过滤 (ID 为整数，直接内联)
+fn push_person_filters(query_builder: &mut String, people: &[i64]) {
+    for cluster_id in people {
+        query_builder.push_str(&format!(
+            " AND path IN (SELECT path FROM faces WHERE cluster_id = {})",
+            cluster_id
+        ));
+    }
+}
+
 fn parent_folder(path: &str) -> String {
     Path::new(path)
         .parent()
@@ -198,6 +950,7 @@ fn file_stem_from_rel_path(path: &str) -> String {
         .unwrap_or_default()
 }
 
+#[allow(clippy::manual_strip)]
 fn strip_trailing_index_suffix(name: &str) -> String {
     if let Some((prefix, suffix)) = name.rsplit_once(" (") {
         if suffix.ends_with(')') {
@@ -218,6 +971,10 @@ fn folder_first_image_prefix(items: &[ImageMetadata]) -> String {
     strip_trailing_index_suffix(&stem)
 }
 
+fn megapixels(item: &ImageMetadata) -> f64 {
+    (item.width as f64 * item.height as f64) / 1_000_000.0
+}
+
 fn folder_mtime(root_dir: &Path, parent: &str) -> f64 {
     let folder_path = if parent.is_empty() {
         root_dir.to_path_buf()
@@ -234,7 +991,12 @@ fn folder_mtime(root_dir: &Path, parent: &str) -> f64 {
         .unwrap_or(0.0)
 }
 
-async fn sync_external_path_to_db(pool: &Pool<Sqlite>, root_dir: &Path, rel_path: &str) -> Result<()> {
+async fn sync_external_path_to_db(
+    pool: &Pool<Sqlite>,
+    root_dir: &Path,
+    rel_path: &str,
+    follow_symlinks: bool,
+) -> Result<()> {
     let normalized = normalize_rel_path(rel_path);
     if normalized.is_empty() {
         return Ok(());
@@ -257,11 +1019,9 @@ async fn sync_external_path_to_db(pool: &Pool<Sqlite>, root_dir: &Path, rel_path
             return results;
         }
 
-        for entry in WalkDir::new(&full_path).into_iter().filter_map(|e| e.ok()) {
-            if entry.file_type().is_file() && is_image_ext(entry.path()) {
-                if let Some(meta) = process_image_metadata_sync(entry.path(), &root_clone) {
-                    results.push(meta);
-                }
+        for entry in walk_image_files(&full_path, follow_symlinks) {
+            if let Some(meta) = process_image_metadata_sync(entry.path(), &root_clone) {
+                results.push(meta);
             }
         }
 
@@ -276,45 +1036,56 @@ async fn sync_external_path_to_db(pool: &Pool<Sqlite>, root_dir: &Path, rel_path
     let mut tx = pool.begin().await?;
 
     for meta in scanned {
-        sqlx::query("INSERT OR REPLACE INTO images (path, mtime, width, height, is_landscape) VALUES (?, ?, ?, ?, ?)")
-            .bind(meta.path)
-            .bind(meta.mtime)
-            .bind(meta.width)
-            .bind(meta.height)
-            .bind(meta.is_landscape)
-            .execute(&mut *tx)
-            .await?;
+        upsert_image_row(&mut tx, &meta).await?;
+        if is_external_key(&meta.path) {
+            sqlx::query("INSERT OR IGNORE INTO external_files (id, path) VALUES (?, ?)")
+                .bind(random_token(22))
+                .bind(&meta.path)
+                .execute(&mut *tx)
+                .await?;
+        }
     }
 
-    let existing_rows: Vec<(String,)> = sqlx::query_as("SELECT path FROM images WHERE path LIKE ? ESCAPE '\\\\'")
-        .bind(like_prefix)
-        .fetch_all(&mut *tx)
-        .await
-        .unwrap_or_default();
+    let existing_rows: Vec<(String,)> = sqlx::query_as(&format!(
+        "SELECT path FROM images WHERE path LIKE ? ESCAPE '\\' AND {}",
+        PRESENT_SQL_FILTER
+    ))
+    .bind(like_prefix)
+    .fetch_all(&mut *tx)
+    .await
+    .unwrap_or_default();
 
-    let mut deleted_count = 0;
+    // 找不到的文件只标记为缺失 (外部磁盘可能只是暂时未挂载)，由全量扫描在宽限期后清除
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs_f64();
+    let mut missing_count = 0;
     for (path,) in existing_rows {
         if !scanned_paths.contains(&path) {
-            sqlx::query("DELETE FROM images WHERE path = ?")
+            sqlx::query("UPDATE images SET missing_since = ? WHERE path = ?")
+                .bind(now)
                 .bind(path)
                 .execute(&mut *tx)
                 .await?;
-            deleted_count += 1;
+            missing_count += 1;
         }
     }
 
     tx.commit().await?;
     tracing::info!(
-        "🔄 [On-demand External Sync] {} | scanned {} | deleted {}",
+        "🔄 [On-demand External Sync] {} | scanned {} | missing {}",
         normalized,
         scanned_paths.len(),
-        deleted_count
+        missing_count
     );
 
     Ok(())
 }
 
-async fn upsert_missing_path_to_db(pool: &Pool<Sqlite>, root_dir: &Path, rel_path: &str) -> Result<()> {
+async fn upsert_missing_path_to_db(
+    pool: &Pool<Sqlite>,
+    root_dir: &Path,
+    rel_path: &str,
+    follow_symlinks: bool,
+) -> Result<()> {
     let normalized = normalize_rel_path(rel_path);
     if normalized.is_empty() || normalized == "." {
         return Ok(());
@@ -336,11 +1107,9 @@ async fn upsert_missing_path_to_db(pool: &Pool<Sqlite>, root_dir: &Path, rel_pat
             return results;
         }
 
-        for entry in WalkDir::new(&full_path).into_iter().filter_map(|e| e.ok()) {
-            if entry.file_type().is_file() && is_image_ext(entry.path()) {
-                if let Some(meta) = process_image_metadata_sync(entry.path(), &root_clone) {
-                    results.push(meta);
-                }
+        for entry in walk_image_files(&full_path, follow_symlinks) {
+            if let Some(meta) = process_image_metadata_sync(entry.path(), &root_clone) {
+                results.push(meta);
             }
         }
         results
@@ -354,22 +1123,191 @@ async fn upsert_missing_path_to_db(pool: &Pool<Sqlite>, root_dir: &Path, rel_pat
 
     let mut tx = pool.begin().await?;
     for meta in scanned {
-        sqlx::query("INSERT OR REPLACE INTO images (path, mtime, width, height, is_landscape) VALUES (?, ?, ?, ?, ?)")
-            .bind(meta.path)
-            .bind(meta.mtime)
-            .bind(meta.width)
-            .bind(meta.height)
-            .bind(meta.is_landscape)
-            .execute(&mut *tx)
-            .await?;
+        upsert_image_row(&mut tx, &meta).await?;
     }
     tx.commit().await?;
 
     Ok(())
 }
 
+/// 不访问文件系统的权限检查：只看路径键本身是否落在 ROOT_DIR 之内
+fn lexically_authorized(root_dir: &Path, rel_path: &str, allow_parent: bool) -> bool {
+    if rel_path.is_empty() || rel_path == "." {
+        return false;
+    }
+    allow_parent
+        || (!is_external_key(rel_path) && is_under_root(root_dir, &resolve_full_path(root_dir, rel_path)))
+}
+
+/// 批量查询哪些路径存在于索引中
+async fn indexed_paths(pool: &Pool<Sqlite>, paths: &[String]) -> HashSet<String> {
+    let mut found = HashSet::new();
+    for chunk in paths.chunks(500) {
+        let placeholders = vec!["?"; chunk.len()].join(", ");
+        let sql = format!("SELECT path FROM images WHERE path IN ({}) AND {}", placeholders, PRESENT_SQL_FILTER);
+        let mut query = sqlx::query_as::<_, (String,)>(&sql);
+        for path in chunk {
+            query = query.bind(path);
+        }
+        let rows = query.fetch_all(pool).await.unwrap_or_default();
+        found.extend(rows.into_iter().map(|(path,)| path));
+    }
+    found
+}
+
+/// 读取某个客户端的"不再显示"列表
+async fn load_blocklist(pool: &Pool<Sqlite>, client_ip: &str) -> HashSet<String> {
+    let rows: Vec<(String,)> = sqlx::query_as("SELECT path FROM blocklist WHERE client_ip = ?")
+        .bind(client_ip)
+        .fetch_all(pool)
+        .await
+        .unwrap_or_default();
+    rows.into_iter().map(|(path,)| path).collect()
+}
+
 // --- 核心逻辑：扫描与数据库 ---
 
+/// 挂在图片上的元数据表：(表名, 建表语句, 索引)。
+/// 路径外键随 images 级联：图片记录被清除时一并删除，路径被改写 (重新关联移动过的文件) 时跟随
+const IMAGE_METADATA_TABLES: &[(&str, &str, &str)] = &[
+    (
+        "image_tags",
+        "CREATE TABLE IF NOT EXISTS image_tags (
+            path TEXT NOT NULL REFERENCES images (path) ON DELETE CASCADE ON UPDATE CASCADE,
+            tag TEXT NOT NULL,
+            PRIMARY KEY (path, tag)
+        )",
+        "CREATE INDEX IF NOT EXISTS idx_image_tags_tag ON image_tags (tag);",
+    ),
+    (
+        "suggested_tags",
+        "CREATE TABLE IF NOT EXISTS suggested_tags (
+            path TEXT NOT NULL REFERENCES images (path) ON DELETE CASCADE ON UPDATE CASCADE,
+            tag TEXT NOT NULL,
+            score REAL NOT NULL,
+            PRIMARY KEY (path, tag)
+        )",
+        "",
+    ),
+    (
+        "faces",
+        "CREATE TABLE IF NOT EXISTS faces (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            path TEXT NOT NULL REFERENCES images (path) ON DELETE CASCADE ON UPDATE CASCADE,
+            x REAL NOT NULL,
+            y REAL NOT NULL,
+            w REAL NOT NULL,
+            h REAL NOT NULL,
+            score REAL NOT NULL,
+            embedding BLOB NOT NULL,
+            cluster_id INTEGER
+        )",
+        "CREATE INDEX IF NOT EXISTS idx_faces_path ON faces (path);
+        CREATE INDEX IF NOT EXISTS idx_faces_cluster ON faces (cluster_id);",
+    ),
+    (
+        "image_positions",
+        "CREATE TABLE IF NOT EXISTS image_positions (
+            path TEXT PRIMARY KEY REFERENCES images (path) ON DELETE CASCADE ON UPDATE CASCADE,
+            folder TEXT NOT NULL,
+            position INTEGER NOT NULL
+        )",
+        "CREATE INDEX IF NOT EXISTS idx_image_positions_folder ON image_positions (folder);",
+    ),
+    (
+        "image_captions",
+        "CREATE TABLE IF NOT EXISTS image_captions (
+            path TEXT PRIMARY KEY REFERENCES images (path) ON DELETE CASCADE ON UPDATE CASCADE,
+            title TEXT,
+            caption TEXT,
+            rating INTEGER,
+            updated_at REAL NOT NULL
+        )",
+        "",
+    ),
+    (
+        "image_edits",
+        "CREATE TABLE IF NOT EXISTS image_edits (
+            path TEXT PRIMARY KEY REFERENCES images (path) ON DELETE CASCADE ON UPDATE CASCADE,
+            crop_x REAL,
+            crop_y REAL,
+            crop_w REAL,
+            crop_h REAL,
+            rotation REAL NOT NULL DEFAULT 0,
+            exposure REAL NOT NULL DEFAULT 0,
+            updated_at REAL NOT NULL
+        )",
+        "",
+    ),
+    (
+        "backups",
+        "CREATE TABLE IF NOT EXISTS backups (
+            path TEXT PRIMARY KEY REFERENCES images (path) ON DELETE CASCADE ON UPDATE CASCADE,
+            object_key TEXT,
+            size INTEGER,
+            mtime REAL,
+            uploaded_at REAL,
+            error TEXT,
+            attempted_at REAL
+        )",
+        "",
+    ),
+];
+
+/// 创建元数据表；旧版本建的表没有外键时重建，指向不存在图片的记录先补一条缺失墓碑，
+/// 按正常的宽限期保留 (文件在原路径重新出现即恢复)
+async fn init_image_metadata_tables(pool: &Pool<Sqlite>) -> Result<()> {
+    for (table, create, indexes) in IMAGE_METADATA_TABLES {
+        let columns: Vec<(String,)> = sqlx::query_as("SELECT name FROM pragma_table_info(?)")
+            .bind(table)
+            .fetch_all(pool)
+            .await?;
+        let (foreign_keys,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM pragma_foreign_key_list(?)")
+            .bind(table)
+            .fetch_one(pool)
+            .await?;
+        if columns.is_empty() || foreign_keys > 0 {
+            sqlx::query(create).execute(pool).await?;
+            sqlx::query(indexes).execute(pool).await?;
+            continue;
+        }
+
+        let mut tx = pool.begin().await?;
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs_f64();
+        let orphans = sqlx::query(&format!(
+            "INSERT OR IGNORE INTO images (path, mtime, width, height, is_landscape, size, missing_since)
+             SELECT DISTINCT path, 0, 0, 0, 0, 0, ? FROM {table} WHERE path NOT IN (SELECT path FROM images)"
+        ))
+        .bind(now)
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+        sqlx::query(&format!("ALTER TABLE {table} RENAME TO {table}_legacy"))
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query(create).execute(&mut *tx).await?;
+        let (new_columns,): (String,) = sqlx::query_as("SELECT group_concat(name, ', ') FROM pragma_table_info(?)")
+            .bind(table)
+            .fetch_one(&mut *tx)
+            .await?;
+        let copied: Vec<&str> = new_columns
+            .split(", ")
+            .filter(|name| columns.iter().any(|(old,)| old == name))
+            .collect();
+        sqlx::query(&format!(
+            "INSERT INTO {table} ({cols}) SELECT {cols} FROM {table}_legacy",
+            cols = copied.join(", ")
+        ))
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query(&format!("DROP TABLE {table}_legacy")).execute(&mut *tx).await?;
+        sqlx::query(indexes).execute(&mut *tx).await?;
+        tx.commit().await?;
+        tracing::info!("🔗 元数据表 {} 已添加外键约束 (为 {} 个失联路径补建缺失记录)", table, orphans);
+    }
+    Ok(())
+}
+
 /// 初始化数据库表
 async fn init_db(pool: &Pool<Sqlite>) -> Result<()> {
     sqlx::query(
@@ -378,20 +1316,295 @@ async fn init_db(pool: &Pool<Sqlite>) -> Result<()> {
             mtime REAL, 
             width INTEGER, 
             height INTEGER, 
-            is_landscape BOOLEAN
+            is_landscape BOOLEAN,
+            size INTEGER NOT NULL DEFAULT 0,
+            hash TEXT,
+            embedding BLOB,
+            faces_scanned INTEGER NOT NULL DEFAULT 0,
+            taken_at REAL,
+            phash TEXT,
+            focus_x REAL,
+            focus_y REAL,
+            missing_since REAL,
+            added_at REAL,
+            color_profile TEXT,
+            hdr_gain_map BOOLEAN,
+            is_panorama BOOLEAN NOT NULL DEFAULT 0
         );
         CREATE TABLE IF NOT EXISTS playlists (
             client_ip TEXT PRIMARY KEY,
             playlist TEXT NOT NULL,
             criteria_json TEXT,
+            created_at REAL NOT NULL,
+            current_index INTEGER,
+            client_settings TEXT,
+            ended_at REAL
+        );
+        CREATE TABLE IF NOT EXISTS blocklist (
+            client_ip TEXT NOT NULL,
+            path TEXT NOT NULL,
+            created_at REAL NOT NULL,
+            PRIMARY KEY (client_ip, path)
+        );
+        CREATE TABLE IF NOT EXISTS folder_subscriptions (
+            client_ip TEXT NOT NULL,
+            prefix TEXT NOT NULL,
+            created_at REAL NOT NULL,
+            PRIMARY KEY (client_ip, prefix)
+        );
+        CREATE TABLE IF NOT EXISTS image_companions (
+            path TEXT NOT NULL,
+            companion TEXT NOT NULL,
+            kind TEXT NOT NULL,
+            PRIMARY KEY (path, companion)
+        );
+        CREATE TABLE IF NOT EXISTS replica_sent (
+            target TEXT NOT NULL,
+            path TEXT NOT NULL,
+            fingerprint TEXT NOT NULL,
+            PRIMARY KEY (target, path)
+        );
+        CREATE TABLE IF NOT EXISTS trash (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            original_path TEXT NOT NULL,
+            trash_path TEXT NOT NULL,
+            size INTEGER NOT NULL DEFAULT 0,
+            deleted_at REAL NOT NULL
+        );
+        CREATE TABLE IF NOT EXISTS image_stats (
+            path TEXT PRIMARY KEY,
+            serve_count INTEGER NOT NULL DEFAULT 0,
+            last_served REAL NOT NULL
+        );
+        CREATE TABLE IF NOT EXISTS people (
+            cluster_id INTEGER PRIMARY KEY,
+            name TEXT
+        );
+        CREATE TABLE IF NOT EXISTS devices (
+            id TEXT PRIMARY KEY,
+            name TEXT,
+            assignments_json TEXT NOT NULL DEFAULT '[]',
+            dark_hours_json TEXT,
+            profile TEXT,
+            created_at REAL NOT NULL,
+            last_seen REAL,
+            last_ip TEXT
+        );
+        CREATE TABLE IF NOT EXISTS device_profiles (
+            name TEXT PRIMARY KEY,
+            profile_json TEXT NOT NULL
+        );
+        CREATE TABLE IF NOT EXISTS shares (
+            token TEXT PRIMARY KEY,
+            kind TEXT NOT NULL,
+            title TEXT,
+            target TEXT,
+            paths_json TEXT,
+            watermark TEXT,
+            expires_at REAL,
+            max_downloads INTEGER,
+            downloads INTEGER NOT NULL DEFAULT 0,
+            allow_comments INTEGER NOT NULL DEFAULT 0,
+            password_hash TEXT,
+            views INTEGER NOT NULL DEFAULT 0,
+            last_viewed_at REAL,
+            revoked_at REAL,
             created_at REAL NOT NULL
-        );"
-    )
-    .execute(pool)
-    .await?;
-
-    let _ = sqlx::query("ALTER TABLE playlists ADD COLUMN criteria_json TEXT")
-        .execute(pool)
+        );
+        CREATE TABLE IF NOT EXISTS share_visitors (
+            token TEXT NOT NULL,
+            client_ip TEXT NOT NULL,
+            views INTEGER NOT NULL DEFAULT 0,
+            first_seen REAL NOT NULL,
+            last_seen REAL NOT NULL,
+            PRIMARY KEY (token, client_ip)
+        );
+        CREATE TABLE IF NOT EXISTS share_comments (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            token TEXT NOT NULL,
+            path TEXT NOT NULL,
+            author TEXT,
+            text TEXT,
+            reaction TEXT,
+            created_at REAL NOT NULL,
+            client_ip TEXT
+        );
+        CREATE INDEX IF NOT EXISTS idx_share_comments_token ON share_comments (token, path);
+        CREATE TABLE IF NOT EXISTS watermarks (
+            name TEXT PRIMARY KEY,
+            config_json TEXT NOT NULL
+        );
+        CREATE TABLE IF NOT EXISTS schedule_rules (
+            position INTEGER PRIMARY KEY,
+            name TEXT NOT NULL,
+            rule_json TEXT NOT NULL
+        );
+        CREATE TABLE IF NOT EXISTS digest_subscriptions (
+            email TEXT PRIMARY KEY,
+            config_json TEXT NOT NULL,
+            last_sent_at REAL
+        );
+        CREATE TABLE IF NOT EXISTS folders (
+            path TEXT PRIMARY KEY,
+            title TEXT,
+            description TEXT,
+            cover TEXT,
+            sort TEXT,
+            updated_at REAL,
+            orientation TEXT,
+            interval_secs INTEGER,
+            archived INTEGER NOT NULL DEFAULT 0,
+            archive_note TEXT
+        );
+        CREATE TABLE IF NOT EXISTS idempotency_keys (
+            key TEXT PRIMARY KEY,
+            fingerprint TEXT NOT NULL,
+            status INTEGER,
+            content_type TEXT,
+            body BLOB,
+            created_at REAL NOT NULL
+        );
+
+        CREATE TABLE IF NOT EXISTS jobs (
+            id TEXT PRIMARY KEY,
+            kind TEXT NOT NULL,
+            status TEXT NOT NULL,
+            progress REAL,
+            error TEXT,
+            result TEXT,
+            created_at REAL NOT NULL,
+            finished_at REAL
+        );
+        CREATE INDEX IF NOT EXISTS idx_jobs_created ON jobs (created_at);
+
+        CREATE TABLE IF NOT EXISTS external_files (
+            id TEXT PRIMARY KEY,
+            path TEXT NOT NULL UNIQUE
+        );
+
+        CREATE TABLE IF NOT EXISTS access_log (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            at REAL NOT NULL,
+            client_ip TEXT NOT NULL,
+            method TEXT NOT NULL,
+            path TEXT NOT NULL,
+            status INTEGER NOT NULL,
+            bytes INTEGER NOT NULL,
+            duration_ms REAL NOT NULL
+        );
+        CREATE INDEX IF NOT EXISTS idx_access_log_at ON access_log (at);
+        CREATE INDEX IF NOT EXISTS idx_access_log_path ON access_log (path);"
+    )
+    .execute(pool)
+    .await?;
+
+    let _ = sqlx::query("ALTER TABLE playlists ADD COLUMN criteria_json TEXT")
+        .execute(pool)
+        .await;
+    let _ = sqlx::query("ALTER TABLE playlists ADD COLUMN current_index INTEGER")
+        .execute(pool)
+        .await;
+    let _ = sqlx::query("ALTER TABLE playlists ADD COLUMN client_settings TEXT")
+        .execute(pool)
+        .await;
+    let _ = sqlx::query("ALTER TABLE playlists ADD COLUMN ended_at REAL")
+        .execute(pool)
+        .await;
+    let _ = sqlx::query("ALTER TABLE folders ADD COLUMN orientation TEXT")
+        .execute(pool)
+        .await;
+    let _ = sqlx::query("ALTER TABLE folders ADD COLUMN interval_secs INTEGER")
+        .execute(pool)
+        .await;
+    let _ = sqlx::query("ALTER TABLE folders ADD COLUMN archived INTEGER NOT NULL DEFAULT 0")
+        .execute(pool)
+        .await;
+    let _ = sqlx::query("ALTER TABLE folders ADD COLUMN archive_note TEXT")
+        .execute(pool)
+        .await;
+    let _ = sqlx::query("ALTER TABLE images ADD COLUMN size INTEGER NOT NULL DEFAULT 0")
+        .execute(pool)
+        .await;
+    let _ = sqlx::query("ALTER TABLE images ADD COLUMN hash TEXT")
+        .execute(pool)
+        .await;
+    let _ = sqlx::query("ALTER TABLE images ADD COLUMN embedding BLOB")
+        .execute(pool)
+        .await;
+    let _ = sqlx::query("ALTER TABLE images ADD COLUMN faces_scanned INTEGER NOT NULL DEFAULT 0")
+        .execute(pool)
+        .await;
+    let _ = sqlx::query("ALTER TABLE images ADD COLUMN taken_at REAL")
+        .execute(pool)
+        .await;
+    let _ = sqlx::query("ALTER TABLE images ADD COLUMN phash TEXT")
+        .execute(pool)
+        .await;
+    let _ = sqlx::query("ALTER TABLE images ADD COLUMN focus_x REAL")
+        .execute(pool)
+        .await;
+    let _ = sqlx::query("ALTER TABLE images ADD COLUMN focus_y REAL")
+        .execute(pool)
+        .await;
+    let _ = sqlx::query("ALTER TABLE images ADD COLUMN missing_since REAL")
+        .execute(pool)
+        .await;
+    let _ = sqlx::query("CREATE INDEX IF NOT EXISTS idx_images_missing_since ON images (missing_since)")
+        .execute(pool)
+        .await;
+    // 旧索引没有记录加入时间，用修改时间近似
+    if sqlx::query("ALTER TABLE images ADD COLUMN added_at REAL").execute(pool).await.is_ok() {
+        let _ = sqlx::query("UPDATE images SET added_at = mtime WHERE added_at IS NULL")
+            .execute(pool)
+            .await;
+    }
+    let _ = sqlx::query("ALTER TABLE images ADD COLUMN color_profile TEXT")
+        .execute(pool)
+        .await;
+    let _ = sqlx::query("ALTER TABLE images ADD COLUMN hdr_gain_map BOOLEAN")
+        .execute(pool)
+        .await;
+    if sqlx::query("ALTER TABLE images ADD COLUMN is_panorama BOOLEAN NOT NULL DEFAULT 0").execute(pool).await.is_ok() {
+        let _ = sqlx::query(&format!(
+            "UPDATE images SET is_panorama = 1 WHERE MIN(width, height) > 0 AND MAX(width, height) >= {} * MIN(width, height)",
+            PANORAMA_MIN_ASPECT
+        ))
+        .execute(pool)
+        .await;
+    }
+    let _ = sqlx::query("ALTER TABLE devices ADD COLUMN dark_hours_json TEXT")
+        .execute(pool)
+        .await;
+    let _ = sqlx::query("ALTER TABLE devices ADD COLUMN profile TEXT")
+        .execute(pool)
+        .await;
+    let _ = sqlx::query("ALTER TABLE devices ADD COLUMN key_hash TEXT")
+        .execute(pool)
+        .await;
+    let _ = sqlx::query("ALTER TABLE image_captions ADD COLUMN rating INTEGER")
+        .execute(pool)
+        .await;
+    init_image_metadata_tables(pool).await?;
+    let _ = sqlx::query("ALTER TABLE shares ADD COLUMN allow_comments INTEGER NOT NULL DEFAULT 0")
+        .execute(pool)
+        .await;
+    for column in [
+        "password_hash TEXT",
+        "views INTEGER NOT NULL DEFAULT 0",
+        "last_viewed_at REAL",
+        "revoked_at REAL",
+    ] {
+        let _ = sqlx::query(&format!("ALTER TABLE shares ADD COLUMN {}", column))
+            .execute(pool)
+            .await;
+    }
+    // 上次退出时仍在处理中的幂等请求结果未知，允许客户端重试
+    let _ = sqlx::query("DELETE FROM idempotency_keys WHERE status IS NULL")
+        .execute(pool)
+        .await;
+    let _ = sqlx::query("UPDATE jobs SET status = 'failed', error = 'Server restarted', finished_at = ? WHERE status = 'running'")
+        .bind(SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs_f64())
+        .execute(pool)
         .await;
     Ok(())
 }
@@ -400,8 +1613,10 @@ async fn init_db(pool: &Pool<Sqlite>) -> Result<()> {
 fn process_image_metadata_sync(full_path: &Path, root_dir: &Path) -> Option<ImageMetadata> {
     if !full_path.exists() { return None; }
     
-    // 获取修改时间
-    let mtime = full_path.metadata().ok()
+    // 获取修改时间与文件大小
+    let file_meta = full_path.metadata().ok();
+    let size = file_meta.as_ref().map(|m| m.len() as i64).unwrap_or(0);
+    let mtime = file_meta
         .and_then(|m| m.modified().ok())
         .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
         .map(|d| d.as_secs_f64())
@@ -411,9 +1626,8 @@ fn process_image_metadata_sync(full_path: &Path, root_dir: &Path) -> Option<Imag
     let (width, height) = image::image_dimensions(full_path).ok()?;
     let is_landscape = width >= height;
 
-    // 计算相对路径
-    let rel_path = diff_paths(full_path, root_dir)?;
-    let rel_path_str = rel_path.to_string_lossy().replace('\\', "/");
+    // 计算路径键 (相对路径或外部绝对路径键)
+    let rel_path_str = db_path_key(root_dir, full_path)?;
 
     Some(ImageMetadata {
         path: rel_path_str,
@@ -421,253 +1635,574 @@ fn process_image_metadata_sync(full_path: &Path, root_dir: &Path) -> Option<Imag
         width,
         height,
         is_landscape,
+        size,
+        taken_at: read_exif_taken_at(full_path),
+        phash: None,
+        added_at: None,
+        color_profile: None,
+        hdr_gain_map: None,
+        is_panorama: is_panorama(width, height),
     })
 }
 
+/// 读取 EXIF DateTimeOriginal (含亚秒)，转换为秒数；时区未知时按 UTC 处理
+fn read_exif_taken_at(full_path: &Path) -> Option<f64> {
+    let file = std::fs::File::open(full_path).ok()?;
+    let exif = exif::Reader::new()
+        .read_from_container(&mut std::io::BufReader::new(file))
+        .ok()?;
+    let ascii = |tag| match exif.get_field(tag, exif::In::PRIMARY).map(|f| &f.value) {
+        Some(exif::Value::Ascii(values)) => values.first().cloned(),
+        _ => None,
+    };
+
+    let mut dt = exif::DateTime::from_ascii(&ascii(exif::Tag::DateTimeOriginal)?).ok()?;
+    if let Some(subsec) = ascii(exif::Tag::SubSecTimeOriginal) {
+        dt.parse_subsec(&subsec).ok();
+    }
+
+    // 公历日期 -> 自 1970-01-01 起的天数
+    let (y, m, d) = (dt.year as i64, dt.month as i64, dt.day as i64);
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let doy = (153 * ((m + 9) % 12) + 2) / 5 + d - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146097 + doe - 719468;
+
+    let secs = days * 86400 + dt.hour as i64 * 3600 + dt.minute as i64 * 60 + dt.second as i64;
+    Some(secs as f64 + dt.nanosecond.unwrap_or(0) as f64 / 1e9)
+}
+
+/// 差值哈希 (dHash)：缩放到 9x8 灰度，比较相邻像素
+fn compute_phash(full_path: &Path) -> Option<u64> {
+    let gray = image::open(full_path)
+        .ok()?
+        .resize_exact(9, 8, image::imageops::FilterType::Triangle)
+        .to_luma8();
+    let mut hash = 0u64;
+    for y in 0..8 {
+        for x in 0..8 {
+            hash <<= 1;
+            if gray.get_pixel(x, y)[0] > gray.get_pixel(x + 1, y)[0] {
+                hash |= 1;
+            }
+        }
+    }
+    Some(hash)
+}
+
+/// 为尚无感知哈希的图片补算 (需要解码整张图，因此放在扫描之后单独进行)
+async fn fingerprint_pending_images(state: &AppState) {
+    let mut processed = 0usize;
+    loop {
+        let batch: Vec<(String,)> = sqlx::query_as("SELECT path FROM images WHERE phash IS NULL AND missing_since IS NULL LIMIT 256")
+            .fetch_all(&state.db)
+            .await
+            .unwrap_or_default();
+        if batch.is_empty() {
+            break;
+        }
+
+        let root_dir = state.root_dir.clone();
+        let hashes: Vec<(String, String)> = tokio::task::spawn_blocking(move || {
+            batch
+                .into_iter()
+                .map(|(path,)| {
+                    let hash = compute_phash(&resolve_full_path(&root_dir, &path))
+                        .map(|h| format!("{:016x}", h))
+                        .unwrap_or_default();
+                    (path, hash)
+                })
+                .collect()
+        })
+        .await
+        .unwrap_or_default();
+        if hashes.is_empty() {
+            break;
+        }
+
+        // 写入失败时本轮停止：否则同一批图片会被无限次重新选出
+        let written = async {
+            let mut tx = state.db.begin().await?;
+            for (path, hash) in &hashes {
+                sqlx::query("UPDATE images SET phash = ? WHERE path = ?")
+                    .bind(hash)
+                    .bind(path)
+                    .execute(&mut *tx)
+                    .await?;
+            }
+            tx.commit().await
+        }
+        .await;
+        if let Err(e) = written {
+            tracing::warn!("⚠️ [Background] 感知哈希写入失败，下次扫描后重试: {}", e);
+            break;
+        }
+        processed += hashes.len();
+    }
+    if processed > 0 {
+        tracing::info!("🧬 [Background] 感知哈希完成: {} 张", processed);
+    }
+}
+
+/// 检测尚未检测过的图片的 ICC 配置文件与 HDR 增益图
+async fn inspect_pending_colors(state: &AppState) {
+    let mut processed = 0usize;
+    loop {
+        let batch: Vec<(String,)> =
+            sqlx::query_as("SELECT path FROM images WHERE hdr_gain_map IS NULL AND missing_since IS NULL LIMIT 256")
+                .fetch_all(&state.db)
+                .await
+                .unwrap_or_default();
+        if batch.is_empty() {
+            break;
+        }
+
+        let root_dir = state.root_dir.clone();
+        let inspected: Vec<(String, color::ColorInfo)> = tokio::task::spawn_blocking(move || {
+            batch
+                .into_iter()
+                .map(|(path,)| {
+                    let info = color::inspect(&resolve_full_path(&root_dir, &path));
+                    (path, info)
+                })
+                .collect()
+        })
+        .await
+        .unwrap_or_default();
+        if inspected.is_empty() {
+            break;
+        }
+
+        // 写入失败时本轮停止：否则同一批图片会被无限次重新选出
+        let written = async {
+            let mut tx = state.db.begin().await?;
+            for (path, info) in &inspected {
+                sqlx::query("UPDATE images SET color_profile = ?, hdr_gain_map = ? WHERE path = ?")
+                    .bind(&info.profile)
+                    .bind(info.hdr_gain_map)
+                    .bind(path)
+                    .execute(&mut *tx)
+                    .await?;
+            }
+            tx.commit().await
+        }
+        .await;
+        if let Err(e) = written {
+            tracing::warn!("⚠️ [Background] 色彩信息写入失败，下次扫描后重试: {}", e);
+            break;
+        }
+        processed += inspected.len();
+    }
+    if processed > 0 {
+        tracing::info!("🎨 [Background] 色彩信息检测完成: {} 张", processed);
+    }
+}
+
+/// 写入扫描得到的元数据；文件变化 (修改时间或大小不同) 时派生数据 (哈希、向量、人脸、焦点、色彩信息) 清空待重新计算，
+/// 用 UPSERT 而不是 REPLACE，以免删除旧行时级联删掉标签等元数据 (加入时间也只在首次写入时设置)
+async fn upsert_image_row(conn: &mut sqlx::SqliteConnection, meta: &ImageMetadata) -> sqlx::Result<()> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs_f64();
+    sqlx::query(
+        "INSERT INTO images (path, mtime, width, height, is_landscape, is_panorama, size, taken_at, added_at)
+         VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)
+         ON CONFLICT(path) DO UPDATE SET mtime = excluded.mtime, width = excluded.width, height = excluded.height,
+             is_landscape = excluded.is_landscape, is_panorama = excluded.is_panorama, size = excluded.size,
+             taken_at = excluded.taken_at,
+             hash = CASE WHEN images.mtime IS NOT excluded.mtime OR images.size IS NOT excluded.size THEN NULL ELSE images.hash END,
+             embedding = CASE WHEN images.mtime IS NOT excluded.mtime OR images.size IS NOT excluded.size THEN NULL ELSE images.embedding END,
+             faces_scanned = CASE WHEN images.mtime IS NOT excluded.mtime OR images.size IS NOT excluded.size THEN 0 ELSE images.faces_scanned END,
+             phash = CASE WHEN images.mtime IS NOT excluded.mtime OR images.size IS NOT excluded.size THEN NULL ELSE images.phash END,
+             focus_x = CASE WHEN images.mtime IS NOT excluded.mtime OR images.size IS NOT excluded.size THEN NULL ELSE images.focus_x END,
+             focus_y = CASE WHEN images.mtime IS NOT excluded.mtime OR images.size IS NOT excluded.size THEN NULL ELSE images.focus_y END,
+             color_profile = CASE WHEN images.mtime IS NOT excluded.mtime OR images.size IS NOT excluded.size THEN NULL ELSE images.color_profile END,
+             hdr_gain_map = CASE WHEN images.mtime IS NOT excluded.mtime OR images.size IS NOT excluded.size THEN NULL ELSE images.hdr_gain_map END,
+             missing_since = NULL",
+    )
+    .bind(&meta.path)
+    .bind(meta.mtime)
+    .bind(meta.width)
+    .bind(meta.height)
+    .bind(meta.is_landscape)
+    .bind(meta.is_panorama)
+    .bind(meta.size)
+    .bind(meta.taken_at)
+    .bind(now)
+    .execute(conn)
+    .await?;
+    Ok(())
+}
+
+/// 全量扫描每个写入事务包含的条目数，取消请求在批次之间检查
+const SCAN_BATCH_SIZE: usize = 500;
+
+/// 一次扫描中新增与消失的图片
+struct ScanChanges {
+    /// 首次建立索引时为空
+    added: Vec<String>,
+    /// 本次新标记为缺失的图片
+    removed: Vec<String>,
+}
+
 /// 后台扫描任务
-async fn scan_library_task(pool: Pool<Sqlite>, root_dir: Arc<PathBuf>) {
+/// 返回本次新加入索引与消失的路径；被 `cancel` 取消时返回 None
+///
+/// 取消是协作式的：遍历文件系统时逐项检查，写库按批次提交，未提交的批次整体回滚，
+/// 已提交的批次保留 (下次扫描会从这里继续补齐)。
+async fn scan_library_task(
+    pool: Pool<Sqlite>,
+    root_dir: Arc<PathBuf>,
+    follow_symlinks: bool,
+    cancel: &tokio_util::sync::CancellationToken,
+) -> Option<ScanChanges> {
     tracing::info!("🔍 [Background] 开始全量扫描...");
     let start = std::time::Instant::now();
 
     // 1. 遍历文件系统 (FS)
     // 使用 spawn_blocking 避免阻塞 Tokio 运行时
     let root_clone = root_dir.clone();
+    let walk_cancel = cancel.clone();
     let fs_files: HashMap<String, PathBuf> = tokio::task::spawn_blocking(move || {
         let mut map = HashMap::new();
-        for entry in WalkDir::new(&*root_clone).into_iter().filter_map(|e| e.ok()) {
-            if entry.file_type().is_file() && is_image_ext(entry.path()) {
-                if let Some(rel) = diff_paths(entry.path(), &*root_clone) {
-                    let rel_str = rel.to_string_lossy().replace('\\', "/");
-                    map.insert(rel_str, entry.path().to_path_buf());
-                }
+        for entry in walk_image_files(&root_clone, follow_symlinks) {
+            if walk_cancel.is_cancelled() {
+                break;
+            }
+            if let Some(rel_str) = db_path_key(&root_clone, entry.path()) {
+                map.insert(rel_str, entry.path().to_path_buf());
             }
         }
         map
     }).await.unwrap();
+    if cancel.is_cancelled() {
+        tracing::info!("🛑 [Background] 扫描在遍历文件系统时被取消，索引未改动");
+        return None;
+    }
 
     // 2. 获取数据库现有记录
-    let db_rows = sqlx::query("SELECT path, mtime FROM images")
+    let db_rows = sqlx::query("SELECT path, mtime, size, missing_since FROM images")
         .fetch_all(&pool)
         .await
         .unwrap_or_default();
-    
-    let db_files: HashMap<String, f64> = db_rows.into_iter()
-        .map(|row| (row.get("path"), row.get("mtime")))
+
+    let tombstoned: HashSet<String> = db_rows
+        .iter()
+        .filter(|row| row.get::<Option<f64>, _>("missing_since").is_some())
+        .map(|row| row.get("path"))
+        .collect();
+    let db_files: HashMap<String, (f64, i64)> = db_rows.into_iter()
+        .map(|row| (row.get("path"), (row.get("mtime"), row.get("size"))))
         .collect();
 
     // 3. 找出需要更新或插入的文件
     let mut to_process = Vec::new();
+    let first_index = db_files.is_empty();
+    let mut added = Vec::new();
+    // 重新出现且未改动的文件 (如磁盘重新挂载)，只需清除缺失标记
+    let mut restored = Vec::new();
     for (path, full_path) in &fs_files {
-        // 如果 DB 里没有，或者 mtime 不一致，则需要处理
-        let mtime = full_path.metadata().ok()
+        // 如果 DB 里没有，或者 mtime / 大小不一致 (旧索引没有大小)，则需要处理
+        let file_meta = full_path.metadata().ok();
+        let size = file_meta.as_ref().map(|m| m.len() as i64).unwrap_or(0);
+        let mtime = file_meta
             .and_then(|m| m.modified().ok())
             .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
             .map(|d| d.as_secs_f64())
             .unwrap_or(0.0);
 
-        if !db_files.contains_key(path) || (db_files.get(path).unwrap() - mtime).abs() > 0.001 {
+        let changed = match db_files.get(path) {
+            Some((db_mtime, db_size)) => (db_mtime - mtime).abs() > 0.001 || *db_size != size,
+            None => true,
+        };
+        if changed {
             to_process.push(full_path.clone());
+        } else if tombstoned.contains(path) {
+            restored.push(path.clone());
+        }
+        if !first_index && !db_files.contains_key(path) {
+            added.push(path.clone());
         }
     }
 
     // 4. 并发处理元数据读取 (Bounded Parallelism)
     if !to_process.is_empty() {
         tracing::info!("🚀 [Background] 发现 {} 个变动文件，开始处理...", to_process.len());
-        let mut updates = Vec::new();
-        
+
         // 使用 stream 处理并发，避免瞬间开启过多线程
         let stream = futures::stream::iter(to_process)
             .map(|path| {
                 let root = root_dir.clone();
                 tokio::task::spawn_blocking(move || process_image_metadata_sync(&path, &root))
             })
-            .buffer_unordered(16); // 控制并发数为 16
-
-        let mut processed_stream = stream;
-        while let Some(result) = processed_stream.next().await {
-            if let Ok(Some(meta)) = result {
-                updates.push(meta);
+            .buffer_unordered(16) // 控制并发数为 16
+            .chunks(SCAN_BATCH_SIZE);
+
+        // 分批写入数据库 (每批一个事务)
+        let mut batches = std::pin::pin!(stream);
+        while let Some(results) = batches.next().await {
+            if cancel.is_cancelled() {
+                tracing::info!("🛑 [Background] 扫描已取消，丢弃未写入的批次");
+                return None;
             }
-        }
-
-        // 批量写入数据库 (事务)
-        if !updates.is_empty() {
             let mut tx = pool.begin().await.unwrap();
-            for meta in updates {
-                sqlx::query("INSERT OR REPLACE INTO images (path, mtime, width, height, is_landscape) VALUES (?, ?, ?, ?, ?)")
-                    .bind(meta.path)
-                    .bind(meta.mtime)
-                    .bind(meta.width)
-                    .bind(meta.height)
-                    .bind(meta.is_landscape)
-                    .execute(&mut *tx)
-                    .await.ok();
+            for meta in results.into_iter().filter_map(|r| r.ok().flatten()) {
+                upsert_image_row(&mut tx, &meta).await.ok();
+            }
+            if cancel.is_cancelled() {
+                tx.rollback().await.ok();
+                tracing::info!("🛑 [Background] 扫描已取消，当前批次已回滚");
+                return None;
             }
             tx.commit().await.unwrap();
         }
     }
 
-    // 5. 清理失效文件 (仅清理 Root 下的)
-    let mut deleted_count = 0;
-    for db_path in db_files.keys() {
-        // 简单判断：如果在 root 目录下且 fs 扫描没扫到，就删掉
-        // 注意：这里需要更严谨的路径判断逻辑防止删除外部挂载的记录，这里简化处理
-        if !fs_files.contains_key(db_path) && !db_path.starts_with("../") {
-            sqlx::query("DELETE FROM images WHERE path = ?")
+    // 5. 失效文件标记为缺失 (仅限 Root 下的)，宽限期后由 purge_missing_images 删除；重新出现的清除标记
+    // 简单判断：如果在 root 目录下且 fs 扫描没扫到，就视为缺失
+    // 注意：这里需要更严谨的路径判断逻辑防止误标外部挂载的记录，这里简化处理
+    // 归档文件夹的磁盘可能离线，其中的记录保持原样
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs_f64();
+    let archived = archived_folders(&pool).await;
+    let missing: Vec<&String> = db_files
+        .keys()
+        .filter(|db_path| {
+            !fs_files.contains_key(*db_path)
+                && !is_external_key(db_path)
+                && !tombstoned.contains(*db_path)
+                && archived_folder_of(&archived, db_path).is_none()
+        })
+        .collect();
+    let removed: Vec<String> = missing.iter().map(|path| (*path).clone()).collect();
+    let marks: Vec<(&String, Option<f64>)> = missing
+        .into_iter()
+        .map(|path| (path, Some(now)))
+        .chain(restored.iter().map(|path| (path, None)))
+        .collect();
+    for batch in marks.chunks(SCAN_BATCH_SIZE) {
+        if cancel.is_cancelled() {
+            tracing::info!("🛑 [Background] 扫描已取消，停止标记缺失文件");
+            return None;
+        }
+        let Ok(mut tx) = pool.begin().await else { break };
+        for (db_path, missing_since) in batch {
+            sqlx::query("UPDATE images SET missing_since = ? WHERE path = ?")
+                .bind(missing_since)
                 .bind(db_path)
-                .execute(&pool)
+                .execute(&mut *tx)
                 .await.ok();
-            deleted_count += 1;
         }
+        tx.commit().await.ok();
+    }
+    if cancel.is_cancelled() {
+        return None;
+    }
+
+    // 6. 伴生文件配对 (Live Photo / RAW+JPEG)，内部路径的配对整体重建
+    let root_clone = root_dir.clone();
+    let pairs = tokio::task::spawn_blocking(move || find_companions(&root_clone, &fs_files))
+        .await
+        .unwrap_or_default();
+    if let Ok(mut tx) = pool.begin().await {
+        sqlx::query(&format!("DELETE FROM image_companions WHERE {}", INTERNAL_PATH_SQL_FILTER))
+            .execute(&mut *tx)
+            .await
+            .ok();
+        for (path, companion, kind) in &pairs {
+            sqlx::query("INSERT OR REPLACE INTO image_companions (path, companion, kind) VALUES (?, ?, ?)")
+                .bind(path)
+                .bind(companion)
+                .bind(kind)
+                .execute(&mut *tx)
+                .await
+                .ok();
+        }
+        tx.commit().await.ok();
     }
 
-    tracing::info!("✅ [Background] 扫描完成，耗时 {:.2}s，清理 {}", start.elapsed().as_secs_f64(), deleted_count);
+    tracing::info!(
+        "✅ [Background] 扫描完成，耗时 {:.2}s，缺失 {}，恢复 {}，伴生文件 {}",
+        start.elapsed().as_secs_f64(),
+        removed.len(),
+        restored.len(),
+        pairs.len()
+    );
+    Some(ScanChanges { added, removed })
 }
 
-// --- Handlers ---
+/// 缺失文件的索引记录保留天数 (`GALLERY_MISSING_GRACE_DAYS`，默认 30；0 为下次扫描即删除)
+fn missing_grace_days(settings: &Settings) -> u64 {
+    settings.parse("GALLERY_MISSING_GRACE_DAYS").unwrap_or(30)
+}
 
-async fn trigger_scan(State(state): State<AppState>) -> Json<serde_json::Value> {
-    tokio::spawn(async move {
-        scan_library_task(state.db, state.root_dir).await;
-    });
-    Json(serde_json::json!({ "status": "scanning_started" }))
+/// 删除缺失超过宽限期的图片记录，返回删除数量
+async fn purge_missing_images(state: &AppState) -> u64 {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs_f64();
+    let cutoff = now - missing_grace_days(&state.settings) as f64 * 86400.0;
+    let purged = sqlx::query("DELETE FROM images WHERE missing_since IS NOT NULL AND missing_since <= ?")
+        .bind(cutoff)
+        .execute(&state.db)
+        .await
+        .map(|r| r.rows_affected())
+        .unwrap_or(0);
+    if purged > 0 {
+        tracing::info!("🧹 [Background] 清除缺失超过宽限期的图片记录 {} 条", purged);
+    }
+    purged
 }
 
-async fn get_playlist(
-    State(state): State<AppState>,
-    connect_info: ConnectInfo<SocketAddr>,
-    Json(req): Json<PlaylistRequest>,
-) -> Json<Vec<String>> {
-    let root_dir = state.root_dir.as_path();
-    let allow_parent = *state.allow_parent_dir_access.read().await;
+fn companion_kind(path: &Path) -> Option<&'static str> {
+    let ext = path.extension()?.to_str()?;
+    COMPANION_EXTENSIONS
+        .iter()
+        .find(|(e, _)| e.eq_ignore_ascii_case(ext))
+        .map(|(_, kind)| *kind)
+}
 
-    // 1. 路径清洗
-    let mut valid_req_paths = Vec::new();
-    for p in req.paths {
-        let rel = normalize_rel_path(&p);
-        let full = resolve_full_path(root_dir, &rel);
-        
-        // 权限检查
-        if !allow_parent && !is_under_root(root_dir, &full) {
-            valid_req_paths.push(".".to_string()); // fallback to root
-        } else {
-            valid_req_paths.push(rel);
+/// 在每张图片所在目录中查找同名 (不区分大小写) 的伴生文件，返回 (图片键, 伴生文件键, 类型)
+fn find_companions(root_dir: &Path, images: &HashMap<String, PathBuf>) -> Vec<(String, String, &'static str)> {
+    let mut by_dir: HashMap<&Path, Vec<(&String, &PathBuf)>> = HashMap::new();
+    for (key, full) in images {
+        if let Some(parent) = full.parent() {
+            by_dir.entry(parent).or_default().push((key, full));
         }
     }
-    let mut seen_req = HashSet::new();
-    valid_req_paths.retain(|p| seen_req.insert(p.clone()));
 
-    let mut external_paths = Vec::new();
-    let mut external_seen = HashSet::new();
-    for p in &valid_req_paths {
-        if p.is_empty() || p == "." {
+    let stem_of = |p: &Path| p.file_stem().map(|s| s.to_string_lossy().to_lowercase());
+    let mut pairs = Vec::new();
+    for (dir, dir_images) in by_dir {
+        let Ok(entries) = std::fs::read_dir(dir) else { continue };
+        let mut companions: HashMap<String, Vec<(PathBuf, &'static str)>> = HashMap::new();
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if let (Some(kind), Some(stem)) = (companion_kind(&path), stem_of(&path)) {
+                if path.is_file() {
+                    companions.entry(stem).or_default().push((path, kind));
+                }
+            }
+        }
+        if companions.is_empty() {
             continue;
         }
-        let full = resolve_full_path(root_dir, p);
-        if !is_under_root(root_dir, &full) && external_seen.insert(p.clone()) {
-            external_paths.push(p.clone());
+        for (key, full) in dir_images {
+            let Some(found) = stem_of(full).and_then(|stem| companions.get(&stem)) else { continue };
+            for (companion, kind) in found {
+                if let Some(companion_key) = db_path_key(root_dir, companion) {
+                    pairs.push((key.clone(), companion_key, *kind));
+                }
+            }
         }
     }
+    pairs
+}
 
-    for ext_path in external_paths {
-        let already_synced = {
-            let guard = state.external_synced_paths_this_boot.read().await;
-            guard.contains(&ext_path)
-        };
+/// 名称排序规则：默认自然排序 (natord)，启用 `icu` 特性后可按语言区域排序，
+/// 让中日韩及带重音的文件名符合当地习惯
+enum NameCollator {
+    Natural,
+    #[cfg(feature = "icu")]
+    Icu(Box<icu_collator::Collator>),
+}
 
-        if !already_synced {
-            if let Err(err) = sync_external_path_to_db(&state.db, root_dir, &ext_path).await {
-                tracing::error!("⚠️ External path sync failed for {}: {}", ext_path, err);
-            }
-            let mut guard = state.external_synced_paths_this_boot.write().await;
-            guard.insert(ext_path);
-        }
+impl NameCollator {
+    fn is_natural(locale: Option<&str>) -> bool {
+        locale.is_none_or(|l| l.is_empty() || l.eq_ignore_ascii_case("natural"))
     }
 
-    let mut missing_paths = Vec::new();
-    for p in &valid_req_paths {
-        if p.is_empty() || p == "." {
-            continue;
-        }
-        let exists_row: Option<(i64,)> = sqlx::query_as("SELECT 1 FROM images WHERE path LIKE ? LIMIT 1")
-            .bind(format!("{}/%", p))
-            .fetch_optional(&state.db)
-            .await
-            .unwrap_or(None);
-        if exists_row.is_none() {
-            missing_paths.push(p.clone());
+    #[cfg(feature = "icu")]
+    fn for_locale(locale: Option<&str>) -> Self {
+        use icu_collator::{Collator, CollatorOptions, Numeric, Strength};
+
+        let Some(tag) = locale.filter(|l| !Self::is_natural(Some(l))) else {
+            return NameCollator::Natural;
+        };
+        let Ok(parsed) = tag.parse::<icu_locid::Locale>() else {
+            tracing::warn!("⚠️ 无法识别的排序语言区域: {}，改用自然排序", tag);
+            return NameCollator::Natural;
+        };
+        let mut options = CollatorOptions::new();
+        options.strength = Some(Strength::Secondary);
+        options.numeric = Some(Numeric::On);
+        match Collator::try_new(&parsed.into(), options) {
+            Ok(collator) => NameCollator::Icu(Box::new(collator)),
+            Err(err) => {
+                tracing::warn!("⚠️ ICU 排序规则 {} 初始化失败: {}，改用自然排序", tag, err);
+                NameCollator::Natural
+            }
         }
     }
 
-    for missing in missing_paths {
-        if let Err(err) = upsert_missing_path_to_db(&state.db, root_dir, &missing).await {
-            tracing::error!("⚠️ Missing-path upsert failed for {}: {}", missing, err);
+    #[cfg(not(feature = "icu"))]
+    fn for_locale(locale: Option<&str>) -> Self {
+        if !Self::is_natural(locale) {
+            tracing::warn!("⚠️ 未启用 icu 特性，忽略排序语言区域 {:?}，改用自然排序", locale);
         }
+        NameCollator::Natural
     }
 
-    // 2. 数据库查询 (直接利用 SQL 筛选，速度极快)
-    // 注意：构建动态 LIKE 查询比较繁琐，这里简化为获取所有符合条件的然后内存过滤
-    // 或者针对每个路径前缀查一次
-    let mut all_images = Vec::new();
-
-    for path_prefix in &valid_req_paths {
-        // 如果不在 DB 中，需要触发即时扫描 (Sync logic similar to Python)
-        // 为简化代码，这里假设后台扫描已覆盖大部分。
-        // 生产环境应在此处检测 DB miss 并回填。
-
-        let (mut query_builder, maybe_prefix_pattern): (String, Option<String>) = if path_prefix == "." || path_prefix.is_empty() {
-            ("SELECT * FROM images WHERE path NOT LIKE '../%'".to_string(), None)
-        } else {
-            (
-                "SELECT * FROM images WHERE path LIKE ?".to_string(),
-                Some(format!("{}/%", path_prefix)),
-            )
-        };
-
-        if !allow_parent && path_prefix != "." && !path_prefix.is_empty() {
-            query_builder.push_str(" AND path NOT LIKE '../%'");
+    fn compare(&self, a: &str, b: &str) -> std::cmp::Ordering {
+        match self {
+            NameCollator::Natural => natord::compare_ignore_case(a, b),
+            #[cfg(feature = "icu")]
+            NameCollator::Icu(collator) => collator.compare(a, b),
         }
-        
-        if req.orientation == "Landscape" {
-            query_builder.push_str(" AND is_landscape = 1");
-        } else if req.orientation == "Portrait" {
-            query_builder.push_str(" AND is_landscape = 0");
-        }
-
-        let rows = if let Some(prefix_pattern) = maybe_prefix_pattern {
-            sqlx::query_as::<_, ImageMetadata>(&query_builder)
-                .bind(prefix_pattern)
-                .fetch_all(&state.db)
-                .await
-                .unwrap_or_default()
-        } else {
-            sqlx::query_as::<_, ImageMetadata>(&query_builder)
-                .fetch_all(&state.db)
-                .await
-                .unwrap_or_default()
-        };
-        
-        all_images.extend(rows);
     }
+}
 
-    // 去重
-    let mut seen = HashSet::new();
-    all_images.retain(|i| seen.insert(i.path.clone()));
-
-    // 3. 排序
-    match req.sort.as_str() {
-        "shuffle" => all_images.shuffle(&mut rand::thread_rng()),
-        "date" => all_images.sort_by(|a, b| b.mtime.partial_cmp(&a.mtime).unwrap()),
-        "name" => all_images.sort_by(|a, b| natord::compare_ignore_case(&a.path, &b.path)),
+/// `sort_images` 支持的排序模式 (接口会拒绝其他值；内部调用遇到未知模式时按名称排序)
+const SORT_MODES: &[&str] = &[
+    "shuffle",
+    "date",
+    "added",
+    "name",
+    "resolution",
+    "subfolder_random",
+    "subfolder_date",
+    "subfolder_prefix",
+    "manual",
+];
+
+/// 按排序模式整理图片列表
+fn sort_images(
+    mut items: Vec<ImageMetadata>,
+    sort: &str,
+    root_dir: &Path,
+    collator: &NameCollator,
+    positions: &HashMap<String, i64>,
+) -> Vec<ImageMetadata> {
+    match sort {
+        "shuffle" => items.shuffle(&mut rand::thread_rng()),
+        "date" => items.sort_by(|a, b| b.mtime.partial_cmp(&a.mtime).unwrap()),
+        // 最近加入索引的在前，同一批加入的按修改时间
+        "added" => items.sort_by(|a, b| {
+            b.added_at
+                .unwrap_or(0.0)
+                .total_cmp(&a.added_at.unwrap_or(0.0))
+                .then_with(|| b.mtime.total_cmp(&a.mtime))
+        }),
+        "name" => items.sort_by(|a, b| collator.compare(&a.path, &b.path)),
+        // 文件夹按名称，文件夹内按手动顺序 (未排的图片按名称跟在后面)
+        "manual" => items.sort_by(|a, b| {
+            collator
+                .compare(&parent_folder(&a.path), &parent_folder(&b.path))
+                .then_with(|| compare_manual(&a.path, &b.path, positions, collator))
+        }),
+        // 按像素数 (宽×高) 从高到低，reverse 方向即从低到高
+        "resolution" => items.sort_by(|a, b| {
+            megapixels(b)
+                .partial_cmp(&megapixels(a))
+                .unwrap_or(std::cmp::Ordering::Equal)
+                .then_with(|| natord::compare_ignore_case(&a.path, &b.path))
+        }),
         "subfolder_random" => {
             let mut grouped: HashMap<String, Vec<ImageMetadata>> = HashMap::new();
-            for item in all_images {
+            for item in items {
                 grouped.entry(parent_folder(&item.path)).or_default().push(item);
             }
-
+    
             let mut subfolders: Vec<String> = grouped.keys().cloned().collect();
             subfolders.shuffle(&mut rand::thread_rng());
-
+    
             let mut flattened = Vec::new();
             for folder in subfolders {
                 if let Some(mut items) = grouped.remove(&folder) {
@@ -675,21 +2210,21 @@ async fn get_playlist(
                     flattened.extend(items);
                 }
             }
-            all_images = flattened;
+            items = flattened;
         }
         "subfolder_date" => {
             let mut grouped: HashMap<String, Vec<ImageMetadata>> = HashMap::new();
-            for item in all_images {
+            for item in items {
                 grouped.entry(parent_folder(&item.path)).or_default().push(item);
             }
-
+    
             let mut subfolders: Vec<String> = grouped.keys().cloned().collect();
             subfolders.sort_by(|a, b| {
                 let ma = folder_mtime(root_dir, a);
                 let mb = folder_mtime(root_dir, b);
                 ma.partial_cmp(&mb).unwrap_or(std::cmp::Ordering::Equal)
             });
-
+    
             let mut flattened = Vec::new();
             for folder in subfolders {
                 if let Some(mut items) = grouped.remove(&folder) {
@@ -697,315 +2232,10325 @@ async fn get_playlist(
                     flattened.extend(items);
                 }
             }
-            all_images = flattened;
+            items = flattened;
         }
         "subfolder_prefix" => {
             let mut grouped: HashMap<String, Vec<ImageMetadata>> = HashMap::new();
-            for item in all_images {
+            for item in items {
                 grouped.entry(parent_folder(&item.path)).or_default().push(item);
             }
-
+    
             let mut folder_orders: Vec<(String, String)> = Vec::new();
             for (folder, items) in &mut grouped {
                 items.sort_by(|a, b| natord::compare_ignore_case(&a.path, &b.path));
                 let prefix = folder_first_image_prefix(items);
                 folder_orders.push((folder.clone(), prefix));
             }
-
+    
             folder_orders.sort_by(|(folder_a, prefix_a), (folder_b, prefix_b)| {
                 natord::compare_ignore_case(prefix_a, prefix_b)
                     .then_with(|| natord::compare_ignore_case(folder_a, folder_b))
             });
-
+    
             let mut flattened = Vec::new();
             for (folder, _) in folder_orders {
                 if let Some(items) = grouped.remove(&folder) {
                     flattened.extend(items);
                 }
             }
-            all_images = flattened;
+            items = flattened;
+        }
+        _ => items.sort_by(|a, b| collator.compare(&a.path, &b.path)),
+    }
+    items
+}
+
+/// 每个子文件夹随机抽取至多 max 张，避免大文件夹淹没小文件夹
+fn sample_per_folder(items: Vec<ImageMetadata>, max: usize) -> Vec<ImageMetadata> {
+    let mut grouped: HashMap<String, Vec<ImageMetadata>> = HashMap::new();
+    for item in items {
+        grouped.entry(parent_folder(&item.path)).or_default().push(item);
+    }
+
+    let mut rng = rand::thread_rng();
+    let mut sampled = Vec::new();
+    for (_, mut folder_items) in grouped {
+        if folder_items.len() > max {
+            folder_items.shuffle(&mut rng);
+            folder_items.truncate(max);
+        }
+        sampled.extend(folder_items);
+    }
+    sampled
+}
+
+/// 连拍折叠：同一文件夹内按拍摄时间 (无 EXIF 时按名称) 顺序扫描，
+/// 与上一张间隔不超过 window_secs 或感知哈希汉明距离不超过 max_distance 的归为一组，
+/// 每组保留分辨率最高的一张
+fn collapse_bursts(items: Vec<ImageMetadata>, window_secs: f64, max_distance: u32) -> Vec<ImageMetadata> {
+    let mut grouped: HashMap<String, Vec<ImageMetadata>> = HashMap::new();
+    for item in items {
+        grouped.entry(parent_folder(&item.path)).or_default().push(item);
+    }
+
+    let phash = |item: &ImageMetadata| {
+        item.phash
+            .as_deref()
+            .filter(|h| !h.is_empty())
+            .and_then(|h| u64::from_str_radix(h, 16).ok())
+    };
+
+    let mut kept = Vec::new();
+    for (_, mut folder_items) in grouped {
+        folder_items.sort_by(|a, b| match (a.taken_at, b.taken_at) {
+            (Some(x), Some(y)) => x.total_cmp(&y),
+            (Some(_), None) => std::cmp::Ordering::Less,
+            (None, Some(_)) => std::cmp::Ordering::Greater,
+            (None, None) => natord::compare_ignore_case(&a.path, &b.path),
+        });
+
+        let mut best: Option<ImageMetadata> = None;
+        let mut prev: Option<ImageMetadata> = None;
+        for item in folder_items {
+            let same_burst = prev.as_ref().is_some_and(|p| {
+                let close_in_time = matches!((p.taken_at, item.taken_at), (Some(a), Some(b)) if (b - a).abs() <= window_secs);
+                let near_identical = matches!((phash(p), phash(&item)), (Some(a), Some(b)) if (a ^ b).count_ones() <= max_distance);
+                close_in_time || near_identical
+            });
+            if !same_burst {
+                kept.extend(best.take());
+            }
+            if best.as_ref().is_none_or(|b| megapixels(&item) > megapixels(b)) {
+                best = Some(item.clone());
+            }
+            prev = Some(item);
+        }
+        kept.extend(best);
+    }
+    kept
+}
+
+/// 混合播放列表最多的来源数
+const MIX_MAX_SOURCES: usize = 16;
+
+/// 加权混合：各来源随机排列后按权重比例交错 (同一图片只计入第一个命中的来源)
+async fn build_mixed_playlist(state: &AppState, mix: &[MixSource], blocked: &HashSet<String>) -> Vec<String> {
+    let mut seen = HashSet::new();
+    let mut sources = Vec::new();
+    for source in mix {
+        let paths: Vec<String> = match run_image_query(state, &source.filter, "shuffle", "forward", blocked).await {
+            Ok(images) => images.into_iter().map(|i| i.path).filter(|p| seen.insert(p.clone())).collect(),
+            Err(err) => {
+                tracing::warn!("⚠️ [Playlist] 混合来源 {:?} 查询失败: {}", source.name, err);
+                Vec::new()
+            }
+        };
+        sources.push((source.weight, paths));
+    }
+    weighted_interleave(sources)
+}
+
+/// 平滑加权轮询：每一步选"累计份额"最高的来源取一张，各来源的占比与权重一致；
+/// 某个来源用完后，剩下的来源按各自权重的比例继续交错，直到全部取完。没有图片的来源不参与
+fn weighted_interleave<T>(sources: Vec<(f64, Vec<T>)>) -> Vec<T> {
+    let mut sources: Vec<(f64, f64, std::vec::IntoIter<T>)> = sources
+        .into_iter()
+        .filter(|(w, items)| *w > 0.0 && !items.is_empty())
+        .map(|(w, items)| (w, 0.0, items.into_iter()))
+        .collect();
+    let mut merged = Vec::with_capacity(sources.iter().map(|s| s.2.len()).sum());
+    while !sources.is_empty() {
+        let total_weight: f64 = sources.iter().map(|s| s.0).sum();
+        for source in sources.iter_mut() {
+            source.1 += source.0;
+        }
+        let Some(pick) = (0..sources.len()).max_by(|&a, &b| sources[a].1.total_cmp(&sources[b].1)) else { break };
+        sources[pick].1 -= total_weight;
+        merged.extend(sources[pick].2.next());
+        if sources[pick].2.len() == 0 {
+            sources.remove(pick);
+        }
+    }
+    merged
+}
+
+/// 轮流从各个来源中取图，直到全部取完
+fn interleave_round_robin<T>(groups: Vec<Vec<T>>) -> Vec<T> {
+    let total = groups.iter().map(|g| g.len()).sum();
+    let mut iters: Vec<_> = groups.into_iter().map(|g| g.into_iter()).collect();
+    let mut merged = Vec::with_capacity(total);
+    while merged.len() < total {
+        for it in iters.iter_mut() {
+            if let Some(item) = it.next() {
+                merged.push(item);
+            }
+        }
+    }
+    merged
+}
+
+/// 间隔约束的默认与最大间隔
+const SPREAD_DEFAULT_DISTANCE: usize = 3;
+const SPREAD_MAX_DISTANCE: usize = 100;
+
+/// 新图优先的最长窗口
+const BOOST_MAX_DAYS: u32 = 3650;
+
+/// 间隔约束的分组键：所在文件夹，或拍摄日期 (没有 EXIF 时用修改日期)
+fn spread_key(image: &ImageMetadata, spread_by: &str) -> String {
+    match spread_by {
+        "date" => chrono::DateTime::from_timestamp(image.taken_at.unwrap_or(image.mtime) as i64, 0)
+            .map(|d| d.date_naive().to_string())
+            .unwrap_or_default(),
+        _ => parent_folder(&image.path),
+    }
+}
+
+/// 约束随机排列：同一分组的两项之间至少隔开 `distance` 个位置。每一步在满足间隔的分组中按剩余数量加权随机挑选，
+/// 各分组内部保持原有 (已打乱的) 顺序；没有分组满足时 (如某个文件夹占了大半) 退而取等待最久的分组，尽量拉开距离
+fn spread_shuffle<T, K: Eq + std::hash::Hash>(
+    items: Vec<T>,
+    key: impl Fn(&T) -> K,
+    distance: usize,
+    rng: &mut impl rand::Rng,
+) -> Vec<T> {
+    let total = items.len();
+    let mut index: HashMap<K, usize> = HashMap::new();
+    let mut groups: Vec<(Option<usize>, std::collections::VecDeque<T>)> = Vec::new();
+    for item in items {
+        let slot = *index.entry(key(&item)).or_insert_with(|| {
+            groups.push((None, std::collections::VecDeque::new()));
+            groups.len() - 1
+        });
+        groups[slot].1.push_back(item);
+    }
+
+    let mut spread = Vec::with_capacity(total);
+    while spread.len() < total {
+        let position = spread.len();
+        let eligible: Vec<usize> = (0..groups.len())
+            .filter(|&g| !groups[g].1.is_empty() && groups[g].0.is_none_or(|last| position - last > distance))
+            .collect();
+        let pick = if eligible.is_empty() {
+            (0..groups.len())
+                .filter(|&g| !groups[g].1.is_empty())
+                .min_by_key(|&g| groups[g].0)
+        } else {
+            let weights: usize = eligible.iter().map(|&g| groups[g].1.len()).sum();
+            let mut roll = rng.gen_range(0..weights);
+            eligible.into_iter().find(|&g| {
+                let len = groups[g].1.len();
+                if roll < len {
+                    true
+                } else {
+                    roll -= len;
+                    false
+                }
+            })
+        };
+        let Some(g) = pick else { break };
+        groups[g].0 = Some(position);
+        spread.extend(groups[g].1.pop_front());
+    }
+    spread
+}
+
+// --- Handlers ---
+
+/// 全量扫描，完成后清空播放列表缓存并 (若启用) 为新图片预生成缩略图、生成自动标签
+/// 返回新增图片数；扫描被取消时返回 None，扫描完成后的取消只跳过后续处理
+async fn rescan_library(state: &AppState, cancel: &tokio_util::sync::CancellationToken) -> Option<usize> {
+    let scanned = scan_library_task(state.db.clone(), state.root_dir.clone(), state.follow_symlinks, cancel).await;
+    let Some(ScanChanges { added, removed }) = scanned else {
+        invalidate_playlist_cache(state).await;
+        return None;
+    };
+    publish_library_changes(state, &added, &removed);
+    *state.index_freshness.write().unwrap() = IndexFreshness::Fresh;
+    hash_curated_images(state).await;
+    purge_missing_images(state).await;
+    #[cfg(feature = "notify")]
+    if let Some(notifier) = &state.notifier {
+        notifier.images_added(&added);
+    }
+    invalidate_playlist_cache(state).await;
+    if cancel.is_cancelled() {
+        return Some(added.len());
+    }
+    fingerprint_pending_images(state).await;
+    inspect_pending_colors(state).await;
+    invalidate_playlist_cache(state).await;
+    thumbnail_cache::prewarm(state, &added).await;
+    #[cfg(feature = "onnx")]
+    if let Some(tagger) = state.autotagger.as_ref().filter(|_| !cancel.is_cancelled()) {
+        autotag_pending_images(state, tagger.clone()).await;
+    }
+    #[cfg(feature = "onnx")]
+    if let Some(analyzer) = state.face_analyzer.as_ref().filter(|_| !cancel.is_cancelled()) {
+        detect_pending_faces(state, analyzer.clone()).await;
+    }
+    Some(added.len())
+}
+
+/// 为尚无向量的图片运行 ONNX 模型，写入向量与建议标签
+/// 分析失败的图片写入空向量，避免每次扫描反复重试
+#[cfg(feature = "onnx")]
+async fn autotag_pending_images(state: &AppState, tagger: Arc<autotag::AutoTagger>) {
+    tracing::info!("🏷️ [Background] 开始自动标签...");
+    let start = std::time::Instant::now();
+    let mut processed = 0usize;
+
+    'rounds: loop {
+        let batch: Vec<(String,)> = sqlx::query_as("SELECT path FROM images WHERE embedding IS NULL AND missing_since IS NULL LIMIT 64")
+            .fetch_all(&state.db)
+            .await
+            .unwrap_or_default();
+        if batch.is_empty() {
+            break;
+        }
+
+        for (path,) in batch {
+            let full_path = resolve_full_path(&state.root_dir, &path);
+            let tagger = tagger.clone();
+            let analysis = tokio::task::spawn_blocking(move || tagger.analyze(&full_path))
+                .await
+                .map_err(anyhow::Error::from)
+                .and_then(|r| r);
+
+            let (blob, tags) = match analysis {
+                Ok(analysis) => (autotag::embedding_to_blob(&analysis.embedding), analysis.tags),
+                Err(err) => {
+                    tracing::warn!("⚠️ Auto-tagging failed for {}: {}", path, err);
+                    (Vec::new(), Vec::new())
+                }
+            };
+            // 写入失败时本轮停止：否则同一批图片会被无限次重新选出
+            let written = async {
+                let mut tx = state.db.begin().await?;
+                sqlx::query("UPDATE images SET embedding = ? WHERE path = ?")
+                    .bind(blob)
+                    .bind(&path)
+                    .execute(&mut *tx)
+                    .await?;
+                sqlx::query("DELETE FROM suggested_tags WHERE path = ?")
+                    .bind(&path)
+                    .execute(&mut *tx)
+                    .await?;
+                for (tag, score) in tags {
+                    sqlx::query("INSERT OR REPLACE INTO suggested_tags (path, tag, score) VALUES (?, ?, ?)")
+                        .bind(&path)
+                        .bind(tag)
+                        .bind(score)
+                        .execute(&mut *tx)
+                        .await?;
+                }
+                tx.commit().await
+            }
+            .await;
+            if let Err(e) = written {
+                tracing::warn!("⚠️ [Background] 自动标签写入失败，下次扫描后重试: {}", e);
+                break 'rounds;
+            }
+            processed += 1;
+        }
+    }
+
+    if processed > 0 {
+        *state.embedding_index.write().await = None;
+    }
+
+    tracing::info!("✅ [Background] 自动标签完成: {} 张, 耗时 {:?}", processed, start.elapsed());
+}
+
+/// 对尚未检测过的图片做人脸检测，并按特征向量增量聚类
+/// 新人脸归入最相近的已有聚类 (相似度达到阈值)，否则新建聚类
+#[cfg(feature = "onnx")]
+async fn detect_pending_faces(state: &AppState, analyzer: Arc<faces::FaceAnalyzer>) {
+    tracing::info!("🙂 [Background] 开始人脸检测...");
+    let start = std::time::Instant::now();
+    let threshold: f32 = autotag::env_parse("GALLERY_FACE_CLUSTER_THRESHOLD").unwrap_or(0.45);
+
+    // 现有聚类的中心 (向量和，比较时归一化)
+    let existing: Vec<(i64, Vec<u8>)> =
+        sqlx::query_as("SELECT cluster_id, embedding FROM faces WHERE cluster_id IS NOT NULL")
+            .fetch_all(&state.db)
+            .await
+            .unwrap_or_default();
+    let mut centroids: HashMap<i64, Vec<f32>> = HashMap::new();
+    for (cluster_id, blob) in existing {
+        let embedding = autotag::blob_to_embedding(&blob);
+        let sum = centroids.entry(cluster_id).or_insert_with(|| vec![0.0; embedding.len()]);
+        if sum.len() == embedding.len() {
+            sum.iter_mut().zip(&embedding).for_each(|(s, v)| *s += v);
+        }
+    }
+    let mut next_cluster = centroids.keys().max().copied().unwrap_or(0) + 1;
+    let mut processed = 0usize;
+
+    'rounds: loop {
+        let batch: Vec<(String,)> = sqlx::query_as("SELECT path FROM images WHERE faces_scanned = 0 AND missing_since IS NULL LIMIT 32")
+            .fetch_all(&state.db)
+            .await
+            .unwrap_or_default();
+        if batch.is_empty() {
+            break;
+        }
+
+        for (path,) in batch {
+            let full_path = resolve_full_path(&state.root_dir, &path);
+            let analyzer = analyzer.clone();
+            let detected = tokio::task::spawn_blocking(move || analyzer.analyze(&full_path))
+                .await
+                .map_err(anyhow::Error::from)
+                .and_then(|r| r)
+                .unwrap_or_else(|err| {
+                    tracing::warn!("⚠️ Face detection failed for {}: {}", path, err);
+                    Vec::new()
+                });
+
+            let mut assigned = Vec::with_capacity(detected.len());
+            for face in detected {
+                let best = centroids
+                    .iter()
+                    .filter(|(_, sum)| sum.len() == face.embedding.len())
+                    .map(|(id, sum)| (*id, autotag::cosine(&autotag::l2_normalize(sum.clone()), &face.embedding)))
+                    .max_by(|a, b| a.1.total_cmp(&b.1));
+                let cluster_id = match best {
+                    Some((id, similarity)) if similarity >= threshold => id,
+                    _ => {
+                        next_cluster += 1;
+                        next_cluster - 1
+                    }
+                };
+                let sum = centroids
+                    .entry(cluster_id)
+                    .or_insert_with(|| vec![0.0; face.embedding.len()]);
+                sum.iter_mut().zip(&face.embedding).for_each(|(s, v)| *s += v);
+                assigned.push((face, cluster_id));
+            }
+
+            // 写入失败时本轮停止：否则同一批图片会被无限次重新选出
+            let written = async {
+                let mut tx = state.db.begin().await?;
+                sqlx::query("DELETE FROM faces WHERE path = ?")
+                    .bind(&path)
+                    .execute(&mut *tx)
+                    .await?;
+                for (face, cluster_id) in &assigned {
+                    sqlx::query("INSERT INTO faces (path, x, y, w, h, score, embedding, cluster_id) VALUES (?, ?, ?, ?, ?, ?, ?, ?)")
+                        .bind(&path)
+                        .bind(face.bbox[0])
+                        .bind(face.bbox[1])
+                        .bind(face.bbox[2])
+                        .bind(face.bbox[3])
+                        .bind(face.score)
+                        .bind(autotag::embedding_to_blob(&face.embedding))
+                        .bind(cluster_id)
+                        .execute(&mut *tx)
+                        .await?;
+                }
+                sqlx::query("UPDATE images SET faces_scanned = 1 WHERE path = ?")
+                    .bind(&path)
+                    .execute(&mut *tx)
+                    .await?;
+                tx.commit().await
+            }
+            .await;
+            if let Err(e) = written {
+                tracing::warn!("⚠️ [Background] 人脸检测结果写入失败，下次扫描后重试: {}", e);
+                break 'rounds;
+            }
+            processed += 1;
+        }
+    }
+
+    tracing::info!("✅ [Background] 人脸检测完成: {} 张, 耗时 {:?}", processed, start.elapsed());
+}
+
+#[derive(Debug, Serialize)]
+struct PersonCluster {
+    id: i64,
+    name: Option<String>,
+    face_count: i64,
+    image_count: i64,
+    /// 置信度最高的人脸，可用于 /api/faces/crop 显示头像
+    cover_face_id: i64,
+}
+
+#[derive(Debug, Deserialize)]
+struct NamePersonRequest {
+    id: i64,
+    /// 为空时清除名字
+    name: Option<String>,
+}
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+struct FaceRecord {
+    id: i64,
+    path: String,
+    x: f64,
+    y: f64,
+    w: f64,
+    h: f64,
+    score: f64,
+    cluster_id: Option<i64>,
+    name: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct FaceCropQuery {
+    id: i64,
+}
+
+/// 接口: GET /api/people，人物 (人脸聚类) 列表，按人脸数降序
+async fn list_people(State(state): State<AppState>) -> Json<Vec<PersonCluster>> {
+    let rows: Vec<(i64, Option<String>, i64, i64, i64)> = sqlx::query_as(
+        "SELECT f.cluster_id, p.name, COUNT(*), COUNT(DISTINCT f.path),
+                (SELECT id FROM faces WHERE cluster_id = f.cluster_id ORDER BY score DESC LIMIT 1)
+         FROM faces f LEFT JOIN people p ON p.cluster_id = f.cluster_id
+         WHERE f.cluster_id IS NOT NULL
+         GROUP BY f.cluster_id
+         ORDER BY COUNT(*) DESC, f.cluster_id",
+    )
+    .fetch_all(&state.db)
+    .await
+    .unwrap_or_default();
+
+    Json(
+        rows.into_iter()
+            .map(|(id, name, face_count, image_count, cover_face_id)| PersonCluster {
+                id,
+                name,
+                face_count,
+                image_count,
+                cover_face_id,
+            })
+            .collect(),
+    )
+}
+
+/// 接口: POST /api/people/name，为人物聚类命名
+async fn name_person(
+    State(state): State<AppState>,
+    Json(req): Json<NamePersonRequest>,
+) -> Result<Json<serde_json::Value>, (StatusCode, Json<serde_json::Value>)> {
+    let name = req.name.map(|n| n.trim().to_string()).filter(|n| !n.is_empty());
+    let result = match &name {
+        Some(name) => sqlx::query("INSERT OR REPLACE INTO people (cluster_id, name) VALUES (?, ?)")
+            .bind(req.id)
+            .bind(name)
+            .execute(&state.db)
+            .await,
+        None => sqlx::query("DELETE FROM people WHERE cluster_id = ?")
+            .bind(req.id)
+            .execute(&state.db)
+            .await,
+    };
+    result.map_err(|_| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(serde_json::json!({ "detail": "Failed to name person" })),
+        )
+    })?;
+    // 按人物名筛选的播放列表需要重新生成
+    invalidate_playlist_cache(&state).await;
+    Ok(Json(serde_json::json!({ "status": "ok", "id": req.id, "name": name })))
+}
+
+/// 接口: GET /api/faces?path=...，单张图片中检测到的人脸
+async fn list_faces(
+    State(state): State<AppState>,
+    Query(query): Query<FileQuery>,
+) -> Json<Vec<FaceRecord>> {
+    let rel = normalize_rel_path(&query.path);
+    let faces = sqlx::query_as::<_, FaceRecord>(
+        "SELECT f.id, f.path, f.x, f.y, f.w, f.h, f.score, f.cluster_id, p.name
+         FROM faces f LEFT JOIN people p ON p.cluster_id = f.cluster_id
+         WHERE f.path = ? ORDER BY f.x",
+    )
+    .bind(rel)
+    .fetch_all(&state.db)
+    .await
+    .unwrap_or_default();
+    Json(faces)
+}
+
+/// 接口: GET /api/faces/crop?id=...，人脸裁剪图 (JPEG，四周留 20% 边距)
+async fn face_crop(State(state): State<AppState>, Query(query): Query<FaceCropQuery>) -> Response {
+    let face: Option<(String, f64, f64, f64, f64)> =
+        sqlx::query_as("SELECT path, x, y, w, h FROM faces WHERE id = ?")
+            .bind(query.id)
+            .fetch_optional(&state.db)
+            .await
+            .unwrap_or(None);
+    let Some((path, x, y, w, h)) = face else {
+        return (StatusCode::NOT_FOUND, "Face not found").into_response();
+    };
+
+    let allow_parent = *state.allow_parent_dir_access.read().await;
+    let full = match resolve_and_authorize(&state.root_dir, &path, allow_parent) {
+        Ok(full) => full,
+        Err(PathAccessError::Forbidden) => {
+            return (StatusCode::FORBIDDEN, "Access outside ROOT_DIR is disabled").into_response();
+        }
+        Err(PathAccessError::NotFound) => return (StatusCode::NOT_FOUND, "File not found").into_response(),
+    };
+
+    let encoded = tokio::task::spawn_blocking(move || -> Option<Vec<u8>> {
+        let img = image::open(&full).ok()?;
+        let (img_w, img_h) = (img.width() as f64, img.height() as f64);
+        let (pad_w, pad_h) = (w * 0.2, h * 0.2);
+        let left = ((x - pad_w).max(0.0) * img_w) as u32;
+        let top = ((y - pad_h).max(0.0) * img_h) as u32;
+        let right = ((x + w + pad_w).min(1.0) * img_w) as u32;
+        let bottom = ((y + h + pad_h).min(1.0) * img_h) as u32;
+        let crop = img.crop_imm(left, top, right.saturating_sub(left).max(1), bottom.saturating_sub(top).max(1));
+        let mut buf = std::io::Cursor::new(Vec::new());
+        crop.to_rgb8().write_to(&mut buf, image::ImageOutputFormat::Jpeg(85)).ok()?;
+        Some(buf.into_inner())
+    })
+    .await
+    .ok()
+    .flatten();
+
+    match encoded {
+        Some(bytes) => (
+            [
+                (header::CONTENT_TYPE, "image/jpeg"),
+                (header::CACHE_CONTROL, "public, max-age=3600"),
+            ],
+            bytes,
+        )
+            .into_response(),
+        None => (StatusCode::UNPROCESSABLE_ENTITY, "Unreadable image").into_response(),
+    }
+}
+
+/// 归一化裁剪窗口 (相对原图，0~1)
+#[derive(Clone, Copy, Debug, Serialize)]
+struct CropWindow {
+    x: f64,
+    y: f64,
+    w: f64,
+    h: f64,
+}
+
+#[derive(Debug, Serialize)]
+struct KenBurnsPlan {
+    path: String,
+    /// 动画焦点 (归一化坐标)
+    focus: (f64, f64),
+    /// faces / saliency / center
+    source: &'static str,
+    start: CropWindow,
+    end: CropWindow,
+}
+
+#[derive(Debug, Deserialize)]
+struct KenBurnsQuery {
+    path: String,
+    /// 显示区域宽高比，默认 16:9
+    aspect: Option<f64>,
+    /// 终点相对起点的放大倍数，默认 1.25
+    zoom: Option<f64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct KenBurnsBatchRequest {
+    paths: Vec<String>,
+    aspect: Option<f64>,
+    zoom: Option<f64>,
+}
+
+/// 简单显著性：缩略灰度图的梯度能量质心，带中心偏置
+fn saliency_focus(full_path: &Path) -> Option<(f64, f64)> {
+    const SIZE: u32 = 64;
+    let gray = image::open(full_path)
+        .ok()?
+        .resize_exact(SIZE, SIZE, image::imageops::FilterType::Triangle)
+        .to_luma8();
+    let at = |x: u32, y: u32| gray.get_pixel(x, y)[0] as f64;
+
+    let (mut sum, mut sx, mut sy) = (0.0, 0.0, 0.0);
+    for y in 1..SIZE - 1 {
+        for x in 1..SIZE - 1 {
+            let energy = (at(x + 1, y) - at(x - 1, y)).abs() + (at(x, y + 1) - at(x, y - 1)).abs();
+            let (nx, ny) = (x as f64 / SIZE as f64, y as f64 / SIZE as f64);
+            let center_bias = 1.0 - 0.5 * (((nx - 0.5).powi(2) + (ny - 0.5).powi(2)).sqrt() / std::f64::consts::FRAC_1_SQRT_2);
+            let weight = energy * center_bias;
+            sum += weight;
+            sx += weight * nx;
+            sy += weight * ny;
+        }
+    }
+    if sum <= 0.0 {
+        return Some((0.5, 0.5));
+    }
+    Some((sx / sum, sy / sum))
+}
+
+/// 宽高比为 `aspect` 的窗口，按 `scale` 缩放后以 `focus` 为中心并限制在图内
+fn crop_window(image_aspect: f64, aspect: f64, scale: f64, focus: (f64, f64)) -> CropWindow {
+    let (w, h) = if aspect > image_aspect {
+        (1.0, image_aspect / aspect)
+    } else {
+        (aspect / image_aspect, 1.0)
+    };
+    let (w, h) = (w * scale, h * scale);
+    CropWindow {
+        x: (focus.0 - w / 2.0).clamp(0.0, 1.0 - w),
+        y: (focus.1 - h / 2.0).clamp(0.0, 1.0 - h),
+        w,
+        h,
+    }
+}
+
+/// 计算单张图片的 Ken Burns 平移/缩放方案：优先人脸，其次显著性 (结果缓存于 images 表)，最后居中
+async fn plan_ken_burns(state: &AppState, raw_path: &str, aspect: f64, zoom: f64) -> Option<KenBurnsPlan> {
+    let (path, full) = authorize_client_path(state, raw_path).await.ok()?;
+
+    let row: Option<(i64, i64, Option<f64>, Option<f64>)> =
+        sqlx::query_as("SELECT width, height, focus_x, focus_y FROM images WHERE path = ?")
+            .bind(&path)
+            .fetch_optional(&state.db)
+            .await
+            .unwrap_or(None);
+    let (width, height) = match row {
+        Some((w, h, _, _)) if w > 0 && h > 0 => (w as f64, h as f64),
+        _ => {
+            let full = full.clone();
+            let (w, h) = tokio::task::spawn_blocking(move || image::image_dimensions(&full).ok())
+                .await
+                .ok()
+                .flatten()?;
+            (w as f64, h as f64)
+        }
+    };
+
+    let faces: Vec<(f64, f64, f64, f64)> = sqlx::query_as("SELECT x, y, w, h FROM faces WHERE path = ?")
+        .bind(&path)
+        .fetch_all(&state.db)
+        .await
+        .unwrap_or_default();
+    let (focus, source) = if !faces.is_empty() {
+        let area: f64 = faces.iter().map(|f| f.2 * f.3).sum::<f64>().max(f64::EPSILON);
+        let fx = faces.iter().map(|f| (f.0 + f.2 / 2.0) * f.2 * f.3).sum::<f64>() / area;
+        let fy = faces.iter().map(|f| (f.1 + f.3 / 2.0) * f.2 * f.3).sum::<f64>() / area;
+        ((fx, fy), "faces")
+    } else if let Some((_, _, Some(fx), Some(fy))) = row {
+        ((fx, fy), "saliency")
+    } else {
+        let focus = tokio::task::spawn_blocking(move || saliency_focus(&full)).await.ok().flatten();
+        match focus {
+            Some(focus) => {
+                let _ = sqlx::query("UPDATE images SET focus_x = ?, focus_y = ? WHERE path = ?")
+                    .bind(focus.0)
+                    .bind(focus.1)
+                    .bind(&path)
+                    .execute(&state.db)
+                    .await;
+                (focus, "saliency")
+            }
+            None => ((0.5, 0.5), "center"),
+        }
+    };
+
+    // 从完整画面推近到焦点；按路径哈希让一半图片反向 (拉远)，避免节奏单调
+    let image_aspect = width / height;
+    let wide = crop_window(image_aspect, aspect, 1.0, (0.5, 0.5));
+    let close = crop_window(image_aspect, aspect, 1.0 / zoom, focus);
+    let mut hasher = DefaultHasher::new();
+    path.hash(&mut hasher);
+    let (start, end) = if hasher.finish() & 1 == 0 { (wide, close) } else { (close, wide) };
+
+    Some(KenBurnsPlan { path, focus, source, start, end })
+}
+
+fn ken_burns_params(aspect: Option<f64>, zoom: Option<f64>) -> (f64, f64) {
+    (
+        aspect.filter(|a| a.is_finite() && *a > 0.1 && *a < 10.0).unwrap_or(16.0 / 9.0),
+        zoom.filter(|z| z.is_finite() && *z >= 1.0 && *z <= 4.0).unwrap_or(1.25),
+    )
+}
+
+/// 接口: GET /api/kenburns?path=...&aspect=&zoom=，单张图片的建议平移/缩放窗口
+async fn ken_burns(
+    State(state): State<AppState>,
+    Query(query): Query<KenBurnsQuery>,
+) -> Result<Json<KenBurnsPlan>, (StatusCode, Json<serde_json::Value>)> {
+    let (aspect, zoom) = ken_burns_params(query.aspect, query.zoom);
+    plan_ken_burns(&state, &query.path, aspect, zoom)
+        .await
+        .map(Json)
+        .ok_or_else(|| (StatusCode::NOT_FOUND, Json(serde_json::json!({ "detail": "Image not found" }))))
+}
+
+/// 接口: POST /api/kenburns，批量计算 (如整个播放列表)，找不到的路径被跳过
+async fn ken_burns_batch(State(state): State<AppState>, Json(req): Json<KenBurnsBatchRequest>) -> Json<Vec<KenBurnsPlan>> {
+    let (aspect, zoom) = ken_burns_params(req.aspect, req.zoom);
+    let mut plans = Vec::with_capacity(req.paths.len());
+    for path in &req.paths {
+        if let Some(plan) = plan_ken_burns(&state, path, aspect, zoom).await {
+            plans.push(plan);
+        }
+    }
+    Json(plans)
+}
+
+#[cfg(feature = "onnx")]
+#[derive(Debug, Deserialize)]
+struct SemanticSearchQuery {
+    q: String,
+    limit: Option<usize>,
+}
+
+#[cfg(feature = "onnx")]
+#[derive(Debug, Serialize)]
+struct SemanticSearchHit {
+    path: String,
+    score: f32,
+}
+
+/// 取得 (必要时从数据库加载) 语义搜索向量表
+#[cfg(feature = "onnx")]
+async fn load_embedding_index(state: &AppState) -> Arc<autotag::EmbeddingIndex> {
+    if let Some(index) = state.embedding_index.read().await.as_ref() {
+        return index.clone();
+    }
+    let rows: Vec<(String, Vec<u8>)> =
+        sqlx::query_as("SELECT path, embedding FROM images WHERE length(embedding) > 0 AND missing_since IS NULL")
+            .fetch_all(&state.db)
+            .await
+            .unwrap_or_default();
+    let index = Arc::new(
+        rows.into_iter()
+            .map(|(path, blob)| (path, autotag::blob_to_embedding(&blob)))
+            .collect::<Vec<_>>(),
+    );
+    *state.embedding_index.write().await = Some(index.clone());
+    index
+}
+
+/// 接口: GET /api/search/semantic?q=...，文本到图片的向量近邻搜索 (暴力余弦相似度)
+#[cfg(feature = "onnx")]
+async fn semantic_search(
+    State(state): State<AppState>,
+    connect_info: ConnectInfo<SocketAddr>,
+    Query(query): Query<SemanticSearchQuery>,
+) -> Result<Json<Vec<SemanticSearchHit>>, (StatusCode, Json<serde_json::Value>)> {
+    let Some(encoder) = state.text_encoder.clone() else {
+        return Err((
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(serde_json::json!({ "detail": "No text model configured (GALLERY_ONNX_TEXT_MODEL)" })),
+        ));
+    };
+    let text = query.q.trim().to_string();
+    if text.is_empty() {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            Json(serde_json::json!({ "detail": "Query must not be empty" })),
+        ));
+    }
+
+    let query_embedding = tokio::task::spawn_blocking(move || encoder.embed(&text))
+        .await
+        .map_err(anyhow::Error::from)
+        .and_then(|r| r)
+        .map_err(|err| {
+            tracing::error!("⚠️ Query embedding failed: {}", err);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(serde_json::json!({ "detail": "Failed to embed query" })),
+            )
+        })?;
+
+    let index = load_embedding_index(&state).await;
+    let allow_parent = *state.allow_parent_dir_access.read().await;
+    let blocked = load_blocklist(&state.db, &connect_info.0.ip().to_string()).await;
+    let limit = query.limit.unwrap_or(50).clamp(1, 500);
+
+    let hits = tokio::task::spawn_blocking(move || {
+        let mut scored: Vec<(f32, &String)> = index
+            .iter()
+            .filter(|(path, embedding)| {
+                embedding.len() == query_embedding.len()
+                    && (allow_parent || !is_external_key(path))
+                    && !blocked.contains(path)
+            })
+            .map(|(path, embedding)| (autotag::cosine(&query_embedding, embedding), path))
+            .collect();
+        scored.sort_by(|a, b| b.0.total_cmp(&a.0));
+        scored
+            .into_iter()
+            .take(limit)
+            .map(|(score, path)| SemanticSearchHit { path: path.clone(), score })
+            .collect::<Vec<_>>()
+    })
+    .await
+    .unwrap_or_default();
+
+    Ok(Json(hits))
+}
+
+#[cfg(not(feature = "onnx"))]
+async fn semantic_search() -> (StatusCode, Json<serde_json::Value>) {
+    (
+        StatusCode::NOT_IMPLEMENTED,
+        Json(serde_json::json!({ "detail": "Semantic search requires the `onnx` build feature" })),
+    )
+}
+
+/// 局域网设备可访问的服务地址：`GALLERY_PUBLIC_URL`，缺省取请求的 Host (回环地址返回 None)
+fn public_base_url(headers: &axum::http::HeaderMap) -> Option<String> {
+    if let Some(url) = config_var("GALLERY_PUBLIC_URL").ok().filter(|v| !v.trim().is_empty()) {
+        return Some(url.trim().trim_end_matches('/').to_string());
+    }
+    let host = headers.get(header::HOST).and_then(|h| h.to_str().ok())?;
+    let host_name = host.rsplit_once(':').map_or(host, |(name, _)| name);
+    if host.is_empty() || host_name == "localhost" || host_name.starts_with("127.") || host_name == "[::1]" {
+        return None;
+    }
+    let scheme = if config_var("GALLERY_SSL_CERT").is_ok() { "https" } else { "http" };
+    Some(format!("{}://{}", scheme, host))
+}
+
+/// 渲染二维码 PNG (每模块 8px，四周保留 4 模块静区)
+fn render_qr_png(data: &str) -> Option<Vec<u8>> {
+    const SCALE: u32 = 8;
+    const QUIET: u32 = 4;
+    let code = qrcode::QrCode::new(data.as_bytes()).ok()?;
+    let width = code.width() as u32;
+    let colors = code.to_colors();
+    let size = (width + QUIET * 2) * SCALE;
+    let img = image::GrayImage::from_fn(size, size, |x, y| {
+        let (mx, my) = (x / SCALE, y / SCALE);
+        let inside = (QUIET..QUIET + width).contains(&mx) && (QUIET..QUIET + width).contains(&my);
+        let dark = inside && colors[((my - QUIET) * width + (mx - QUIET)) as usize] == qrcode::Color::Dark;
+        image::Luma([if dark { 0 } else { 255 }])
+    });
+    let mut buf = std::io::Cursor::new(Vec::new());
+    img.write_to(&mut buf, image::ImageOutputFormat::Png).ok()?;
+    Some(buf.into_inner())
+}
+
+/// 接口: GET /api/pairing/qr，签发一次性配对令牌并返回包含服务地址与令牌的二维码
+///
+/// 二维码内容为 `{服务地址}/?pair={令牌}`，同时通过 `X-Pairing-Url` 头返回便于手动输入。
+/// 相框扫码后用 `POST /api/pairing/redeem` 兑换令牌，得到自己的设备密钥。
+async fn pairing_qr(State(state): State<AppState>, headers: axum::http::HeaderMap) -> Response {
+    let Some(base_url) = public_base_url(&headers) else {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(serde_json::json!({ "detail": "Devices cannot reach a loopback address; set GALLERY_PUBLIC_URL" })),
+        )
+            .into_response();
+    };
+
+    let token = random_token(32);
+    {
+        let mut tokens = state.pairing_tokens.lock().unwrap();
+        tokens.retain(|_, issued| issued.elapsed() < state.pairing_token_ttl);
+        tokens.insert(token.clone(), Instant::now());
+    }
+
+    let url = format!("{}/?pair={}", base_url, token);
+    match render_qr_png(&url) {
+        Some(png) => {
+            let mut response = ([(header::CONTENT_TYPE, "image/png"), (header::CACHE_CONTROL, "no-store")], png)
+                .into_response();
+            if let Ok(value) = HeaderValue::from_str(&url) {
+                response.headers_mut().insert(HeaderName::from_static("x-pairing-url"), value);
+            }
+            response
+        }
+        None => (StatusCode::INTERNAL_SERVER_ERROR, "Failed to render QR code").into_response(),
+    }
+}
+
+/// 取走一次性配对令牌：不存在、已用过或已过期时返回 false
+fn take_pairing_token(tokens: &mut HashMap<String, Instant>, token: &str, ttl: Duration) -> bool {
+    tokens.remove(token).is_some_and(|issued| issued.elapsed() < ttl)
+}
+
+#[derive(Debug, Deserialize)]
+struct RedeemPairingRequest {
+    token: String,
+    /// 相框自己的设备 id (规则同 `/api/devices`)
+    id: String,
+    name: Option<String>,
+}
+
+/// 接口: POST /api/pairing/redeem，新相框扫码后兑换令牌 (每个令牌只能使用一次，`GALLERY_PAIRING_TTL_SECS` 后失效)
+///
+/// 兑换成功即注册该设备 (已存在时保留其分配) 并签发设备密钥，之后 `/api/device/{id}/assignment`
+/// 必须携带该密钥 (`Authorization: Bearer` 或 `?key=`)。再次配对会替换旧密钥。
+async fn redeem_pairing(
+    State(state): State<AppState>,
+    connect_info: ConnectInfo<SocketAddr>,
+    Json(req): Json<RedeemPairingRequest>,
+) -> Result<Json<serde_json::Value>, (StatusCode, Json<serde_json::Value>)> {
+    let id = req.id.trim();
+    if !valid_device_id(id) {
+        return Err((StatusCode::BAD_REQUEST, Json(serde_json::json!({ "detail": DEVICE_ID_RULE }))));
+    }
+    let redeemed = take_pairing_token(&mut state.pairing_tokens.lock().unwrap(), req.token.trim(), state.pairing_token_ttl);
+    if !redeemed {
+        return Err((
+            StatusCode::GONE,
+            Json(serde_json::json!({ "detail": "Pairing token is invalid, used or expired" })),
+        ));
+    }
+
+    let key = random_token(32);
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs_f64();
+    let ip = connect_info.0.ip().to_string();
+    sqlx::query(
+        "INSERT INTO devices (id, name, created_at, key_hash, last_seen, last_ip) VALUES (?, ?, ?, ?, ?, ?)
+         ON CONFLICT(id) DO UPDATE SET key_hash = excluded.key_hash, name = COALESCE(excluded.name, devices.name),
+             last_seen = excluded.last_seen, last_ip = excluded.last_ip",
+    )
+    .bind(id)
+    .bind(&req.name)
+    .bind(now)
+    .bind(blake3::hash(key.as_bytes()).to_hex().as_str())
+    .bind(now)
+    .bind(&ip)
+    .execute(&state.db)
+    .await
+    .map_err(|e| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(serde_json::json!({ "detail": format!("Failed to save device: {}", e) })),
+        )
+    })?;
+
+    tracing::info!("🔗 Device paired: {} from {}", id, ip);
+    publish_event(&state, None, "device_paired", serde_json::json!({ "id": id, "client_ip": ip }));
+    Ok(Json(serde_json::json!({ "id": id, "key": key })))
+}
+
+// --- 图片缩放与设备预处理 ---
+
+/// 缩放方式
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum ResizeFit {
+    /// 等比缩小到框内 (不放大、不填充)
+    #[default]
+    Contain,
+    /// 等比缩放并居中裁剪，输出恰好为目标尺寸
+    Cover,
+    /// 等比缩放并用背景色补边，输出恰好为目标尺寸
+    Pad,
+}
+
+/// 设备预处理配置，如 1600x1200 的 7 色墨水屏：
+/// `{"name": "eink7", "width": 1600, "height": 1200, "dither": true,
+///   "palette": ["#000000", "#ffffff", "#00ff00", "#0000ff", "#ff0000", "#ffff00", "#ff8000"]}`
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct DeviceProfile {
+    name: String,
+    width: u32,
+    height: u32,
+    #[serde(default = "default_profile_fit")]
+    fit: ResizeFit,
+    #[serde(default)]
+    grayscale: bool,
+    /// 每通道位数 (1~8)，如 4 位灰阶墨水屏
+    bit_depth: Option<u8>,
+    /// 限定输出颜色 (十六进制)，优先于 bit_depth
+    #[serde(default)]
+    palette: Vec<String>,
+    /// Floyd–Steinberg 误差扩散
+    #[serde(default)]
+    dither: bool,
+    /// fit=pad 时的补边颜色，默认黑色
+    background: Option<String>,
+}
+
+fn default_profile_fit() -> ResizeFit {
+    ResizeFit::Cover
+}
+
+fn parse_hex_color(value: &str) -> Option<[u8; 3]> {
+    let hex = value.trim().trim_start_matches('#');
+    if hex.len() != 6 {
+        return None;
+    }
+    let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).ok();
+    Some([channel(0)?, channel(2)?, channel(4)?])
+}
+
+/// 一次渲染的完整参数
+#[derive(Clone, Debug, Default)]
+struct RenderSpec {
+    width: u32,
+    height: u32,
+    fit: ResizeFit,
+    grayscale: bool,
+    bit_depth: Option<u8>,
+    palette: Vec<[u8; 3]>,
+    dither: bool,
+    background: [u8; 3],
+    /// 缩放后、量化前叠加
+    watermark: Option<PreparedWatermark>,
+    /// JPEG 输出是否保留原图的 EXIF / ICC；量化输出 (PNG) 总是转换到 sRGB
+    metadata: color::MetadataMode,
+    /// 缩放前应用的非破坏性编辑
+    edit: Option<ImageEdit>,
+}
+
+impl RenderSpec {
+    fn fit_within(width: u32, height: u32) -> Self {
+        Self { width, height, ..Default::default() }
+    }
+
+    fn from_profile(profile: &DeviceProfile) -> Result<Self, String> {
+        if !(1..=RENDER_MAX_SIDE).contains(&profile.width) || !(1..=RENDER_MAX_SIDE).contains(&profile.height) {
+            return Err(format!("Profile size must be between 1 and {} pixels", RENDER_MAX_SIDE));
+        }
+        if profile.bit_depth.is_some_and(|b| !(1..=8).contains(&b)) {
+            return Err("bit_depth must be between 1 and 8".to_string());
+        }
+        let palette = profile
+            .palette
+            .iter()
+            .map(|c| parse_hex_color(c).ok_or_else(|| format!("Invalid palette color {:?}", c)))
+            .collect::<Result<Vec<_>, _>>()?;
+        let background = match &profile.background {
+            Some(c) => parse_hex_color(c).ok_or_else(|| format!("Invalid background color {:?}", c))?,
+            None => [0, 0, 0],
+        };
+        Ok(Self {
+            width: profile.width,
+            height: profile.height,
+            fit: profile.fit,
+            grayscale: profile.grayscale,
+            bit_depth: profile.bit_depth,
+            palette,
+            dither: profile.dither,
+            background,
+            watermark: None,
+            metadata: color::MetadataMode::Strip,
+            edit: None,
+        })
+    }
+
+    fn quantizes(&self) -> bool {
+        !self.palette.is_empty() || self.bit_depth.is_some_and(|b| b < 8)
+    }
+}
+
+/// 最近颜色调色板
+struct PaletteMap(Vec<[u8; 3]>);
+
+impl image::imageops::colorops::ColorMap for PaletteMap {
+    type Color = image::Rgb<u8>;
+
+    fn index_of(&self, color: &Self::Color) -> usize {
+        let distance = |c: &[u8; 3]| -> i32 {
+            (0..3).map(|i| (c[i] as i32 - color[i] as i32).pow(2)).sum()
+        };
+        (0..self.0.len()).min_by_key(|i| distance(&self.0[*i])).unwrap_or(0)
+    }
+
+    fn map_color(&self, color: &mut Self::Color) {
+        if let Some(c) = self.0.get(self.index_of(color)) {
+            color.0 = *c;
+        }
+    }
+}
+
+/// 每通道量化到 2^bits 个等距色阶
+struct LevelMap(u8);
+
+impl image::imageops::colorops::ColorMap for LevelMap {
+    type Color = image::Rgb<u8>;
+
+    fn index_of(&self, _color: &Self::Color) -> usize {
+        0
+    }
+
+    fn map_color(&self, color: &mut Self::Color) {
+        let steps = ((1u32 << self.0) - 1) as f32;
+        for c in color.0.iter_mut() {
+            *c = ((*c as f32 / 255.0 * steps).round() / steps * 255.0).round() as u8;
+        }
+    }
+}
+
+/// 按参数渲染图片，返回 (字节, MIME)；量化后的结果用 PNG 保证像素精确
+fn render_image(full_path: &Path, spec: &RenderSpec) -> Option<(Vec<u8>, &'static str)> {
+    let mut rgb = render_rgb(full_path, spec)?;
+    quantize_rgb(&mut rgb, spec);
+    encode_rendered(&rgb, spec, Some(full_path))
+}
+
+/// 渲染到量化之前 (编辑、色彩转换、缩放、水印、灰度)
+fn render_rgb(full_path: &Path, spec: &RenderSpec) -> Option<image::RgbImage> {
+    use image::imageops::FilterType;
+
+    let mut img = image::open(full_path).ok()?;
+    // 主图为 HDR 的增益图 JPEG 先映射到 SDR (主图为 SDR 的 Ultra HDR 直接用主图，增益图不会进入输出)
+    if let Some(hdr) = color::HdrBase::read(full_path) {
+        img = image::DynamicImage::ImageRgb8(hdr.to_sdr(img.to_rgb8()));
+    }
+    if let Some(edit) = &spec.edit {
+        img = image::DynamicImage::ImageRgb8(apply_image_edit(img, edit));
+    }
+    // 不保留配置文件时先把像素转换到 sRGB (缩放之后进行，补边颜色本身就是 sRGB)
+    let keep_metadata = spec.metadata == color::MetadataMode::Keep && !spec.quantizes();
+    let icc = color::read_icc_profile(full_path);
+    let transform = icc.as_deref().filter(|_| !keep_metadata).and_then(color::SrgbTransform::from_icc);
+    let to_srgb = |mut rgb: image::RgbImage| {
+        if let Some(transform) = &transform {
+            transform.apply(&mut rgb);
+        }
+        rgb
+    };
+    let (w, h) = (spec.width, spec.height);
+    let mut rgb = match spec.fit {
+        ResizeFit::Contain if img.width() > w || img.height() > h => to_srgb(img.resize(w, h, FilterType::Lanczos3).to_rgb8()),
+        ResizeFit::Contain => to_srgb(img.to_rgb8()),
+        ResizeFit::Cover => to_srgb(img.resize_to_fill(w, h, FilterType::Lanczos3).to_rgb8()),
+        ResizeFit::Pad => {
+            let scaled = to_srgb(img.resize(w, h, FilterType::Lanczos3).to_rgb8());
+            let mut canvas = image::RgbImage::from_pixel(w, h, image::Rgb(spec.background));
+            let x = (w - scaled.width()) / 2;
+            let y = (h - scaled.height()) / 2;
+            image::imageops::overlay(&mut canvas, &scaled, x as i64, y as i64);
+            canvas
+        }
+    };
+
+    if let Some(watermark) = &spec.watermark {
+        let taken_at = if watermark.date_stamp {
+            read_exif_taken_at(full_path).or_else(|| {
+                let modified = full_path.metadata().ok()?.modified().ok()?;
+                Some(modified.duration_since(UNIX_EPOCH).ok()?.as_secs_f64())
+            })
+        } else {
+            None
+        };
+        watermark.apply(&mut rgb, taken_at);
+    }
+
+    if spec.grayscale {
+        for pixel in rgb.pixels_mut() {
+            let [r, g, b] = pixel.0;
+            let luma = (0.299 * r as f32 + 0.587 * g as f32 + 0.114 * b as f32).round() as u8;
+            pixel.0 = [luma; 3];
+        }
+    }
+    Some(rgb)
+}
+
+/// 按设备配置的调色板或位深量化 (可选抖动)
+fn quantize_rgb(rgb: &mut image::RgbImage, spec: &RenderSpec) {
+    use image::imageops::colorops::ColorMap;
+
+    if spec.quantizes() {
+        let map: Box<dyn ColorMap<Color = image::Rgb<u8>>> = if spec.palette.is_empty() {
+            Box::new(LevelMap(spec.bit_depth.unwrap_or(8)))
+        } else {
+            Box::new(PaletteMap(spec.palette.clone()))
+        };
+        if spec.dither {
+            image::imageops::dither(rgb, map.as_ref());
+        } else {
+            rgb.pixels_mut().for_each(|p| map.map_color(p));
+        }
+    }
+}
+
+/// 编码渲染结果；给出原图时按 `spec.metadata` 决定是否嵌入原图的 EXIF / ICC
+fn encode_rendered(rgb: &image::RgbImage, spec: &RenderSpec, source: Option<&Path>) -> Option<(Vec<u8>, &'static str)> {
+    let mut buf = std::io::Cursor::new(Vec::new());
+    if spec.quantizes() {
+        rgb.write_to(&mut buf, image::ImageOutputFormat::Png).ok()?;
+        return Some((buf.into_inner(), "image/png"));
+    }
+    rgb.write_to(&mut buf, image::ImageOutputFormat::Jpeg(90)).ok()?;
+    let jpeg = match source.filter(|_| spec.metadata == color::MetadataMode::Keep) {
+        Some(full_path) => color::embed_jpeg_metadata(
+            buf.into_inner(),
+            color::read_exif_block(full_path).as_deref(),
+            color::read_icc_profile(full_path).as_deref(),
+        ),
+        None => buf.into_inner(),
+    };
+    Some((jpeg, "image/jpeg"))
+}
+
+/// 按编辑记录旋转、裁剪并调整曝光
+fn apply_image_edit(img: image::DynamicImage, edit: &ImageEdit) -> image::RgbImage {
+    let quarter_turns = (edit.rotation / 90.0).round();
+    let fine = edit.rotation - quarter_turns * 90.0;
+    let mut rgb = match (quarter_turns as i32).rem_euclid(4) {
+        1 => img.rotate90(),
+        2 => img.rotate180(),
+        3 => img.rotate270(),
+        _ => img,
+    }
+    .to_rgb8();
+    if fine.abs() >= 0.01 {
+        rgb = rotate_and_trim(&rgb, fine.to_radians());
+    }
+    if let Some(crop) = edit.crop {
+        let (w, h) = rgb.dimensions();
+        let x = ((crop.x * w as f32).round() as u32).min(w - 1);
+        let y = ((crop.y * h as f32).round() as u32).min(h - 1);
+        let cw = ((crop.w * w as f32).round() as u32).clamp(1, w - x);
+        let ch = ((crop.h * h as f32).round() as u32).clamp(1, h - y);
+        rgb = image::imageops::crop_imm(&rgb, x, y, cw, ch).to_image();
+    }
+    if edit.exposure != 0.0 {
+        color::adjust_exposure(&mut rgb, edit.exposure);
+    }
+    rgb
+}
+
+/// 旋转后完全落在原图内、宽高比不变的最大矩形的尺寸
+fn trimmed_size(width: u32, height: u32, angle: f32) -> (u32, u32) {
+    let (w, h) = (width as f32, height as f32);
+    let (sin, cos) = (angle.sin().abs(), angle.cos().abs());
+    let scale = (w / (w * cos + h * sin)).min(h / (w * sin + h * cos));
+    (((w * scale).floor() as u32).max(1), ((h * scale).floor() as u32).max(1))
+}
+
+/// 应用编辑后的图片尺寸 (与 apply_image_edit 的结果一致)
+fn edited_size(width: u32, height: u32, edit: &ImageEdit) -> (u32, u32) {
+    let quarter_turns = (edit.rotation / 90.0).round();
+    let fine = edit.rotation - quarter_turns * 90.0;
+    let (mut w, mut h) = if (quarter_turns as i32).rem_euclid(2) == 1 { (height, width) } else { (width, height) };
+    if fine.abs() >= 0.01 {
+        (w, h) = trimmed_size(w, h, fine.to_radians());
+    }
+    if let Some(crop) = edit.crop {
+        let x = ((crop.x * w as f32).round() as u32).min(w - 1);
+        let y = ((crop.y * h as f32).round() as u32).min(h - 1);
+        (w, h) = (((crop.w * w as f32).round() as u32).clamp(1, w - x), ((crop.h * h as f32).round() as u32).clamp(1, h - y));
+    }
+    (w, h)
+}
+
+/// 绕中心顺时针旋转 (弧度)，输出旋转后完全落在原图内、宽高比不变的最大矩形
+fn rotate_and_trim(src: &image::RgbImage, angle: f32) -> image::RgbImage {
+    let (w, h) = (src.width() as f32, src.height() as f32);
+    let (sin, cos) = angle.sin_cos();
+    let (out_w, out_h) = trimmed_size(src.width(), src.height(), angle);
+    let mut out = image::RgbImage::new(out_w, out_h);
+    for (x, y, pixel) in out.enumerate_pixels_mut() {
+        // 输出像素中心相对画面中心的偏移，逆时针转回原图坐标
+        let dx = x as f32 + 0.5 - out_w as f32 / 2.0;
+        let dy = y as f32 + 0.5 - out_h as f32 / 2.0;
+        let sx = (w / 2.0 + dx * cos + dy * sin - 0.5).clamp(0.0, w - 1.0);
+        let sy = (h / 2.0 - dx * sin + dy * cos - 0.5).clamp(0.0, h - 1.0);
+        if let Some(sample) = image::imageops::interpolate_bilinear(src, sx, sy) {
+            *pixel = sample;
+        }
+    }
+    out
+}
+
+/// 渲染缓存使用的文件版本 (大小, mtime 秒)，与 images 表中的 `size` / `mtime` 取值一致
+fn file_version(meta: &std::fs::Metadata) -> (i64, f64) {
+    let mtime = meta
+        .modified()
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_secs_f64())
+        .unwrap_or(0.0);
+    (meta.len() as i64, mtime)
+}
+
+/// 渲染结果的磁盘缓存键，按 (路径, 文件版本, 渲染参数) 区分；带水印的结果不缓存。
+/// 版本取自索引同样记录的值，原图离线时 (归档文件夹) 也能算出同一个键
+fn render_cache_key(rel: &str, (size, mtime): (i64, f64), spec: &RenderSpec) -> Option<String> {
+    spec.watermark.is_none().then(|| {
+        blake3::hash(format!("{}:{}:{}:{:?}", rel, size, mtime, spec).as_bytes()).to_hex().to_string()
+    })
+}
+
+fn rendered_response(bytes: Vec<u8>, mime: &'static str) -> Response {
+    ([(header::CONTENT_TYPE, mime), (header::CACHE_CONTROL, "public, max-age=3600")], bytes).into_response()
+}
+
+async fn serve_rendered(state: &AppState, path: &str, mut spec: RenderSpec) -> Response {
+    let rel = normalize_rel_path(path);
+    let allow_parent = *state.allow_parent_dir_access.read().await;
+    let full = match resolve_and_authorize(&state.root_dir, path, allow_parent) {
+        Ok(full) => full,
+        Err(PathAccessError::Forbidden) => {
+            return (StatusCode::FORBIDDEN, "Access outside ROOT_DIR is disabled").into_response();
+        }
+        Err(PathAccessError::NotFound) => {
+            let Some(offline) = archived_offline_response(state, &rel).await else {
+                return (StatusCode::NOT_FOUND, "File not found").into_response();
+            };
+            // 归档文件夹离线时，按索引中的版本找之前渲染好的缓存
+            spec.edit = load_image_edit(&state.db, &rel).await;
+            let version: Option<(i64, f64)> = sqlx::query_as("SELECT size, mtime FROM images WHERE path = ?")
+                .bind(&rel)
+                .fetch_optional(&state.db)
+                .await
+                .ok()
+                .flatten();
+            if let Some(key) = version.and_then(|version| render_cache_key(&rel, version, &spec)) {
+                if let Some((bytes, mime)) = thumbnail_cache::lookup(state, &key).await {
+                    return rendered_response(bytes, mime);
+                }
+            }
+            return offline;
+        }
+    };
+
+    spec.edit = load_image_edit(&state.db, &rel).await;
+    let cache_key = tokio::fs::metadata(&full)
+        .await
+        .ok()
+        .and_then(|meta| render_cache_key(&rel, file_version(&meta), &spec));
+    let cached = match &cache_key {
+        Some(key) => thumbnail_cache::lookup(state, key).await,
+        None => None,
+    };
+    let rendered = match cached {
+        Some(hit) => Some(hit),
+        None => {
+            let rendered = tokio::task::spawn_blocking(move || render_image(&full, &spec))
+                .await
+                .ok()
+                .flatten();
+            if let (Some(key), Some((bytes, mime))) = (&cache_key, &rendered) {
+                thumbnail_cache::store(state, key, bytes, mime).await;
+            }
+            rendered
+        }
+    };
+
+    match rendered {
+        Some((bytes, mime)) => rendered_response(bytes, mime),
+        None => (StatusCode::UNPROCESSABLE_ENTITY, "Unreadable image").into_response(),
+    }
+}
+
+/// 过渡动画的默认帧数与上限 (含首尾两帧)
+const TRANSITION_DEFAULT_FRAMES: u32 = 8;
+const TRANSITION_MAX_FRAMES: u32 = 30;
+/// 过渡动画的最大边长；整段一次渲染，比单张图片限制得严 (精灵图高度不超过 JPEG 上限)
+const TRANSITION_MAX_SIDE: u32 = 1920;
+/// 整张精灵图 (宽 × 高 × 帧数) 的像素上限，约 40 MP；大尺寸时需要减少帧数
+const TRANSITION_MAX_PIXELS: u64 = 40_000_000;
+const TRANSITION_DEFAULT_DURATION_MS: u32 = 1000;
+const TRANSITION_MAX_DURATION_MS: u32 = 10_000;
+
+/// 过渡动画的输出格式
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum TransitionFormat {
+    /// 所有帧从上到下拼成一张图 (JPEG；设备配置量化时为 PNG)，相框按帧高逐段显示
+    #[default]
+    Sprite,
+    /// 只播放一次的 GIF 动画
+    Gif,
+}
+
+#[derive(Debug, Deserialize)]
+struct TransitionQuery {
+    from: Option<String>,
+    to: Option<String>,
+    /// 代替 from/to：本机会话播放列表第 index 张到下一张 (末尾回到开头)
+    index: Option<usize>,
+    width: Option<u32>,
+    height: Option<u32>,
+    /// 两帧必须同样大小，`contain` 按 `pad` 处理
+    #[serde(default)]
+    fit: ResizeFit,
+    profile: Option<String>,
+    frames: Option<u32>,
+    #[serde(default)]
+    format: TransitionFormat,
+    /// GIF 的总时长
+    duration_ms: Option<u32>,
+}
+
+/// 接口: GET /api/transition?from=...&to=...&width=&height= 或 ?index=N&profile=...
+///
+/// 预先合成两张相邻图片之间的交叉淡化，性能弱的相框 (旧平板、墨水屏驱动) 只需按帧显示。
+/// 帧先在全色下混合再按设备配置量化，响应头 `x-transition-frames` 给出帧数。
+async fn transition_frames(
+    State(state): State<AppState>,
+    connect_info: ConnectInfo<SocketAddr>,
+    Query(query): Query<TransitionQuery>,
+) -> Response {
+    let error = |status: StatusCode, detail: String| (status, Json(serde_json::json!({ "detail": detail }))).into_response();
+
+    let (from, to) = match (query.from, query.to, query.index) {
+        (Some(from), Some(to), None) => match (client_path_key(&state, &from).await, client_path_key(&state, &to).await) {
+            (Ok(from), Ok(to)) => (from, to),
+            (Err(PathAccessError::Forbidden), _) | (_, Err(PathAccessError::Forbidden)) => {
+                return error(StatusCode::FORBIDDEN, "Files outside ROOT_DIR must be referenced by ID".to_string())
+            }
+            _ => return error(StatusCode::NOT_FOUND, "Image not found".to_string()),
+        },
+        (None, None, Some(index)) => {
+            let ip = connect_info.0.ip().to_string();
+            let blocked = load_blocklist(&state.db, &ip).await;
+            let playlist = visible_session_playlist(&state, &ip, &blocked).await.unwrap_or_default();
+            if index >= playlist.len() {
+                return error(StatusCode::NOT_FOUND, "index is outside the session playlist".to_string());
+            }
+            (playlist[index].clone(), playlist[(index + 1) % playlist.len()].clone())
+        }
+        _ => return error(StatusCode::BAD_REQUEST, "Give either from and to, or index".to_string()),
+    };
+
+    let mut spec = match &query.profile {
+        Some(name) => {
+            let Some(profile) = load_device_profile(&state.db, name).await else {
+                return error(StatusCode::NOT_FOUND, "Profile not found".to_string());
+            };
+            match RenderSpec::from_profile(&profile) {
+                Ok(spec) => spec,
+                Err(detail) => return error(StatusCode::BAD_REQUEST, detail),
+            }
+        }
+        None => {
+            let (Some(width), Some(height)) = (query.width, query.height) else {
+                return error(StatusCode::BAD_REQUEST, "width and height, or profile, are required".to_string());
+            };
+            RenderSpec { width, height, fit: query.fit, ..Default::default() }
+        }
+    };
+    if spec.fit == ResizeFit::Contain {
+        spec.fit = ResizeFit::Pad;
+    }
+    if !(1..=TRANSITION_MAX_SIDE).contains(&spec.width) || !(1..=TRANSITION_MAX_SIDE).contains(&spec.height) {
+        return error(StatusCode::BAD_REQUEST, format!("Size must be between 1 and {} pixels", TRANSITION_MAX_SIDE));
+    }
+    let frames = query.frames.unwrap_or(TRANSITION_DEFAULT_FRAMES);
+    if !(2..=TRANSITION_MAX_FRAMES).contains(&frames) {
+        return error(StatusCode::BAD_REQUEST, format!("frames must be between 2 and {}", TRANSITION_MAX_FRAMES));
+    }
+    if spec.width as u64 * spec.height as u64 * frames as u64 > TRANSITION_MAX_PIXELS {
+        return error(
+            StatusCode::BAD_REQUEST,
+            format!(
+                "{}x{} with {} frames exceeds the {} MP sprite limit; lower the size or frame count",
+                spec.width,
+                spec.height,
+                frames,
+                TRANSITION_MAX_PIXELS / 1_000_000
+            ),
+        );
+    }
+    let duration_ms = query.duration_ms.unwrap_or(TRANSITION_DEFAULT_DURATION_MS);
+    if !(1..=TRANSITION_MAX_DURATION_MS).contains(&duration_ms) {
+        return error(StatusCode::BAD_REQUEST, format!("duration_ms must be between 1 and {}", TRANSITION_MAX_DURATION_MS));
+    }
+
+    let allow_parent = *state.allow_parent_dir_access.read().await;
+    let mut sources = Vec::new();
+    for rel in [&from, &to] {
+        match resolve_and_authorize(&state.root_dir, rel, allow_parent) {
+            Ok(full) if full.is_file() && is_image_ext(&full) => {
+                let edit = load_image_edit(&state.db, rel).await;
+                let meta = tokio::fs::metadata(&full).await.ok();
+                sources.push((full, edit, meta.map(|m| file_etag(&m)).unwrap_or_default()));
+            }
+            Err(PathAccessError::Forbidden) => {
+                return error(StatusCode::FORBIDDEN, "Access outside ROOT_DIR is disabled".to_string())
+            }
+            _ => return error(StatusCode::NOT_FOUND, format!("Image not found: {}", rel)),
+        }
+    }
+
+    // 与 render_cache_key 一样按 (路径, 文件版本, 编辑, 渲染参数) 区分
+    let key_source = format!(
+        "transition:{}:{}:{:?}:{}:{}:{:?}:{}:{:?}:{}:{:?}",
+        from, sources[0].2, sources[0].1, to, sources[1].2, sources[1].1, frames, query.format, duration_ms, spec
+    );
+    let cache_key = blake3::hash(key_source.as_bytes()).to_hex().to_string();
+    let rendered = match thumbnail_cache::lookup(&state, &cache_key).await {
+        Some(hit) => Some(hit),
+        None => {
+            let format = query.format;
+            let rendered = tokio::task::spawn_blocking(move || {
+                let [(from_path, from_edit, _), (to_path, to_edit, _)]: [_; 2] = sources.try_into().ok()?;
+                let a = render_rgb(&from_path, &RenderSpec { edit: from_edit, ..spec.clone() })?;
+                let b = render_rgb(&to_path, &RenderSpec { edit: to_edit, ..spec.clone() })?;
+                render_transition(&a, &b, &spec, frames, format, duration_ms)
+            })
+            .await
+            .ok()
+            .flatten();
+            if let Some((bytes, mime)) = &rendered {
+                thumbnail_cache::store(&state, &cache_key, bytes, mime).await;
+            }
+            rendered
+        }
+    };
+
+    match rendered {
+        Some((bytes, mime)) => (
+            [
+                (header::CONTENT_TYPE, mime.to_string()),
+                (header::CACHE_CONTROL, "public, max-age=3600".to_string()),
+                (HeaderName::from_static("x-transition-frames"), frames.to_string()),
+            ],
+            bytes,
+        )
+            .into_response(),
+        None => error(StatusCode::UNPROCESSABLE_ENTITY, "Unreadable image".to_string()),
+    }
+}
+
+/// 交叉淡化：第一帧为 a、最后一帧为 b，每帧混合后再量化
+fn render_transition(
+    a: &image::RgbImage,
+    b: &image::RgbImage,
+    spec: &RenderSpec,
+    frames: u32,
+    format: TransitionFormat,
+    duration_ms: u32,
+) -> Option<(Vec<u8>, &'static str)> {
+    let (w, h) = a.dimensions();
+    if b.dimensions() != (w, h) {
+        return None;
+    }
+    let blended = (0..frames).map(|i| {
+        let t = i as f32 / (frames - 1) as f32;
+        let mut frame = image::RgbImage::from_fn(w, h, |x, y| {
+            let (pa, pb) = (a.get_pixel(x, y).0, b.get_pixel(x, y).0);
+            image::Rgb(std::array::from_fn(|c| (pa[c] as f32 * (1.0 - t) + pb[c] as f32 * t).round() as u8))
+        });
+        quantize_rgb(&mut frame, spec);
+        frame
+    });
+
+    match format {
+        TransitionFormat::Sprite => {
+            let mut sprite = image::RgbImage::new(w, h * frames);
+            for (i, frame) in blended.enumerate() {
+                image::imageops::replace(&mut sprite, &frame, 0, i as i64 * h as i64);
+            }
+            encode_rendered(&sprite, spec, None)
+        }
+        TransitionFormat::Gif => {
+            let mut buf = Vec::new();
+            {
+                let mut encoder = image::codecs::gif::GifEncoder::new_with_speed(&mut buf, 10);
+                let delay = image::Delay::from_numer_denom_ms(duration_ms, frames);
+                encoder
+                    .encode_frames(blended.map(|frame| {
+                        image::Frame::from_parts(image::DynamicImage::ImageRgb8(frame).to_rgba8(), 0, 0, delay)
+                    }))
+                    .ok()?;
+            }
+            Some((buf, "image/gif"))
+        }
+    }
+}
+
+/// srcset 清单中的标准档位 (名称, 宽度)；不小于图片宽度的档位省略，由原图代替
+const SRCSET_TIERS: &[(&str, u32)] = &[("thumbnail", 320), ("medium", 1280), ("full", 2560)];
+
+#[derive(Debug, Deserialize)]
+struct SrcsetQuery {
+    path: String,
+    /// 在后台渲染清单中尚未缓存的档位，默认开启
+    prewarm: Option<bool>,
+}
+
+#[derive(Debug, Serialize)]
+struct SrcsetRendition {
+    name: &'static str,
+    width: u32,
+    height: u32,
+    url: String,
+    /// 渲染缓存中已有 (原图总是 true)
+    cached: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct SrcsetResponse {
+    path: String,
+    /// 显示尺寸 (已应用编辑)
+    width: u32,
+    height: u32,
+    renditions: Vec<SrcsetRendition>,
+    /// 可直接用作 `<img srcset>` 的字符串
+    srcset: String,
+}
+
+/// 接口: GET /api/srcset?path=...，列出可用的各档宽度与 URL
+///
+/// 档位 URL 与 `/api/resize?width=` 相同，命中同一份渲染缓存。图片有编辑记录时原图 (`original`) 不含编辑效果，
+/// srcset 中改用原尺寸的编辑后版本 (`edited`)。
+async fn srcset_manifest(
+    State(state): State<AppState>,
+    Query(query): Query<SrcsetQuery>,
+    headers: axum::http::HeaderMap,
+) -> Result<Json<SrcsetResponse>, (StatusCode, Json<serde_json::Value>)> {
+    let error = |status: StatusCode, detail: &str| (status, Json(serde_json::json!({ "detail": detail })));
+    let (rel, full) = match authorize_client_path(&state, &query.path).await {
+        Ok((rel, full)) if full.is_file() && is_image_ext(&full) => (rel, full),
+        Err(PathAccessError::Forbidden) => return Err(error(StatusCode::FORBIDDEN, "Access outside ROOT_DIR is disabled")),
+        _ => return Err(error(StatusCode::NOT_FOUND, "Image not found")),
+    };
+    let full_for_size = full.clone();
+    let Ok(Ok((original_w, original_h))) = tokio::task::spawn_blocking(move || image::image_dimensions(&full_for_size)).await
+    else {
+        return Err(error(StatusCode::UNPROCESSABLE_ENTITY, "Unreadable image"));
+    };
+    let file_meta = tokio::fs::metadata(&full).await.ok();
+    let edit = load_image_edit(&state.db, &rel).await;
+    let (width, height) = match &edit {
+        Some(edit) => edited_size(original_w, original_h, edit),
+        None => (original_w, original_h),
+    };
+
+    let base = format!("{}{}/api/v1", request_base_url(&headers), state.base_path);
+    // 按 ID 模式下链接里用 `id:` 引用
+    let client_ref = playlist_for_client(&state, vec![rel.clone()]).await.pop().unwrap_or_else(|| rel.clone());
+    let encoded = urlencoding::encode(&client_ref).into_owned();
+    let mut widths: Vec<(&'static str, u32)> = SRCSET_TIERS.iter().copied().filter(|(_, w)| *w < width).collect();
+    if edit.is_some() {
+        widths.push(("edited", width));
+    }
+    let mut renditions = Vec::new();
+    let mut missing = Vec::new();
+    for (name, w) in widths {
+        let spec = RenderSpec { width: w, height: RENDER_MAX_SIDE, edit: edit.clone(), ..Default::default() };
+        let key = file_meta.as_ref().and_then(|meta| render_cache_key(&rel, file_version(meta), &spec));
+        let cached = key.as_ref().is_some_and(|key| thumbnail_cache::contains(&state.root_dir, key));
+        if let (false, Some(key)) = (cached, key) {
+            missing.push((key, spec));
+        }
+        renditions.push(SrcsetRendition {
+            name,
+            width: w,
+            height: ((height as f64 * w as f64 / width as f64).round() as u32).max(1),
+            url: format!("{}/resize?path={}&width={}", base, encoded, w),
+            cached,
+        });
+    }
+    let mut srcset: Vec<String> = renditions.iter().map(|r| format!("{} {}w", r.url, r.width)).collect();
+    let original_url = format!("{}/file?path={}", base, encoded);
+    if edit.is_none() {
+        srcset.push(format!("{} {}w", original_url, original_w));
+    }
+    renditions.push(SrcsetRendition { name: "original", width: original_w, height: original_h, url: original_url, cached: true });
+
+    // 没缓存的档位排进后台预热队列 (限并发、去重)，前端随后按 srcset 请求时多半能直接命中缓存
+    if query.prewarm.unwrap_or(true) && !missing.is_empty() {
+        thumbnail_cache::warm(&state, &full, missing);
+    }
+
+    Ok(Json(SrcsetResponse { path: client_ref, width, height, renditions, srcset: srcset.join(", ") }))
+}
+
+#[derive(Debug, Deserialize)]
+struct ResizeQuery {
+    path: String,
+    width: Option<u32>,
+    height: Option<u32>,
+    #[serde(default)]
+    fit: ResizeFit,
+    profile: Option<String>,
+    /// 叠加已注册的水印 (见 `/api/watermarks`)
+    watermark: Option<String>,
+    #[serde(default)]
+    metadata: color::MetadataMode,
+}
+
+/// 接口: GET /api/resize?path=...&width=&height=&fit=contain|cover|pad 或 &profile=...
+///
+/// 指定 profile 时按设备配置输出恰好尺寸、已量化/抖动的图片，忽略其余参数。
+/// `metadata=keep` 时保留原图的 EXIF 与 ICC 配置文件，默认转换到 sRGB 并去掉元数据。
+/// 输出总是 SDR：HDR 增益图 JPEG (`/api/info` 的 `hdr_gain_map`) 在这里得到色调映射后的版本。
+/// 图片有编辑记录 (`/api/edits`) 时先应用编辑再缩放。
+async fn resize_image(State(state): State<AppState>, Query(query): Query<ResizeQuery>) -> Response {
+    let bad_request = |detail: String| (StatusCode::BAD_REQUEST, Json(serde_json::json!({ "detail": detail }))).into_response();
+
+    let mut spec = match &query.profile {
+        Some(name) => {
+            let Some(profile) = load_device_profile(&state.db, name).await else {
+                return (StatusCode::NOT_FOUND, Json(serde_json::json!({ "detail": "Profile not found" }))).into_response();
+            };
+            match RenderSpec::from_profile(&profile) {
+                Ok(spec) => spec,
+                Err(detail) => return bad_request(detail),
+            }
+        }
+        None => {
+            let (width, height) = match (query.width, query.height, query.fit) {
+                (Some(w), Some(h), _) => (w, h),
+                (Some(w), None, ResizeFit::Contain) => (w, RENDER_MAX_SIDE),
+                (None, Some(h), ResizeFit::Contain) => (RENDER_MAX_SIDE, h),
+                (None, None, _) => return bad_request("width, height or profile is required".to_string()),
+                _ => return bad_request("fit=cover and fit=pad need both width and height".to_string()),
+            };
+            if !(1..=RENDER_MAX_SIDE).contains(&width) || !(1..=RENDER_MAX_SIDE).contains(&height) {
+                return bad_request(format!("Size must be between 1 and {} pixels", RENDER_MAX_SIDE));
+            }
+            RenderSpec { width, height, fit: query.fit, metadata: query.metadata, ..Default::default() }
+        }
+    };
+    if let Some(name) = &query.watermark {
+        match load_watermark(&state, name).await {
+            Ok(watermark) => spec.watermark = Some(watermark),
+            Err((status, detail)) => return (status, Json(serde_json::json!({ "detail": detail }))).into_response(),
+        }
+    }
+
+    match client_path_key(&state, &query.path).await {
+        Ok(rel) => serve_rendered(&state, &rel, spec).await,
+        Err(PathAccessError::Forbidden) => (StatusCode::FORBIDDEN, "Access outside ROOT_DIR is disabled").into_response(),
+        Err(PathAccessError::NotFound) => (StatusCode::NOT_FOUND, "File not found").into_response(),
+    }
+}
+
+async fn load_device_profile(pool: &Pool<Sqlite>, name: &str) -> Option<DeviceProfile> {
+    let row: Option<(String,)> = sqlx::query_as("SELECT profile_json FROM device_profiles WHERE name = ?")
+        .bind(name.trim())
+        .fetch_optional(pool)
+        .await
+        .unwrap_or(None);
+    row.and_then(|(json,)| serde_json::from_str(&json).ok())
+}
+
+/// 接口: GET /api/profiles
+async fn list_device_profiles(State(state): State<AppState>) -> Json<Vec<DeviceProfile>> {
+    let rows: Vec<(String,)> = sqlx::query_as("SELECT profile_json FROM device_profiles ORDER BY name")
+        .fetch_all(&state.db)
+        .await
+        .unwrap_or_default();
+    Json(rows.iter().filter_map(|(json,)| serde_json::from_str(json).ok()).collect())
+}
+
+/// 接口: POST /api/profiles，注册或覆盖同名设备配置
+async fn save_device_profile(
+    State(state): State<AppState>,
+    Json(profile): Json<DeviceProfile>,
+) -> Result<Json<serde_json::Value>, (StatusCode, Json<serde_json::Value>)> {
+    let bad_request = |detail: String| (StatusCode::BAD_REQUEST, Json(serde_json::json!({ "detail": detail })));
+    if profile.name.trim().is_empty() {
+        return Err(bad_request("Profile name is required".to_string()));
+    }
+    RenderSpec::from_profile(&profile).map_err(bad_request)?;
+
+    let profile = DeviceProfile { name: profile.name.trim().to_string(), ..profile };
+    sqlx::query("INSERT OR REPLACE INTO device_profiles (name, profile_json) VALUES (?, ?)")
+        .bind(&profile.name)
+        .bind(serde_json::to_string(&profile).unwrap_or_default())
+        .execute(&state.db)
+        .await
+        .map_err(|e| {
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(serde_json::json!({ "detail": format!("Failed to save profile: {}", e) })),
+            )
+        })?;
+    Ok(Json(serde_json::json!({ "name": profile.name })))
+}
+
+#[derive(Debug, Deserialize)]
+struct ProfileQuery {
+    name: String,
+}
+
+/// 接口: DELETE /api/profiles?name=...
+async fn delete_device_profile(State(state): State<AppState>, Query(query): Query<ProfileQuery>) -> Json<serde_json::Value> {
+    let removed = sqlx::query("DELETE FROM device_profiles WHERE name = ?")
+        .bind(query.name.trim())
+        .execute(&state.db)
+        .await
+        .map(|r| r.rows_affected())
+        .unwrap_or(0);
+    Json(serde_json::json!({ "removed": removed }))
+}
+
+/// 水印位置
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum WatermarkPosition {
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    #[default]
+    BottomRight,
+    Center,
+}
+
+/// 水印配置：文字 (需 `GALLERY_WATERMARK_FONT` 指定 TTF/OTF 字体) 和/或 PNG 图标，可附加 EXIF 拍摄日期
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct WatermarkConfig {
+    name: String,
+    text: Option<String>,
+    /// PNG 文件路径 (绝对路径或相对 ROOT_DIR)
+    image: Option<String>,
+    /// 在文字后追加拍摄日期 (EXIF，缺失时用修改时间)
+    #[serde(default)]
+    date_stamp: bool,
+    #[serde(default)]
+    position: WatermarkPosition,
+    /// 不透明度 0~1，默认 0.7
+    opacity: Option<f32>,
+    /// 文字高度 / PNG 宽度占图片短边的比例，默认 0.04 / 0.2
+    scale: Option<f32>,
+    /// 文字颜色，默认白色
+    color: Option<String>,
+}
+
+/// 解析后的水印，可直接绘制
+#[derive(Clone)]
+struct PreparedWatermark {
+    text: Option<String>,
+    logo: Option<Arc<image::RgbaImage>>,
+    date_stamp: bool,
+    position: WatermarkPosition,
+    opacity: f32,
+    scale: Option<f32>,
+    color: [u8; 3],
+}
+
+impl std::fmt::Debug for PreparedWatermark {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PreparedWatermark").field("text", &self.text).finish_non_exhaustive()
+    }
+}
+
+fn watermark_font() -> Option<&'static ab_glyph::FontArc> {
+    static FONT: std::sync::OnceLock<Option<ab_glyph::FontArc>> = std::sync::OnceLock::new();
+    FONT.get_or_init(|| {
+        let path = config_var("GALLERY_WATERMARK_FONT").ok().filter(|v| !v.trim().is_empty())?;
+        match std::fs::read(&path).map_err(|e| e.to_string()).and_then(|data| {
+            ab_glyph::FontArc::try_from_vec(data).map_err(|e| e.to_string())
+        }) {
+            Ok(font) => Some(font),
+            Err(err) => {
+                tracing::error!("⚠️ Failed to load watermark font {}: {}", path, err);
+                None
+            }
+        }
+    })
+    .as_ref()
+}
+
+impl PreparedWatermark {
+    fn prepare(config: &WatermarkConfig, root_dir: &Path) -> Result<Self, String> {
+        if config.text.is_none() && config.image.is_none() && !config.date_stamp {
+            return Err("Watermark needs text, image or date_stamp".to_string());
+        }
+        if (config.text.is_some() || config.date_stamp) && watermark_font().is_none() {
+            return Err("Text watermarks need GALLERY_WATERMARK_FONT pointing to a TTF/OTF font".to_string());
+        }
+        let logo = match &config.image {
+            Some(path) => {
+                let full = if Path::new(path).is_absolute() { PathBuf::from(path) } else { root_dir.join(path) };
+                let logo = image::open(&full).map_err(|e| format!("Cannot read watermark image {}: {}", path, e))?;
+                Some(Arc::new(logo.to_rgba8()))
+            }
+            None => None,
+        };
+        let color = match &config.color {
+            Some(c) => parse_hex_color(c).ok_or_else(|| format!("Invalid color {:?}", c))?,
+            None => [255, 255, 255],
+        };
+        Ok(Self {
+            text: config.text.clone().filter(|t| !t.trim().is_empty()),
+            logo,
+            date_stamp: config.date_stamp,
+            position: config.position,
+            opacity: config.opacity.unwrap_or(0.7).clamp(0.0, 1.0),
+            scale: config.scale.filter(|s| *s > 0.0 && *s <= 1.0),
+            color,
+        })
+    }
+
+    /// 计算元素左上角坐标 (留出短边 2% 的边距)
+    fn place(&self, canvas: (u32, u32), item: (u32, u32)) -> (i64, i64) {
+        let margin = (canvas.0.min(canvas.1) as f32 * 0.02) as i64;
+        let (cw, ch, iw, ih) = (canvas.0 as i64, canvas.1 as i64, item.0 as i64, item.1 as i64);
+        match self.position {
+            WatermarkPosition::TopLeft => (margin, margin),
+            WatermarkPosition::TopRight => (cw - iw - margin, margin),
+            WatermarkPosition::BottomLeft => (margin, ch - ih - margin),
+            WatermarkPosition::BottomRight => (cw - iw - margin, ch - ih - margin),
+            WatermarkPosition::Center => ((cw - iw) / 2, (ch - ih) / 2),
+        }
+    }
+
+    /// 绘制到已缩放的图片上；`taken_at` 为拍摄时间 (Unix 秒)
+    fn apply(&self, rgb: &mut image::RgbImage, taken_at: Option<f64>) {
+        let short_side = rgb.width().min(rgb.height()) as f32;
+
+        if let Some(logo) = &self.logo {
+            let target_w = (short_side * self.scale.unwrap_or(0.2)).max(1.0) as u32;
+            let target_h = ((logo.height() as f32 * target_w as f32 / logo.width().max(1) as f32) as u32).max(1);
+            let logo = image::imageops::resize(logo.as_ref(), target_w, target_h, image::imageops::FilterType::Triangle);
+            let (x0, y0) = self.place(rgb.dimensions(), logo.dimensions());
+            for (x, y, px) in logo.enumerate_pixels() {
+                let alpha = px[3] as f32 / 255.0 * self.opacity;
+                blend_pixel(rgb, x0 + x as i64, y0 + y as i64, [px[0], px[1], px[2]], alpha);
+            }
+        }
+
+        let date = self
+            .date_stamp
+            .then(|| taken_at.and_then(|t| chrono::DateTime::from_timestamp(t as i64, 0)))
+            .flatten()
+            .map(|t| t.format("%Y-%m-%d").to_string());
+        let text = match (&self.text, date) {
+            (Some(text), Some(date)) => format!("{} · {}", text, date),
+            (Some(text), None) => text.clone(),
+            (None, Some(date)) => date,
+            (None, None) => return,
+        };
+        let Some(font) = watermark_font() else {
+            return;
+        };
+        let px = (short_side * self.scale.unwrap_or(0.04)).max(8.0);
+        draw_text(rgb, font, &text, px, self, self.color);
+    }
+}
+
+fn blend_pixel(rgb: &mut image::RgbImage, x: i64, y: i64, color: [u8; 3], alpha: f32) {
+    if x < 0 || y < 0 || x >= rgb.width() as i64 || y >= rgb.height() as i64 || alpha <= 0.0 {
+        return;
+    }
+    let px = rgb.get_pixel_mut(x as u32, y as u32);
+    for c in 0..3 {
+        px[c] = (px[c] as f32 * (1.0 - alpha) + color[c] as f32 * alpha).round() as u8;
+    }
+}
+
+/// 单行文字 (带 1px 阴影以保证浅色背景上可读)
+fn draw_text(rgb: &mut image::RgbImage, font: &ab_glyph::FontArc, text: &str, px: f32, mark: &PreparedWatermark, color: [u8; 3]) {
+    use ab_glyph::{point, Font, ScaleFont};
+    let scaled = font.as_scaled(px);
+
+    let mut glyphs = Vec::new();
+    let mut caret = 0.0f32;
+    let mut previous = None;
+    for c in text.chars() {
+        let id = scaled.glyph_id(c);
+        if let Some(prev) = previous {
+            caret += scaled.kern(prev, id);
+        }
+        glyphs.push(id.with_scale_and_position(px, point(caret, scaled.ascent())));
+        caret += scaled.h_advance(id);
+        previous = Some(id);
+    }
+    let size = (caret.ceil() as u32, (scaled.ascent() - scaled.descent()).ceil() as u32);
+    let (x0, y0) = mark.place(rgb.dimensions(), size);
+    let shadow = (px / 24.0).max(1.0) as i64;
+
+    for glyph in glyphs {
+        let Some(outlined) = font.outline_glyph(glyph) else {
+            continue;
+        };
+        let bounds = outlined.px_bounds();
+        let (bx, by) = (x0 + bounds.min.x as i64, y0 + bounds.min.y as i64);
+        outlined.draw(|gx, gy, coverage| {
+            blend_pixel(rgb, bx + gx as i64 + shadow, by + gy as i64 + shadow, [0, 0, 0], coverage * mark.opacity * 0.6);
+        });
+        outlined.draw(|gx, gy, coverage| {
+            blend_pixel(rgb, bx + gx as i64, by + gy as i64, color, coverage * mark.opacity);
+        });
+    }
+}
+
+async fn load_watermark(state: &AppState, name: &str) -> Result<PreparedWatermark, (StatusCode, String)> {
+    let row: Option<(String,)> = sqlx::query_as("SELECT config_json FROM watermarks WHERE name = ?")
+        .bind(name.trim())
+        .fetch_optional(&state.db)
+        .await
+        .unwrap_or(None);
+    let config: WatermarkConfig = row
+        .and_then(|(json,)| serde_json::from_str(&json).ok())
+        .ok_or((StatusCode::NOT_FOUND, "Watermark not found".to_string()))?;
+    PreparedWatermark::prepare(&config, &state.root_dir).map_err(|e| (StatusCode::UNPROCESSABLE_ENTITY, e))
+}
+
+/// 接口: GET /api/watermarks
+async fn list_watermarks(State(state): State<AppState>) -> Json<Vec<WatermarkConfig>> {
+    let rows: Vec<(String,)> = sqlx::query_as("SELECT config_json FROM watermarks ORDER BY name")
+        .fetch_all(&state.db)
+        .await
+        .unwrap_or_default();
+    Json(rows.iter().filter_map(|(json,)| serde_json::from_str(json).ok()).collect())
+}
+
+/// 接口: POST /api/watermarks，注册或覆盖同名水印
+async fn save_watermark(
+    State(state): State<AppState>,
+    Json(config): Json<WatermarkConfig>,
+) -> Result<Json<serde_json::Value>, (StatusCode, Json<serde_json::Value>)> {
+    let bad_request = |detail: String| (StatusCode::BAD_REQUEST, Json(serde_json::json!({ "detail": detail })));
+    if config.name.trim().is_empty() {
+        return Err(bad_request("Watermark name is required".to_string()));
+    }
+    PreparedWatermark::prepare(&config, &state.root_dir).map_err(bad_request)?;
+
+    let config = WatermarkConfig { name: config.name.trim().to_string(), ..config };
+    sqlx::query("INSERT OR REPLACE INTO watermarks (name, config_json) VALUES (?, ?)")
+        .bind(&config.name)
+        .bind(serde_json::to_string(&config).unwrap_or_default())
+        .execute(&state.db)
+        .await
+        .map_err(|e| {
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(serde_json::json!({ "detail": format!("Failed to save watermark: {}", e) })),
+            )
+        })?;
+    Ok(Json(serde_json::json!({ "name": config.name })))
+}
+
+/// 接口: DELETE /api/watermarks?name=...
+async fn delete_watermark(State(state): State<AppState>, Query(query): Query<ProfileQuery>) -> Json<serde_json::Value> {
+    let removed = sqlx::query("DELETE FROM watermarks WHERE name = ?")
+        .bind(query.name.trim())
+        .execute(&state.db)
+        .await
+        .map(|r| r.rows_affected())
+        .unwrap_or(0);
+    Json(serde_json::json!({ "removed": removed }))
+}
+
+/// 拼图布局
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum CollageLayout {
+    /// 等大方格，图片居中裁剪
+    #[default]
+    Grid,
+    /// 两端对齐的行 (保持各图比例，行高自适应)
+    Mosaic,
+}
+
+#[derive(Debug, Deserialize)]
+struct CollageRequest {
+    paths: Vec<String>,
+    #[serde(default)]
+    layout: CollageLayout,
+    /// 输出宽度，默认 1920
+    width: Option<u32>,
+    /// grid 列数，默认约为 sqrt(N)
+    columns: Option<u32>,
+    /// grid 单元格宽高比，默认 1
+    cell_aspect: Option<f64>,
+    /// mosaic 目标行高，默认 宽度 / 4
+    row_height: Option<u32>,
+    /// 间距像素，默认 8
+    gap: Option<u32>,
+    /// 背景色 (十六进制)，默认白色
+    background: Option<String>,
+    /// jpeg (默认) 或 png
+    format: Option<String>,
+}
+
+const COLLAGE_MAX_IMAGES: usize = 100;
+const COLLAGE_MAX_SIDE: u32 = 8192;
+
+impl CollageRequest {
+    fn canvas_width(&self) -> u32 {
+        self.width.unwrap_or(1920).clamp(64, COLLAGE_MAX_SIDE)
+    }
+
+    fn gap(&self) -> u32 {
+        self.gap.unwrap_or(8).min(self.canvas_width() / 8)
+    }
+
+    /// grid 布局的 (列数, 行数, 单元格宽)；间距把宽度 (或最大高度) 占满时返回 None
+    fn grid(&self, n: u32) -> Option<(u32, u32, u32)> {
+        let gap = self.gap();
+        let columns = self.columns.unwrap_or_else(|| (n as f64).sqrt().ceil() as u32).clamp(1, n.max(1));
+        let rows = n.div_ceil(columns);
+        let cell_w = self.canvas_width().checked_sub(gap.checked_mul(columns + 1)?)? / columns;
+        COLLAGE_MAX_SIDE.checked_sub(gap.checked_mul(rows + 1)?)?;
+        (cell_w > 0).then_some((columns, rows, cell_w))
+    }
+}
+
+/// 把已解码的图片按布局绘制到画布上
+fn compose_collage(images: &[image::DynamicImage], req: &CollageRequest, background: [u8; 3]) -> image::RgbImage {
+    use image::imageops::FilterType;
+    let width = req.canvas_width();
+    let gap = req.gap();
+    let n = images.len() as u32;
+
+    match req.layout {
+        CollageLayout::Grid => {
+            // 处理函数已按请求的路径数校验过；读不出的图片改变了布局时退回单列，不会下溢
+            let (columns, rows, cell_w) = req.grid(n).unwrap_or((1, n, 1));
+            let cell_h = ((cell_w as f64 / req.cell_aspect.filter(|a| *a > 0.05).unwrap_or(1.0)) as u32).max(1);
+            let height = (rows * cell_h + gap * (rows + 1)).min(COLLAGE_MAX_SIDE);
+            let mut canvas = image::RgbImage::from_pixel(width, height, image::Rgb(background));
+            for (i, img) in images.iter().enumerate() {
+                let (col, row) = (i as u32 % columns, i as u32 / columns);
+                let cell = img.resize_to_fill(cell_w, cell_h, FilterType::Lanczos3).to_rgb8();
+                let x = gap + col * (cell_w + gap);
+                let y = gap + row * (cell_h + gap);
+                image::imageops::overlay(&mut canvas, &cell, x as i64, y as i64);
+            }
+            canvas
+        }
+        CollageLayout::Mosaic => {
+            let target_h = req.row_height.unwrap_or(width / 4).max(16) as f64;
+            let inner = (width - gap * 2) as f64;
+
+            // 贪心分行：行内图片按目标行高排开超出宽度时收尾，再整体缩放到恰好铺满
+            let mut rows: Vec<(Vec<usize>, f64)> = Vec::new();
+            let mut current: Vec<usize> = Vec::new();
+            let mut aspect_sum = 0.0;
+            for (i, img) in images.iter().enumerate() {
+                current.push(i);
+                aspect_sum += img.width() as f64 / img.height().max(1) as f64;
+                let row_w = aspect_sum * target_h + gap as f64 * (current.len() as f64 - 1.0);
+                if row_w >= inner {
+                    let h = (inner - gap as f64 * (current.len() as f64 - 1.0)) / aspect_sum;
+                    rows.push((std::mem::take(&mut current), h));
+                    aspect_sum = 0.0;
+                }
+            }
+            if !current.is_empty() {
+                rows.push((current, target_h));
+            }
+
+            let height = (rows.iter().map(|(_, h)| h.round() as u32 + gap).sum::<u32>() + gap).min(COLLAGE_MAX_SIDE);
+            let mut canvas = image::RgbImage::from_pixel(width, height, image::Rgb(background));
+            let mut y = gap;
+            for (indices, row_h) in rows {
+                let row_h = (row_h.round() as u32).max(1);
+                let mut x = gap;
+                for i in indices {
+                    let img = &images[i];
+                    let w = ((img.width() as f64 * row_h as f64 / img.height().max(1) as f64).round() as u32).max(1);
+                    let tile = img.resize_exact(w, row_h, FilterType::Lanczos3).to_rgb8();
+                    image::imageops::overlay(&mut canvas, &tile, x as i64, y as i64);
+                    x += w + gap;
+                }
+                y += row_h + gap;
+            }
+            canvas
+        }
+    }
+}
+
+/// 接口: POST /api/collage，把选中的图片合成为一张拼图 (grid / mosaic)
+async fn create_collage(State(state): State<AppState>, Json(req): Json<CollageRequest>) -> Response {
+    let bad_request = |detail: String| (StatusCode::BAD_REQUEST, Json(serde_json::json!({ "detail": detail }))).into_response();
+    if req.paths.is_empty() || req.paths.len() > COLLAGE_MAX_IMAGES {
+        return bad_request(format!("Provide between 1 and {} paths", COLLAGE_MAX_IMAGES));
+    }
+    let background = match &req.background {
+        Some(c) => match parse_hex_color(c) {
+            Some(rgb) => rgb,
+            None => return bad_request(format!("Invalid background color {:?}", c)),
+        },
+        None => [255, 255, 255],
+    };
+    let png = match req.format.as_deref() {
+        None | Some("jpeg") | Some("jpg") => false,
+        Some("png") => true,
+        Some(other) => return bad_request(format!("Unsupported format {:?}", other)),
+    };
+
+    let allow_parent = *state.allow_parent_dir_access.read().await;
+    let files: Vec<PathBuf> = req
+        .paths
+        .iter()
+        .filter_map(|p| resolve_and_authorize(&state.root_dir, &normalize_rel_path(p), allow_parent).ok())
+        .collect();
+    if req.layout == CollageLayout::Grid && req.grid(files.len() as u32).is_none() {
+        return bad_request(format!(
+            "Gap {} leaves no room for the grid cells at width {}",
+            req.gap(),
+            req.canvas_width()
+        ));
+    }
+
+    let encoded = tokio::task::spawn_blocking(move || -> Option<Vec<u8>> {
+        let images: Vec<image::DynamicImage> = files.iter().filter_map(|f| image::open(f).ok()).collect();
+        if images.is_empty() {
+            return None;
+        }
+        let canvas = compose_collage(&images, &req, background);
+        let mut buf = std::io::Cursor::new(Vec::new());
+        let format = if png { image::ImageOutputFormat::Png } else { image::ImageOutputFormat::Jpeg(90) };
+        canvas.write_to(&mut buf, format).ok()?;
+        Some(buf.into_inner())
+    })
+    .await
+    .ok()
+    .flatten();
+
+    match encoded {
+        Some(bytes) => (
+            [
+                (header::CONTENT_TYPE, if png { "image/png" } else { "image/jpeg" }),
+                (header::CACHE_CONTROL, "no-store"),
+            ],
+            bytes,
+        )
+            .into_response(),
+        None => (
+            StatusCode::UNPROCESSABLE_ENTITY,
+            Json(serde_json::json!({ "detail": "None of the images could be read" })),
+        )
+            .into_response(),
+    }
+}
+
+// --- PDF 索引页 (contact sheet) ---
+
+#[derive(Deserialize)]
+struct ContactSheetRequest {
+    folder: Option<String>,
+    playlist: Option<PlaylistRequest>,
+    /// 页眉标题，默认为文件夹名
+    title: Option<String>,
+    /// 每行缩略图数，默认 4
+    columns: Option<u32>,
+    /// 每页行数，默认 5
+    rows: Option<u32>,
+    /// a4 (默认) 或 letter
+    page_size: Option<String>,
+    #[serde(default)]
+    landscape: bool,
+    /// 文件名下方显示拍摄日期 (无 EXIF 时为修改日期)，默认开启
+    #[serde(default = "default_true")]
+    show_dates: bool,
+}
+
+const CONTACT_SHEET_MAX_IMAGES: usize = 2000;
+const CONTACT_SHEET_MARGIN: f32 = 36.0;
+const CONTACT_SHEET_GAP: f32 = 10.0;
+/// 缩略图按约 200 DPI 嵌入，打印时足够清晰
+const CONTACT_SHEET_DPI: f32 = 200.0;
+
+struct ContactSheetEntry {
+    name: String,
+    date: Option<String>,
+    full_path: PathBuf,
+}
+
+/// 可嵌入 PDF 的 TrueType 字体：`GALLERY_PDF_FONT`，未设置时沿用 `GALLERY_WATERMARK_FONT`
+/// (中文等非拉丁文件名需要；缺省使用内置 Helvetica，无法显示的字符替换为 `?`)
+fn pdf_font() -> Option<&'static (Vec<u8>, ab_glyph::FontVec)> {
+    static FONT: std::sync::OnceLock<Option<(Vec<u8>, ab_glyph::FontVec)>> = std::sync::OnceLock::new();
+    FONT.get_or_init(|| {
+        let path = ["GALLERY_PDF_FONT", "GALLERY_WATERMARK_FONT"]
+            .iter()
+            .find_map(|key| config_var(key).ok().filter(|v| !v.trim().is_empty()))?;
+        match std::fs::read(&path).map_err(|e| e.to_string()).and_then(|data| {
+            let font = ab_glyph::FontVec::try_from_vec(data.clone()).map_err(|e| e.to_string())?;
+            Ok((data, font))
+        }) {
+            Ok(font) => Some(font),
+            Err(err) => {
+                tracing::error!("⚠️ Failed to load PDF font {}: {}", path, err);
+                None
+            }
+        }
+    })
+    .as_ref()
+}
+
+/// 索引页的文字编码：嵌入字体时按字形 ID (Identity-H) 编码并记录用到的字形，否则使用 WinAnsi
+struct PdfText {
+    font: Option<&'static (Vec<u8>, ab_glyph::FontVec)>,
+    used_glyphs: std::collections::BTreeMap<u16, char>,
+}
+
+impl PdfText {
+    fn glyph_width(&self, c: char) -> f32 {
+        use ab_glyph::Font;
+        match self.font {
+            Some((_, font)) => {
+                let upem = font.units_per_em().unwrap_or(1000.0);
+                font.h_advance_unscaled(font.glyph_id(c)) / upem * 1000.0
+            }
+            // Helvetica 平均字宽
+            None => 556.0,
+        }
+    }
+
+    fn width(&self, text: &str, size: f32) -> f32 {
+        text.chars().map(|c| self.glyph_width(c)).sum::<f32>() * size / 1000.0
+    }
+
+    /// 超出宽度时截断并加省略号
+    fn fit(&self, text: &str, size: f32, max_width: f32) -> String {
+        if self.width(text, size) <= max_width {
+            return text.to_string();
+        }
+        let budget = max_width - self.width("…", size);
+        let mut used = 0.0;
+        let mut fitted: String = text
+            .chars()
+            .take_while(|c| {
+                used += self.glyph_width(*c) * size / 1000.0;
+                used <= budget
+            })
+            .collect();
+        fitted.push('…');
+        fitted
+    }
+
+    fn encode(&mut self, text: &str) -> Vec<u8> {
+        use ab_glyph::Font;
+        match self.font {
+            Some((_, font)) => text
+                .chars()
+                .flat_map(|c| {
+                    let id = font.glyph_id(c).0;
+                    self.used_glyphs.entry(id).or_insert(c);
+                    id.to_be_bytes()
+                })
+                .collect(),
+            None => text
+                .chars()
+                .map(|c| match c as u32 {
+                    0x20..=0x7E | 0xA0..=0xFF => c as u8,
+                    0x2026 => 0x85,
+                    _ => b'?',
+                })
+                .collect(),
+        }
+    }
+
+    /// 写入字体对象 (内置字体或 Type0 + CIDFontType2 + 字体文件 + ToUnicode)
+    fn write_font(&self, pdf: &mut pdf_writer::Pdf, next_ref: &mut impl FnMut() -> pdf_writer::Ref) -> pdf_writer::Ref {
+        use ab_glyph::Font;
+        use pdf_writer::{types::{CidFontType, FontFlags, SystemInfo, UnicodeCmap}, Filter, Finish, Name, Rect, Str};
+
+        let font_id = next_ref();
+        let Some((data, font)) = self.font else {
+            pdf.type1_font(font_id)
+                .base_font(Name(b"Helvetica"))
+                .encoding_predefined(Name(b"WinAnsiEncoding"));
+            return font_id;
+        };
+        let (cid_id, descriptor_id, file_id, cmap_id) = (next_ref(), next_ref(), next_ref(), next_ref());
+        let system_info = SystemInfo { registry: Str(b"Adobe"), ordering: Str(b"Identity"), supplement: 0 };
+        let upem = font.units_per_em().unwrap_or(1000.0);
+        let scale = |v: f32| v / upem * 1000.0;
+
+        pdf.type0_font(font_id)
+            .base_font(Name(b"GalleryFont"))
+            .encoding_predefined(Name(b"Identity-H"))
+            .descendant_font(cid_id)
+            .to_unicode(cmap_id);
+
+        let mut cid = pdf.cid_font(cid_id);
+        cid.subtype(CidFontType::Type2)
+            .base_font(Name(b"GalleryFont"))
+            .system_info(system_info)
+            .font_descriptor(descriptor_id)
+            .default_width(1000.0)
+            .cid_to_gid_map_predefined(Name(b"Identity"));
+        let mut widths = cid.widths();
+        for id in self.used_glyphs.keys() {
+            widths.consecutive(*id, [scale(font.h_advance_unscaled(ab_glyph::GlyphId(*id)))]);
+        }
+        widths.finish();
+        cid.finish();
+
+        let (ascent, descent) = (scale(font.ascent_unscaled()), scale(font.descent_unscaled()));
+        pdf.font_descriptor(descriptor_id)
+            .name(Name(b"GalleryFont"))
+            .flags(FontFlags::NON_SYMBOLIC)
+            .bbox(Rect::new(0.0, descent, 1000.0, ascent))
+            .italic_angle(0.0)
+            .ascent(ascent)
+            .descent(descent)
+            .cap_height(ascent * 0.7)
+            .stem_v(80.0)
+            .font_file2(file_id);
+
+        let compressed = {
+            use std::io::Write;
+            let mut encoder = flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+            let _ = encoder.write_all(data);
+            encoder.finish().unwrap_or_default()
+        };
+        pdf.stream(file_id, &compressed)
+            .filter(Filter::FlateDecode)
+            .pair(Name(b"Length1"), data.len() as i32);
+
+        let mut cmap = UnicodeCmap::new(Name(b"Gallery-UTF16"), system_info);
+        for (id, c) in &self.used_glyphs {
+            cmap.pair(*id, *c);
+        }
+        pdf.cmap(cmap_id, &cmap.finish());
+        font_id
+    }
+}
+
+/// 解码并缩放缩略图，返回 JPEG 字节与像素尺寸
+fn contact_sheet_thumbnail(full_path: &Path, max_w: u32, max_h: u32) -> Option<(Vec<u8>, u32, u32)> {
+    let img = image::open(full_path).ok()?;
+    let thumb = img.thumbnail(max_w, max_h).to_rgb8();
+    let mut buf = std::io::Cursor::new(Vec::new());
+    thumb.write_to(&mut buf, image::ImageOutputFormat::Jpeg(85)).ok()?;
+    Some((buf.into_inner(), thumb.width(), thumb.height()))
+}
+
+/// 排版整个文档 (阻塞调用)
+fn render_contact_sheet(
+    entries: &[ContactSheetEntry],
+    title: &str,
+    (page_w, page_h): (f32, f32),
+    columns: u32,
+    rows: u32,
+    show_dates: bool,
+) -> Vec<u8> {
+    use pdf_writer::{Content, Filter, Finish, Name, Pdf, Rect, Ref, Str, TextStr};
+
+    let mut pdf = Pdf::new();
+    let mut next = 0;
+    let mut next_ref = || {
+        next += 1;
+        Ref::new(next)
+    };
+    let catalog_id = next_ref();
+    let tree_id = next_ref();
+    let info_id = next_ref();
+    let mut text = PdfText { font: pdf_font(), used_glyphs: Default::default() };
+    let font_name = Name(b"F1");
+
+    const TITLE_SIZE: f32 = 14.0;
+    const CAPTION_SIZE: f32 = 7.0;
+    const LINE: f32 = CAPTION_SIZE * 1.3;
+    let header_h = TITLE_SIZE + 12.0;
+    let footer_h = 16.0;
+    let caption_h = if show_dates { LINE * 2.0 + 4.0 } else { LINE + 4.0 };
+    let grid_w = page_w - 2.0 * CONTACT_SHEET_MARGIN;
+    let grid_h = page_h - 2.0 * CONTACT_SHEET_MARGIN - header_h - footer_h;
+    let cell_w = (grid_w - (columns - 1) as f32 * CONTACT_SHEET_GAP) / columns as f32;
+    let cell_h = (grid_h - (rows - 1) as f32 * CONTACT_SHEET_GAP) / rows as f32;
+    let box_h = (cell_h - caption_h).max(1.0);
+    let px = |pt: f32| ((pt * CONTACT_SHEET_DPI / 72.0) as u32).clamp(16, 1024);
+
+    let per_page = (columns * rows) as usize;
+    let page_count = entries.len().div_ceil(per_page).max(1);
+    let mut page_ids = Vec::with_capacity(page_count);
+    let mut pending_pages = Vec::with_capacity(page_count);
+
+    for page_index in 0..page_count {
+        let page_id = next_ref();
+        let content_id = next_ref();
+        page_ids.push(page_id);
+        let mut content = Content::new();
+        let mut images = Vec::new();
+
+        let top = page_h - CONTACT_SHEET_MARGIN;
+        let heading = text.fit(title, TITLE_SIZE, grid_w);
+        content.begin_text();
+        content.set_font(font_name, TITLE_SIZE);
+        content.next_line(CONTACT_SHEET_MARGIN, top - TITLE_SIZE);
+        content.show(Str(&text.encode(&heading)));
+        content.end_text();
+
+        let footer = format!("{} / {}", page_index + 1, page_count);
+        content.begin_text();
+        content.set_font(font_name, 8.0);
+        content.next_line(page_w - CONTACT_SHEET_MARGIN - text.width(&footer, 8.0), CONTACT_SHEET_MARGIN);
+        content.show(Str(&text.encode(&footer)));
+        content.end_text();
+
+        let grid_top = top - header_h;
+        for (slot, entry) in entries.iter().skip(page_index * per_page).take(per_page).enumerate() {
+            let (col, row) = ((slot as u32 % columns) as f32, (slot as u32 / columns) as f32);
+            let x = CONTACT_SHEET_MARGIN + col * (cell_w + CONTACT_SHEET_GAP);
+            let cell_top = grid_top - row * (cell_h + CONTACT_SHEET_GAP);
+            let box_bottom = cell_top - box_h;
+
+            match contact_sheet_thumbnail(&entry.full_path, px(cell_w), px(box_h)) {
+                Some((jpeg, w, h)) => {
+                    let image_id = next_ref();
+                    let name = format!("Im{}", slot);
+                    let scale = (cell_w / w as f32).min(box_h / h as f32);
+                    let (draw_w, draw_h) = (w as f32 * scale, h as f32 * scale);
+                    content.save_state();
+                    content.transform([
+                        draw_w,
+                        0.0,
+                        0.0,
+                        draw_h,
+                        x + (cell_w - draw_w) / 2.0,
+                        box_bottom + (box_h - draw_h) / 2.0,
+                    ]);
+                    content.x_object(Name(name.as_bytes()));
+                    content.restore_state();
+                    images.push((name, image_id, jpeg, w, h));
+                }
+                None => {
+                    content.save_state();
+                    content.set_stroke_rgb(0.75, 0.75, 0.75);
+                    content.set_line_width(0.5);
+                    content.rect(x, box_bottom, cell_w, box_h);
+                    content.stroke();
+                    content.restore_state();
+                }
+            }
+
+            let mut lines = vec![entry.name.as_str()];
+            if show_dates {
+                lines.extend(entry.date.as_deref());
+            }
+            for (i, line) in lines.into_iter().enumerate() {
+                let fitted = text.fit(line, CAPTION_SIZE, cell_w);
+                content.begin_text();
+                content.set_font(font_name, CAPTION_SIZE);
+                content.next_line(x, box_bottom - 2.0 - LINE * (i + 1) as f32 + 1.5);
+                content.show(Str(&text.encode(&fitted)));
+                content.end_text();
+            }
+        }
+        pending_pages.push((page_id, content_id, content.finish(), images));
+    }
+
+    let font_id = text.write_font(&mut pdf, &mut next_ref);
+    for (page_id, content_id, content, images) in &pending_pages {
+        let mut page = pdf.page(*page_id);
+        page.media_box(Rect::new(0.0, 0.0, page_w, page_h));
+        page.parent(tree_id);
+        page.contents(*content_id);
+        let mut resources = page.resources();
+        resources.fonts().pair(font_name, font_id);
+        let mut x_objects = resources.x_objects();
+        for (name, image_id, _, _, _) in images {
+            x_objects.pair(Name(name.as_bytes()), *image_id);
+        }
+        x_objects.finish();
+        resources.finish();
+        page.finish();
+        pdf.stream(*content_id, content);
+        for (_, image_id, jpeg, w, h) in images {
+            let mut image = pdf.image_xobject(*image_id, jpeg);
+            image.filter(Filter::DctDecode);
+            image.width(*w as i32);
+            image.height(*h as i32);
+            image.color_space().device_rgb();
+            image.bits_per_component(8);
+        }
+    }
+
+    pdf.catalog(catalog_id).pages(tree_id);
+    pdf.pages(tree_id).kids(page_ids.iter().copied()).count(page_count as i32);
+    pdf.document_info(info_id)
+        .title(TextStr(title))
+        .producer(TextStr("Gravity Gallery"));
+    pdf.finish()
+}
+
+/// 接口: POST /api/contact-sheet，把文件夹 (含子文件夹) 或播放列表排版为可打印的 PDF 索引页
+async fn contact_sheet(
+    State(state): State<AppState>,
+    connect_info: ConnectInfo<SocketAddr>,
+    Json(mut req): Json<ContactSheetRequest>,
+) -> Response {
+    let error = |status: StatusCode, detail: String| (status, Json(serde_json::json!({ "detail": detail }))).into_response();
+    let columns = req.columns.unwrap_or(4);
+    let rows = req.rows.unwrap_or(5);
+    if !(1..=10).contains(&columns) || !(1..=12).contains(&rows) {
+        return error(StatusCode::BAD_REQUEST, "columns must be 1-10 and rows 1-12".to_string());
+    }
+    let (mut page_w, mut page_h) = match req.page_size.as_deref().map(str::to_ascii_lowercase).as_deref() {
+        None | Some("a4") => (595.28, 841.89),
+        Some("letter") => (612.0, 792.0),
+        Some(other) => return error(StatusCode::BAD_REQUEST, format!("Unsupported page_size {:?}", other)),
+    };
+    if req.landscape {
+        std::mem::swap(&mut page_w, &mut page_h);
+    }
+
+    let allow_parent = *state.allow_parent_dir_access.read().await;
+    let (default_title, paths) = match (req.folder.take(), req.playlist.take()) {
+        (Some(folder), None) => {
+            let rel = normalize_rel_path(&folder);
+            match resolve_and_authorize(&state.root_dir, &rel, allow_parent) {
+                Ok(full) if full.is_dir() => {}
+                Err(PathAccessError::Forbidden) => {
+                    return error(StatusCode::FORBIDDEN, "Access outside ROOT_DIR is disabled".to_string())
+                }
+                _ => return error(StatusCode::NOT_FOUND, "Folder not found".to_string()),
+            }
+            prepare_request_paths(&state, std::slice::from_ref(&rel)).await;
+            let title = rel.rsplit('/').next().filter(|n| !n.is_empty() && *n != ".").unwrap_or("Gallery").to_string();
+            (title, folder_share_paths(&state, &rel).await)
+        }
+        (None, Some(mut playlist)) => {
+            if playlist.collation.is_none() {
+                playlist.collation = state.default_collation.clone();
+            }
+            playlist.current_path = None;
+            let valid_req_paths = prepare_request_paths(&state, &playlist.paths).await;
+            let ip = connect_info.0.ip().to_string();
+            ("Playlist".to_string(), generate_playlist(&state, &playlist, &valid_req_paths, &ip).await)
+        }
+        _ => return error(StatusCode::BAD_REQUEST, "Exactly one of folder or playlist is required".to_string()),
+    };
+    if paths.is_empty() {
+        return error(StatusCode::BAD_REQUEST, "No images to include".to_string());
+    }
+    if paths.len() > CONTACT_SHEET_MAX_IMAGES {
+        return error(
+            StatusCode::BAD_REQUEST,
+            format!("Contact sheets are limited to {} images ({} requested)", CONTACT_SHEET_MAX_IMAGES, paths.len()),
+        );
+    }
+
+    let mut entries = Vec::with_capacity(paths.len());
+    for path in paths {
+        let Ok(full_path) = resolve_and_authorize(&state.root_dir, &path, allow_parent) else {
+            continue;
+        };
+        let date = if req.show_dates {
+            let row: Option<(Option<f64>, f64)> = sqlx::query_as("SELECT taken_at, mtime FROM images WHERE path = ?")
+                .bind(&path)
+                .fetch_optional(&state.db)
+                .await
+                .ok()
+                .flatten();
+            row.and_then(|(taken_at, mtime)| chrono::DateTime::from_timestamp(taken_at.unwrap_or(mtime) as i64, 0))
+                .map(|t| t.format("%Y-%m-%d").to_string())
+        } else {
+            None
+        };
+        let name = path.rsplit('/').next().unwrap_or(&path).to_string();
+        entries.push(ContactSheetEntry { name, date, full_path });
+    }
+
+    let title = req.title.filter(|t| !t.trim().is_empty()).unwrap_or(default_title);
+    let file_name = format!("{}.pdf", title.replace(['/', '\\', '"'], "_"));
+    let show_dates = req.show_dates;
+    let started = Instant::now();
+    let Ok(bytes) = tokio::task::spawn_blocking(move || {
+        render_contact_sheet(&entries, &title, (page_w, page_h), columns, rows, show_dates)
+    })
+    .await
+    else {
+        return error(StatusCode::INTERNAL_SERVER_ERROR, "Failed to render contact sheet".to_string());
+    };
+    tracing::info!("🗂️ Contact sheet rendered in {:?} ({} KB)", started.elapsed(), bytes.len() / 1024);
+
+    (
+        [
+            (header::CONTENT_TYPE, "application/pdf".to_string()),
+            (header::CACHE_CONTROL, "no-store".to_string()),
+            (
+                header::CONTENT_DISPOSITION,
+                format!("attachment; filename*=UTF-8''{}", urlencoding::encode(&file_name)),
+            ),
+        ],
+        bytes,
+    )
+        .into_response()
+}
+
+// --- 公开分享链接 ---
+
+/// 分享对象：单张图片、文件夹 (含子文件夹，访问时实时列出) 或创建时快照的播放列表
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum ShareKind {
+    Image,
+    Folder,
+    Playlist,
+}
+
+impl ShareKind {
+    fn as_str(self) -> &'static str {
+        match self {
+            ShareKind::Image => "image",
+            ShareKind::Folder => "folder",
+            ShareKind::Playlist => "playlist",
+        }
+    }
+
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "image" => Some(ShareKind::Image),
+            "folder" => Some(ShareKind::Folder),
+            "playlist" => Some(ShareKind::Playlist),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct CreateShareRequest {
+    /// 单张图片
+    path: Option<String>,
+    folder: Option<String>,
+    /// 播放列表条件，创建时生成并固定顺序
+    playlist: Option<PlaylistRequest>,
+    title: Option<String>,
+    /// 有效期 (秒)，为空表示永不过期
+    expires_in_secs: Option<u64>,
+    /// 原图下载次数上限 (缩略图不计)
+    max_downloads: Option<i64>,
+    /// 对分享出去的图片叠加水印 (见 `/api/watermarks`)
+    watermark: Option<String>,
+    /// 允许访客对图片留言 / 表情回应
+    #[serde(default)]
+    allow_comments: bool,
+    /// 设置后访客需先输入密码 (服务端只保存 Argon2 哈希)
+    password: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct ShareCreatedResponse {
+    token: String,
+    url: String,
+    kind: ShareKind,
+    count: usize,
+    expires_at: Option<f64>,
+    max_downloads: Option<i64>,
+    allow_comments: bool,
+    password_protected: bool,
+}
+
+#[derive(Debug, sqlx::FromRow)]
+struct ShareRecord {
+    token: String,
+    kind: String,
+    title: Option<String>,
+    target: Option<String>,
+    paths_json: Option<String>,
+    watermark: Option<String>,
+    expires_at: Option<f64>,
+    max_downloads: Option<i64>,
+    downloads: i64,
+    allow_comments: bool,
+    password_hash: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct ShareItem {
+    name: String,
+    url: String,
+    thumbnail_url: String,
+}
+
+#[derive(Debug, Serialize)]
+struct ShareManifest {
+    title: Option<String>,
+    kind: ShareKind,
+    expires_at: Option<f64>,
+    downloads_remaining: Option<i64>,
+    allow_comments: bool,
+    items: Vec<ShareItem>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ShareFileQuery {
+    /// 设置后返回不超过该宽高的缩略图 (不计入下载次数)
+    width: Option<u32>,
+    key: Option<String>,
+}
+
+/// 访问密码保护的分享：`key` 为解锁后下发的访问密钥 (浏览器通过 Cookie 携带)
+#[derive(Debug, Deserialize)]
+struct ShareAccessQuery {
+    key: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct UnlockShareRequest {
+    password: String,
+}
+
+/// 分享者视角的分享概况
+#[derive(Debug, Serialize, sqlx::FromRow)]
+struct ShareSummary {
+    token: String,
+    kind: String,
+    title: Option<String>,
+    target: Option<String>,
+    created_at: f64,
+    expires_at: Option<f64>,
+    max_downloads: Option<i64>,
+    downloads: i64,
+    views: i64,
+    unique_visitors: i64,
+    last_viewed_at: Option<f64>,
+    comments: i64,
+    allow_comments: bool,
+    password_protected: bool,
+    revoked_at: Option<f64>,
+}
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+struct ShareVisitor {
+    client_ip: String,
+    views: i64,
+    first_seen: f64,
+    last_seen: f64,
+}
+
+const SHARE_SUMMARY_SQL: &str = "SELECT s.token, s.kind, s.title, s.target, s.created_at, s.expires_at, s.max_downloads,
+        s.downloads, s.views, s.last_viewed_at, s.allow_comments, s.revoked_at,
+        s.password_hash IS NOT NULL AS password_protected,
+        (SELECT COUNT(*) FROM share_visitors v WHERE v.token = s.token) AS unique_visitors,
+        (SELECT COUNT(*) FROM share_comments c WHERE c.token = s.token) AS comments
+    FROM shares s";
+
+const SHARE_THUMBNAIL_MAX: u32 = 1024;
+
+fn random_token(len: usize) -> String {
+    use rand::{distributions::Alphanumeric, Rng};
+    rand::thread_rng().sample_iter(&Alphanumeric).take(len).map(char::from).collect()
+}
+
+/// 后台通知 (新图片推送、每周摘要) 附带的播放列表分享：`ttl_secs` 后过期，返回公开链接。
+/// 没有对外地址 (`GALLERY_PUBLIC_URL`) 时不创建分享，返回 `Ok(None)`
+#[cfg(any(feature = "smtp", feature = "notify"))]
+async fn create_playlist_share_link(
+    state: &AppState,
+    title: &str,
+    paths: &[String],
+    ttl_secs: f64,
+) -> anyhow::Result<Option<String>> {
+    let Some(base) = public_base_url(&axum::http::HeaderMap::new()) else {
+        return Ok(None);
+    };
+    let token = random_token(24);
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs_f64();
+    sqlx::query("INSERT INTO shares (token, kind, title, paths_json, expires_at, created_at) VALUES (?, 'playlist', ?, ?, ?, ?)")
+        .bind(&token)
+        .bind(title)
+        .bind(serde_json::to_string(paths)?)
+        .bind(now + ttl_secs)
+        .bind(now)
+        .execute(&state.db)
+        .await?;
+    Ok(Some(format!("{}{}/share/{}", base, state.base_path, token)))
+}
+
+fn escape_html(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+/// 接口: POST /api/share，为图片、文件夹或播放列表创建免认证的公开链接
+async fn create_share(
+    State(state): State<AppState>,
+    connect_info: ConnectInfo<SocketAddr>,
+    headers: axum::http::HeaderMap,
+    Json(mut req): Json<CreateShareRequest>,
+) -> Result<Json<ShareCreatedResponse>, (StatusCode, Json<serde_json::Value>)> {
+    let error = |status: StatusCode, detail: &str| (status, Json(serde_json::json!({ "detail": detail })));
+    let allow_parent = *state.allow_parent_dir_access.read().await;
+
+    let (kind, target, paths) = match (req.path.take(), req.folder.take(), req.playlist.take()) {
+        (Some(path), None, None) => {
+            let rel = normalize_rel_path(&path);
+            match resolve_and_authorize(&state.root_dir, &rel, allow_parent) {
+                Ok(full) if full.is_file() && is_image_ext(&full) => (ShareKind::Image, Some(rel.clone()), vec![rel]),
+                Err(PathAccessError::Forbidden) => {
+                    return Err(error(StatusCode::FORBIDDEN, "Access outside ROOT_DIR is disabled"))
+                }
+                _ => return Err(error(StatusCode::NOT_FOUND, "Image not found")),
+            }
+        }
+        (None, Some(folder), None) => {
+            let rel = normalize_rel_path(&folder);
+            match resolve_and_authorize(&state.root_dir, &rel, allow_parent) {
+                Ok(full) if full.is_dir() => {}
+                Err(PathAccessError::Forbidden) => {
+                    return Err(error(StatusCode::FORBIDDEN, "Access outside ROOT_DIR is disabled"))
+                }
+                _ => return Err(error(StatusCode::NOT_FOUND, "Folder not found")),
+            }
+            prepare_request_paths(&state, std::slice::from_ref(&rel)).await;
+            let paths = folder_share_paths(&state, &rel).await;
+            (ShareKind::Folder, Some(rel), paths)
+        }
+        (None, None, Some(mut playlist)) => {
+            if playlist.collation.is_none() {
+                playlist.collation = state.default_collation.clone();
+            }
+            playlist.current_path = None;
+            let valid_req_paths = prepare_request_paths(&state, &playlist.paths).await;
+            let ip = connect_info.0.ip().to_string();
+            let paths = generate_playlist(&state, &playlist, &valid_req_paths, &ip).await;
+            (ShareKind::Playlist, None, paths)
+        }
+        _ => return Err(error(StatusCode::BAD_REQUEST, "Exactly one of path, folder or playlist is required")),
+    };
+    if paths.is_empty() {
+        return Err(error(StatusCode::BAD_REQUEST, "Nothing to share"));
+    }
+    if req.max_downloads.is_some_and(|m| m < 1) {
+        return Err(error(StatusCode::BAD_REQUEST, "max_downloads must be at least 1"));
+    }
+    if let Some(name) = &req.watermark {
+        load_watermark(&state, name).await.map_err(|(status, detail)| error(status, &detail))?;
+    }
+    let password_hash = match req.password.as_deref().filter(|p| !p.is_empty()) {
+        Some(password) => {
+            let password = password.to_string();
+            let hashed = tokio::task::spawn_blocking(move || hash_share_password(&password)).await.ok().flatten();
+            Some(hashed.ok_or_else(|| error(StatusCode::INTERNAL_SERVER_ERROR, "Failed to hash password"))?)
+        }
+        None => None,
+    };
+
+    let token = random_token(24);
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs_f64();
+    let expires_at = req.expires_in_secs.map(|secs| now + secs as f64);
+    // 文件夹分享同样保存创建时的列表快照：序号不会因新增文件而错位，查看时也无需重新查询整个文件夹
+    let paths_json = (kind != ShareKind::Image).then(|| serde_json::to_string(&paths).unwrap_or_default());
+    sqlx::query(
+        "INSERT INTO shares
+            (token, kind, title, target, paths_json, watermark, expires_at, max_downloads, allow_comments, password_hash, created_at)
+         VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+    )
+    .bind(&token)
+    .bind(kind.as_str())
+    .bind(req.title.as_deref().map(str::trim).filter(|t| !t.is_empty()))
+    .bind(&target)
+    .bind(&paths_json)
+    .bind(req.watermark.as_deref().map(str::trim))
+    .bind(expires_at)
+    .bind(req.max_downloads)
+    .bind(req.allow_comments)
+    .bind(&password_hash)
+    .bind(now)
+    .execute(&state.db)
+    .await
+    .map_err(|e| error(StatusCode::INTERNAL_SERVER_ERROR, &format!("Failed to create share: {}", e)))?;
+
+    tracing::info!("🔗 Share created: {} ({} items)", kind.as_str(), paths.len());
+    let relative = format!("{}/share/{}", state.base_path, token);
+    Ok(Json(ShareCreatedResponse {
+        url: public_base_url(&headers).map_or_else(|| relative.clone(), |base| format!("{}{}", base, relative)),
+        token,
+        kind,
+        count: paths.len(),
+        expires_at,
+        max_downloads: req.max_downloads,
+        allow_comments: req.allow_comments,
+        password_protected: password_hash.is_some(),
+    }))
+}
+
+/// 文件夹分享的图片 (含子文件夹，自然排序)。
+/// 前缀按大小写精确匹配 (`LIKE` 对 ASCII 不区分大小写，分享 `Trip` 会连带暴露 `trip/…`)
+async fn folder_share_paths(state: &AppState, folder: &str) -> Vec<String> {
+    let rows: Vec<(String,)> = if folder.is_empty() || folder == "." {
+        sqlx::query_as(&format!(
+            "SELECT path FROM images WHERE {} AND {} ORDER BY path COLLATE {}",
+            PRESENT_SQL_FILTER, INTERNAL_PATH_SQL_FILTER, NATURAL_COLLATION
+        ))
+        .fetch_all(&state.db)
+        .await
+    } else {
+        sqlx::query_as(&format!(
+            "SELECT path FROM images WHERE substr(path, 1, ?) = ? AND {} ORDER BY path COLLATE {}",
+            PRESENT_SQL_FILTER, NATURAL_COLLATION
+        ))
+        .bind(folder.chars().count() as i64 + 1)
+        .bind(format!("{}/", folder))
+        .fetch_all(&state.db)
+        .await
+    }
+    .unwrap_or_default();
+    rows.into_iter().map(|(path,)| path).collect()
+}
+
+/// 读取仍然有效的分享 (不存在、已过期或下载次数用尽时返回 410)
+async fn load_active_share(state: &AppState, token: &str) -> Result<(ShareRecord, ShareKind), Response> {
+    let gone = || (StatusCode::GONE, Json(serde_json::json!({ "detail": "Share link is invalid or expired" }))).into_response();
+    let record: ShareRecord = sqlx::query_as(
+        "SELECT token, kind, title, target, paths_json, watermark, expires_at, max_downloads, downloads, allow_comments,
+                password_hash
+         FROM shares WHERE token = ? AND revoked_at IS NULL",
+    )
+    .bind(token)
+    .fetch_optional(&state.db)
+    .await
+    .ok()
+    .flatten()
+    .ok_or_else(gone)?;
+
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs_f64();
+    if record.expires_at.is_some_and(|at| at <= now) || record.max_downloads.is_some_and(|max| record.downloads >= max) {
+        return Err(gone());
+    }
+    let kind = ShareKind::parse(&record.kind).ok_or_else(gone)?;
+    Ok((record, kind))
+}
+
+fn hash_share_password(password: &str) -> Option<String> {
+    use argon2::password_hash::{rand_core::OsRng, PasswordHasher, SaltString};
+    let salt = SaltString::generate(&mut OsRng);
+    Some(argon2::Argon2::default().hash_password(password.as_bytes(), &salt).ok()?.to_string())
+}
+
+fn verify_share_password(password: &str, hash: &str) -> bool {
+    use argon2::password_hash::{PasswordHash, PasswordVerifier};
+    PasswordHash::new(hash)
+        .is_ok_and(|parsed| argon2::Argon2::default().verify_password(password.as_bytes(), &parsed).is_ok())
+}
+
+/// 解锁后的访问密钥：由令牌与密码哈希 (含随机盐) 派生，修改密码或撤销后自然失效
+fn share_access_key(record: &ShareRecord) -> Option<blake3::Hash> {
+    let hash = record.password_hash.as_deref()?;
+    Some(blake3::hash(format!("{}:{}", record.token, hash).as_bytes()))
+}
+
+fn share_cookie_name(token: &str) -> String {
+    format!("share_{}", token)
+}
+
+/// 未设置密码，或请求通过 `?key=` / Cookie 携带了正确的访问密钥
+fn share_unlocked(record: &ShareRecord, headers: &axum::http::HeaderMap, key: Option<&str>) -> bool {
+    let Some(expected) = share_access_key(record) else {
+        return true;
+    };
+    let cookie_name = share_cookie_name(&record.token);
+    let from_cookie = headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(name, _)| *name == cookie_name)
+        .map(|(_, value)| value);
+    // blake3::Hash 的比较是常量时间的
+    key.or(from_cookie)
+        .and_then(|candidate| blake3::Hash::from_hex(candidate.trim()).ok())
+        .is_some_and(|candidate| candidate == expected)
+}
+
+fn share_locked_response() -> Response {
+    (
+        StatusCode::UNAUTHORIZED,
+        Json(serde_json::json!({ "detail": "This share is password protected", "password_required": true })),
+    )
+        .into_response()
+}
+
+/// 读取有效分享并校验访问密钥
+async fn load_unlocked_share(
+    state: &AppState,
+    token: &str,
+    headers: &axum::http::HeaderMap,
+    key: Option<&str>,
+) -> Result<(ShareRecord, ShareKind), Response> {
+    let (record, kind) = load_active_share(state, token).await?;
+    if !share_unlocked(&record, headers, key) {
+        return Err(share_locked_response());
+    }
+    Ok((record, kind))
+}
+
+/// 分享页面自带 CSP：需要提交密码表单并让图片请求带上 Cookie，因此不能使用全局的 sandbox 策略
+const SHARE_PAGE_CSP: &str = "default-src 'none'; img-src 'self'; style-src 'unsafe-inline'; form-action 'self'";
+
+fn share_html_response(status: StatusCode, html: String) -> Response {
+    (
+        status,
+        [
+            (header::CONTENT_TYPE, "text/html; charset=utf-8"),
+            (header::CACHE_CONTROL, "no-store"),
+            (header::CONTENT_SECURITY_POLICY, SHARE_PAGE_CSP),
+        ],
+        html,
+    )
+        .into_response()
+}
+
+fn share_password_page(base_path: &str, token: &str, failed: bool) -> Response {
+    let message = if failed { "<p>Wrong password, please try again.</p>" } else { "" };
+    let html = format!(
+        "<!DOCTYPE html><html><head><meta charset=\"utf-8\"><meta name=\"viewport\" content=\"width=device-width, initial-scale=1\">\
+         <title>Protected share</title><style>body{{margin:0;padding:16px;font-family:sans-serif;background:#111;color:#eee}}</style></head>\
+         <body><h1>This share is password protected</h1>{message}\
+         <form method=\"post\" action=\"{base_path}/share/{token}/unlock\"><input type=\"password\" name=\"password\" autofocus>\
+         <button type=\"submit\">Open</button></form></body></html>",
+        base_path = escape_html(base_path),
+        token = escape_html(token),
+    );
+    share_html_response(StatusCode::UNAUTHORIZED, html)
+}
+
+/// 允许连续输错的次数，之后每次失败的等待时间翻倍 (1s, 2s, 4s … 最长 15 分钟)
+const UNLOCK_FREE_ATTEMPTS: u32 = 5;
+const UNLOCK_MAX_BACKOFF: Duration = Duration::from_secs(15 * 60);
+/// 超过这么久没有再输错则清零；记录的 IP 数超过上限时先清理过期条目
+const UNLOCK_FAILURE_TTL: Duration = Duration::from_secs(3600);
+const UNLOCK_TRACKED_MAX: usize = 4096;
+/// 同时进行的 Argon2 校验数量上限，其余请求排队等待
+const UNLOCK_MAX_CONCURRENT_VERIFIES: usize = 4;
+
+/// 分享密码尝试的失败记录：客户端 IP -> (连续失败次数, 最后一次失败时间)
+#[derive(Default)]
+struct UnlockThrottle {
+    failures: HashMap<String, (u32, Instant)>,
+}
+
+impl UnlockThrottle {
+    /// 该 IP 还需等待多久才能再试；None 表示现在可以尝试
+    fn retry_after(&self, ip: &str) -> Option<Duration> {
+        let (failures, last) = self.failures.get(ip)?;
+        let extra = failures.checked_sub(UNLOCK_FREE_ATTEMPTS)?;
+        let backoff = Duration::from_secs(1 << extra.min(10)).min(UNLOCK_MAX_BACKOFF);
+        backoff.checked_sub(last.elapsed()).filter(|wait| !wait.is_zero())
+    }
+
+    /// 开始一次尝试：仍在退避期内时返回需要等待的时间；否则先按失败记下 (密码正确时再清除)，
+    /// 这样同时发出的多个请求在校验完成前就已计入次数
+    fn begin_attempt(&mut self, ip: &str) -> Result<(), Duration> {
+        if let Some(wait) = self.retry_after(ip) {
+            return Err(wait);
+        }
+        self.record_failure(ip);
+        Ok(())
+    }
+
+    fn record_failure(&mut self, ip: &str) {
+        if self.failures.len() >= UNLOCK_TRACKED_MAX {
+            self.failures.retain(|_, (_, last)| last.elapsed() < UNLOCK_FAILURE_TTL);
+        }
+        let entry = self.failures.entry(ip.to_string()).or_insert((0, Instant::now()));
+        if entry.1.elapsed() >= UNLOCK_FAILURE_TTL {
+            entry.0 = 0;
+        }
+        entry.0 += 1;
+        entry.1 = Instant::now();
+    }
+
+    fn clear(&mut self, ip: &str) {
+        self.failures.remove(ip);
+    }
+}
+
+/// 接口: POST /share/{token}/unlock，校验密码并下发访问密钥
+///
+/// 支持 JSON (`{"password"}`，返回 `{"key"}`) 与浏览器表单提交 (设置 Cookie 后跳回分享页)。
+/// 同一 IP 连续输错 5 次后按指数退避，等待期间返回 429 与 `Retry-After`。
+async fn unlock_share(
+    State(state): State<AppState>,
+    axum::extract::Path(token): axum::extract::Path<String>,
+    request: Request,
+) -> Response {
+    use axum::extract::FromRequest;
+
+    let (record, _) = match load_active_share(&state, &token).await {
+        Ok(share) => share,
+        Err(response) => return response,
+    };
+    let client_ip = request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip().to_string())
+        .unwrap_or_default();
+    let is_form = request
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("application/x-www-form-urlencoded"));
+    let password = if is_form {
+        axum::Form::<UnlockShareRequest>::from_request(request, &()).await.map(|f| f.0.password).ok()
+    } else {
+        Json::<UnlockShareRequest>::from_request(request, &()).await.map(|j| j.0.password).ok()
+    };
+    let Some(password) = password else {
+        return (StatusCode::BAD_REQUEST, Json(serde_json::json!({ "detail": "password is required" }))).into_response();
+    };
+
+    let (Some(hash), Some(key)) = (record.password_hash.clone(), share_access_key(&record)) else {
+        return (StatusCode::BAD_REQUEST, Json(serde_json::json!({ "detail": "This share has no password" }))).into_response();
+    };
+    let attempt = state.share_unlock_throttle.lock().unwrap().begin_attempt(&client_ip);
+    if let Err(wait) = attempt {
+        let retry_secs = wait.as_secs_f64().ceil() as u64;
+        return (
+            StatusCode::TOO_MANY_REQUESTS,
+            [(header::RETRY_AFTER, retry_secs.to_string())],
+            Json(serde_json::json!({ "detail": format!("Too many wrong passwords; try again in {} s", retry_secs) })),
+        )
+            .into_response();
+    }
+    // 信号量从不关闭，acquire 不会失败
+    let _permit = state.share_unlock_verifies.acquire().await;
+    let valid = tokio::task::spawn_blocking(move || verify_share_password(&password, &hash))
+        .await
+        .unwrap_or(false);
+    if !valid {
+        tracing::warn!("⚠️ Wrong password for share {} from {}", record.token, client_ip);
+        return if is_form {
+            share_password_page(&state.base_path, &record.token, true)
+        } else {
+            (StatusCode::UNAUTHORIZED, Json(serde_json::json!({ "detail": "Wrong password" }))).into_response()
+        };
+    }
+
+    state.share_unlock_throttle.lock().unwrap().clear(&client_ip);
+    let key = key.to_hex().to_string();
+    let secure = if config_var("GALLERY_SSL_CERT").is_ok() { "; Secure" } else { "" };
+    let cookie = format!(
+        "{}={}; Path={}/share/{}; HttpOnly; SameSite=Lax{}",
+        share_cookie_name(&record.token),
+        key,
+        state.base_path,
+        record.token,
+        secure
+    );
+    let mut response = if is_form {
+        (StatusCode::SEE_OTHER, [(header::LOCATION, format!("{}/share/{}", state.base_path, record.token))]).into_response()
+    } else {
+        Json(serde_json::json!({ "key": key })).into_response()
+    };
+    if let Ok(value) = HeaderValue::from_str(&cookie) {
+        response.headers_mut().insert(header::SET_COOKIE, value);
+    }
+    response
+}
+
+/// 记录一次分享页访问 (总次数 + 按访客 IP 统计)
+async fn record_share_view(state: &AppState, token: &str, client_ip: &str) {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs_f64();
+    let _ = sqlx::query("UPDATE shares SET views = views + 1, last_viewed_at = ? WHERE token = ?")
+        .bind(now)
+        .bind(token)
+        .execute(&state.db)
+        .await;
+    let _ = sqlx::query(
+        "INSERT INTO share_visitors (token, client_ip, views, first_seen, last_seen) VALUES (?, ?, 1, ?, ?)
+         ON CONFLICT(token, client_ip) DO UPDATE SET views = views + 1, last_seen = excluded.last_seen",
+    )
+    .bind(token)
+    .bind(client_ip)
+    .bind(now)
+    .bind(now)
+    .execute(&state.db)
+    .await;
+}
+
+/// 接口: GET /api/shares，列出全部分享及其访问统计 (含已撤销的)
+async fn list_shares(State(state): State<AppState>) -> Json<Vec<ShareSummary>> {
+    let shares = sqlx::query_as(&format!("{} ORDER BY s.created_at DESC", SHARE_SUMMARY_SQL))
+        .fetch_all(&state.db)
+        .await
+        .unwrap_or_default();
+    Json(shares)
+}
+
+/// 接口: GET /api/share/{token}，单个分享的统计与访客明细
+async fn share_details(
+    State(state): State<AppState>,
+    axum::extract::Path(token): axum::extract::Path<String>,
+) -> Result<Json<serde_json::Value>, (StatusCode, Json<serde_json::Value>)> {
+    let summary: ShareSummary = sqlx::query_as(&format!("{} WHERE s.token = ?", SHARE_SUMMARY_SQL))
+        .bind(token.trim())
+        .fetch_optional(&state.db)
+        .await
+        .ok()
+        .flatten()
+        .ok_or((StatusCode::NOT_FOUND, Json(serde_json::json!({ "detail": "Share not found" }))))?;
+    let visitors: Vec<ShareVisitor> = sqlx::query_as(
+        "SELECT client_ip, views, first_seen, last_seen FROM share_visitors WHERE token = ? ORDER BY last_seen DESC",
+    )
+    .bind(&summary.token)
+    .fetch_all(&state.db)
+    .await
+    .unwrap_or_default();
+    Ok(Json(serde_json::json!({ "share": summary, "visitors": visitors })))
+}
+
+/// 接口: DELETE /api/share/{token}，撤销分享 (保留记录与统计，链接立即失效)
+async fn revoke_share(
+    State(state): State<AppState>,
+    axum::extract::Path(token): axum::extract::Path<String>,
+) -> Json<serde_json::Value> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs_f64();
+    let revoked = sqlx::query("UPDATE shares SET revoked_at = ? WHERE token = ? AND revoked_at IS NULL")
+        .bind(now)
+        .bind(token.trim())
+        .execute(&state.db)
+        .await
+        .map(|r| r.rows_affected())
+        .unwrap_or(0);
+    if revoked > 0 {
+        tracing::info!("🔒 Share revoked: {}", token.trim());
+    }
+    Json(serde_json::json!({ "revoked": revoked }))
+}
+
+async fn share_paths(state: &AppState, record: &ShareRecord, kind: ShareKind) -> Vec<String> {
+    if kind == ShareKind::Image {
+        return record.target.iter().cloned().collect();
+    }
+    if let Some(paths) = record.paths_json.as_deref().and_then(|json| serde_json::from_str(json).ok()) {
+        return paths;
+    }
+    if kind == ShareKind::Playlist {
+        return Vec::new();
+    }
+
+    // 早期的文件夹分享没有快照：首次访问时补上，之后按快照提供
+    let paths = folder_share_paths(state, record.target.as_deref().unwrap_or("")).await;
+    if let Err(e) = sqlx::query("UPDATE shares SET paths_json = ? WHERE token = ? AND paths_json IS NULL")
+        .bind(serde_json::to_string(&paths).unwrap_or_default())
+        .bind(&record.token)
+        .execute(&state.db)
+        .await
+    {
+        tracing::warn!("⚠️ [Share] 保存文件夹分享快照失败: {}", e);
+    }
+    paths
+}
+
+/// 接口: GET /share/{token}，浏览器访问返回简单的图片墙页面，其余返回 JSON 清单
+async fn view_share(
+    State(state): State<AppState>,
+    axum::extract::Path(token): axum::extract::Path<String>,
+    Query(query): Query<ShareAccessQuery>,
+    connect_info: ConnectInfo<SocketAddr>,
+    headers: axum::http::HeaderMap,
+) -> Response {
+    let wants_html = headers
+        .get(header::ACCEPT)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|accept| accept.contains("text/html"));
+    let (record, kind) = match load_active_share(&state, &token).await {
+        Ok(share) => share,
+        Err(response) => return response,
+    };
+    if !share_unlocked(&record, &headers, query.key.as_deref()) {
+        return if wants_html { share_password_page(&state.base_path, &record.token, false) } else { share_locked_response() };
+    }
+    record_share_view(&state, &record.token, &connect_info.0.ip().to_string()).await;
+
+    // 通过 `?key=` 访问时，清单里的地址同样带上密钥
+    let key_param = query.key.as_deref().map(|k| format!("key={}", urlencoding::encode(k)));
+    let items: Vec<ShareItem> = share_paths(&state, &record, kind)
+        .await
+        .iter()
+        .enumerate()
+        .map(|(index, path)| ShareItem {
+            name: path.rsplit('/').next().unwrap_or(path).to_string(),
+            url: match &key_param {
+                Some(key) => format!("{}/share/{}/{}?{}", state.base_path, record.token, index, key),
+                None => format!("{}/share/{}/{}", state.base_path, record.token, index),
+            },
+            thumbnail_url: match &key_param {
+                Some(key) => format!("{}/share/{}/{}?width=400&{}", state.base_path, record.token, index, key),
+                None => format!("{}/share/{}/{}?width=400", state.base_path, record.token, index),
+            },
+        })
+        .collect();
+    let manifest = ShareManifest {
+        title: record.title.clone(),
+        kind,
+        expires_at: record.expires_at,
+        downloads_remaining: record.max_downloads.map(|max| (max - record.downloads).max(0)),
+        allow_comments: record.allow_comments,
+        items,
+    };
+
+    if !wants_html {
+        return ([(header::CACHE_CONTROL, "no-store")], Json(manifest)).into_response();
+    }
+
+    let title = escape_html(manifest.title.as_deref().unwrap_or("Shared photos"));
+    let mut html = format!(
+        "<!DOCTYPE html><html><head><meta charset=\"utf-8\"><meta name=\"viewport\" content=\"width=device-width, initial-scale=1\">\
+         <title>{title}</title><style>body{{margin:0;padding:16px;font-family:sans-serif;background:#111;color:#eee}}\
+         main{{display:grid;grid-template-columns:repeat(auto-fill,minmax(200px,1fr));gap:8px}}\
+         img{{width:100%;aspect-ratio:1;object-fit:cover;display:block;border-radius:4px}}</style></head>\
+         <body><h1>{title}</h1><main>"
+    );
+    for item in &manifest.items {
+        html.push_str(&format!(
+            "<a href=\"{}\"><img src=\"{}\" alt=\"{}\" loading=\"lazy\"></a>",
+            escape_html(&item.url),
+            escape_html(&item.thumbnail_url),
+            escape_html(&item.name)
+        ));
+    }
+    html.push_str("</main></body></html>");
+    share_html_response(StatusCode::OK, html)
+}
+
+/// 接口: GET /share/{token}/{index}，返回分享中的第 index 张图片 (原图计入下载次数)
+async fn share_file(
+    State(state): State<AppState>,
+    axum::extract::Path((token, index)): axum::extract::Path<(String, usize)>,
+    Query(query): Query<ShareFileQuery>,
+    request: Request,
+) -> Response {
+    let (record, kind) = match load_unlocked_share(&state, &token, request.headers(), query.key.as_deref()).await {
+        Ok(share) => share,
+        Err(response) => return response,
+    };
+    let Some(path) = share_paths(&state, &record, kind).await.into_iter().nth(index) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let watermark = match &record.watermark {
+        Some(name) => match load_watermark(&state, name).await {
+            Ok(watermark) => Some(watermark),
+            Err((status, detail)) => return (status, Json(serde_json::json!({ "detail": detail }))).into_response(),
+        },
+        None => None,
+    };
+
+    if let Some(width) = query.width {
+        let side = width.clamp(1, SHARE_THUMBNAIL_MAX);
+        let spec = RenderSpec { watermark, ..RenderSpec::fit_within(side, side) };
+        return serve_rendered(&state, &path, spec).await;
+    }
+
+    // 先确认文件存在且可读，再占用下载额度：缺失的文件不应消耗次数
+    let allow_parent = *state.allow_parent_dir_access.read().await;
+    let full = match resolve_and_authorize(&state.root_dir, &path, allow_parent) {
+        Ok(full) if full.is_file() => full,
+        _ => return StatusCode::NOT_FOUND.into_response(),
+    };
+    if tokio::fs::File::open(&full).await.is_err() {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+
+    // 原子地占用一次下载额度，避免并发请求超出上限
+    let claimed = sqlx::query(
+        "UPDATE shares SET downloads = downloads + 1
+         WHERE token = ? AND (max_downloads IS NULL OR downloads < max_downloads)",
+    )
+    .bind(&record.token)
+    .execute(&state.db)
+    .await
+    .map(|r| r.rows_affected() > 0)
+    .unwrap_or(false);
+    if !claimed {
+        return (StatusCode::GONE, Json(serde_json::json!({ "detail": "Share link is invalid or expired" }))).into_response();
+    }
+
+    if watermark.is_some() {
+        let spec = RenderSpec { watermark, ..RenderSpec::fit_within(8192, 8192) };
+        return serve_rendered(&state, &path, spec).await;
+    }
+
+    let mime = from_path(&full).first_or_octet_stream();
+    let client_ip = request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip().to_string())
+        .unwrap_or_default();
+    match ServeFile::new_with_mime(&full, &mime).oneshot(request).await {
+        Ok(res) => {
+            let mut res = res.map(Body::new);
+            res.headers_mut().insert(header::CACHE_CONTROL, HeaderValue::from_static("private, max-age=3600"));
+            throttle_response(&state, &client_ip, res)
+        }
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ShareCommentRequest {
+    author: Option<String>,
+    text: Option<String>,
+    /// 简短表情回应 (如 "❤️")
+    reaction: Option<String>,
+}
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+struct ShareComment {
+    id: i64,
+    path: String,
+    author: Option<String>,
+    text: Option<String>,
+    reaction: Option<String>,
+    created_at: f64,
+}
+
+const SHARE_COMMENT_MAX_CHARS: usize = 1000;
+const SHARE_COMMENT_AUTHOR_MAX_CHARS: usize = 64;
+const SHARE_REACTION_MAX_CHARS: usize = 8;
+/// 单个分享最多保留的留言数，防止公开链接被刷屏
+const SHARE_COMMENTS_MAX: i64 = 1000;
+
+/// 找到分享中的第 index 张图片，并要求该分享已开启留言
+async fn commentable_share_item(
+    state: &AppState,
+    token: &str,
+    index: usize,
+    headers: &axum::http::HeaderMap,
+    key: Option<&str>,
+) -> Result<(ShareRecord, String), Response> {
+    let (record, kind) = load_unlocked_share(state, token, headers, key).await?;
+    if !record.allow_comments {
+        return Err((
+            StatusCode::FORBIDDEN,
+            Json(serde_json::json!({ "detail": "Comments are disabled for this share" })),
+        )
+            .into_response());
+    }
+    match share_paths(state, &record, kind).await.into_iter().nth(index) {
+        Some(path) => Ok((record, path)),
+        None => Err(StatusCode::NOT_FOUND.into_response()),
+    }
+}
+
+/// 接口: GET /share/{token}/{index}/comments，访客查看某张图片的留言
+async fn list_share_item_comments(
+    State(state): State<AppState>,
+    axum::extract::Path((token, index)): axum::extract::Path<(String, usize)>,
+    Query(query): Query<ShareAccessQuery>,
+    headers: axum::http::HeaderMap,
+) -> Response {
+    let (record, path) = match commentable_share_item(&state, &token, index, &headers, query.key.as_deref()).await {
+        Ok(item) => item,
+        Err(response) => return response,
+    };
+    let comments: Vec<ShareComment> = sqlx::query_as(
+        "SELECT id, path, author, text, reaction, created_at FROM share_comments
+         WHERE token = ? AND path = ? ORDER BY created_at",
+    )
+    .bind(&record.token)
+    .bind(&path)
+    .fetch_all(&state.db)
+    .await
+    .unwrap_or_default();
+    ([(header::CACHE_CONTROL, "no-store")], Json(comments)).into_response()
+}
+
+/// 接口: POST /share/{token}/{index}/comments，访客留言或点表情 (无需认证)
+async fn add_share_comment(
+    State(state): State<AppState>,
+    axum::extract::Path((token, index)): axum::extract::Path<(String, usize)>,
+    Query(query): Query<ShareAccessQuery>,
+    connect_info: ConnectInfo<SocketAddr>,
+    headers: axum::http::HeaderMap,
+    Json(req): Json<ShareCommentRequest>,
+) -> Response {
+    let bad_request = |detail: &str| (StatusCode::BAD_REQUEST, Json(serde_json::json!({ "detail": detail }))).into_response();
+    let (record, path) = match commentable_share_item(&state, &token, index, &headers, query.key.as_deref()).await {
+        Ok(item) => item,
+        Err(response) => return response,
+    };
+
+    let clean = |value: Option<String>| value.map(|v| v.trim().to_string()).filter(|v| !v.is_empty());
+    let (author, text, reaction) = (clean(req.author), clean(req.text), clean(req.reaction));
+    if text.is_none() && reaction.is_none() {
+        return bad_request("text or reaction is required");
+    }
+    if text.as_ref().is_some_and(|t| t.chars().count() > SHARE_COMMENT_MAX_CHARS)
+        || author.as_ref().is_some_and(|a| a.chars().count() > SHARE_COMMENT_AUTHOR_MAX_CHARS)
+        || reaction.as_ref().is_some_and(|r| r.chars().count() > SHARE_REACTION_MAX_CHARS)
+    {
+        return bad_request("Comment is too long");
+    }
+
+    let (count,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM share_comments WHERE token = ?")
+        .bind(&record.token)
+        .fetch_one(&state.db)
+        .await
+        .unwrap_or((0,));
+    if count >= SHARE_COMMENTS_MAX {
+        return (
+            StatusCode::TOO_MANY_REQUESTS,
+            Json(serde_json::json!({ "detail": "This share has reached its comment limit" })),
+        )
+            .into_response();
+    }
+
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs_f64();
+    let client_ip = connect_info.0.ip().to_string();
+    let inserted = sqlx::query(
+        "INSERT INTO share_comments (token, path, author, text, reaction, created_at, client_ip)
+         VALUES (?, ?, ?, ?, ?, ?, ?)",
+    )
+    .bind(&record.token)
+    .bind(&path)
+    .bind(&author)
+    .bind(&text)
+    .bind(&reaction)
+    .bind(now)
+    .bind(&client_ip)
+    .execute(&state.db)
+    .await;
+    let id = match inserted {
+        Ok(result) => result.last_insert_rowid(),
+        Err(err) => {
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(serde_json::json!({ "detail": format!("Failed to save comment: {}", err) })),
+            )
+                .into_response()
+        }
+    };
+
+    let comment = ShareComment { id, path, author, text, reaction, created_at: now };
+    tracing::info!("💬 New comment on share {} ({})", record.token, comment.path);
+    publish_event(
+        &state,
+        None,
+        "share_comment",
+        serde_json::json!({ "token": record.token, "comment": &comment }),
+    );
+    (StatusCode::CREATED, Json(comment)).into_response()
+}
+
+/// 接口: GET /api/share/{token}/comments，分享者查看该分享下的全部留言
+async fn list_share_comments(
+    State(state): State<AppState>,
+    axum::extract::Path(token): axum::extract::Path<String>,
+) -> Json<Vec<ShareComment>> {
+    let comments = sqlx::query_as(
+        "SELECT id, path, author, text, reaction, created_at FROM share_comments
+         WHERE token = ? ORDER BY created_at",
+    )
+    .bind(token.trim())
+    .fetch_all(&state.db)
+    .await
+    .unwrap_or_default();
+    Json(comments)
+}
+
+// --- iCalendar 导出 ---
+
+/// 从文件夹名解析日期范围 (含首尾) 与剩余标题
+///
+/// 支持 `2024-06 Italy` (整月)、`2024-06-15 Party`、`20240615`、`2024_06_15`，
+/// 以及 `2024-06-15~2024-06-20 Trip` / `2024-06-15 - 06-20` 这样的区间。
+fn parse_folder_date(name: &str) -> Option<(chrono::NaiveDate, chrono::NaiveDate, String)> {
+    use chrono::{Datelike, NaiveDate};
+
+    /// 读取开头的日期，返回 (起始日, 是否只有年月, 剩余部分)；`default_year` 用于区间结尾省略年份的情况
+    fn leading_date(text: &str, default_year: Option<i32>) -> Option<(NaiveDate, bool, &str)> {
+        let number = |s: &str, n: usize| -> Option<u32> {
+            s.get(..n).filter(|d| d.bytes().all(|b| b.is_ascii_digit()))?.parse().ok()
+        };
+        fn skip_separator(s: &str) -> &str {
+            s.strip_prefix(['-', '_', '.']).unwrap_or(s)
+        }
+        let ends_number = |s: &str| !s.starts_with(|c: char| c.is_ascii_digit());
+
+        let (year, rest) = match number(text, 4).filter(|y| *y >= 1900) {
+            Some(year) => (year as i32, skip_separator(&text[4..])),
+            None => (default_year?, text),
+        };
+        let month = number(rest, 2)?;
+        let rest = &rest[2..];
+        let day_text = skip_separator(rest);
+        match number(day_text, 2) {
+            Some(day) if ends_number(&day_text[2..]) => Some((NaiveDate::from_ymd_opt(year, month, day)?, false, &day_text[2..])),
+            _ if ends_number(rest) => Some((NaiveDate::from_ymd_opt(year, month, 1)?, true, rest)),
+            _ => None,
+        }
+    }
+
+    let (start, month_only, rest) = leading_date(name.trim(), None)?;
+    let mut end = if month_only {
+        let next = if start.month() == 12 {
+            NaiveDate::from_ymd_opt(start.year() + 1, 1, 1)?
+        } else {
+            NaiveDate::from_ymd_opt(start.year(), start.month() + 1, 1)?
+        };
+        next.pred_opt()?
+    } else {
+        start
+    };
+
+    let mut rest = rest;
+    if !month_only {
+        let trimmed = rest.trim_start();
+        for separator in ["~", "..", "--", "- ", "to "] {
+            if let Some(after) = trimmed.strip_prefix(separator) {
+                let after = after.trim_start();
+                if let Some((mut range_end, false, after_end)) = leading_date(after, Some(start.year())) {
+                    // 省略年份的结尾早于开头时视为跨年 (如 `2023-12-30 - 01-02`)
+                    let has_year = after.get(..4).is_some_and(|y| y.bytes().all(|b| b.is_ascii_digit()));
+                    if range_end < start && !has_year {
+                        range_end = range_end.with_year(start.year() + 1).unwrap_or(range_end);
+                    }
+                    if range_end >= start {
+                        end = range_end;
+                        rest = after_end;
+                    }
+                }
+                break;
+            }
+        }
+    }
+
+    let title = rest.trim_matches(|c: char| c.is_whitespace() || matches!(c, '-' | '_' | '.' | ',')).to_string();
+    Some((start, end, title))
+}
+
+/// 转义 iCalendar TEXT 值
+fn ical_escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace(';', "\\;")
+        .replace(',', "\\,")
+        .replace('\n', "\\n")
+}
+
+/// 按 RFC 5545 把长行折叠为不超过 75 字节 (不拆开 UTF-8 字符)
+fn ical_fold(line: &str, out: &mut String) {
+    let mut width = 0;
+    for c in line.chars() {
+        if width + c.len_utf8() > 75 {
+            out.push_str("\r\n ");
+            width = 1;
+        }
+        out.push(c);
+        width += c.len_utf8();
+    }
+    out.push_str("\r\n");
+}
+
+#[derive(Debug, Deserialize)]
+struct CalendarQuery {
+    /// 只导出该文件夹下的相册
+    #[serde(default)]
+    path: String,
+}
+
+/// 接口: GET /api/calendar.ics，文件夹名带日期的相册作为全天事件导出
+///
+/// 事件链接默认为 `{服务地址}/?folder={路径}`，可通过 `GALLERY_CALENDAR_LINK` 模板
+/// (`{base}`、`{path}` 占位符) 指向其他页面。
+async fn calendar_feed(
+    State(state): State<AppState>,
+    Query(query): Query<CalendarQuery>,
+    headers: axum::http::HeaderMap,
+) -> Response {
+    let rows: Vec<(String,)> =
+        sqlx::query_as(&format!("SELECT path FROM images WHERE {} AND {}", PRESENT_SQL_FILTER, INTERNAL_PATH_SQL_FILTER))
+            .fetch_all(&state.db)
+            .await
+            .unwrap_or_default();
+
+    // 每个祖先文件夹的图片数 (子文件夹计入上级，便于 "2024-06 Italy/Day 1" 这类结构)
+    let scope = normalize_rel_path(&query.path);
+    let scope = if scope == "." { String::new() } else { scope };
+    let mut folders: BTreeMap<String, usize> = BTreeMap::new();
+    for (path,) in &rows {
+        let mut folder = parent_folder(path);
+        while !folder.is_empty() && folder != "." {
+            if scope.is_empty() || folder == scope || folder.starts_with(&format!("{}/", scope)) {
+                *folders.entry(folder.clone()).or_default() += 1;
+            }
+            folder = parent_folder(&folder);
+        }
+    }
+
+    let base = public_base_url(&headers).unwrap_or_default();
+    let template = config_var("GALLERY_CALENDAR_LINK").unwrap_or_else(|_| "{base}/?folder={path}".to_string());
+    let host = headers.get(header::HOST).and_then(|h| h.to_str().ok()).unwrap_or("gravity-gallery");
+    let stamp = chrono::Utc::now().format("%Y%m%dT%H%M%SZ").to_string();
+
+    let mut ics = String::new();
+    for line in [
+        "BEGIN:VCALENDAR",
+        "VERSION:2.0",
+        "PRODID:-//Gravity Gallery//Photo Journal//EN",
+        "CALSCALE:GREGORIAN",
+        "X-WR-CALNAME:Gravity Gallery",
+    ] {
+        ical_fold(line, &mut ics);
+    }
+    let mut events = 0;
+    for (folder, count) in &folders {
+        let name = folder.rsplit('/').next().unwrap_or(folder);
+        let Some((start, end, title)) = parse_folder_date(name) else {
+            continue;
+        };
+        let summary = if title.is_empty() { name.to_string() } else { title };
+        let link = template
+            .replace("{base}", &base)
+            .replace("{path}", &urlencoding::encode(folder));
+        let Some(day_after) = end.succ_opt() else {
+            continue;
+        };
+        for line in [
+            "BEGIN:VEVENT".to_string(),
+            format!("UID:{}@{}", blake3::hash(folder.as_bytes()).to_hex().as_str().get(..32).unwrap_or_default(), host),
+            format!("DTSTAMP:{}", stamp),
+            format!("DTSTART;VALUE=DATE:{}", start.format("%Y%m%d")),
+            format!("DTEND;VALUE=DATE:{}", day_after.format("%Y%m%d")),
+            format!("SUMMARY:{}", ical_escape(&summary)),
+            format!("DESCRIPTION:{}", ical_escape(&format!("{} photos in {}\n{}", count, folder, link))),
+            format!("URL:{}", link),
+            "TRANSP:TRANSPARENT".to_string(),
+            "END:VEVENT".to_string(),
+        ] {
+            ical_fold(&line, &mut ics);
+        }
+        events += 1;
+    }
+    ical_fold("END:VCALENDAR", &mut ics);
+    tracing::debug!("📅 Calendar feed: {} dated albums", events);
+
+    (
+        [
+            (header::CONTENT_TYPE, "text/calendar; charset=utf-8"),
+            (header::CACHE_CONTROL, "no-cache"),
+        ],
+        ics,
+    )
+        .into_response()
+}
+
+// --- IIIF Image API 3.0 ---
+
+/// 最近解码过的原图 (大图切片时避免每个请求都重新解码)，按解码后的字节数限制总量
+#[derive(Default)]
+struct DecodedImageCache {
+    /// 解码后像素数据的总字节上限 (`GALLERY_DECODED_CACHE_MB`)
+    capacity_bytes: usize,
+    used_bytes: usize,
+    /// (路径, 修改时间, 图像)，最近使用的在末尾
+    entries: Vec<(PathBuf, SystemTime, Arc<image::DynamicImage>)>,
+}
+
+impl DecodedImageCache {
+    fn insert(&mut self, path: PathBuf, modified: SystemTime, img: Arc<image::DynamicImage>) {
+        if let Some(pos) = self.entries.iter().position(|(p, _, _)| *p == path) {
+            let (_, _, old) = self.entries.remove(pos);
+            self.used_bytes -= old.as_bytes().len();
+        }
+        // 单张就超过上限的图片不缓存
+        let size = img.as_bytes().len();
+        if size > self.capacity_bytes {
+            return;
+        }
+        while self.used_bytes + size > self.capacity_bytes && !self.entries.is_empty() {
+            let (_, _, evicted) = self.entries.remove(0);
+            self.used_bytes -= evicted.as_bytes().len();
+        }
+        self.used_bytes += size;
+        self.entries.push((path, modified, img));
+    }
+}
+
+/// 读取并解码原图，按 (路径, 修改时间) 缓存
+async fn load_decoded_image(state: &AppState, full_path: &Path) -> Option<Arc<image::DynamicImage>> {
+    let modified = tokio::fs::metadata(full_path).await.ok()?.modified().ok()?;
+    if let Ok(mut cache) = state.decoded_images.lock() {
+        if let Some(pos) = cache.entries.iter().position(|(p, m, _)| p == full_path && *m == modified) {
+            let entry = cache.entries.remove(pos);
+            let img = entry.2.clone();
+            cache.entries.push(entry);
+            return Some(img);
+        }
+    }
+
+    let path = full_path.to_path_buf();
+    let img = Arc::new(tokio::task::spawn_blocking(move || image::open(path).ok()).await.ok()??);
+    if let Ok(mut cache) = state.decoded_images.lock() {
+        cache.insert(full_path.to_path_buf(), modified, img.clone());
+    }
+    Some(img)
+}
+
+/// 带协议与主机名的服务地址 (IIIF 的 `id` 必须是绝对 URI；本机访问时也退回 Host 头)
+fn request_base_url(headers: &axum::http::HeaderMap) -> String {
+    public_base_url(headers).unwrap_or_else(|| {
+        let host = headers.get(header::HOST).and_then(|h| h.to_str().ok()).unwrap_or("localhost");
+        let scheme = if config_var("GALLERY_SSL_CERT").is_ok() { "https" } else { "http" };
+        format!("{}://{}", scheme, host)
+    })
+}
+
+fn iiif_max_side(settings: &Settings) -> u32 {
+    settings.parse::<u32>("GALLERY_IIIF_MAX_SIDE").unwrap_or(4096).clamp(256, 16384)
+}
+
+const IIIF_TILE_SIZE: u32 = 512;
+
+/// 解析 region 参数，返回裁剪区域 (x, y, w, h)
+fn parse_iiif_region(region: &str, width: u32, height: u32) -> Option<(u32, u32, u32, u32)> {
+    let (x, y, w, h) = match region {
+        "full" => return Some((0, 0, width, height)),
+        "square" => {
+            let side = width.min(height);
+            return Some(((width - side) / 2, (height - side) / 2, side, side));
+        }
+        _ => {
+            let (pct, values) = match region.strip_prefix("pct:") {
+                Some(rest) => (true, rest),
+                None => (false, region),
+            };
+            let values: Vec<f64> = values.split(',').map(|v| v.parse::<f64>().ok()).collect::<Option<_>>()?;
+            let [x, y, w, h] = values[..] else {
+                return None;
+            };
+            if pct {
+                let (fw, fh) = (width as f64 / 100.0, height as f64 / 100.0);
+                (x * fw, y * fh, w * fw, h * fh)
+            } else {
+                (x, y, w, h)
+            }
+        }
+    };
+    if x < 0.0 || y < 0.0 || w <= 0.0 || h <= 0.0 {
+        return None;
+    }
+    // 超出图像的部分裁掉；完全落在图像外则无效
+    let (x, y) = (x.round() as u32, y.round() as u32);
+    if x >= width || y >= height {
+        return None;
+    }
+    let w = (w.round() as u32).clamp(1, width - x);
+    let h = (h.round() as u32).clamp(1, height - y);
+    Some((x, y, w, h))
+}
+
+/// 解析 size 参数，返回输出尺寸；违反规范 (未加 `^` 却放大、超过上限) 时返回 None
+fn parse_iiif_size(size: &str, region_w: u32, region_h: u32, max_side: u32) -> Option<(u32, u32)> {
+    let (upscale, size) = match size.strip_prefix('^') {
+        Some(rest) => (true, rest),
+        None => (false, size),
+    };
+    let (rw, rh) = (region_w as f64, region_h as f64);
+    let scaled = |scale: f64| ((rw * scale).round().max(1.0) as u32, (rh * scale).round().max(1.0) as u32);
+
+    let (w, h) = if size == "max" {
+        let limit = (max_side as f64 / rw).min(max_side as f64 / rh);
+        scaled(if upscale { limit } else { limit.min(1.0) })
+    } else if let Some(pct) = size.strip_prefix("pct:") {
+        scaled(pct.parse::<f64>().ok().filter(|p| *p > 0.0)? / 100.0)
+    } else {
+        let (confined, size) = match size.strip_prefix('!') {
+            Some(rest) => (true, rest),
+            None => (false, size),
+        };
+        let (w, h) = size.split_once(',')?;
+        let w: Option<u32> = if w.is_empty() { None } else { Some(w.parse().ok()?) };
+        let h: Option<u32> = if h.is_empty() { None } else { Some(h.parse().ok()?) };
+        match (w, h, confined) {
+            (Some(w), Some(h), true) => scaled((w as f64 / rw).min(h as f64 / rh)),
+            (Some(w), Some(h), false) => (w, h),
+            (Some(w), None, false) => (w, (rh * w as f64 / rw).round().max(1.0) as u32),
+            (None, Some(h), false) => ((rw * h as f64 / rh).round().max(1.0) as u32, h),
+            _ => return None,
+        }
+    };
+
+    if w == 0 || h == 0 || w > max_side || h > max_side || (!upscale && (w > region_w || h > region_h)) {
+        return None;
+    }
+    Some((w, h))
+}
+
+fn iiif_error(status: StatusCode, detail: &str) -> Response {
+    (status, Json(serde_json::json!({ "detail": detail }))).into_response()
+}
+
+/// 解析 IIIF 标识符 (即 URL 编码后的相对路径) 并做权限检查
+async fn resolve_iiif_identifier(state: &AppState, identifier: &str) -> Result<(String, PathBuf), Response> {
+    match authorize_client_path(state, identifier).await {
+        Ok((rel, full)) if full.is_file() && is_image_ext(&full) => Ok((rel, full)),
+        Err(PathAccessError::Forbidden) => Err(iiif_error(StatusCode::FORBIDDEN, "Access outside ROOT_DIR is disabled")),
+        _ => Err(iiif_error(StatusCode::NOT_FOUND, "Image not found")),
+    }
+}
+
+/// 接口: GET /iiif/{id}，按规范重定向到 info.json
+async fn iiif_base(
+    State(state): State<AppState>,
+    axum::extract::Path(identifier): axum::extract::Path<String>,
+) -> Response {
+    let location = format!("{}/iiif/{}/info.json", state.base_path, urlencoding::encode(&identifier));
+    (StatusCode::SEE_OTHER, [(header::LOCATION, location)]).into_response()
+}
+
+/// 接口: GET /iiif/{id}/info.json，图像信息 (level2 能力声明 + 切片参数)
+async fn iiif_info(
+    State(state): State<AppState>,
+    axum::extract::Path(identifier): axum::extract::Path<String>,
+    headers: axum::http::HeaderMap,
+) -> Response {
+    let (rel, full) = match resolve_iiif_identifier(&state, &identifier).await {
+        Ok(resolved) => resolved,
+        Err(response) => return response,
+    };
+    let Ok((width, height)) = image::image_dimensions(&full) else {
+        return iiif_error(StatusCode::UNPROCESSABLE_ENTITY, "Unreadable image");
+    };
+
+    let max_side = iiif_max_side(&state.settings);
+    // 缩放因子一直翻倍到整图能放进单个切片为止
+    let mut scale_factors = vec![1u32];
+    while width.max(height) / scale_factors.last().copied().unwrap_or(1) > IIIF_TILE_SIZE {
+        scale_factors.push(scale_factors.last().copied().unwrap_or(1) * 2);
+    }
+    let sizes: Vec<serde_json::Value> = scale_factors
+        .iter()
+        .rev()
+        .map(|f| (width.div_ceil(*f), height.div_ceil(*f)))
+        .filter(|(w, h)| *w <= max_side && *h <= max_side)
+        .map(|(w, h)| serde_json::json!({ "type": "Size", "width": w, "height": h }))
+        .collect();
+
+    let info = serde_json::json!({
+        "@context": "http://iiif.io/api/image/3/context.json",
+        "id": format!("{}{}/iiif/{}", request_base_url(&headers), state.base_path, urlencoding::encode(&rel)),
+        "type": "ImageService3",
+        "protocol": "http://iiif.io/api/image",
+        "profile": "level2",
+        "width": width,
+        "height": height,
+        "maxWidth": max_side,
+        "maxHeight": max_side,
+        "sizes": sizes,
+        "tiles": [{ "type": "Tile", "width": IIIF_TILE_SIZE, "scaleFactors": scale_factors }],
+        "extraQualities": ["gray", "bitonal"],
+        "extraFeatures": ["mirroring", "regionSquare", "rotationBy90s", "sizeUpscaling"],
+    });
+    (
+        [
+            (header::CONTENT_TYPE, "application/ld+json;profile=\"http://iiif.io/api/image/3/context.json\""),
+            (header::CACHE_CONTROL, "public, max-age=3600"),
+        ],
+        info.to_string(),
+    )
+        .into_response()
+}
+
+/// 接口: GET /iiif/{id}/{region}/{size}/{rotation}/{quality}.{format}
+///
+/// 支持 full/square/像素/百分比区域，max/w,/,h/w,h/!w,h/pct: 尺寸 (含 `^` 放大)，
+/// 90° 倍数旋转与镜像，default/color/gray/bitonal 质量，jpg/png 格式。
+async fn iiif_image(
+    State(state): State<AppState>,
+    axum::extract::Path((identifier, region, size, rotation, file)): axum::extract::Path<(String, String, String, String, String)>,
+) -> Response {
+    use image::imageops::FilterType;
+
+    let (_, full) = match resolve_iiif_identifier(&state, &identifier).await {
+        Ok(resolved) => resolved,
+        Err(response) => return response,
+    };
+    let Some((quality, format)) = file.rsplit_once('.') else {
+        return iiif_error(StatusCode::BAD_REQUEST, "Missing format");
+    };
+    let (output_format, mime) = match format {
+        "jpg" => (image::ImageOutputFormat::Jpeg(90), "image/jpeg"),
+        "png" => (image::ImageOutputFormat::Png, "image/png"),
+        _ => return iiif_error(StatusCode::BAD_REQUEST, "Unsupported format"),
+    };
+    if !matches!(quality, "default" | "color" | "gray" | "bitonal") {
+        return iiif_error(StatusCode::BAD_REQUEST, "Unsupported quality");
+    }
+    let (mirror, angle) = match rotation.strip_prefix('!') {
+        Some(rest) => (true, rest),
+        None => (false, rotation.as_str()),
+    };
+    let angle = match angle.parse::<f64>() {
+        Ok(a) if matches!(a, 0.0 | 90.0 | 180.0 | 270.0) => a as u32,
+        Ok(a) if (0.0..=360.0).contains(&a) => return iiif_error(StatusCode::NOT_IMPLEMENTED, "Only 90° rotations are supported"),
+        _ => return iiif_error(StatusCode::BAD_REQUEST, "Invalid rotation"),
+    };
+
+    // 先用文件头校验参数，再解码 (解码结果会缓存，便于后续切片请求)
+    let Ok((width, height)) = image::image_dimensions(&full) else {
+        return iiif_error(StatusCode::UNPROCESSABLE_ENTITY, "Unreadable image");
+    };
+    let Some((x, y, w, h)) = parse_iiif_region(&region, width, height) else {
+        return iiif_error(StatusCode::BAD_REQUEST, "Invalid region");
+    };
+    let Some((out_w, out_h)) = parse_iiif_size(&size, w, h, iiif_max_side(&state.settings)) else {
+        return iiif_error(StatusCode::BAD_REQUEST, "Invalid size");
+    };
+    let Some(source) = load_decoded_image(&state, &full).await else {
+        return iiif_error(StatusCode::UNPROCESSABLE_ENTITY, "Unreadable image");
+    };
+
+    let quality = quality.to_string();
+    let encoded = tokio::task::spawn_blocking(move || {
+        let mut img = source.crop_imm(x, y, w, h);
+        if (out_w, out_h) != (w, h) {
+            img = img.resize_exact(out_w, out_h, FilterType::CatmullRom);
+        }
+        if mirror {
+            img = img.fliph();
+        }
+        img = match angle {
+            90 => img.rotate90(),
+            180 => img.rotate180(),
+            270 => img.rotate270(),
+            _ => img,
+        };
+        img = match quality.as_str() {
+            "gray" => image::DynamicImage::ImageLuma8(img.to_luma8()),
+            "bitonal" => {
+                let mut luma = img.to_luma8();
+                luma.pixels_mut().for_each(|p| p.0[0] = if p.0[0] >= 128 { 255 } else { 0 });
+                image::DynamicImage::ImageLuma8(luma)
+            }
+            _ => image::DynamicImage::ImageRgb8(img.to_rgb8()),
+        };
+        let mut buf = std::io::Cursor::new(Vec::new());
+        img.write_to(&mut buf, output_format).ok()?;
+        Some(buf.into_inner())
+    })
+    .await
+    .ok()
+    .flatten();
+
+    match encoded {
+        Some(bytes) => (
+            [
+                (header::CONTENT_TYPE, mime),
+                (header::CACHE_CONTROL, "public, max-age=86400"),
+                (header::LINK, "<http://iiif.io/api/image/3/level2.json>;rel=\"profile\""),
+            ],
+            bytes,
+        )
+            .into_response(),
+        None => iiif_error(StatusCode::INTERNAL_SERVER_ERROR, "Failed to encode image"),
+    }
+}
+
+// --- Deep Zoom 切片 ---
+
+const DZI_TILE_SIZE: u32 = 254;
+const DZI_OVERLAP: u32 = 1;
+
+/// 超过该像素数 (百万) 的图片才提供切片，默认 16
+fn tile_min_megapixels(settings: &Settings) -> f64 {
+    settings.parse("GALLERY_TILE_MIN_MEGAPIXELS").unwrap_or(16.0)
+}
+
+/// 切片缓存目录，默认 `{ROOT_DIR}/.gallery-cache/tiles`
+fn tile_cache_dir(state: &AppState) -> PathBuf {
+    state
+        .settings
+        .own("GALLERY_TILE_CACHE_DIR")
+        .filter(|v| !v.trim().is_empty())
+        .map(PathBuf::from)
+        .unwrap_or_else(|| state.root_dir.join(CACHE_DIR_NAME).join("tiles"))
+}
+
+/// 最高层级：长边缩到 1px 需要的层数 (DZI 约定 ceil(log2(长边)))
+fn dzi_max_level(width: u32, height: u32) -> u32 {
+    32 - (width.max(height).max(1) - 1).leading_zeros()
+}
+
+fn dzi_level_size(width: u32, height: u32, level: u32, max_level: u32) -> (u32, u32) {
+    let factor = 1u32 << (max_level - level).min(31);
+    (width.div_ceil(factor).max(1), height.div_ceil(factor).max(1))
+}
+
+/// 检查路径并确认图片足够大 (全景图不限大小，以便滚动浏览)，返回 (相对路径, 完整路径, 宽, 高)
+async fn resolve_tiled_image(state: &AppState, raw_path: &str) -> Result<(String, PathBuf, u32, u32), Response> {
+    let (rel, full) = match authorize_client_path(state, raw_path).await {
+        Ok((rel, full)) if full.is_file() && is_image_ext(&full) => (rel, full),
+        Err(PathAccessError::Forbidden) => {
+            return Err(iiif_error(StatusCode::FORBIDDEN, "Access outside ROOT_DIR is disabled"))
+        }
+        _ => return Err(iiif_error(StatusCode::NOT_FOUND, "Image not found")),
+    };
+    let Ok((width, height)) = image::image_dimensions(&full) else {
+        return Err(iiif_error(StatusCode::UNPROCESSABLE_ENTITY, "Unreadable image"));
+    };
+    if !is_panorama(width, height) && (width as f64 * height as f64) / 1_000_000.0 < tile_min_megapixels(&state.settings) {
+        return Err(iiif_error(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "Image is small enough to be displayed directly",
+        ));
+    }
+    Ok((rel, full, width, height))
+}
+
+/// 接口: GET /api/tiles?path=...，返回 JSON 形式的 DZI 描述 (OpenSeadragon 可直接使用)
+///
+/// 全景图额外给出 `panorama`：沿长边滚动的方向与长宽比，相框可以按短边铺满屏幕后平移浏览。
+async fn tile_descriptor(
+    State(state): State<AppState>,
+    Query(query): Query<FileQuery>,
+    headers: axum::http::HeaderMap,
+) -> Response {
+    let (rel, _, width, height) = match resolve_tiled_image(&state, &query.path).await {
+        Ok(resolved) => resolved,
+        Err(response) => return response,
+    };
+    let encoded: Vec<String> = rel.split('/').map(|s| urlencoding::encode(s).into_owned()).collect();
+    let mut descriptor = serde_json::json!({
+        "Image": {
+            "xmlns": "http://schemas.microsoft.com/deepzoom/2008",
+            "Url": format!("{}{}/api/v1/tiles/{}/", request_base_url(&headers), state.base_path, encoded.join("/")),
+            "Format": "jpg",
+            "Overlap": DZI_OVERLAP.to_string(),
+            "TileSize": DZI_TILE_SIZE.to_string(),
+            "Size": { "Width": width.to_string(), "Height": height.to_string() },
+        }
+    });
+    if is_panorama(width, height) {
+        descriptor["panorama"] = serde_json::json!({
+            "scroll": if width >= height { "horizontal" } else { "vertical" },
+            "aspect_ratio": width.max(height) as f64 / width.min(height) as f64,
+        });
+    }
+    Json(descriptor).into_response()
+}
+
+/// 接口: GET /api/tiles/{path}/{level}/{x}_{y}.jpg，按需生成并缓存到磁盘
+async fn tile_image(State(state): State<AppState>, axum::extract::Path(rest): axum::extract::Path<String>) -> Response {
+    let mut parts = rest.rsplitn(3, '/');
+    let (Some(file), Some(level), Some(path)) = (parts.next(), parts.next(), parts.next()) else {
+        return iiif_error(StatusCode::NOT_FOUND, "Invalid tile address");
+    };
+    let coords = file
+        .strip_suffix(".jpg")
+        .and_then(|c| c.split_once('_'))
+        .and_then(|(x, y)| Some((x.parse::<u32>().ok()?, y.parse::<u32>().ok()?)));
+    let (Some((tx, ty)), Ok(level)) = (coords, level.parse::<u32>()) else {
+        return iiif_error(StatusCode::NOT_FOUND, "Invalid tile address");
+    };
+
+    let (rel, full, width, height) = match resolve_tiled_image(&state, path).await {
+        Ok(resolved) => resolved,
+        Err(response) => return response,
+    };
+    let max_level = dzi_max_level(width, height);
+    if level > max_level {
+        return iiif_error(StatusCode::NOT_FOUND, "Level out of range");
+    }
+    let (level_w, level_h) = dzi_level_size(width, height, level, max_level);
+    // 切片在该层级上的起点；坐标过大 (乘法溢出) 同样视为越界
+    let origin = tx.checked_mul(DZI_TILE_SIZE).zip(ty.checked_mul(DZI_TILE_SIZE));
+    let Some((tile_x, tile_y)) = origin.filter(|&(x, y)| x < level_w && y < level_h) else {
+        return iiif_error(StatusCode::NOT_FOUND, "Tile out of range");
+    };
+
+    // 缓存按 (路径, 修改时间, 大小) 区分，原图变化后自动使用新目录
+    let meta = match tokio::fs::metadata(&full).await {
+        Ok(meta) => meta,
+        Err(_) => return iiif_error(StatusCode::NOT_FOUND, "Image not found"),
+    };
+    let version = blake3::hash(format!("{}:{}", rel, file_etag(&meta)).as_bytes());
+    let cached = tile_cache_dir(&state)
+        .join(&version.to_hex()[..32])
+        .join(level.to_string())
+        .join(format!("{}_{}.jpg", tx, ty));
+    let tile_response = |bytes: Vec<u8>| {
+        ([(header::CONTENT_TYPE, "image/jpeg"), (header::CACHE_CONTROL, "public, max-age=86400")], bytes).into_response()
+    };
+    if let Ok(bytes) = tokio::fs::read(&cached).await {
+        thumbnail_cache::touch(cached);
+        return tile_response(bytes);
+    }
+
+    let Some(source) = load_decoded_image(&state, &full).await else {
+        return iiif_error(StatusCode::UNPROCESSABLE_ENTITY, "Unreadable image");
+    };
+    let encoded = tokio::task::spawn_blocking(move || {
+        // 该层级上的切片范围 (含重叠像素)，再映射回原图坐标裁剪缩放
+        let x0 = tile_x.saturating_sub(if tx > 0 { DZI_OVERLAP } else { 0 });
+        let y0 = tile_y.saturating_sub(if ty > 0 { DZI_OVERLAP } else { 0 });
+        let x1 = tile_x.saturating_add(DZI_TILE_SIZE + DZI_OVERLAP).min(level_w);
+        let y1 = tile_y.saturating_add(DZI_TILE_SIZE + DZI_OVERLAP).min(level_h);
+        let (sx, sy) = (width as f64 / level_w as f64, height as f64 / level_h as f64);
+        let src_x = ((x0 as f64 * sx) as u32).min(width - 1);
+        let src_y = ((y0 as f64 * sy) as u32).min(height - 1);
+        let src_w = ((x1 as f64 * sx).ceil() as u32).min(width).saturating_sub(src_x).max(1);
+        let src_h = ((y1 as f64 * sy).ceil() as u32).min(height).saturating_sub(src_y).max(1);
+
+        let mut tile = source.crop_imm(src_x, src_y, src_w, src_h);
+        if src_w >= (x1 - x0) * 2 {
+            // 低层级要缩小整张大图，用快速的盒式采样
+            tile = tile.thumbnail_exact(x1 - x0, y1 - y0);
+        } else if (src_w, src_h) != (x1 - x0, y1 - y0) {
+            tile = tile.resize_exact(x1 - x0, y1 - y0, image::imageops::FilterType::Triangle);
+        }
+        let mut buf = std::io::Cursor::new(Vec::new());
+        image::DynamicImage::ImageRgb8(tile.to_rgb8())
+            .write_to(&mut buf, image::ImageOutputFormat::Jpeg(85))
+            .ok()?;
+        Some(buf.into_inner())
+    })
+    .await
+    .ok()
+    .flatten();
+    let Some(bytes) = encoded else {
+        return iiif_error(StatusCode::INTERNAL_SERVER_ERROR, "Failed to render tile");
+    };
+
+    // 先写临时文件再改名，避免并发请求读到半个文件
+    if let Some(dir) = cached.parent() {
+        let tmp = cached.with_extension(format!("tmp{}", rand::random::<u32>()));
+        let written = async {
+            tokio::fs::create_dir_all(dir).await?;
+            tokio::fs::write(&tmp, &bytes).await?;
+            tokio::fs::rename(&tmp, &cached).await
+        }
+        .await;
+        if let Err(err) = written {
+            tracing::warn!("⚠️ Failed to cache tile {}: {}", cached.display(), err);
+            let _ = tokio::fs::remove_file(&tmp).await;
+        }
+    }
+    tile_response(bytes)
+}
+
+// --- 新图片邮件摘要 ---
+
+/// 一位收件人的摘要订阅
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct DigestSubscription {
+    email: String,
+    /// 称呼，用于邮件问候语
+    name: Option<String>,
+    /// 只统计这些文件夹 (含子文件夹)；为空表示整个图库
+    #[serde(default)]
+    folders: Vec<String>,
+    /// 发送日 (0=周日 … 6=周六)，默认周日
+    #[serde(default)]
+    weekday: u8,
+    /// 发送时刻 (服务器本地时间)，默认 9 点
+    #[serde(default = "default_digest_hour")]
+    hour: u8,
+    #[serde(default, skip_deserializing)]
+    last_sent_at: Option<f64>,
+}
+
+fn default_digest_hour() -> u8 {
+    9
+}
+
+#[derive(Debug, Deserialize)]
+struct DigestQuery {
+    email: String,
+}
+
+async fn load_digest_subscriptions(state: &AppState) -> Vec<DigestSubscription> {
+    let rows: Vec<(String, Option<f64>)> =
+        sqlx::query_as("SELECT config_json, last_sent_at FROM digest_subscriptions ORDER BY email")
+            .fetch_all(&state.db)
+            .await
+            .unwrap_or_default();
+    rows.into_iter()
+        .filter_map(|(json, last_sent_at)| {
+            let subscription: DigestSubscription = serde_json::from_str(&json).ok()?;
+            Some(DigestSubscription { last_sent_at, ..subscription })
+        })
+        .collect()
+}
+
+/// 接口: GET /api/digests
+async fn list_digests(State(state): State<AppState>) -> Json<Vec<DigestSubscription>> {
+    Json(load_digest_subscriptions(&state).await)
+}
+
+/// 接口: POST /api/digests，新增或覆盖某个收件人的订阅
+async fn save_digest(
+    State(state): State<AppState>,
+    Json(subscription): Json<DigestSubscription>,
+) -> Result<Json<serde_json::Value>, (StatusCode, Json<serde_json::Value>)> {
+    let bad_request = |detail: &str| (StatusCode::BAD_REQUEST, Json(serde_json::json!({ "detail": detail })));
+    let email = subscription.email.trim().to_string();
+    let valid_email = email
+        .split_once('@')
+        .is_some_and(|(user, domain)| !user.is_empty() && domain.contains('.') && !email.contains(char::is_whitespace));
+    if !valid_email {
+        return Err(bad_request("A valid email address is required"));
+    }
+    if subscription.weekday > 6 || subscription.hour > 23 {
+        return Err(bad_request("weekday must be 0-6 and hour 0-23"));
+    }
+    let folders = subscription
+        .folders
+        .iter()
+        .map(|f| normalize_rel_path(f))
+        .filter(|f| !f.is_empty() && f != ".")
+        .collect();
+
+    let subscription = DigestSubscription { email, folders, last_sent_at: None, ..subscription };
+    sqlx::query(
+        "INSERT INTO digest_subscriptions (email, config_json) VALUES (?, ?)
+         ON CONFLICT(email) DO UPDATE SET config_json = excluded.config_json",
+    )
+    .bind(&subscription.email)
+    .bind(serde_json::to_string(&subscription).unwrap_or_default())
+    .execute(&state.db)
+    .await
+    .map_err(|e| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(serde_json::json!({ "detail": format!("Failed to save digest subscription: {}", e) })),
+        )
+    })?;
+    Ok(Json(serde_json::json!({ "email": subscription.email })))
+}
+
+/// 接口: DELETE /api/digests?email=...
+async fn delete_digest(State(state): State<AppState>, Query(query): Query<DigestQuery>) -> Json<serde_json::Value> {
+    let removed = sqlx::query("DELETE FROM digest_subscriptions WHERE email = ?")
+        .bind(query.email.trim())
+        .execute(&state.db)
+        .await
+        .map(|r| r.rows_affected())
+        .unwrap_or(0);
+    Json(serde_json::json!({ "removed": removed }))
+}
+
+/// 接口: POST /api/digests/send?email=...，立即发送一封摘要 (用于测试配置)
+#[cfg(feature = "smtp")]
+async fn send_digest_now(
+    State(state): State<AppState>,
+    Query(query): Query<DigestQuery>,
+) -> Result<Json<serde_json::Value>, (StatusCode, Json<serde_json::Value>)> {
+    let error = |status: StatusCode, detail: String| (status, Json(serde_json::json!({ "detail": detail })));
+    let Some(mailer) = state.mailer.clone() else {
+        return Err(error(StatusCode::SERVICE_UNAVAILABLE, "SMTP is not configured (GALLERY_SMTP_URL)".to_string()));
+    };
+    let Some(subscription) = load_digest_subscriptions(&state)
+        .await
+        .into_iter()
+        .find(|s| s.email.eq_ignore_ascii_case(query.email.trim()))
+    else {
+        return Err(error(StatusCode::NOT_FOUND, "Digest subscription not found".to_string()));
+    };
+    let images = digest::send_digest(&state, &mailer, &subscription)
+        .await
+        .map_err(|e| error(StatusCode::BAD_GATEWAY, format!("Failed to send digest: {:#}", e)))?;
+    Ok(Json(serde_json::json!({ "email": subscription.email, "images": images, "sent": images > 0 })))
+}
+
+#[cfg(not(feature = "smtp"))]
+async fn send_digest_now() -> (StatusCode, Json<serde_json::Value>) {
+    (
+        StatusCode::NOT_IMPLEMENTED,
+        Json(serde_json::json!({ "detail": "Email digests require the `smtp` build feature" })),
+    )
+}
+
+// --- 文件名规则 ---
+
+/// 单个文件名的最大字节数 (ext4、NTFS、APFS 等的共同上限)
+const FILE_NAME_MAX_BYTES: usize = 255;
+/// Windows 不允许出现在文件名中的字符
+const WINDOWS_FORBIDDEN_CHARS: &[char] = &['<', '>', ':', '"', '/', '\\', '|', '?', '*'];
+/// Windows 保留的设备名 (不区分大小写，带扩展名同样不可用)
+const WINDOWS_RESERVED_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8", "COM9", "LPT1", "LPT2",
+    "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// 截断到不超过 `max` 字节 (不切开多字节字符)
+fn truncate_to_bytes(s: &str, max: usize) -> &str {
+    let mut end = s.len().min(max);
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    &s[..end]
+}
+
+/// 文件名超长时截断主干部分，保留扩展名
+fn truncate_file_name(name: &str) -> String {
+    if name.len() <= FILE_NAME_MAX_BYTES {
+        return name.to_string();
+    }
+    let (stem, ext) = match name.rsplit_once('.') {
+        Some((stem, ext)) if !stem.is_empty() && ext.len() < 16 => (stem, format!(".{}", ext)),
+        _ => (name, String::new()),
+    };
+    format!("{}{}", truncate_to_bytes(stem, FILE_NAME_MAX_BYTES - ext.len()).trim_end_matches(['.', ' ']), ext)
+}
+
+/// 写入 ROOT_DIR 的文件或文件夹名 (导入、移动等) 须在所有平台上都能原样保存。
+/// 默认修正问题：替换控制字符与 Windows 禁用字符、去掉开头的空格和结尾的点与空格、避开设备名、截断过长的名称；
+/// `GALLERY_STRICT_FILE_NAMES` 开启时改为拒绝，错误信息说明原因
+fn sanitize_file_name(name: &str, strict: bool) -> Result<String, String> {
+    let name: String = name.nfc().collect();
+    if name.trim_matches(['.', ' ']).is_empty() {
+        return Err(format!("File name {:?} has no usable characters", name));
+    }
+    let stem = name.split('.').next().unwrap_or_default().trim_end();
+    let reserved = WINDOWS_RESERVED_NAMES.iter().any(|r| r.eq_ignore_ascii_case(stem));
+
+    let mut problems = Vec::new();
+    if name.chars().any(char::is_control) {
+        problems.push("contains control characters".to_string());
+    }
+    if name.contains(WINDOWS_FORBIDDEN_CHARS) {
+        problems.push("contains characters Windows cannot store (< > : \" / \\ | ? *)".to_string());
+    }
+    if name.starts_with(' ') {
+        problems.push("starts with a space".to_string());
+    }
+    if name.ends_with(['.', ' ']) {
+        problems.push("ends with a dot or space, which Windows drops".to_string());
+    }
+    if reserved {
+        problems.push(format!("uses the reserved Windows device name {:?}", stem));
+    }
+    if name.len() > FILE_NAME_MAX_BYTES {
+        problems.push(format!("is longer than {} bytes", FILE_NAME_MAX_BYTES));
+    }
+    if problems.is_empty() {
+        return Ok(name);
+    }
+    if strict {
+        return Err(format!("File name {:?} {}; rename it and try again", name, problems.join(", ")));
+    }
+
+    let replaced: String = name
+        .chars()
+        .map(|c| if c.is_control() || WINDOWS_FORBIDDEN_CHARS.contains(&c) { '_' } else { c })
+        .collect();
+    let mut cleaned = replaced.trim_start_matches(' ').trim_end_matches(['.', ' ']).to_string();
+    if reserved {
+        cleaned.insert(stem.len(), '_');
+    }
+    Ok(truncate_file_name(&cleaned))
+}
+
+/// 对相对路径的每一段应用 `sanitize_file_name`
+fn sanitize_rel_path(rel: &str, strict: bool) -> Result<String, String> {
+    if rel.is_empty() {
+        return Ok(String::new());
+    }
+    let segments = rel.split('/').map(|segment| sanitize_file_name(segment, strict)).collect::<Result<Vec<_>, _>>()?;
+    Ok(segments.join("/"))
+}
+
+// --- 按日期整理的导入 ---
+
+#[derive(Debug, Default, Deserialize)]
+struct ImportRequest {
+    /// 暂存目录 (`GALLERY_IMPORT_DIR`) 下的子目录，默认整个暂存目录
+    source: Option<String>,
+    /// 导入到 ROOT_DIR 下的哪个文件夹，默认根目录 (即 `YYYY/MM/DD`)
+    destination: Option<String>,
+    /// 复制而不是移动 (保留暂存文件)
+    #[serde(default)]
+    copy: bool,
+    /// 只返回计划，不改动任何文件
+    #[serde(default)]
+    dry_run: bool,
+    /// 与图库中已有图片内容相同时的处理方式
+    #[serde(default)]
+    on_duplicate: DuplicatePolicy,
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum DuplicatePolicy {
+    /// 不导入，留在暂存目录 (默认)
+    #[default]
+    Skip,
+    /// 照常导入，在结果中用 `duplicate_of` 标出已有的副本，留给之后的去重工具处理
+    Flag,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum ImportStatus {
+    Imported,
+    Planned,
+    Duplicate,
+    Failed,
+}
+
+#[derive(Debug, Serialize)]
+struct ImportItem {
+    /// 相对暂存目录
+    source: String,
+    status: ImportStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    destination: Option<String>,
+    /// 库中 (或本批次中) 内容相同的图片；`on_duplicate: flag` 时导入的图片也会给出
+    #[serde(skip_serializing_if = "Option::is_none")]
+    duplicate_of: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// 同一时间只允许一个导入任务
+static IMPORT_LOCK: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+/// 导入文件的日期文件夹：EXIF 拍摄时间 (按原始时钟)，缺失时用本地时区的修改时间
+fn import_date_folder(full_path: &Path) -> String {
+    if let Some(taken_at) = read_exif_taken_at(full_path) {
+        if let Some(t) = chrono::DateTime::from_timestamp(taken_at as i64, 0) {
+            return t.format("%Y/%m/%d").to_string();
+        }
+    }
+    let modified = full_path
+        .metadata()
+        .and_then(|m| m.modified())
+        .map(chrono::DateTime::<chrono::Local>::from)
+        .unwrap_or_else(|_| chrono::Local::now());
+    modified.format("%Y/%m/%d").to_string()
+}
+
+/// 在目标文件夹中找一个不冲突的文件名 (`name.jpg`、`name-1.jpg`、`name-2.jpg` …)；
+/// `taken` 记录本批次已分配但尚未落盘的路径
+fn import_target_name(dir: &Path, file_name: &str, taken: &HashSet<PathBuf>) -> PathBuf {
+    let (stem, ext) = match file_name.rsplit_once('.') {
+        Some((stem, ext)) if !stem.is_empty() => (stem, format!(".{}", ext)),
+        _ => (file_name, String::new()),
+    };
+    (0..)
+        .map(|n| match n {
+            0 => dir.join(file_name),
+            n => {
+                let suffix = format!("-{}{}", n, ext);
+                dir.join(format!("{}{}", truncate_to_bytes(stem, FILE_NAME_MAX_BYTES - suffix.len()), suffix))
+            }
+        })
+        .find(|candidate| !candidate.exists() && !taken.contains(candidate))
+        .expect("unbounded candidate names")
+}
+
+/// 移动文件；跨文件系统时退化为复制后删除
+fn move_or_copy(from: &Path, to: &Path, copy: bool) -> std::io::Result<()> {
+    if let Some(parent) = to.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    if !copy && std::fs::rename(from, to).is_ok() {
+        return Ok(());
+    }
+    std::fs::copy(from, to)?;
+    if !copy {
+        std::fs::remove_file(from)?;
+    }
+    Ok(())
+}
+
+/// 在索引中查找内容相同的图片 (按大小预筛，缺失的哈希顺带补齐)
+async fn find_indexed_duplicate(state: &AppState, hash: &str, size: i64) -> Option<String> {
+    let known: Option<(String,)> = sqlx::query_as("SELECT path FROM images WHERE hash = ? AND missing_since IS NULL LIMIT 1")
+        .bind(hash)
+        .fetch_optional(&state.db)
+        .await
+        .ok()
+        .flatten();
+    if let Some((path,)) = known {
+        return Some(path);
+    }
+    let candidates: Vec<(String,)> = sqlx::query_as("SELECT path FROM images WHERE size = ? AND hash IS NULL AND missing_since IS NULL")
+        .bind(size)
+        .fetch_all(&state.db)
+        .await
+        .unwrap_or_default();
+    for (path,) in candidates {
+        let full = resolve_full_path(&state.root_dir, &path);
+        if ensure_image_hash(&state.db, &path, &full).await.as_deref() == Some(hash) {
+            return Some(path);
+        }
+    }
+    None
+}
+
+/// 接口: POST /api/import，把暂存目录中的图片按拍摄日期归档到 `YYYY/MM/DD` 并立即索引
+/// (带 `Prefer: respond-async` 时在后台执行，返回任务 ID)
+async fn import_images(
+    State(state): State<AppState>,
+    headers: axum::http::HeaderMap,
+    body: Option<Json<ImportRequest>>,
+) -> Result<Response, (StatusCode, Json<serde_json::Value>)> {
+    let req = body.map(|Json(req)| req).unwrap_or_default();
+    if prefers_async(&headers) {
+        let job_state = state.clone();
+        let id = spawn_job(&state, "import", move |job| async move {
+            run_import(job_state, req, Some(job)).await.map(|Json(result)| result).map_err(job_error)
+        })
+        .await;
+        return Ok(job_accepted(&state, &id));
+    }
+    run_import(state, req, None).await.map(IntoResponse::into_response)
+}
+
+async fn run_import(
+    state: AppState,
+    req: ImportRequest,
+    job: Option<JobContext>,
+) -> Result<Json<serde_json::Value>, (StatusCode, Json<serde_json::Value>)> {
+    let error = |status: StatusCode, detail: &str| (status, Json(serde_json::json!({ "detail": detail })));
+    let Some(import_dir) = state.settings.own("GALLERY_IMPORT_DIR").filter(|v| !v.trim().is_empty()).map(PathBuf::from)
+    else {
+        return Err(error(StatusCode::SERVICE_UNAVAILABLE, "Imports require GALLERY_IMPORT_DIR (GALLERY_PROFILE_{NAME}_IMPORT_DIR for profiles)"));
+    };
+    // 暂存目录位于图库内时，每个待导入文件都已被索引，查重会把它报告为自身的重复
+    let canonical = |dir: &Path| dir.canonicalize().unwrap_or_else(|_| dir.to_path_buf());
+    let (staging, library) = (canonical(&import_dir), canonical(&state.root_dir));
+    if staging.starts_with(&library) || library.starts_with(&staging) {
+        return Err(error(StatusCode::SERVICE_UNAVAILABLE, "GALLERY_IMPORT_DIR must not overlap ROOT_DIR"));
+    }
+
+    // 暂存子目录与目标文件夹都不允许越界
+    let source_rel = normalize_rel_path(req.source.as_deref().unwrap_or(""));
+    let source_dir = match resolve_and_authorize(&import_dir, &source_rel, false) {
+        Ok(dir) if dir.is_dir() => dir,
+        Err(PathAccessError::Forbidden) => return Err(error(StatusCode::FORBIDDEN, "source must stay inside GALLERY_IMPORT_DIR")),
+        _ => return Err(error(StatusCode::NOT_FOUND, "Import source folder not found")),
+    };
+    let strict_names = state.settings.flag("GALLERY_STRICT_FILE_NAMES");
+    let destination_rel = sanitize_rel_path(&normalize_rel_path(req.destination.as_deref().unwrap_or("")), strict_names)
+        .map_err(|detail| error(StatusCode::BAD_REQUEST, &detail))?;
+    let destination_dir = match resolve_and_authorize(&state.root_dir, &destination_rel, false) {
+        Ok(dir) => dir,
+        Err(PathAccessError::Forbidden) => return Err(error(StatusCode::FORBIDDEN, "destination must stay inside ROOT_DIR")),
+        Err(PathAccessError::NotFound) => state.root_dir.join(&destination_rel),
+    };
+
+    let Ok(_guard) = IMPORT_LOCK.try_lock() else {
+        return Err(error(StatusCode::CONFLICT, "Another import is already running"));
+    };
+    let started = Instant::now();
+    let follow_symlinks = state.follow_symlinks;
+    let walk_dir = source_dir.clone();
+    let files: Vec<PathBuf> = tokio::task::spawn_blocking(move || {
+        let mut files: Vec<PathBuf> = walk_image_files(&walk_dir, follow_symlinks).map(|e| e.into_path()).collect();
+        files.sort();
+        files
+    })
+    .await
+    .unwrap_or_default();
+
+    let mut items = Vec::with_capacity(files.len());
+    let mut batch_hashes: HashMap<String, String> = HashMap::new();
+    let mut taken: HashSet<PathBuf> = HashSet::new();
+    let mut imported_paths = Vec::new();
+
+    let total = files.len();
+    for (done, file) in files.into_iter().enumerate() {
+        if let Some(job) = &job {
+            job.set_progress(done, total).await;
+        }
+        let source = file.strip_prefix(&import_dir).unwrap_or(&file).to_string_lossy().replace('\\', "/");
+        let mut item = ImportItem { source, status: ImportStatus::Failed, destination: None, duplicate_of: None, error: None };
+
+        let probe = file.clone();
+        let Ok(Some((hash, size, date_folder))) = tokio::task::spawn_blocking(move || {
+            let hash = compute_file_hash(&probe)?;
+            let size = probe.metadata().ok()?.len() as i64;
+            Some((hash, size, import_date_folder(&probe)))
+        })
+        .await
+        else {
+            item.error = Some("Failed to read file".to_string());
+            items.push(item);
+            continue;
+        };
+
+        let duplicate = match batch_hashes.get(&hash) {
+            Some(path) => Some(path.clone()),
+            None => find_indexed_duplicate(&state, &hash, size).await,
+        };
+        if let Some(existing) = duplicate {
+            item.duplicate_of = Some(existing);
+            if req.on_duplicate == DuplicatePolicy::Skip {
+                item.status = ImportStatus::Duplicate;
+                items.push(item);
+                continue;
+            }
+        }
+
+        let file_name = file.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+        let file_name = match sanitize_file_name(&file_name, strict_names) {
+            Ok(name) => name,
+            Err(detail) => {
+                item.error = Some(detail);
+                items.push(item);
+                continue;
+            }
+        };
+        let target = import_target_name(&destination_dir.join(&date_folder), &file_name, &taken);
+        let Some(rel) = db_path_key(&state.root_dir, &target) else {
+            item.error = Some("Destination is outside ROOT_DIR".to_string());
+            items.push(item);
+            continue;
+        };
+        taken.insert(target.clone());
+        batch_hashes.entry(hash.clone()).or_insert_with(|| rel.clone());
+        item.destination = Some(rel.clone());
+        if req.dry_run {
+            item.status = ImportStatus::Planned;
+            items.push(item);
+            continue;
+        }
+
+        let (from, to, copy, root) = (file.clone(), target.clone(), req.copy, state.root_dir.clone());
+        let moved = tokio::task::spawn_blocking(move || {
+            move_or_copy(&from, &to, copy).map_err(|e| e.to_string())?;
+            Ok::<_, String>(process_image_metadata_sync(&to, &root))
+        })
+        .await
+        .unwrap_or_else(|e| Err(e.to_string()));
+        match moved {
+            Ok(meta) => {
+                if let Some(meta) = meta {
+                    if let Ok(mut conn) = state.db.acquire().await {
+                        upsert_image_row(&mut conn, &meta).await.ok();
+                    }
+                    sqlx::query("UPDATE images SET hash = ? WHERE path = ?")
+                        .bind(&hash)
+                        .bind(&rel)
+                        .execute(&state.db)
+                        .await
+                        .ok();
+                }
+                item.status = ImportStatus::Imported;
+                imported_paths.push(rel);
+            }
+            Err(err) => {
+                tracing::warn!("⚠️ Import of {} failed: {}", item.source, err);
+                if batch_hashes.get(&hash) == Some(&rel) {
+                    batch_hashes.remove(&hash);
+                }
+                item.error = Some(err);
+            }
+        }
+        items.push(item);
+    }
+
+    if !imported_paths.is_empty() {
+        invalidate_playlist_cache(&state).await;
+        publish_library_changes(&state, &imported_paths, &[]);
+        #[cfg(feature = "notify")]
+        if let Some(notifier) = &state.notifier {
+            notifier.images_added(&imported_paths);
+        }
+    }
+    let count = |status: ImportStatus| items.iter().filter(|i| i.status == status).count();
+    let (imported, duplicates, failed) = (count(ImportStatus::Imported), count(ImportStatus::Duplicate), count(ImportStatus::Failed));
+    let flagged = items
+        .iter()
+        .filter(|i| matches!(i.status, ImportStatus::Imported | ImportStatus::Planned) && i.duplicate_of.is_some())
+        .count();
+    tracing::info!(
+        "📥 Import finished in {:.2}s: {} imported ({} flagged as duplicates), {} duplicates skipped, {} failed",
+        started.elapsed().as_secs_f64(),
+        imported,
+        flagged,
+        duplicates,
+        failed
+    );
+    Ok(Json(serde_json::json!({
+        "dry_run": req.dry_run,
+        "imported": imported,
+        "planned": count(ImportStatus::Planned),
+        "duplicates": duplicates,
+        "flagged": flagged,
+        "failed": failed,
+        "items": items,
+    })))
+}
+
+// --- 文件夹元数据 ---
+
+/// `FolderMeta` 对应的 `folders` 列
+const FOLDER_META_COLUMNS: &str = "title, description, cover, sort, orientation, interval_secs, archived, archive_note";
+
+#[derive(sqlx::FromRow)]
+struct FolderRow {
+    path: String,
+    #[sqlx(flatten)]
+    meta: FolderMeta,
+}
+
+/// PATCH 语义：省略的字段保持不变，`null` 清除
+#[derive(Debug, Deserialize)]
+struct FolderPatch {
+    path: String,
+    #[serde(default, deserialize_with = "deserialize_present")]
+    title: Option<Option<String>>,
+    #[serde(default, deserialize_with = "deserialize_present")]
+    description: Option<Option<String>>,
+    #[serde(default, deserialize_with = "deserialize_present")]
+    cover: Option<Option<String>>,
+    #[serde(default, deserialize_with = "deserialize_present")]
+    sort: Option<Option<String>>,
+    #[serde(default, deserialize_with = "deserialize_present")]
+    orientation: Option<Option<String>>,
+    #[serde(default, deserialize_with = "deserialize_present")]
+    interval_secs: Option<Option<u32>>,
+    archived: Option<bool>,
+    #[serde(default, deserialize_with = "deserialize_present")]
+    archive_note: Option<Option<String>>,
+}
+
+/// 区分 "字段缺失" (None) 与 "显式 null" (Some(None))
+fn deserialize_present<'de, T, D>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    T: Deserialize<'de>,
+    D: serde::Deserializer<'de>,
+{
+    T::deserialize(deserializer).map(Some)
+}
+
+const FOLDER_TITLE_MAX_CHARS: usize = 200;
+const FOLDER_DESCRIPTION_MAX_CHARS: usize = 5000;
+/// 文件夹默认播放间隔的上限 (秒)
+const FOLDER_INTERVAL_MAX_SECS: u32 = 86400;
+
+async fn load_folder_meta(state: &AppState, path: &str) -> Option<FolderMeta> {
+    sqlx::query_as(&format!("SELECT {} FROM folders WHERE path = ?", FOLDER_META_COLUMNS))
+        .bind(path)
+        .fetch_optional(&state.db)
+        .await
+        .ok()
+        .flatten()
+}
+
+/// 接口: PATCH /api/folder，编辑文件夹标题、描述、封面、默认播放设置与归档状态
+async fn patch_folder(
+    State(state): State<AppState>,
+    Json(patch): Json<FolderPatch>,
+) -> Result<Json<serde_json::Value>, (StatusCode, Json<serde_json::Value>)> {
+    let error = |status: StatusCode, detail: String| (status, Json(serde_json::json!({ "detail": detail })));
+    let allow_parent = *state.allow_parent_dir_access.read().await;
+    let mut rel = normalize_rel_path(&patch.path);
+    if rel == "." {
+        rel.clear();
+    }
+    match resolve_and_authorize(&state.root_dir, &rel, allow_parent) {
+        Ok(full) if full.is_dir() => {}
+        Err(PathAccessError::Forbidden) => {
+            return Err(error(StatusCode::FORBIDDEN, "Access outside ROOT_DIR is disabled".to_string()))
+        }
+        // 离线的归档文件夹 (磁盘未挂载) 只要仍有索引记录也可以编辑
+        _ if has_indexed_images(&state, &rel).await => {}
+        _ => return Err(error(StatusCode::NOT_FOUND, "Folder not found".to_string())),
+    }
+
+    // 空字符串等同于清除
+    let clean = |value: Option<String>| value.map(|v| v.trim().to_string()).filter(|v| !v.is_empty());
+    let mut meta = load_folder_meta(&state, &rel).await.unwrap_or_default();
+    if let Some(title) = patch.title {
+        meta.title = clean(title);
+    }
+    if let Some(description) = patch.description {
+        meta.description = clean(description);
+    }
+    // 只在修改封面时检查，离线的归档文件夹可以保留原有封面
+    let cover_changed = patch.cover.is_some();
+    if let Some(cover) = patch.cover {
+        meta.cover = clean(cover).map(|c| normalize_rel_path(&c));
+    }
+    if let Some(sort) = patch.sort {
+        meta.sort = clean(sort);
+    }
+    if let Some(orientation) = patch.orientation {
+        meta.orientation = clean(orientation);
+    }
+    if let Some(interval_secs) = patch.interval_secs {
+        meta.interval_secs = interval_secs;
+    }
+    let newly_archived = patch.archived == Some(true) && !meta.archived;
+    if let Some(archived) = patch.archived {
+        meta.archived = archived;
+    }
+    if let Some(note) = patch.archive_note {
+        meta.archive_note = clean(note);
+    }
+
+    if meta.title.as_ref().is_some_and(|t| t.chars().count() > FOLDER_TITLE_MAX_CHARS) {
+        return Err(error(StatusCode::BAD_REQUEST, format!("title is limited to {} characters", FOLDER_TITLE_MAX_CHARS)));
+    }
+    if meta.description.as_ref().is_some_and(|d| d.chars().count() > FOLDER_DESCRIPTION_MAX_CHARS) {
+        return Err(error(
+            StatusCode::BAD_REQUEST,
+            format!("description is limited to {} characters", FOLDER_DESCRIPTION_MAX_CHARS),
+        ));
+    }
+    if let Some(sort) = &meta.sort {
+        if !SORT_MODES.contains(&sort.as_str()) {
+            return Err(error(StatusCode::BAD_REQUEST, format!("Unknown sort mode {:?}", sort)));
+        }
+    }
+    if let Some(orientation) = &meta.orientation {
+        if !ORIENTATIONS.contains(&orientation.as_str()) {
+            return Err(error(StatusCode::BAD_REQUEST, format!("Unknown orientation {:?}", orientation)));
+        }
+    }
+    if meta.interval_secs.is_some_and(|i| !(1..=FOLDER_INTERVAL_MAX_SECS).contains(&i)) {
+        return Err(error(
+            StatusCode::BAD_REQUEST,
+            format!("interval_secs must be between 1 and {}", FOLDER_INTERVAL_MAX_SECS),
+        ));
+    }
+    if let Some(cover) = meta.cover.as_ref().filter(|_| cover_changed) {
+        let in_folder = rel.is_empty() || cover.strip_prefix(rel.as_str()).is_some_and(|rest| rest.starts_with('/'));
+        let is_image = resolve_and_authorize(&state.root_dir, cover, allow_parent)
+            .is_ok_and(|full| full.is_file() && is_image_ext(&full));
+        if !in_folder || !is_image {
+            return Err(error(StatusCode::BAD_REQUEST, "cover must be an image inside the folder".to_string()));
+        }
+    }
+
+    let result = if meta.is_empty() {
+        sqlx::query("DELETE FROM folders WHERE path = ?").bind(&rel).execute(&state.db).await
+    } else {
+        sqlx::query(
+            "INSERT INTO folders (path, title, description, cover, sort, orientation, interval_secs, archived, archive_note, updated_at)
+             VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+             ON CONFLICT(path) DO UPDATE SET title = excluded.title, description = excluded.description,
+                 cover = excluded.cover, sort = excluded.sort, orientation = excluded.orientation,
+                 interval_secs = excluded.interval_secs, archived = excluded.archived,
+                 archive_note = excluded.archive_note, updated_at = excluded.updated_at",
+        )
+        .bind(&rel)
+        .bind(&meta.title)
+        .bind(&meta.description)
+        .bind(&meta.cover)
+        .bind(&meta.sort)
+        .bind(&meta.orientation)
+        .bind(meta.interval_secs)
+        .bind(meta.archived)
+        .bind(&meta.archive_note)
+        .bind(SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs_f64())
+        .execute(&state.db)
+        .await
+    };
+    result.map_err(|e| error(StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to save folder metadata: {}", e)))?;
+
+    // 归档前磁盘已经离线的话，扫描可能已把其中的图片标记为缺失：归档即声明原片仍然存在
+    if newly_archived {
+        let restored = sqlx::query(
+            "UPDATE images SET missing_since = NULL WHERE missing_since IS NOT NULL AND path LIKE ? ESCAPE '\\'",
+        )
+        .bind(folder_like_pattern(&rel))
+        .execute(&state.db)
+        .await
+        .map(|r| r.rows_affected())
+        .unwrap_or(0);
+        tracing::info!("🗄️ 文件夹 {:?} 已归档，恢复缺失标记 {} 条", rel, restored);
+    }
+    Ok(Json(serde_json::json!({ "path": rel, "meta": meta })))
+}
+
+// --- 冷存储 (归档文件夹) ---
+
+/// 归档文件夹中离线原片的状态码 (非标准)。与 404 不同，它表示索引记录仍然有效、
+/// 只是原片暂时不可读，客户端应保留该图片并显示提示，而不是当作已删除
+const ARCHIVED_OFFLINE_STATUS: u16 = 453;
+
+/// 匹配文件夹下所有路径的 LIKE 模式 (配合 `ESCAPE '\\'`；根目录匹配全部)
+fn folder_like_pattern(folder: &str) -> String {
+    if folder.is_empty() {
+        "%".to_string()
+    } else {
+        format!("{}/%", escape_like_pattern(folder))
+    }
+}
+
+async fn has_indexed_images(state: &AppState, folder: &str) -> bool {
+    sqlx::query_scalar("SELECT EXISTS (SELECT 1 FROM images WHERE path LIKE ? ESCAPE '\\')")
+        .bind(folder_like_pattern(folder))
+        .fetch_one(&state.db)
+        .await
+        .unwrap_or(false)
+}
+
+/// 所有归档文件夹 (路径, 存放位置说明)
+async fn archived_folders(pool: &Pool<Sqlite>) -> Vec<(String, Option<String>)> {
+    sqlx::query_as("SELECT path, archive_note FROM folders WHERE archived")
+        .fetch_all(pool)
+        .await
+        .unwrap_or_default()
+}
+
+/// 包含该路径的 (最内层) 归档文件夹
+fn archived_folder_of<'a>(folders: &'a [(String, Option<String>)], path: &str) -> Option<&'a (String, Option<String>)> {
+    folders
+        .iter()
+        .filter(|(folder, _)| {
+            folder.is_empty() || path.strip_prefix(folder.as_str()).is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+        })
+        .max_by_key(|(folder, _)| folder.len())
+}
+
+/// 原片读不到时：位于归档文件夹且仍在索引中则返回 453 与提示，否则为 None (照常 404)
+async fn archived_offline_response(state: &AppState, rel: &str) -> Option<Response> {
+    let folders = archived_folders(&state.db).await;
+    let (folder, note) = archived_folder_of(&folders, rel)?;
+    let indexed: bool = sqlx::query_scalar(&format!(
+        "SELECT EXISTS (SELECT 1 FROM images WHERE path = ? AND {})",
+        PRESENT_SQL_FILTER
+    ))
+    .bind(rel)
+    .fetch_one(&state.db)
+    .await
+    .unwrap_or(false);
+    if !indexed {
+        return None;
+    }
+    let hint = match note {
+        Some(note) => format!("The original is stored offline ({}). Reconnect the storage to view it.", note),
+        None => "The original is stored offline. Reconnect the storage to view it.".to_string(),
+    };
+    Some(
+        (
+            StatusCode::from_u16(ARCHIVED_OFFLINE_STATUS).unwrap(),
+            Json(serde_json::json!({
+                "detail": "Original is in an archived folder that is offline",
+                "archived": true,
+                "folder": folder,
+                "archive_note": note,
+                "hint": hint,
+            })),
+        )
+            .into_response(),
+    )
+}
+
+/// 离线归档文件夹的内容 (来自索引)：直接包含的图片与子文件夹
+async fn browse_offline_folder(state: &AppState, rel: &str) -> Vec<BrowseItem> {
+    let paths: Vec<(String,)> = sqlx::query_as(&format!(
+        "SELECT path FROM images WHERE path LIKE ? ESCAPE '\\' AND {}",
+        PRESENT_SQL_FILTER
+    ))
+    .bind(folder_like_pattern(rel))
+    .fetch_all(&state.db)
+    .await
+    .unwrap_or_default();
+    let mut folders = std::collections::BTreeSet::new();
+    let mut items = Vec::new();
+    for (path,) in paths {
+        let rest = if rel.is_empty() { path.as_str() } else { &path[rel.len() + 1..] };
+        match rest.split_once('/') {
+            Some((name, _)) => {
+                folders.insert(name.to_string());
+            }
+            None => items.push(BrowseItem {
+                name: rest.to_string(),
+                path: path.clone(),
+                item_type: "file".to_string(),
+                meta: None,
+                file_id: None,
+            }),
+        }
+    }
+    items.extend(folders.into_iter().map(|name| BrowseItem {
+        path: if rel.is_empty() { name.clone() } else { format!("{}/{}", rel, name) },
+        name,
+        item_type: "folder".to_string(),
+        meta: None,
+        file_id: None,
+    }));
+    items
+}
+
+// --- 相册内手动排序 ---
+
+#[derive(Debug, Deserialize)]
+struct FolderOrderRequest {
+    path: String,
+    /// 该文件夹内图片的新顺序 (拖放后的完整列表)；未列出的图片排在后面按名称排序
+    order: Vec<String>,
+}
+
+/// 所有手动排序位置 (`sort=manual` 使用)
+async fn load_manual_positions(state: &AppState) -> HashMap<String, i64> {
+    let rows: Vec<(String, i64)> = sqlx::query_as("SELECT path, position FROM image_positions")
+        .fetch_all(&state.db)
+        .await
+        .unwrap_or_default();
+    rows.into_iter().collect()
+}
+
+/// 文件夹内的手动顺序比较：有位置的在前，其余按名称
+fn compare_manual(a: &str, b: &str, positions: &HashMap<String, i64>, collator: &NameCollator) -> std::cmp::Ordering {
+    use std::cmp::Ordering;
+    match (positions.get(a), positions.get(b)) {
+        (Some(x), Some(y)) => x.cmp(y),
+        (Some(_), None) => Ordering::Less,
+        (None, Some(_)) => Ordering::Greater,
+        (None, None) => collator.compare(a, b),
+    }
+}
+
+fn folder_order_key(path: &str) -> Result<String, (StatusCode, Json<serde_json::Value>)> {
+    let rel = normalize_rel_path(path);
+    if is_external_key(&rel) {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            Json(serde_json::json!({ "detail": "Manual order is only supported inside ROOT_DIR" })),
+        ));
+    }
+    Ok(if rel == "." { String::new() } else { rel })
+}
+
+/// 接口: GET /api/folder/order?path=...，返回文件夹 (不含子文件夹) 内图片的当前手动顺序
+async fn get_folder_order(
+    State(state): State<AppState>,
+    Query(query): Query<BrowseQuery>,
+) -> Result<Json<serde_json::Value>, (StatusCode, Json<serde_json::Value>)> {
+    let folder = folder_order_key(&query.path)?;
+    const SELECT: &str = "SELECT i.path, p.position FROM images i LEFT JOIN image_positions p ON p.path = i.path";
+    let rows: Vec<(String, Option<i64>)> = if folder.is_empty() {
+        // 外部路径键都含有 '/'，不会混进根目录
+        sqlx::query_as(&format!("{} WHERE i.path NOT LIKE '%/%' AND i.{}", SELECT, PRESENT_SQL_FILTER))
+            .fetch_all(&state.db)
+            .await
+    } else {
+        sqlx::query_as(&format!(
+            "{} WHERE i.path LIKE ? ESCAPE '\\' AND i.path NOT LIKE ? ESCAPE '\\' AND i.{}",
+            SELECT, PRESENT_SQL_FILTER
+        ))
+            .bind(format!("{}/%", escape_like_pattern(&folder)))
+            .bind(format!("{}/%/%", escape_like_pattern(&folder)))
+            .fetch_all(&state.db)
+            .await
+    }
+    .unwrap_or_default();
+
+    let ranked = rows.iter().filter(|(_, position)| position.is_some()).count();
+    let positions: HashMap<String, i64> =
+        rows.iter().filter_map(|(path, position)| Some((path.clone(), (*position)?))).collect();
+    let collator = NameCollator::for_locale(state.default_collation.as_deref());
+    let mut paths: Vec<String> = rows.into_iter().map(|(path, _)| path).collect();
+    paths.sort_by(|a, b| compare_manual(a, b, &positions, &collator));
+    Ok(Json(serde_json::json!({ "path": folder, "ranked": ranked, "order": paths })))
+}
+
+/// 接口: PUT /api/folder/order，保存拖放后的顺序 (空列表即清除手动排序)
+async fn set_folder_order(
+    State(state): State<AppState>,
+    Json(req): Json<FolderOrderRequest>,
+) -> Result<Json<serde_json::Value>, (StatusCode, Json<serde_json::Value>)> {
+    let folder = folder_order_key(&req.path)?;
+    let mut seen = HashSet::new();
+    let mut order = Vec::with_capacity(req.order.len());
+    for path in &req.order {
+        let path = normalize_rel_path(path);
+        if parent_folder(&path) != folder {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                Json(serde_json::json!({ "detail": format!("{} is not directly inside {:?}", path, folder) })),
+            ));
+        }
+        if seen.insert(path.clone()) {
+            order.push(path);
+        }
+    }
+
+    // 只记录已索引的图片 (位置挂在索引记录上)，其余的排在末尾
+    let indexed = indexed_paths(&state.db, &order).await;
+    order.retain(|path| indexed.contains(path));
+
+    let internal_error =
+        |e: sqlx::Error| (StatusCode::INTERNAL_SERVER_ERROR, Json(serde_json::json!({ "detail": e.to_string() })));
+    let mut tx = state.db.begin().await.map_err(internal_error)?;
+    sqlx::query("DELETE FROM image_positions WHERE folder = ?")
+        .bind(&folder)
+        .execute(&mut *tx)
+        .await
+        .map_err(internal_error)?;
+    for (position, path) in order.iter().enumerate() {
+        sqlx::query("INSERT OR REPLACE INTO image_positions (path, folder, position) VALUES (?, ?, ?)")
+            .bind(path)
+            .bind(&folder)
+            .bind(position as i64)
+            .execute(&mut *tx)
+            .await
+            .map_err(internal_error)?;
+    }
+    tx.commit().await.map_err(internal_error)?;
+    invalidate_playlist_cache(&state).await;
+    Ok(Json(serde_json::json!({ "path": folder, "ranked": order.len() })))
+}
+
+// --- 图片标题与说明 ---
+
+/// PATCH 语义同 `FolderPatch`：省略的字段保持不变，`null` 或空字符串清除
+#[derive(Debug, Deserialize)]
+struct CaptionPatch {
+    path: String,
+    #[serde(default, deserialize_with = "deserialize_present")]
+    title: Option<Option<String>>,
+    #[serde(default, deserialize_with = "deserialize_present")]
+    caption: Option<Option<String>>,
+    /// 0 或 `null` 清除
+    #[serde(default, deserialize_with = "deserialize_present")]
+    rating: Option<Option<i64>>,
+    /// 同时写回 XMP 附属文件；默认取 `GALLERY_XMP_SIDECARS`
+    write_xmp: Option<bool>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ImageInfoBatchRequest {
+    paths: Vec<String>,
+}
+
+const IMAGE_TITLE_MAX_CHARS: usize = 200;
+const IMAGE_CAPTION_MAX_CHARS: usize = 5000;
+const IMAGE_INFO_BATCH_MAX: usize = 200;
+
+async fn load_image_caption(db: &Pool<Sqlite>, path: &str) -> ImageCaption {
+    sqlx::query_as("SELECT title, caption, rating FROM image_captions WHERE path = ?")
+        .bind(path)
+        .fetch_optional(db)
+        .await
+        .ok()
+        .flatten()
+        .unwrap_or_default()
+}
+
+/// 编辑记录的最大曝光补偿 (EV)
+const EDIT_MAX_EXPOSURE: f32 = 4.0;
+/// 裁剪区域的最小边长 (比例)
+const EDIT_MIN_CROP: f32 = 0.01;
+
+#[derive(sqlx::FromRow)]
+struct ImageEditRow {
+    crop_x: Option<f32>,
+    crop_y: Option<f32>,
+    crop_w: Option<f32>,
+    crop_h: Option<f32>,
+    rotation: f32,
+    exposure: f32,
+}
+
+async fn load_image_edit(db: &Pool<Sqlite>, path: &str) -> Option<ImageEdit> {
+    let row: ImageEditRow =
+        sqlx::query_as("SELECT crop_x, crop_y, crop_w, crop_h, rotation, exposure FROM image_edits WHERE path = ?")
+            .bind(path)
+            .fetch_optional(db)
+            .await
+            .ok()
+            .flatten()?;
+    let crop = match (row.crop_x, row.crop_y, row.crop_w, row.crop_h) {
+        (Some(x), Some(y), Some(w), Some(h)) => Some(gallery_client::types::CropRect { x, y, w, h }),
+        _ => None,
+    };
+    Some(ImageEdit { crop, rotation: row.rotation, exposure: row.exposure })
+}
+
+fn validate_image_edit(edit: &ImageEdit) -> Result<(), String> {
+    if !(-180.0..=180.0).contains(&edit.rotation) {
+        return Err("rotation must be between -180 and 180 degrees".to_string());
+    }
+    if !(-EDIT_MAX_EXPOSURE..=EDIT_MAX_EXPOSURE).contains(&edit.exposure) {
+        return Err(format!("exposure must be between -{0} and {0} EV", EDIT_MAX_EXPOSURE));
+    }
+    if let Some(crop) = edit.crop {
+        let inside = crop.x >= 0.0 && crop.y >= 0.0 && crop.x + crop.w <= 1.0 + 1e-4 && crop.y + crop.h <= 1.0 + 1e-4;
+        if !inside || crop.w < EDIT_MIN_CROP || crop.h < EDIT_MIN_CROP {
+            return Err("crop must be a rectangle inside the image, in 0-1 coordinates".to_string());
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug, Deserialize)]
+struct ImageEditRequest {
+    path: String,
+    #[serde(flatten)]
+    edit: ImageEdit,
+}
+
+/// 接口: GET /api/edits?path=...
+async fn get_image_edit(
+    State(state): State<AppState>,
+    Query(query): Query<FileQuery>,
+) -> Result<Json<serde_json::Value>, (StatusCode, Json<serde_json::Value>)> {
+    let rel = normalize_rel_path(&query.path);
+    match load_image_edit(&state.db, &rel).await {
+        Some(edit) => Ok(Json(serde_json::json!({ "path": rel, "edit": edit }))),
+        None => Err((StatusCode::NOT_FOUND, Json(serde_json::json!({ "detail": "Image has no edits" })))),
+    }
+}
+
+/// 接口: PUT /api/edits，保存一张图片的裁剪、旋转与曝光 (整体替换；全为默认值时等同删除)
+async fn save_image_edit(
+    State(state): State<AppState>,
+    Json(req): Json<ImageEditRequest>,
+) -> Result<Json<serde_json::Value>, (StatusCode, Json<serde_json::Value>)> {
+    let error = |status: StatusCode, detail: String| (status, Json(serde_json::json!({ "detail": detail })));
+    let allow_parent = *state.allow_parent_dir_access.read().await;
+    let rel = normalize_rel_path(&req.path);
+    match resolve_and_authorize(&state.root_dir, &rel, allow_parent) {
+        Ok(full) if full.is_file() && is_image_ext(&full) => {}
+        Err(PathAccessError::Forbidden) => {
+            return Err(error(StatusCode::FORBIDDEN, "Access outside ROOT_DIR is disabled".to_string()))
+        }
+        _ => return Err(error(StatusCode::NOT_FOUND, "Image not found".to_string())),
+    }
+    validate_image_edit(&req.edit).map_err(|detail| error(StatusCode::BAD_REQUEST, detail))?;
+
+    let result = if req.edit.is_empty() {
+        sqlx::query("DELETE FROM image_edits WHERE path = ?").bind(&rel).execute(&state.db).await
+    } else {
+        if indexed_paths(&state.db, std::slice::from_ref(&rel)).await.is_empty() {
+            if let Err(err) = upsert_missing_path_to_db(&state.db, &state.root_dir, &rel, state.follow_symlinks).await {
+                return Err(error(StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to index image: {}", err)));
+            }
+        }
+        let crop = req.edit.crop;
+        sqlx::query(
+            "INSERT INTO image_edits (path, crop_x, crop_y, crop_w, crop_h, rotation, exposure, updated_at)
+             VALUES (?, ?, ?, ?, ?, ?, ?, ?)
+             ON CONFLICT(path) DO UPDATE SET crop_x = excluded.crop_x, crop_y = excluded.crop_y,
+                 crop_w = excluded.crop_w, crop_h = excluded.crop_h, rotation = excluded.rotation,
+                 exposure = excluded.exposure, updated_at = excluded.updated_at",
+        )
+        .bind(&rel)
+        .bind(crop.map(|c| c.x))
+        .bind(crop.map(|c| c.y))
+        .bind(crop.map(|c| c.w))
+        .bind(crop.map(|c| c.h))
+        .bind(req.edit.rotation)
+        .bind(req.edit.exposure)
+        .bind(SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs_f64())
+        .execute(&state.db)
+        .await
+    };
+    result.map_err(|e| error(StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to save edit: {}", e)))?;
+    let edit = (!req.edit.is_empty()).then_some(req.edit);
+    Ok(Json(serde_json::json!({ "path": rel, "edit": edit })))
+}
+
+/// 接口: DELETE /api/edits?path=...，恢复原图
+async fn delete_image_edit(State(state): State<AppState>, Query(query): Query<FileQuery>) -> Json<serde_json::Value> {
+    let removed = sqlx::query("DELETE FROM image_edits WHERE path = ?")
+        .bind(normalize_rel_path(&query.path))
+        .execute(&state.db)
+        .await
+        .map(|r| r.rows_affected())
+        .unwrap_or(0);
+    Json(serde_json::json!({ "removed": removed }))
+}
+
+/// XMP 附属文件：沿用已有的 `name.xmp` (Lightroom) 或 `name.jpg.xmp` (darktable / digiKam)，否则新建后者
+fn xmp_sidecar_path(image: &Path) -> PathBuf {
+    let mut appended = image.as_os_str().to_owned();
+    appended.push(".xmp");
+    let appended = PathBuf::from(appended);
+    let replaced = image.with_extension("xmp");
+    if !appended.exists() && replaced.exists() {
+        replaced
+    } else {
+        appended
+    }
+}
+
+fn xmp_lang_alt(tag: &str, value: &str) -> String {
+    format!(
+        "<{tag}><rdf:Alt><rdf:li xml:lang=\"x-default\">{}</rdf:li></rdf:Alt></{tag}>",
+        escape_html(value)
+    )
+}
+
+/// 删除 XMP 中某个属性形式的字段 (如 `xmp:Rating="3"`)
+fn xmp_remove_attribute(xml: &mut String, name: &str) {
+    let needle = format!(" {}=\"", name);
+    while let Some(start) = xml.find(&needle) {
+        let value_start = start + needle.len();
+        let Some(len) = xml[value_start..].find('"') else { break };
+        xml.replace_range(start..value_start + len + 1, "");
+    }
+}
+
+/// 删除 XMP 中的某个元素 (含内容)
+fn xmp_remove_element(xml: &mut String, tag: &str) {
+    let open = format!("<{}", tag);
+    let close = format!("</{}>", tag);
+    while let Some(start) = xml.find(&open) {
+        // 避免把 `<dc:titleX` 之类的同前缀元素当成目标
+        if !xml[start + open.len()..].starts_with(['>', ' ', '/', '\n', '\r', '\t']) {
+            break;
+        }
+        let end = match (xml[start..].find("/>"), xml[start..].find('>'), xml[start..].find(&close)) {
+            (Some(slash), Some(gt), _) if slash + 1 == gt => start + gt + 1,
+            (_, _, Some(close_at)) => start + close_at + close.len(),
+            _ => break,
+        };
+        xml.replace_range(start..end, "");
+    }
+}
+
+/// 把标题 (`dc:title`)、说明 (`dc:description`) 与评分 (`xmp:Rating`) 写入 XMP 附属文件，保留其它内容
+fn write_xmp_sidecar(image: &Path, caption: &ImageCaption) -> anyhow::Result<Option<PathBuf>> {
+    let sidecar = xmp_sidecar_path(image);
+    let existing = match std::fs::read_to_string(&sidecar) {
+        Ok(xml) => Some(xml),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+        Err(e) => return Err(e.into()),
+    };
+    let mut elements: String = [("dc:title", &caption.title), ("dc:description", &caption.caption)]
+        .iter()
+        .filter_map(|(tag, value)| value.as_deref().map(|v| xmp_lang_alt(tag, v)))
+        .collect();
+    if let Some(rating) = caption.rating {
+        elements.push_str(&format!("<xmp:Rating>{}</xmp:Rating>", rating));
+    }
+
+    let xml = match existing {
+        None if caption.is_empty() => return Ok(None),
+        None => format!(
+            "<?xpacket begin=\"\u{feff}\" id=\"W5M0MpCehiHzreSzNTczkc9d\"?>\n\
+             <x:xmpmeta xmlns:x=\"adobe:ns:meta/\">\n\
+             <rdf:RDF xmlns:rdf=\"http://www.w3.org/1999/02/22-rdf-syntax-ns#\">\n\
+             <rdf:Description rdf:about=\"\" xmlns:dc=\"http://purl.org/dc/elements/1.1/\" \
+             xmlns:xmp=\"http://ns.adobe.com/xap/1.0/\">{}</rdf:Description>\n\
+             </rdf:RDF>\n\
+             </x:xmpmeta>\n\
+             <?xpacket end=\"w\"?>\n",
+            elements
+        ),
+        Some(mut xml) => {
+            xmp_remove_element(&mut xml, "dc:title");
+            xmp_remove_element(&mut xml, "dc:description");
+            xmp_remove_element(&mut xml, "xmp:Rating");
+            xmp_remove_attribute(&mut xml, "xmp:Rating");
+            let Some(start) = xml.find("<rdf:Description") else {
+                anyhow::bail!("{} has no rdf:Description", sidecar.display());
+            };
+            let tag_end = start + xml[start..].find('>').ok_or_else(|| anyhow::anyhow!("malformed XMP"))?;
+            // 自闭合的 <rdf:Description .../> 展开成成对标签
+            if xml[..tag_end].ends_with('/') {
+                xml.replace_range(tag_end - 1..tag_end + 1, "></rdf:Description>");
+            }
+            for (prefix, uri) in [("dc", "http://purl.org/dc/elements/1.1/"), ("xmp", "http://ns.adobe.com/xap/1.0/")] {
+                if !xml.contains(&format!("xmlns:{}=", prefix)) {
+                    xml.insert_str(start + "<rdf:Description".len(), &format!(" xmlns:{}=\"{}\"", prefix, uri));
+                }
+            }
+            let close = xml.find("</rdf:Description>").ok_or_else(|| anyhow::anyhow!("malformed XMP"))?;
+            xml.insert_str(close, &elements);
+            xml
+        }
+    };
+    std::fs::write(&sidecar, xml)?;
+    Ok(Some(sidecar))
+}
+
+/// 接口: PATCH /api/info，编辑图片标题、说明与评分
+async fn patch_image_caption(
+    State(state): State<AppState>,
+    Json(patch): Json<CaptionPatch>,
+) -> Result<Json<serde_json::Value>, (StatusCode, Json<serde_json::Value>)> {
+    let error = |status: StatusCode, detail: String| (status, Json(serde_json::json!({ "detail": detail })));
+    let allow_parent = *state.allow_parent_dir_access.read().await;
+    let rel = normalize_rel_path(&patch.path);
+    let full = match resolve_and_authorize(&state.root_dir, &rel, allow_parent) {
+        Ok(full) if full.is_file() && is_image_ext(&full) => full,
+        Err(PathAccessError::Forbidden) => {
+            return Err(error(StatusCode::FORBIDDEN, "Access outside ROOT_DIR is disabled".to_string()))
+        }
+        _ => return Err(error(StatusCode::NOT_FOUND, "Image not found".to_string())),
+    };
+
+    let clean = |value: Option<String>| value.map(|v| v.trim().to_string()).filter(|v| !v.is_empty());
+    let mut caption = load_image_caption(&state.db, &rel).await;
+    if let Some(title) = patch.title {
+        caption.title = clean(title);
+    }
+    if let Some(text) = patch.caption {
+        caption.caption = clean(text);
+    }
+    if let Some(rating) = patch.rating {
+        caption.rating = rating.filter(|r| *r != 0);
+    }
+    if caption.rating.is_some_and(|r| !(1..=5).contains(&r)) {
+        return Err(error(StatusCode::BAD_REQUEST, "rating must be between 0 and 5".to_string()));
+    }
+    if caption.title.as_ref().is_some_and(|t| t.chars().count() > IMAGE_TITLE_MAX_CHARS) {
+        return Err(error(StatusCode::BAD_REQUEST, format!("title is limited to {} characters", IMAGE_TITLE_MAX_CHARS)));
+    }
+    if caption.caption.as_ref().is_some_and(|c| c.chars().count() > IMAGE_CAPTION_MAX_CHARS) {
+        return Err(error(
+            StatusCode::BAD_REQUEST,
+            format!("caption is limited to {} characters", IMAGE_CAPTION_MAX_CHARS),
+        ));
+    }
+
+    // 说明挂在索引记录上 (外键)，尚未扫描到的图片先补建索引
+    if !caption.is_empty() && indexed_paths(&state.db, std::slice::from_ref(&rel)).await.is_empty() {
+        if let Err(err) = upsert_missing_path_to_db(&state.db, &state.root_dir, &rel, state.follow_symlinks).await {
+            return Err(error(StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to index image: {}", err)));
+        }
+    }
+    let result = if caption.is_empty() {
+        sqlx::query("DELETE FROM image_captions WHERE path = ?").bind(&rel).execute(&state.db).await
+    } else {
+        sqlx::query(
+            "INSERT INTO image_captions (path, title, caption, rating, updated_at) VALUES (?, ?, ?, ?, ?)
+             ON CONFLICT(path) DO UPDATE SET title = excluded.title, caption = excluded.caption,
+                 rating = excluded.rating, updated_at = excluded.updated_at",
+        )
+        .bind(&rel)
+        .bind(&caption.title)
+        .bind(&caption.caption)
+        .bind(caption.rating)
+        .bind(SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs_f64())
+        .execute(&state.db)
+        .await
+    };
+    result.map_err(|e| error(StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to save caption: {}", e)))?;
+
+    // 附属文件写失败不影响数据库里的结果，只在响应里说明
+    let mut xmp = serde_json::Value::Null;
+    if patch.write_xmp.unwrap_or_else(|| state.settings.flag("GALLERY_XMP_SIDECARS")) {
+        let sidecar_caption = caption.clone();
+        let written = tokio::task::spawn_blocking(move || write_xmp_sidecar(&full, &sidecar_caption))
+            .await
+            .map_err(anyhow::Error::from)
+            .and_then(|r| r);
+        xmp = match written {
+            Ok(Some(sidecar)) => serde_json::json!({ "written": db_path_key(&state.root_dir, &sidecar) }),
+            Ok(None) => serde_json::json!({ "written": null }),
+            Err(e) => {
+                tracing::warn!("⚠️ Failed to write XMP sidecar for {}: {}", rel, e);
+                serde_json::json!({ "error": e.to_string() })
+            }
+        };
+    }
+    Ok(Json(serde_json::json!({
+        "path": rel,
+        "title": caption.title,
+        "caption": caption.caption,
+        "rating": caption.rating,
+        "xmp": xmp,
+    })))
+}
+
+/// 接口: POST /api/info/batch，一次取多张图片的元数据 (含标题与说明)
+async fn image_info_batch(
+    State(state): State<AppState>,
+    Json(req): Json<ImageInfoBatchRequest>,
+) -> Result<Json<serde_json::Value>, (StatusCode, Json<serde_json::Value>)> {
+    if req.paths.len() > IMAGE_INFO_BATCH_MAX {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            Json(serde_json::json!({ "detail": format!("At most {} paths per request", IMAGE_INFO_BATCH_MAX) })),
+        ));
+    }
+    let mut items = Vec::with_capacity(req.paths.len());
+    let mut errors = Vec::new();
+    for path in &req.paths {
+        match collect_image_info(&state, path).await {
+            Ok(info) => items.push(info),
+            Err((status, Json(detail))) => errors.push(serde_json::json!({
+                "path": path,
+                "status": status.as_u16(),
+                "detail": detail.get("detail").cloned().unwrap_or_default(),
+            })),
+        }
+    }
+    Ok(Json(serde_json::json!({ "items": items, "errors": errors })))
+}
+
+// --- 请求体校验 ---
+
+const ORIENTATIONS: &[&str] = &["Landscape", "Portrait", "Both"];
+const DIRECTIONS: &[&str] = &["forward", "reverse"];
+const QUERY_KINDS: &[&str] = &["still", "live", "raw"];
+const SPREAD_KEYS: &[&str] = &["folder", "date"];
+
+/// 请求体中的一处错误，格式与 FastAPI 的 422 响应一致：`{"detail": [{"loc": [...], "msg": ..., "type": ...}]}`
+#[derive(Debug, Serialize)]
+struct FieldError {
+    loc: Vec<String>,
+    msg: String,
+    #[serde(rename = "type")]
+    kind: &'static str,
+}
+
+impl FieldError {
+    /// `field` 用点号分隔嵌套字段 (如 `filter.kind`、`tags.0`)
+    fn new(field: &str, msg: impl Into<String>) -> Self {
+        let mut loc = vec!["body".to_string()];
+        loc.extend(field.split('.').map(str::to_string));
+        Self { loc, msg: msg.into(), kind: "value_error" }
+    }
+}
+
+/// 请求体的语义校验：一次列出所有不合法的字段，而不是静默回退到默认行为
+trait Validate {
+    fn validate(&self) -> Vec<FieldError>;
+}
+
+fn check_one_of(errors: &mut Vec<FieldError>, field: &str, value: &str, allowed: &[&str]) {
+    if !allowed.contains(&value) {
+        errors.push(FieldError::new(
+            field,
+            format!("Unknown value {:?}, expected one of: {}", value, allowed.join(", ")),
+        ));
+    }
+}
+
+fn check_tags(errors: &mut Vec<FieldError>, field: &str, tags: &[String]) {
+    for (i, tag) in tags.iter().enumerate() {
+        if tag.trim().trim_matches('/').is_empty() {
+            errors.push(FieldError::new(&format!("{}.{}", field, i), "Tag must not be empty"));
+        }
+    }
+}
+
+impl Validate for PlaylistRequest {
+    fn validate(&self) -> Vec<FieldError> {
+        let mut errors = Vec::new();
+        if self.paths.is_empty() && !self.scheduled && self.mix.is_empty() {
+            errors.push(FieldError::new("paths", "At least one folder is required (\".\" for the whole library)"));
+        }
+        if let Some(sort) = &self.sort {
+            check_one_of(&mut errors, "sort", sort, SORT_MODES);
+        }
+        if let Some(orientation) = &self.orientation {
+            check_one_of(&mut errors, "orientation", orientation, ORIENTATIONS);
+        }
+        check_one_of(&mut errors, "direction", &self.direction, DIRECTIONS);
+        check_tags(&mut errors, "tags", &self.tags);
+        if self.mix.len() > MIX_MAX_SOURCES {
+            errors.push(FieldError::new("mix", format!("At most {} sources", MIX_MAX_SOURCES)));
+        }
+        for (i, source) in self.mix.iter().enumerate() {
+            if !(source.weight.is_finite() && source.weight > 0.0) {
+                errors.push(FieldError::new(&format!("mix.{}.weight", i), "Weight must be a positive number"));
+            }
+            if let Some(orientation) = &source.filter.orientation {
+                check_one_of(&mut errors, &format!("mix.{}.filter.orientation", i), orientation, ORIENTATIONS);
+            }
+            check_tags(&mut errors, &format!("mix.{}.filter.tags", i), &source.filter.tags);
+        }
+        if let Some(spread_by) = &self.spread_by {
+            check_one_of(&mut errors, "spread_by", spread_by, SPREAD_KEYS);
+        }
+        if self.boost_recent_days.is_some_and(|days| days > BOOST_MAX_DAYS) {
+            errors.push(FieldError::new("boost_recent_days", format!("At most {} days", BOOST_MAX_DAYS)));
+        }
+        if let Some(distance) = self.spread_distance {
+            if !(1..=SPREAD_MAX_DISTANCE).contains(&distance) {
+                errors.push(FieldError::new(
+                    "spread_distance",
+                    format!("Must be between 1 and {}", SPREAD_MAX_DISTANCE),
+                ));
+            }
+        }
+        errors
+    }
+}
+
+impl Validate for QueryRequest {
+    fn validate(&self) -> Vec<FieldError> {
+        let mut errors = Vec::new();
+        check_one_of(&mut errors, "sort", &self.sort, SORT_MODES);
+        check_one_of(&mut errors, "direction", &self.direction, DIRECTIONS);
+        if self.limit.is_some_and(|l| l == 0 || l > QUERY_MAX_LIMIT) {
+            errors.push(FieldError::new("limit", format!("Must be between 1 and {}", QUERY_MAX_LIMIT)));
+        }
+
+        let filter = &self.filter;
+        if let Some(orientation) = &filter.orientation {
+            check_one_of(&mut errors, "filter.orientation", orientation, ORIENTATIONS);
+        }
+        if let Some(kind) = &filter.kind {
+            check_one_of(&mut errors, "filter.kind", kind, QUERY_KINDS);
+        }
+        check_tags(&mut errors, "filter.tags", &filter.tags);
+        for (field, rating) in [("filter.min_rating", filter.min_rating), ("filter.max_rating", filter.max_rating)] {
+            if rating.is_some_and(|r| !(0..=5).contains(&r)) {
+                errors.push(FieldError::new(field, "Rating must be between 0 and 5"));
+            }
+        }
+        if let (Some(min), Some(max)) = (filter.min_rating, filter.max_rating) {
+            if min > max {
+                errors.push(FieldError::new("filter.min_rating", "Must not be greater than max_rating"));
+            }
+        }
+        let mut bounds = [None, None];
+        for (i, (field, value, end_of_day)) in
+            [("filter.date_from", &filter.date_from, false), ("filter.date_to", &filter.date_to, true)].into_iter().enumerate()
+        {
+            if let Some(value) = value {
+                bounds[i] = parse_query_date(value, end_of_day);
+                if bounds[i].is_none() {
+                    errors.push(FieldError::new(field, format!("Invalid date {:?}, expected YYYY-MM-DD or RFC 3339", value)));
+                }
+            }
+        }
+        if let [Some(from), Some(to)] = bounds {
+            if from > to {
+                errors.push(FieldError::new("filter.date_from", "Must not be later than date_to"));
+            }
+        }
+        errors
+    }
+}
+
+fn validation_failed(errors: Vec<FieldError>) -> Response {
+    (StatusCode::UNPROCESSABLE_ENTITY, Json(serde_json::json!({ "detail": errors }))).into_response()
+}
+
+/// 带校验的 JSON 请求体：解析失败与校验失败都返回结构化的 `detail` 列表
+struct ValidJson<T>(T);
+
+#[axum::async_trait]
+impl<T, S> axum::extract::FromRequest<S> for ValidJson<T>
+where
+    T: serde::de::DeserializeOwned + Validate,
+    S: Send + Sync,
+{
+    type Rejection = Response;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        use axum::extract::rejection::JsonRejection;
+
+        let Json(value) = Json::<T>::from_request(req, state).await.map_err(|rejection| {
+            let kind = match &rejection {
+                JsonRejection::JsonDataError(_) => "value_error",
+                JsonRejection::JsonSyntaxError(_) => "json_invalid",
+                JsonRejection::MissingJsonContentType(_) => "content_type",
+                _ => "body_error",
+            };
+            let error = FieldError { loc: vec!["body".to_string()], msg: rejection.body_text(), kind };
+            (rejection.status(), Json(serde_json::json!({ "detail": [error] }))).into_response()
+        })?;
+        let errors = value.validate();
+        if !errors.is_empty() {
+            return Err(validation_failed(errors));
+        }
+        Ok(ValidJson(value))
+    }
+}
+
+// --- 组合查询 ---
+
+const QUERY_DEFAULT_LIMIT: usize = 100;
+const QUERY_MAX_LIMIT: usize = 1000;
+
+/// 解析日期边界；纯日期在 `end_of_day` 时取当天最后一秒
+fn parse_query_date(value: &str, end_of_day: bool) -> Option<f64> {
+    let value = value.trim();
+    if let Ok(t) = chrono::DateTime::parse_from_rfc3339(value) {
+        return Some(t.timestamp() as f64);
+    }
+    let date = chrono::NaiveDate::parse_from_str(value, "%Y-%m-%d").ok()?;
+    let time = if end_of_day { date.and_hms_opt(23, 59, 59)? } else { date.and_hms_opt(0, 0, 0)? };
+    Some(time.and_utc().timestamp() as f64)
+}
+
+/// 把过滤条件追加到单个来源的查询上 (数值直接内联，文本返回待绑定的参数)
+fn push_query_filters(query_builder: &mut String, filter: &QueryFilter) -> Result<Vec<String>, String> {
+    let mut binds = push_tag_filters(query_builder, &filter.tags);
+    push_person_filters(query_builder, &filter.people);
+
+    for rating in [filter.min_rating, filter.max_rating].into_iter().flatten() {
+        if !(0..=5).contains(&rating) {
+            return Err("ratings must be between 0 and 5".to_string());
+        }
+    }
+    let rating_sql = "COALESCE((SELECT rating FROM image_captions c WHERE c.path = images.path), 0)";
+    if let Some(min) = filter.min_rating {
+        query_builder.push_str(&format!(" AND {} >= {}", rating_sql, min));
+    }
+    if let Some(max) = filter.max_rating {
+        query_builder.push_str(&format!(" AND {} <= {}", rating_sql, max));
+    }
+
+    for (value, end_of_day, op) in [(&filter.date_from, false, ">="), (&filter.date_to, true, "<=")] {
+        if let Some(value) = value {
+            let ts = parse_query_date(value, end_of_day).ok_or_else(|| format!("Invalid date {:?}", value))?;
+            query_builder.push_str(&format!(" AND COALESCE(taken_at, mtime) {} {}", op, ts));
+        }
+    }
+
+    match filter.kind.as_deref() {
+        None => {}
+        Some("still") => query_builder
+            .push_str(" AND path NOT IN (SELECT path FROM image_companions WHERE kind = 'live_video')"),
+        Some("live") => {
+            query_builder.push_str(" AND path IN (SELECT path FROM image_companions WHERE kind = 'live_video')")
+        }
+        Some("raw") => query_builder.push_str(" AND path IN (SELECT path FROM image_companions WHERE kind = 'raw')"),
+        Some(other) => return Err(format!("Unknown kind {:?}", other)),
+    }
+
+    if let Some(text) = filter.text.as_deref().map(str::trim).filter(|t| !t.is_empty()) {
+        query_builder.push_str(
+            " AND (path LIKE ? ESCAPE '\\'
+                 OR path IN (SELECT path FROM image_captions WHERE title LIKE ? ESCAPE '\\' OR caption LIKE ? ESCAPE '\\')
+                 OR path IN (SELECT path FROM image_tags WHERE tag LIKE ? ESCAPE '\\'))",
+        );
+        let pattern = format!("%{}%", escape_like_pattern(text));
+        binds.extend(std::iter::repeat_n(pattern, 4));
+    }
+    Ok(binds)
+}
+
+/// 按过滤条件取出并排序 (与播放列表共用来源查询、标签/人物过滤与排序)
+async fn run_image_query(
+    state: &AppState,
+    filter: &QueryFilter,
+    sort: &str,
+    direction: &str,
+    blocked: &HashSet<String>,
+) -> Result<Vec<ImageMetadata>, String> {
+    let allow_parent = *state.allow_parent_dir_access.read().await;
+    let orientation = filter.orientation.as_deref().unwrap_or("Both");
+    if !ORIENTATIONS.contains(&orientation) {
+        return Err(format!("Unknown orientation {:?}", orientation));
+    }
+    if !SORT_MODES.contains(&sort) {
+        return Err(format!("Unknown sort mode {:?}", sort));
+    }
+    let prefixes = if filter.paths.is_empty() {
+        vec![".".to_string()]
+    } else {
+        prepare_request_paths(state, &filter.paths).await
+    };
+
+    let mut seen = HashSet::new();
+    let mut images = Vec::new();
+    for prefix in &prefixes {
+        let (mut query_builder, maybe_prefix_pattern) = build_source_query(
+            prefix,
+            allow_parent,
+            orientation,
+            PanoramaFilter::new(filter.panoramas_only, filter.include_panoramas),
+        );
+        let binds = push_query_filters(&mut query_builder, filter)?;
+        let mut query = sqlx::query_as::<_, ImageMetadata>(&query_builder);
+        if let Some(prefix_pattern) = maybe_prefix_pattern {
+            query = query.bind(prefix_pattern);
+        }
+        for value in binds {
+            query = query.bind(value);
+        }
+        let rows = query.fetch_all(&state.db).await.map_err(|e| e.to_string())?;
+        images.extend(rows.into_iter().filter(|i| !blocked.contains(&i.path) && seen.insert(i.path.clone())));
+    }
+
+    let positions = if sort == "manual" { load_manual_positions(state).await } else { HashMap::new() };
+    let collator = NameCollator::for_locale(state.default_collation.as_deref());
+    let mut images = sort_images(images, sort, &state.root_dir, &collator, &positions);
+    if direction == "reverse" {
+        images.reverse();
+    }
+    Ok(images)
+}
+
+/// 接口: POST /api/query，结构化过滤 + 排序 + 分页，输出浏览结果或播放列表
+async fn query_images(
+    State(state): State<AppState>,
+    connect_info: ConnectInfo<SocketAddr>,
+    ValidJson(req): ValidJson<QueryRequest>,
+) -> Result<Json<QueryResponse>, (StatusCode, Json<serde_json::Value>)> {
+    let ip = connect_info.0.ip().to_string();
+    let blocked = load_blocklist(&state.db, &ip).await;
+    let images = run_image_query(&state, &req.filter, &req.sort, &req.direction, &blocked)
+        .await
+        .map_err(|detail| (StatusCode::BAD_REQUEST, Json(serde_json::json!({ "detail": detail }))))?;
+
+    let total = images.len();
+    let limit = req.limit.unwrap_or(QUERY_DEFAULT_LIMIT).clamp(1, QUERY_MAX_LIMIT);
+    let page = images.iter().skip(req.offset).take(limit);
+
+    let mut response = QueryResponse { total, offset: req.offset, limit, items: None, playlist: None };
+    match req.output {
+        QueryOutput::Playlist => {
+            response.playlist = Some(playlist_for_client(&state, page.map(|i| i.path.clone()).collect()).await);
+            if req.session {
+                let all: Vec<String> = images.iter().map(|i| i.path.clone()).collect();
+                store_session_playlist(&state, &ip, all, None, GenerationStatus::Complete).await;
+            }
+        }
+        QueryOutput::Items => {
+            let mut items = Vec::with_capacity(limit.min(total));
+            for image in page {
+                items.push(QueryItem {
+                    path: image.path.clone(),
+                    width: image.width,
+                    height: image.height,
+                    orientation: if image.is_landscape { "landscape" } else { "portrait" }.to_string(),
+                    mtime: image.mtime,
+                    size: image.size,
+                    taken_at: image.taken_at,
+                    file_id: external_file_id(&state.db, &image.path).await,
+                    caption: load_image_caption(&state.db, &image.path).await,
+                });
+            }
+            response.items = Some(items);
+        }
+    }
+    Ok(Json(response))
+}
+
+#[cfg(feature = "graphql")]
+use graphql::{execute as execute_graphql, graphiql};
+
+#[cfg(not(feature = "graphql"))]
+async fn execute_graphql() -> (StatusCode, Json<serde_json::Value>) {
+    graphql_unavailable()
+}
+
+#[cfg(not(feature = "graphql"))]
+async fn graphiql() -> (StatusCode, Json<serde_json::Value>) {
+    graphql_unavailable()
+}
+
+#[cfg(not(feature = "graphql"))]
+fn graphql_unavailable() -> (StatusCode, Json<serde_json::Value>) {
+    (
+        StatusCode::NOT_IMPLEMENTED,
+        Json(serde_json::json!({ "detail": "GraphQL requires the `graphql` build feature" })),
+    )
+}
+
+// --- 幂等键 (Idempotency-Key) ---
+
+/// 保存响应的时长；过期后同一个键会被当作新请求
+const IDEMPOTENCY_TTL_SECS: f64 = 24.0 * 3600.0;
+const IDEMPOTENCY_KEY_MAX_LEN: usize = 255;
+/// 请求体上限 (计算请求指纹时需要完整读入)
+const IDEMPOTENCY_MAX_REQUEST_BYTES: usize = 16 * 1024 * 1024;
+/// 超过此大小的响应不保存 (如拼贴大图)，直接流式返回，重试时会重新执行
+const IDEMPOTENCY_MAX_RESPONSE_BYTES: usize = 4 * 1024 * 1024;
+/// 处理中的记录超过这么久仍没有结果，视为已放弃 (进程被杀、连接任务异常退出等)
+const IDEMPOTENCY_PENDING_TIMEOUT_SECS: f64 = 15.0 * 60.0;
+
+#[derive(sqlx::FromRow)]
+struct StoredIdempotentResponse {
+    fingerprint: String,
+    /// 为空表示首次请求仍在处理中
+    status: Option<i64>,
+    content_type: Option<String>,
+    body: Option<Vec<u8>>,
+}
+
+/// 占用中的幂等键：请求被取消 (客户端断开) 或处理时 panic 导致 future 被丢弃时，删除处理中的记录，允许客户端重试
+struct PendingIdempotencyKey {
+    db: Pool<Sqlite>,
+    key: Option<String>,
+}
+
+impl PendingIdempotencyKey {
+    /// 放弃这个键 (不保存响应)，之后的重试会重新执行
+    async fn release(mut self) {
+        if let Some(key) = self.key.take() {
+            let _ = sqlx::query("DELETE FROM idempotency_keys WHERE key = ? AND status IS NULL")
+                .bind(&key)
+                .execute(&self.db)
+                .await;
+        }
+    }
+
+    /// 保存响应，之后的重试直接返回它
+    async fn complete(mut self, status: StatusCode, content_type: Option<&str>, body: &[u8]) {
+        if let Some(key) = self.key.take() {
+            let _ = sqlx::query("UPDATE idempotency_keys SET status = ?, content_type = ?, body = ? WHERE key = ?")
+                .bind(status.as_u16() as i64)
+                .bind(content_type)
+                .bind(body)
+                .bind(&key)
+                .execute(&self.db)
+                .await;
+        }
+    }
+}
+
+impl Drop for PendingIdempotencyKey {
+    fn drop(&mut self) {
+        let Some(key) = self.key.take() else { return };
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else { return };
+        let db = self.db.clone();
+        runtime.spawn(async move {
+            let _ = sqlx::query("DELETE FROM idempotency_keys WHERE key = ? AND status IS NULL")
+                .bind(&key)
+                .execute(&db)
+                .await;
+        });
+    }
+}
+
+/// 带 `Idempotency-Key` 头的修改类请求 (POST/PUT/PATCH/DELETE)：首次执行后保存响应，
+/// 同一个键的重试直接返回保存的响应 (附带 `Idempotent-Replayed: true`)，避免弱网重试导致重复导入或重复删除。
+/// 同一个键配上不同的请求返回 422；首次请求仍在处理时重试返回 409；5xx 响应不保存，可以重试。
+async fn idempotency_middleware(State(db): State<Pool<Sqlite>>, request: Request, next: Next) -> Response {
+    let error = |status: StatusCode, detail: &str| (status, Json(serde_json::json!({ "detail": detail }))).into_response();
+    let mutating = matches!(
+        *request.method(),
+        axum::http::Method::POST | axum::http::Method::PUT | axum::http::Method::PATCH | axum::http::Method::DELETE
+    );
+    let Some(key) = request.headers().get("idempotency-key").filter(|_| mutating) else {
+        return next.run(request).await;
+    };
+    let key = match key.to_str().map(str::trim) {
+        Ok(key) if !key.is_empty() && key.len() <= IDEMPOTENCY_KEY_MAX_LEN => key.to_string(),
+        _ => {
+            return error(
+                StatusCode::BAD_REQUEST,
+                &format!("Idempotency-Key must be 1-{} visible ASCII characters", IDEMPOTENCY_KEY_MAX_LEN),
+            )
+        }
+    };
+
+    // 请求指纹：方法 + 路径 (不含 /api 与版本前缀) + 请求体
+    let (parts, body) = request.into_parts();
+    let Ok(body) = axum::body::to_bytes(body, IDEMPOTENCY_MAX_REQUEST_BYTES).await else {
+        return error(StatusCode::PAYLOAD_TOO_LARGE, "Request body too large");
+    };
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(parts.method.as_str().as_bytes());
+    hasher.update(b"\n");
+    hasher.update(parts.uri.path_and_query().map(|p| p.as_str()).unwrap_or_default().as_bytes());
+    hasher.update(b"\n");
+    hasher.update(&body);
+    let fingerprint = hasher.finalize().to_hex().to_string();
+
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs_f64();
+    let _ = sqlx::query("DELETE FROM idempotency_keys WHERE created_at < ? OR (status IS NULL AND created_at < ?)")
+        .bind(now - IDEMPOTENCY_TTL_SECS)
+        .bind(now - IDEMPOTENCY_PENDING_TIMEOUT_SECS)
+        .execute(&db)
+        .await;
+    let claimed = sqlx::query("INSERT OR IGNORE INTO idempotency_keys (key, fingerprint, created_at) VALUES (?, ?, ?)")
+        .bind(&key)
+        .bind(&fingerprint)
+        .bind(now)
+        .execute(&db)
+        .await
+        .map(|r| r.rows_affected() == 1);
+    match claimed {
+        Ok(true) => {}
+        Ok(false) => {
+            let stored: Option<StoredIdempotentResponse> = sqlx::query_as(
+                "SELECT fingerprint, status, content_type, body FROM idempotency_keys WHERE key = ?",
+            )
+            .bind(&key)
+            .fetch_optional(&db)
+            .await
+            .ok()
+            .flatten();
+            return match stored {
+                Some(stored) if stored.fingerprint != fingerprint => error(
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                    "Idempotency-Key was already used for a different request",
+                ),
+                Some(StoredIdempotentResponse { status: Some(status), content_type, body, .. }) => {
+                    tracing::info!("🔁 幂等键 {} 重复请求，返回已保存的响应", key);
+                    let mut response = Response::new(Body::from(body.unwrap_or_default()));
+                    *response.status_mut() = StatusCode::from_u16(status as u16).unwrap_or(StatusCode::OK);
+                    if let Some(value) = content_type.and_then(|c| HeaderValue::from_str(&c).ok()) {
+                        response.headers_mut().insert(header::CONTENT_TYPE, value);
+                    }
+                    response
+                        .headers_mut()
+                        .insert(HeaderName::from_static("idempotent-replayed"), HeaderValue::from_static("true"));
+                    response
+                }
+                _ => error(StatusCode::CONFLICT, "A request with this Idempotency-Key is still being processed"),
+            };
+        }
+        Err(err) => {
+            tracing::error!("❌ 幂等键记录失败: {}", err);
+            return error(StatusCode::INTERNAL_SERVER_ERROR, "Failed to record Idempotency-Key");
+        }
+    }
+
+    let pending = PendingIdempotencyKey { db, key: Some(key) };
+    let response = next.run(Request::from_parts(parts, Body::from(body))).await;
+    let status = response.status();
+    let (parts, body) = response.into_parts();
+    if status.is_server_error() || axum::body::HttpBody::size_hint(&body).lower() > IDEMPOTENCY_MAX_RESPONSE_BYTES as u64 {
+        pending.release().await;
+        return Response::from_parts(parts, body);
+    }
+
+    // 边读边判断大小：超过上限时放弃保存，已读到的部分与剩下的响应体一起流式返回
+    let mut stream = body.into_data_stream();
+    let mut chunks: Vec<axum::body::Bytes> = Vec::new();
+    let mut size = 0;
+    while let Some(chunk) = stream.next().await {
+        let Ok(chunk) = chunk else {
+            pending.release().await;
+            return error(StatusCode::INTERNAL_SERVER_ERROR, "Failed to read response");
+        };
+        size += chunk.len();
+        chunks.push(chunk);
+        if size > IDEMPOTENCY_MAX_RESPONSE_BYTES {
+            pending.release().await;
+            let head = futures::stream::iter(chunks.into_iter().map(Ok::<_, axum::Error>));
+            return Response::from_parts(parts, Body::from_stream(head.chain(stream)));
+        }
+    }
+    let body = chunks.concat();
+    let content_type = parts.headers.get(header::CONTENT_TYPE).and_then(|v| v.to_str().ok());
+    pending.complete(status, content_type, &body).await;
+    Response::from_parts(parts, Body::from(body))
+}
+
+// --- 后台任务 ---
+
+/// 已结束的任务记录保留天数
+const JOB_RETENTION_DAYS: f64 = 7.0;
+
+/// jobs 表中的一条任务记录
+#[derive(Debug, Serialize, sqlx::FromRow)]
+struct JobRecord {
+    id: String,
+    /// `scan` / `import` / `duplicates` / `relink` / `prewarm`
+    kind: String,
+    /// `running` / `succeeded` / `failed` / `cancelled`
+    status: String,
+    /// 0~1，任务不汇报进度时为空
+    progress: Option<f64>,
+    error: Option<String>,
+    /// 任务成功时的结果 (与同步调用时的响应相同)
+    result: Option<sqlx::types::Json<serde_json::Value>>,
+    created_at: f64,
+    finished_at: Option<f64>,
+}
+
+/// 交给任务函数的句柄，用于汇报进度
+#[derive(Clone)]
+struct JobContext {
+    id: String,
+    db: Pool<Sqlite>,
+    /// 取消令牌，协作式任务 (`spawn_cooperative_job`) 自行在安全点检查
+    cancel: tokio_util::sync::CancellationToken,
+}
+
+impl JobContext {
+    async fn set_progress(&self, done: usize, total: usize) {
+        let progress = if total == 0 { 1.0 } else { done as f64 / total as f64 };
+        let _ = sqlx::query("UPDATE jobs SET progress = ? WHERE id = ?")
+            .bind(progress)
+            .bind(&self.id)
+            .execute(&self.db)
+            .await;
+    }
+}
+
+/// 启动后台任务并立即返回任务 ID；状态、结果与失败原因写入 jobs 表，结束时推送 `job` 事件。
+/// 取消 (DELETE /api/jobs/:id) 会在下一个 await 点丢弃任务，已完成的部分不会回滚。
+async fn spawn_job<F, Fut>(state: &AppState, kind: &str, run: F) -> String
+where
+    F: FnOnce(JobContext) -> Fut + Send + 'static,
+    Fut: std::future::Future<Output = Result<serde_json::Value, String>> + Send + 'static,
+{
+    start_job(state, kind, false, run).await
+}
+
+/// 与 `spawn_job` 相同，但取消时不丢弃任务：任务通过 `JobContext::cancel` 自行在安全点退出 (如回滚未提交的事务)，
+/// 取消后返回的结果仍会写入任务记录
+async fn spawn_cooperative_job<F, Fut>(state: &AppState, kind: &str, run: F) -> String
+where
+    F: FnOnce(JobContext) -> Fut + Send + 'static,
+    Fut: std::future::Future<Output = Result<serde_json::Value, String>> + Send + 'static,
+{
+    start_job(state, kind, true, run).await
+}
+
+async fn start_job<F, Fut>(state: &AppState, kind: &str, cooperative: bool, run: F) -> String
+where
+    F: FnOnce(JobContext) -> Fut + Send + 'static,
+    Fut: std::future::Future<Output = Result<serde_json::Value, String>> + Send + 'static,
+{
+    let id = random_token(16);
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs_f64();
+    let _ = sqlx::query("DELETE FROM jobs WHERE finished_at < ?")
+        .bind(now - JOB_RETENTION_DAYS * 86400.0)
+        .execute(&state.db)
+        .await;
+    if let Err(err) = sqlx::query("INSERT INTO jobs (id, kind, status, created_at) VALUES (?, ?, 'running', ?)")
+        .bind(&id)
+        .bind(kind)
+        .bind(now)
+        .execute(&state.db)
+        .await
+    {
+        tracing::error!("❌ 任务记录写入失败 ({}): {}", kind, err);
+    }
+
+    let cancel = tokio_util::sync::CancellationToken::new();
+    state.jobs.lock().unwrap().insert(id.clone(), cancel.clone());
+    let ctx = JobContext { id: id.clone(), db: state.db.clone(), cancel: cancel.clone() };
+    let (state, kind) = (state.clone(), kind.to_string());
+    let job_id = id.clone();
+    tokio::spawn(async move {
+        let outcome = if cooperative {
+            Some(run(ctx).await)
+        } else {
+            tokio::select! {
+                result = run(ctx) => Some(result),
+                _ = cancel.cancelled() => None,
+            }
+        };
+        state.jobs.lock().unwrap().remove(&job_id);
+        let (status, result, error) = match outcome {
+            Some(Ok(result)) if cancel.is_cancelled() => {
+                tracing::info!("🛑 后台任务 {} ({}) 已取消", job_id, kind);
+                ("cancelled", Some(result), None)
+            }
+            Some(Ok(result)) => ("succeeded", Some(result), None),
+            Some(Err(err)) => {
+                tracing::warn!("⚠️ 后台任务 {} ({}) 失败: {}", job_id, kind, err);
+                ("failed", None, Some(err))
+            }
+            None => {
+                tracing::info!("🛑 后台任务 {} ({}) 已取消", job_id, kind);
+                ("cancelled", None, None)
+            }
+        };
+        let finished_at = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs_f64();
+        let _ = sqlx::query(
+            "UPDATE jobs SET status = ?, result = ?, error = ?, finished_at = ?,
+                 progress = CASE WHEN ? = 'succeeded' THEN 1.0 ELSE progress END
+             WHERE id = ?",
+        )
+        .bind(status)
+        .bind(result.as_ref().map(|r| r.to_string()))
+        .bind(&error)
+        .bind(finished_at)
+        .bind(status)
+        .bind(&job_id)
+        .execute(&state.db)
+        .await;
+        publish_event(
+            &state,
+            None,
+            "job",
+            serde_json::json!({ "id": job_id, "kind": kind, "status": status, "error": error }),
+        );
+    });
+    id
+}
+
+/// 客户端通过 `Prefer: respond-async` 要求在后台执行 (RFC 7240)
+fn prefers_async(headers: &axum::http::HeaderMap) -> bool {
+    headers
+        .get_all("prefer")
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|p| p.trim().eq_ignore_ascii_case("respond-async"))
+}
+
+/// 后台执行时的 202 响应，`Location` 指向任务状态
+fn job_accepted(state: &AppState, id: &str) -> Response {
+    let location = format!("{}/api/v1/jobs/{}", state.base_path, id);
+    (
+        StatusCode::ACCEPTED,
+        [
+            (header::LOCATION, location.clone()),
+            (HeaderName::from_static("preference-applied"), "respond-async".to_string()),
+        ],
+        Json(serde_json::json!({ "job_id": id, "status": "running", "location": location })),
+    )
+        .into_response()
+}
+
+/// 把接口的错误响应转换为任务的失败原因
+fn job_error((status, Json(detail)): (StatusCode, Json<serde_json::Value>)) -> String {
+    match detail.get("detail") {
+        Some(serde_json::Value::String(detail)) => detail.clone(),
+        _ => status.to_string(),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct JobListQuery {
+    kind: Option<String>,
+    limit: Option<i64>,
+}
+
+/// 接口: GET /api/jobs，最近的任务 (新的在前)
+async fn list_jobs(State(state): State<AppState>, Query(query): Query<JobListQuery>) -> Json<Vec<JobRecord>> {
+    let limit = query.limit.unwrap_or(50).clamp(1, 500);
+    let jobs = match &query.kind {
+        Some(kind) => {
+            sqlx::query_as("SELECT * FROM jobs WHERE kind = ? ORDER BY created_at DESC LIMIT ?")
+                .bind(kind)
+                .bind(limit)
+                .fetch_all(&state.db)
+                .await
+        }
+        None => {
+            sqlx::query_as("SELECT * FROM jobs ORDER BY created_at DESC LIMIT ?")
+                .bind(limit)
+                .fetch_all(&state.db)
+                .await
+        }
+    };
+    Json(jobs.unwrap_or_default())
+}
+
+async fn load_job(state: &AppState, id: &str) -> Result<JobRecord, (StatusCode, Json<serde_json::Value>)> {
+    sqlx::query_as("SELECT * FROM jobs WHERE id = ?")
+        .bind(id)
+        .fetch_optional(&state.db)
+        .await
+        .ok()
+        .flatten()
+        .ok_or((StatusCode::NOT_FOUND, Json(serde_json::json!({ "detail": "Job not found" }))))
+}
+
+/// 接口: GET /api/jobs/:id
+async fn job_status(
+    State(state): State<AppState>,
+    axum::extract::Path(id): axum::extract::Path<String>,
+) -> Result<Json<JobRecord>, (StatusCode, Json<serde_json::Value>)> {
+    load_job(&state, &id).await.map(Json)
+}
+
+/// 接口: DELETE /api/jobs/:id，请求取消运行中的任务
+async fn cancel_job(
+    State(state): State<AppState>,
+    axum::extract::Path(id): axum::extract::Path<String>,
+) -> Result<(StatusCode, Json<serde_json::Value>), (StatusCode, Json<serde_json::Value>)> {
+    let job = load_job(&state, &id).await?;
+    let token = state.jobs.lock().unwrap().get(&id).cloned();
+    match token {
+        Some(token) => {
+            token.cancel();
+            Ok((StatusCode::ACCEPTED, Json(serde_json::json!({ "id": id, "status": "cancelling" }))))
+        }
+        None => Err((
+            StatusCode::CONFLICT,
+            Json(serde_json::json!({ "detail": format!("Job already {}", job.status) })),
+        )),
+    }
+}
+
+// --- 文件访问日志 ---
+
+/// 一次 `/api/file` 访问 (传输结束或客户端断开时记录)
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+struct AccessRecord {
+    at: f64,
+    /// 客户端 IP (即会话键)
+    client_ip: String,
+    method: String,
+    path: String,
+    status: i64,
+    /// 实际发送的字节数 (中途断开时小于文件大小)
+    bytes: i64,
+    duration_ms: f64,
+}
+
+/// 随响应体一起释放：统计已发送的字节数，结束时写入内存缓冲
+struct AccessLogGuard {
+    buffer: Arc<std::sync::Mutex<Vec<AccessRecord>>>,
+    record: AccessRecord,
+    started: Instant,
+}
+
+impl Drop for AccessLogGuard {
+    fn drop(&mut self) {
+        self.record.duration_ms = self.started.elapsed().as_secs_f64() * 1000.0;
+        if let Ok(mut buffer) = self.buffer.lock() {
+            buffer.push(self.record.clone());
+        }
+    }
+}
+
+/// 包装文件响应，记录访问日志 (由后台任务定期批量落库)
+fn track_file_access(state: &AppState, rel: String, method: String, client_ip: String, started: Instant, response: Response) -> Response {
+    if state.access_log_retention_days.load(std::sync::atomic::Ordering::Relaxed) == 0 {
+        return response;
+    }
+    let (parts, body) = response.into_parts();
+    let mut guard = AccessLogGuard {
+        buffer: state.access_log.clone(),
+        record: AccessRecord {
+            at: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs_f64(),
+            client_ip,
+            method,
+            path: rel,
+            status: parts.status.as_u16() as i64,
+            bytes: 0,
+            duration_ms: 0.0,
+        },
+        started,
+    };
+    let stream = body.into_data_stream().map(move |chunk| {
+        // 按整体借用，让 guard 随流一起移动并在流结束时释放
+        let guard = &mut guard;
+        if let Ok(data) = &chunk {
+            guard.record.bytes += data.len() as i64;
+        }
+        chunk
+    });
+    Response::from_parts(parts, Body::from_stream(stream))
+}
+
+/// 把缓冲中的访问记录写入数据库，并删除超过保留期的记录 (失败时放回缓冲，下次重试)
+async fn flush_access_log(state: &AppState) {
+    if state.access_log_retention_days.load(std::sync::atomic::Ordering::Relaxed) == 0 {
+        return;
+    }
+    let pending: Vec<AccessRecord> = match state.access_log.lock() {
+        Ok(mut buffer) => std::mem::take(&mut *buffer),
+        Err(_) => return,
+    };
+    let written = async {
+        let mut tx = state.db.begin().await?;
+        for record in &pending {
+            sqlx::query(
+                "INSERT INTO access_log (at, client_ip, method, path, status, bytes, duration_ms)
+                 VALUES (?, ?, ?, ?, ?, ?, ?)",
+            )
+            .bind(record.at)
+            .bind(&record.client_ip)
+            .bind(&record.method)
+            .bind(&record.path)
+            .bind(record.status)
+            .bind(record.bytes)
+            .bind(record.duration_ms)
+            .execute(&mut *tx)
+            .await?;
+        }
+        let cutoff = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs_f64()
+            - state.access_log_retention_days.load(std::sync::atomic::Ordering::Relaxed) as f64 * 86400.0;
+        sqlx::query("DELETE FROM access_log WHERE at < ?").bind(cutoff).execute(&mut *tx).await?;
+        tx.commit().await
+    }
+    .await;
+    if let Err(e) = written {
+        tracing::warn!("⚠️ [Access Log] 写入 {} 条访问记录失败，保留到下次写入: {}", pending.len(), e);
+        if let Ok(mut buffer) = state.access_log.lock() {
+            // 放回缓冲前部，保持时间顺序
+            buffer.splice(0..0, pending);
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct AccessLogQuery {
+    /// 文件或文件夹 (匹配其下所有文件)
+    path: Option<String>,
+    client: Option<String>,
+    /// `YYYY-MM-DD` 或 RFC 3339
+    since: Option<String>,
+    until: Option<String>,
+    limit: Option<i64>,
+    #[serde(default)]
+    offset: i64,
+}
+
+/// 接口: GET /api/admin/access-log，按路径、客户端与时间过滤访问记录 (新的在前)
+async fn access_log(
+    State(state): State<AppState>,
+    Query(query): Query<AccessLogQuery>,
+) -> Result<Json<serde_json::Value>, (StatusCode, Json<serde_json::Value>)> {
+    // 先把缓冲落库，刚发生的访问也能查到
+    flush_access_log(&state).await;
+
+    let mut sql = "SELECT at, client_ip, method, path, status, bytes, duration_ms FROM access_log WHERE 1 = 1".to_string();
+    let mut binds: Vec<String> = Vec::new();
+    if let Some(path) = query.path.as_deref().map(normalize_rel_path).filter(|p| !p.is_empty() && p != ".") {
+        sql.push_str(" AND (path = ? OR path LIKE ? ESCAPE '\\')");
+        binds.push(path.clone());
+        binds.push(format!("{}/%", escape_like_pattern(&path)));
+    }
+    if let Some(client) = &query.client {
+        sql.push_str(" AND client_ip = ?");
+        binds.push(client.clone());
+    }
+    for (value, end_of_day, op) in [(&query.since, false, ">="), (&query.until, true, "<=")] {
+        if let Some(value) = value {
+            let ts = parse_query_date(value, end_of_day).ok_or_else(|| {
+                (StatusCode::BAD_REQUEST, Json(serde_json::json!({ "detail": format!("Invalid date {:?}", value) })))
+            })?;
+            sql.push_str(&format!(" AND at {} {}", op, ts));
+        }
+    }
+    let limit = query.limit.unwrap_or(100).clamp(1, 1000);
+    sql.push_str(&format!(" ORDER BY at DESC LIMIT {} OFFSET {}", limit, query.offset.max(0)));
+
+    let mut q = sqlx::query_as::<_, AccessRecord>(&sql);
+    for value in binds {
+        q = q.bind(value);
+    }
+    let entries = q.fetch_all(&state.db).await.unwrap_or_default();
+    Ok(Json(serde_json::json!({
+        "retention_days": state.access_log_retention_days.load(std::sync::atomic::Ordering::Relaxed),
+        "entries": entries,
+    })))
+}
+
+// --- 带宽限制 ---
+
+/// 限速时响应体拆分的片大小，避免以大块为单位长时间停顿
+const THROTTLE_SLICE: usize = 16 * 1024;
+
+/// 单个客户端的令牌桶 (单位：字节)
+struct TokenBucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// 按客户端 IP 限制文件传输带宽 (`GALLERY_BANDWIDTH_LIMIT_KBPS`)，同一客户端的并发下载共享额度
+struct BandwidthLimiter {
+    /// 字节/秒
+    rate: f64,
+    /// 桶容量：空闲后允许的突发字节数 (`GALLERY_BANDWIDTH_BURST_KB`，默认为一秒的额度)
+    burst: f64,
+    buckets: std::sync::Mutex<HashMap<String, TokenBucket>>,
+}
+
+impl BandwidthLimiter {
+    fn from_settings(settings: &Settings) -> Option<Self> {
+        let kbps = settings.parse::<f64>("GALLERY_BANDWIDTH_LIMIT_KBPS").filter(|k| *k > 0.0)?;
+        let rate = kbps * 1024.0;
+        let burst = settings
+            .parse::<f64>("GALLERY_BANDWIDTH_BURST_KB")
+            .filter(|b| *b > 0.0)
+            .map_or(rate, |b| b * 1024.0);
+        Some(Self { rate, burst, buckets: std::sync::Mutex::new(HashMap::new()) })
+    }
+
+    /// 预留 `bytes` 字节，返回发送前需要等待的时长 (额度不足时记为欠账，同一客户端的后续数据一并等待)
+    fn reserve(&self, client: &str, bytes: usize) -> Duration {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() > 1024 {
+            // 已经回满的桶与新建的桶没有区别，可以丢弃
+            buckets.retain(|_, b| b.tokens + now.duration_since(b.updated).as_secs_f64() * self.rate < self.burst);
+        }
+        let bucket = buckets.entry(client.to_string()).or_insert(TokenBucket { tokens: self.burst, updated: now });
+        bucket.tokens = (bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * self.rate).min(self.burst);
+        bucket.updated = now;
+        bucket.tokens -= bytes as f64;
+        if bucket.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-bucket.tokens / self.rate)
+        }
+    }
+
+    /// 按额度等待后再交出数据
+    async fn pace(&self, client: &str, bytes: usize) {
+        let wait = self.reserve(client, bytes);
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+/// 对文件响应体按客户端限速 (未配置时原样返回)
+fn throttle_response(state: &AppState, client_ip: &str, response: Response) -> Response {
+    let Some(limiter) = state.bandwidth.read().unwrap().clone() else {
+        return response;
+    };
+    let client = client_ip.to_string();
+    let (parts, body) = response.into_parts();
+    let stream = body
+        .into_data_stream()
+        .flat_map(|chunk| {
+            let pieces: Vec<Result<axum::body::Bytes, axum::Error>> = match chunk {
+                Ok(data) => (0..data.len())
+                    .step_by(THROTTLE_SLICE)
+                    .map(|start| Ok(data.slice(start..(start + THROTTLE_SLICE).min(data.len()))))
+                    .collect(),
+                Err(err) => vec![Err(err)],
+            };
+            futures::stream::iter(pieces)
+        })
+        .then(move |chunk| {
+            let (limiter, client) = (limiter.clone(), client.clone());
+            async move {
+                if let Ok(data) = &chunk {
+                    limiter.pace(&client, data.len()).await;
+                }
+                chunk
+            }
+        });
+    Response::from_parts(parts, Body::from_stream(stream))
+}
+
+// --- 配置文件热加载 ---
+
+/// 无需重启即可生效的配置项 (其余项变更后只记录警告)
+const RELOADABLE_SETTINGS: &[&str] = &[
+    "GALLERY_ALLOW_PARENT_DIR_ACCESS",
+    "GALLERY_DARK_HOURS",
+    "GALLERY_BANDWIDTH_LIMIT_KBPS",
+    "GALLERY_BANDWIDTH_BURST_KB",
+    "GALLERY_ACCESS_LOG_DAYS",
+    "GALLERY_MISSING_GRACE_DAYS",
+];
+
+/// 只在启动时读取的配置 (监听地址、证书、图库根目录)；重新加载时忽略文件中对它们的修改
+const STARTUP_ONLY_SETTINGS: &[&str] = &[
+    "GALLERY_HOST",
+    "GALLERY_PORT",
+    "GALLERY_HTTP3_PORT",
+    "GALLERY_SSL_CERT",
+    "GALLERY_SSL_KEY",
+    "GALLERY_ROOT_DIR",
+    "GALLERY_PROFILES",
+];
+
+/// 配置文件中的值；热加载时整体替换，从不改写进程环境 (其它线程可能正在读取环境变量)
+static CONFIG_FILE_VALUES: std::sync::RwLock<BTreeMap<String, String>> = std::sync::RwLock::new(BTreeMap::new());
+
+/// 读取配置项：配置文件中的值优先，其次是进程环境变量
+fn config_var(name: &str) -> Result<String, env::VarError> {
+    match CONFIG_FILE_VALUES.read().unwrap().get(name) {
+        Some(value) => Ok(value.clone()),
+        None => env::var(name),
+    }
+}
+
+/// `GALLERY_CONFIG` 指向的配置文件 (dotenv 格式的 `GALLERY_*=...`，优先于进程环境变量)。
+/// 启动时先于其它配置读取；收到 SIGHUP 或文件被修改后重新加载，会话等内存状态不受影响。
+/// `STARTUP_ONLY_SETTINGS` 不参与重新加载，修改后需要重启
+struct ConfigFile {
+    path: PathBuf,
+    modified: std::sync::Mutex<Option<SystemTime>>,
+}
+
+impl ConfigFile {
+    fn from_env() -> Option<Self> {
+        let path = env::var("GALLERY_CONFIG").ok().filter(|p| !p.trim().is_empty())?;
+        Some(Self { path: PathBuf::from(path), modified: std::sync::Mutex::new(None) })
+    }
+
+    fn mtime(&self) -> Option<SystemTime> {
+        std::fs::metadata(&self.path).and_then(|m| m.modified()).ok()
+    }
+
+    /// 文件自上次加载后是否被修改
+    fn changed_on_disk(&self) -> bool {
+        self.mtime() != *self.modified.lock().unwrap()
+    }
+
+    /// 读取文件并替换 `CONFIG_FILE_VALUES`，返回值有变化的变量名 (`reload` 时保留只在启动时读取的项)
+    fn load(&self, reload: bool) -> Result<Vec<String>, dotenvy::Error> {
+        *self.modified.lock().unwrap() = self.mtime();
+        let mut values = BTreeMap::new();
+        for item in dotenvy::from_path_iter(&self.path)? {
+            let (key, value) = item?;
+            values.insert(key, value);
+        }
+
+        let mut current = CONFIG_FILE_VALUES.write().unwrap();
+        if reload {
+            for key in STARTUP_ONLY_SETTINGS {
+                if values.get(*key) == current.get(*key) {
+                    continue;
+                }
+                tracing::warn!("⚠️ {} 只在启动时读取，忽略配置文件中的修改 (需要重启)", key);
+                match current.get(*key) {
+                    Some(value) => values.insert(key.to_string(), value.clone()),
+                    None => values.remove(*key),
+                };
+            }
+        }
+        let changed: std::collections::BTreeSet<String> =
+            current.keys().chain(values.keys()).filter(|key| current.get(*key) != values.get(*key)).cloned().collect();
+        *current = values;
+        Ok(changed.into_iter().collect())
+    }
+}
+
+fn access_log_days(settings: &Settings) -> u64 {
+    settings.parse("GALLERY_ACCESS_LOG_DAYS").unwrap_or(0)
+}
+
+/// 把变化的配置应用到运行中的状态 (只改动 `changed` 涉及的项，不覆盖通过接口做的修改)
+async fn apply_config_changes(state: &AppState, changed: &[String]) {
+    let settings = &state.settings;
+    let has = |name: &str| settings.changed(changed, name);
+    if has("GALLERY_ALLOW_PARENT_DIR_ACCESS") {
+        *state.allow_parent_dir_access.write().await =
+            settings.get("GALLERY_ALLOW_PARENT_DIR_ACCESS").unwrap_or_default() == "1";
+    }
+    if has("GALLERY_DARK_HOURS") {
+        *state.dark_hours.write().unwrap() =
+            Arc::new(parse_dark_hours(&settings.get("GALLERY_DARK_HOURS").unwrap_or_default()));
+    }
+    if has("GALLERY_BANDWIDTH_LIMIT_KBPS") || has("GALLERY_BANDWIDTH_BURST_KB") {
+        *state.bandwidth.write().unwrap() = BandwidthLimiter::from_settings(settings).map(Arc::new);
+    }
+    if has("GALLERY_ACCESS_LOG_DAYS") {
+        state.access_log_retention_days.store(access_log_days(settings), std::sync::atomic::Ordering::Relaxed);
+    }
+}
+
+/// 后台任务：SIGHUP 或配置文件修改 (每 `GALLERY_CONFIG_POLL_SECS` 秒检查，默认 5) 时重新加载
+fn spawn_config_reloader(states: Vec<AppState>, config: ConfigFile) {
+    let poll = env_duration_secs("GALLERY_CONFIG_POLL_SECS").unwrap_or(Duration::from_secs(5));
+    tokio::spawn(async move {
+        #[cfg(unix)]
+        let mut hangup = match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()) {
+            Ok(signal) => Some(signal),
+            Err(err) => {
+                tracing::error!("⚠️ 无法监听 SIGHUP: {}", err);
+                None
+            }
+        };
+        let mut ticker = tokio::time::interval(poll);
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            #[cfg(unix)]
+            let hup = async {
+                match hangup.as_mut() {
+                    Some(signal) => signal.recv().await,
+                    None => std::future::pending().await,
+                }
+            };
+            #[cfg(not(unix))]
+            let hup = std::future::pending::<Option<()>>();
+            let reason = tokio::select! {
+                _ = hup => "SIGHUP",
+                _ = ticker.tick() => {
+                    if !config.changed_on_disk() {
+                        continue;
+                    }
+                    "文件已修改"
+                }
+            };
+            match config.load(true) {
+                Ok(changed) => {
+                    tracing::info!("🔄 重新加载配置 {} ({})，变化: {:?}", config.path.display(), reason, changed);
+                    for state in &states {
+                        apply_config_changes(state, &changed).await;
+                    }
+                    // profile 专属的变量 (`GALLERY_PROFILE_{NAME}_*`) 按后缀判断
+                    let pending: Vec<&str> = changed
+                        .iter()
+                        .map(String::as_str)
+                        .filter(|k| !RELOADABLE_SETTINGS.iter().any(|r| k == r || k.ends_with(&r["GALLERY".len()..])))
+                        .collect();
+                    if !pending.is_empty() {
+                        tracing::warn!("⚠️ 以下配置需要重启才能生效: {}", pending.join(", "));
+                    }
+                }
+                Err(err) => tracing::error!("❌ 配置文件 {} 加载失败，保留当前配置: {}", config.path.display(), err),
+            }
+        }
+    });
+}
+
+// --- 多图库 (profiles) ---
+
+/// 图库的配置来源：默认图库读取 `GALLERY_*`；profile 优先读取 `GALLERY_PROFILE_{NAME}_*`
+/// (如 `GALLERY_PROFILE_ART_FOLLOW_SYMLINKS`)，未设置时沿用全局值
+#[derive(Clone, Debug, Default)]
+struct Settings {
+    profile: Option<String>,
+}
+
+impl Settings {
+    fn for_profile(name: &str) -> Self {
+        Self { profile: Some(name.to_string()) }
+    }
+
+    /// profile 专属的变量名
+    fn profile_key(&self, name: &str) -> Option<String> {
+        let profile = self.profile.as_ref()?;
+        Some(format!(
+            "GALLERY_PROFILE_{}_{}",
+            profile.to_ascii_uppercase().replace('-', "_"),
+            name.trim_start_matches("GALLERY_")
+        ))
+    }
+
+    fn get(&self, name: &str) -> Option<String> {
+        self.profile_key(name).and_then(|key| config_var(&key).ok()).or_else(|| config_var(name).ok())
+    }
+
+    /// 只属于本图库的配置 (目录类)：profile 不回退到全局值，免得多个图库共用同一个目录
+    fn own(&self, name: &str) -> Option<String> {
+        match self.profile_key(name) {
+            Some(key) => config_var(&key).ok(),
+            None => config_var(name).ok(),
+        }
+    }
+
+    fn parse<T: std::str::FromStr>(&self, name: &str) -> Option<T> {
+        self.get(name).and_then(|v| v.trim().parse().ok())
+    }
+
+    /// 同 `env_flag_enabled`
+    fn flag(&self, name: &str) -> bool {
+        self.get(name)
+            .is_some_and(|v| matches!(v.trim().to_ascii_lowercase().as_str(), "1" | "true" | "yes" | "on"))
+    }
+
+    /// 同 `env_duration_secs`
+    fn duration_secs(&self, name: &str) -> Option<Duration> {
+        self.parse::<u64>(name).filter(|secs| *secs > 0).map(Duration::from_secs)
+    }
+
+    /// `changed` 中是否包含该配置 (全局或本 profile 的变量)
+    fn changed(&self, changed: &[String], name: &str) -> bool {
+        let profile_key = self.profile_key(name);
+        changed.iter().any(|k| k == name || Some(k) == profile_key.as_ref())
+    }
+}
+
+/// 各图库共用的进程级组件 (模型、投屏、邮件、通知)
+#[derive(Clone, Default)]
+struct SharedServices {
+    #[cfg(feature = "onnx")]
+    autotagger: Option<Arc<autotag::AutoTagger>>,
+    #[cfg(feature = "onnx")]
+    text_encoder: Option<Arc<autotag::TextEncoder>>,
+    #[cfg(feature = "onnx")]
+    face_analyzer: Option<Arc<faces::FaceAnalyzer>>,
+    #[cfg(feature = "cast")]
+    cast: Arc<cast::CastManager>,
+    #[cfg(feature = "smtp")]
+    mailer: Option<Arc<digest::Mailer>>,
+    #[cfg(feature = "notify")]
+    notifier: Option<Arc<notify::Notifier>>,
+}
+
+/// 解析 `GALLERY_PROFILES`，如 `family=/srv/photos/family,art=/srv/art` (名称只能包含小写字母、数字、`-`、`_`)
+fn parse_profiles(spec: &str) -> Vec<(String, PathBuf)> {
+    let mut profiles: Vec<(String, PathBuf)> = Vec::new();
+    for part in spec.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+        let Some((name, root)) = part.split_once('=').map(|(n, r)| (n.trim(), r.trim())) else {
+            tracing::warn!("⚠️ Ignoring invalid profile {:?} (expected name=/path)", part);
+            continue;
+        };
+        let valid_name = !name.is_empty()
+            && name.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_');
+        if !valid_name || root.is_empty() || profiles.iter().any(|(n, _)| n == name) {
+            tracing::warn!("⚠️ Ignoring invalid or duplicate profile {:?}", part);
+            continue;
+        }
+        profiles.push((name.to_string(), PathBuf::from(root)));
+    }
+    profiles
+}
+
+/// 打开一个图库：连接 (并初始化) 其 ROOT_DIR 下的数据库，按 `settings` 构造状态
+#[cfg_attr(
+    not(any(feature = "onnx", feature = "cast", feature = "smtp", feature = "notify")),
+    allow(unused_variables)
+)]
+async fn open_library(root_dir: PathBuf, settings: Settings, shared: &SharedServices) -> Result<AppState> {
+    let db_path = root_dir.join("gallery_metadata.db");
+    let db_url = format!("sqlite://{}?mode=rwc", db_path.to_string_lossy());
+    let connect_options = db_url.parse::<SqliteConnectOptions>()?
+        .collation(NATURAL_COLLATION, |a: &str, b: &str| natord::compare_ignore_case(a, b));
+    let pool = SqlitePoolOptions::new()
+        .max_connections(10)
+        .connect_with(connect_options)
+        .await?;
+    init_db(&pool).await?;
+
+    Ok(AppState {
+        db: pool,
+        root_dir: Arc::new(root_dir),
+        allow_parent_dir_access: Arc::new(RwLock::new(settings.get("GALLERY_ALLOW_PARENT_DIR_ACCESS").unwrap_or_default() == "1")),
+        external_syncs: Arc::new(SyncTracker::new(settings.duration_secs("GALLERY_EXTERNAL_RESYNC_SECS"))),
+        folder_syncs: Arc::new(SyncTracker::new(settings.duration_secs("GALLERY_FOLDER_RESYNC_SECS"))),
+        user_sessions: Arc::new(RwLock::new(HashMap::new())),
+        access_log: Arc::new(std::sync::Mutex::new(Vec::new())),
+        access_log_retention_days: Arc::new(access_log_days(&settings).into()),
+        bandwidth: Arc::new(std::sync::RwLock::new(BandwidthLimiter::from_settings(&settings).map(Arc::new))),
+        external_files_by_id_only: settings.flag("GALLERY_EXTERNAL_FILES_BY_ID"),
+        follow_symlinks: settings.flag("GALLERY_FOLLOW_SYMLINKS"),
+        default_collation: settings.get("GALLERY_COLLATION").filter(|v| !v.trim().is_empty()),
+        playlist_cache: Arc::new(RwLock::new(HashMap::new())),
+        playlist_cache_ttl: Duration::from_secs(settings.parse("GALLERY_PLAYLIST_CACHE_TTL_SECS").unwrap_or(60)),
+        events: broadcast::channel(256).0,
+        serve_stats: Arc::new(std::sync::Mutex::new(HashMap::new())),
+        now_showing: Arc::new(RwLock::new(HashMap::new())),
+        pairing_tokens: Arc::default(),
+        pairing_token_ttl: Duration::from_secs(settings.parse("GALLERY_PAIRING_TTL_SECS").unwrap_or(600)),
+        share_unlock_throttle: Arc::default(),
+        share_unlock_verifies: Arc::new(tokio::sync::Semaphore::new(UNLOCK_MAX_CONCURRENT_VERIFIES)),
+        dark_hours: Arc::new(std::sync::RwLock::new(Arc::new(parse_dark_hours(
+            &settings.get("GALLERY_DARK_HOURS").unwrap_or_default(),
+        )))),
+        decoded_images: Arc::new(std::sync::Mutex::new(DecodedImageCache {
+            capacity_bytes: settings
+                .parse::<usize>("GALLERY_DECODED_CACHE_MB")
+                .unwrap_or(512)
+                .saturating_mul(1024 * 1024),
+            ..Default::default()
+        })),
+        thumbnail_cache: Arc::default(),
+        jobs: Arc::new(std::sync::Mutex::new(HashMap::new())),
+        index_freshness: Arc::new(std::sync::RwLock::new(IndexFreshness::Fresh)),
+        analytics_sample_rate: settings.parse::<f64>("GALLERY_ANALYTICS_SAMPLE_RATE").unwrap_or(1.0).clamp(0.0, 1.0),
+        base_path: settings.profile.as_ref().map(|name| format!("/g/{}", name)).unwrap_or_default(),
+        settings,
+        #[cfg(feature = "onnx")]
+        autotagger: shared.autotagger.clone(),
+        #[cfg(feature = "onnx")]
+        text_encoder: shared.text_encoder.clone(),
+        #[cfg(feature = "onnx")]
+        embedding_index: Arc::new(RwLock::new(None)),
+        #[cfg(feature = "onnx")]
+        face_analyzer: shared.face_analyzer.clone(),
+        #[cfg(feature = "cast")]
+        cast: shared.cast.clone(),
+        #[cfg(feature = "smtp")]
+        mailer: shared.mailer.clone(),
+        #[cfg(feature = "notify")]
+        notifier: shared.notifier.clone(),
+    })
+}
+
+/// 图库的启动扫描与定期维护任务
+async fn start_library(state: &AppState) {
+    // 启动扫描期间直接使用上次运行留下的索引，客户端通过 index_freshness 得知结果可能不完整
+    let indexed: Option<(i64,)> = sqlx::query_as("SELECT 1 FROM images LIMIT 1")
+        .fetch_optional(&state.db)
+        .await
+        .unwrap_or(None);
+    *state.index_freshness.write().unwrap() =
+        if indexed.is_some() { IndexFreshness::Stale } else { IndexFreshness::Building };
+
+    let scan_state = state.clone();
+    spawn_cooperative_job(state, "scan", move |ctx| async move {
+        Ok(serde_json::json!({ "added": rescan_library(&scan_state, &ctx.cancel).await }))
+    })
+    .await;
+
+    // 展示统计定期落库
+    let flush_state = state.clone();
+    let flush_interval = state.settings.parse::<u64>("GALLERY_ANALYTICS_FLUSH_SECS").unwrap_or(30).max(1);
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(Duration::from_secs(flush_interval));
+        loop {
+            ticker.tick().await;
+            flush_serve_stats(&flush_state).await;
+            flush_access_log(&flush_state).await;
+        }
+    });
+
+    // 回收站过期清理 (每小时)
+    let retention_days = trash_retention_days(&state.settings);
+    if retention_days > 0 {
+        let purge_state = state.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(Duration::from_secs(3600));
+            loop {
+                ticker.tick().await;
+                purge_expired_trash(&purge_state, retention_days).await;
+            }
+        });
+    }
+
+    thumbnail_cache::spawn_pruner(state.clone());
+}
+
+// --- 元数据重新关联 ---
+
+/// SQL 条件：人工整理过的图片 (有标签、标题/说明/评分或手动位置)
+const CURATED_SQL_FILTER: &str = "(path IN (SELECT path FROM image_tags) OR path IN (SELECT path FROM image_captions)
+     OR path IN (SELECT path FROM image_positions) OR path IN (SELECT path FROM image_edits))";
+
+/// 为整理过的图片补齐内容哈希，文件移动后可按哈希重新关联
+async fn hash_curated_images(state: &AppState) {
+    let rows: Vec<(String,)> = sqlx::query_as(&format!(
+        "SELECT path FROM images WHERE hash IS NULL AND {} AND {}",
+        PRESENT_SQL_FILTER, CURATED_SQL_FILTER
+    ))
+    .fetch_all(&state.db)
+    .await
+    .unwrap_or_default();
+    for (path,) in rows {
+        let full = resolve_full_path(&state.root_dir, &path);
+        ensure_image_hash(&state.db, &path, &full).await;
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct RelinkRequest {
+    /// 只返回匹配结果，不修改索引
+    #[serde(default)]
+    dry_run: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct RelinkedImage {
+    from: String,
+    to: String,
+    /// `hash`；旧记录没有哈希时为 `name_size_mtime` (同名、同大小、同修改时间)
+    matched_by: &'static str,
+}
+
+#[derive(Debug, Serialize)]
+struct AmbiguousRelink {
+    path: String,
+    candidates: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct RelinkResponse {
+    dry_run: bool,
+    relinked: Vec<RelinkedImage>,
+    /// 有多个候选文件，需要人工处理
+    ambiguous: Vec<AmbiguousRelink>,
+    /// 找不到对应文件 (仍按宽限期保留)
+    unmatched: Vec<String>,
+}
+
+/// 接口: POST /api/relink，把缺失图片上的标签、说明、评分、手动位置等转移到移动后的新路径
+/// (带 `Prefer: respond-async` 时在后台执行，返回任务 ID)
+///
+/// 缺失记录与新路径上尚未整理过的图片按内容哈希匹配；改写 images.path 后外键级联带走所有元数据。
+async fn relink_metadata(
+    State(state): State<AppState>,
+    headers: axum::http::HeaderMap,
+    body: Option<Json<RelinkRequest>>,
+) -> Result<Response, (StatusCode, Json<serde_json::Value>)> {
+    let req = body.map(|Json(req)| req).unwrap_or_default();
+    if prefers_async(&headers) {
+        let job_state = state.clone();
+        let id = spawn_job(&state, "relink", move |job| async move {
+            let Json(response) = run_relink(job_state, req, Some(job)).await.map_err(job_error)?;
+            serde_json::to_value(response).map_err(|e| e.to_string())
+        })
+        .await;
+        return Ok(job_accepted(&state, &id));
+    }
+    run_relink(state, req, None).await.map(IntoResponse::into_response)
+}
+
+async fn run_relink(
+    state: AppState,
+    req: RelinkRequest,
+    job: Option<JobContext>,
+) -> Result<Json<RelinkResponse>, (StatusCode, Json<serde_json::Value>)> {
+    let db_error =
+        |e: sqlx::Error| (StatusCode::INTERNAL_SERVER_ERROR, Json(serde_json::json!({ "detail": e.to_string() })));
+    let tombstones: Vec<(String, Option<String>, i64, f64)> = sqlx::query_as(&format!(
+        "SELECT path, hash, size, mtime FROM images WHERE missing_since IS NOT NULL AND size > 0 AND {}
+         ORDER BY path",
+        CURATED_SQL_FILTER
+    ))
+    .fetch_all(&state.db)
+    .await
+    .map_err(db_error)?;
+
+    let mut response = RelinkResponse {
+        dry_run: req.dry_run,
+        relinked: Vec::new(),
+        ambiguous: Vec::new(),
+        unmatched: Vec::new(),
+    };
+    let mut claimed: HashSet<String> = HashSet::new();
+    let total = tombstones.len();
+    for (done, (path, hash, size, mtime)) in tombstones.into_iter().enumerate() {
+        if let Some(job) = &job {
+            job.set_progress(done, total).await;
+        }
+        let candidates: Vec<(String, f64)> = sqlx::query_as(&format!(
+            "SELECT path, mtime FROM images WHERE size = ? AND {} AND NOT {}",
+            PRESENT_SQL_FILTER, CURATED_SQL_FILTER
+        ))
+        .bind(size)
+        .fetch_all(&state.db)
+        .await
+        .map_err(db_error)?;
+
+        let file_name = path.rsplit('/').next().unwrap_or(&path);
+        let mut matches = Vec::new();
+        for (candidate, candidate_mtime) in candidates {
+            if claimed.contains(&candidate) {
+                continue;
+            }
+            let matched = match &hash {
+                Some(hash) => {
+                    let full = resolve_full_path(&state.root_dir, &candidate);
+                    ensure_image_hash(&state.db, &candidate, &full).await.as_ref() == Some(hash)
+                }
+                None => {
+                    candidate.rsplit('/').next() == Some(file_name) && (candidate_mtime - mtime).abs() < 0.001
+                }
+            };
+            if matched {
+                matches.push((candidate, candidate_mtime));
+            }
+        }
+
+        match matches.len() {
+            0 => response.unmatched.push(path),
+            1 => {
+                let (to, to_mtime) = matches.remove(0);
+                if !req.dry_run {
+                    relink_image(&state, &path, &to, to_mtime).await.map_err(db_error)?;
+                }
+                claimed.insert(to.clone());
+                let matched_by = if hash.is_some() { "hash" } else { "name_size_mtime" };
+                response.relinked.push(RelinkedImage { from: path, to, matched_by });
+            }
+            _ => response.ambiguous.push(AmbiguousRelink {
+                path,
+                candidates: matches.into_iter().map(|(candidate, _)| candidate).collect(),
+            }),
+        }
+    }
+
+    if !req.dry_run && !response.relinked.is_empty() {
+        tracing::info!("🔗 [Relink] 已重新关联 {} 张移动过的图片", response.relinked.len());
+        invalidate_playlist_cache(&state).await;
+    }
+    Ok(Json(response))
+}
+
+/// 用缺失记录替换新路径上的记录：新记录的派生数据随之删除，旧记录改写路径后标签等元数据级联跟随
+async fn relink_image(state: &AppState, from: &str, to: &str, to_mtime: f64) -> sqlx::Result<()> {
+    let mut tx = state.db.begin().await?;
+    sqlx::query("DELETE FROM images WHERE path = ?").bind(to).execute(&mut *tx).await?;
+    sqlx::query("UPDATE images SET path = ?, mtime = ?, missing_since = NULL WHERE path = ?")
+        .bind(to)
+        .bind(to_mtime)
+        .bind(from)
+        .execute(&mut *tx)
+        .await?;
+    // 手动位置只在原文件夹内有意义
+    sqlx::query("DELETE FROM image_positions WHERE path = ? AND folder != ?")
+        .bind(to)
+        .bind(parent_folder(to))
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query("UPDATE OR IGNORE blocklist SET path = ? WHERE path = ?")
+        .bind(to)
+        .bind(from)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await
+}
+
+// --- 能力发现 ---
+
+/// 已启用的 cargo feature 名称
+fn enabled_features() -> Vec<&'static str> {
+    CARGO_FEATURES.iter().filter(|(_, on)| *on).map(|(name, _)| *name).collect()
+}
+
+/// (自动标签, 文本搜图, 人脸) 是否可用：需要编译 onnx 且加载了对应模型
+#[cfg(feature = "onnx")]
+fn onnx_capabilities(state: &AppState) -> (bool, bool, bool) {
+    (state.autotagger.is_some(), state.text_encoder.is_some(), state.face_analyzer.is_some())
+}
+
+#[cfg(not(feature = "onnx"))]
+fn onnx_capabilities(_state: &AppState) -> (bool, bool, bool) {
+    (false, false, false)
+}
+
+/// 接口: GET /api/capabilities
+async fn get_capabilities(State(state): State<AppState>) -> Json<CapabilitiesResponse> {
+    let (autotag, semantic_search, faces) = onnx_capabilities(&state);
+    #[cfg(feature = "smtp")]
+    let email_digests = state.mailer.is_some();
+    #[cfg(not(feature = "smtp"))]
+    let email_digests = false;
+    #[cfg(feature = "notify")]
+    let notifications = state.notifier.is_some();
+    #[cfg(not(feature = "notify"))]
+    let notifications = false;
+
+    Json(CapabilitiesResponse {
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        features: enabled_features().into_iter().map(String::from).collect(),
+        capabilities: Capabilities {
+            heic: false,
+            video: false,
+            live_photos: COMPANION_EXTENSIONS.iter().any(|(_, kind)| *kind == "live_video"),
+            faces,
+            autotag,
+            semantic_search,
+            dlna: false,
+            cast: cfg!(feature = "cast"),
+            email_digests,
+            notifications,
+            locale_collation: cfg!(feature = "icu"),
+            backup: backup::S3Config::from_state(&state).is_some(),
+        },
+        sort_modes: SORT_MODES.iter().map(|s| s.to_string()).collect(),
+        formats: SupportedFormats {
+            images: ALLOWED_EXTENSIONS.iter().map(|e| e.to_string()).collect(),
+            companions: COMPANION_EXTENSIONS
+                .iter()
+                .map(|(extension, kind)| CompanionFormat { extension: extension.to_string(), kind: kind.to_string() })
+                .collect(),
+        },
+        limits: Limits {
+            query_max_limit: QUERY_MAX_LIMIT,
+            info_batch_max: IMAGE_INFO_BATCH_MAX,
+            collage_max_images: COLLAGE_MAX_IMAGES,
+            contact_sheet_max_images: CONTACT_SHEET_MAX_IMAGES,
+            render_max_side: RENDER_MAX_SIDE,
+            image_title_max_chars: IMAGE_TITLE_MAX_CHARS,
+            image_caption_max_chars: IMAGE_CAPTION_MAX_CHARS,
+            folder_title_max_chars: FOLDER_TITLE_MAX_CHARS,
+            folder_description_max_chars: FOLDER_DESCRIPTION_MAX_CHARS,
+            share_comment_max_chars: SHARE_COMMENT_MAX_CHARS,
+            idempotency_max_request_bytes: IDEMPOTENCY_MAX_REQUEST_BYTES,
+        },
+    })
+}
+
+/// Cast 接收器可稳定显示的最大尺寸
+const CAST_FRAME_MAX: (u32, u32) = (1920, 1080);
+
+/// 接口: GET /api/cast/frame?path=...，转码为不超过 1080p 的 JPEG 供投屏设备加载
+async fn cast_frame(State(state): State<AppState>, Query(query): Query<FileQuery>) -> Response {
+    let (width, height) = CAST_FRAME_MAX;
+    match client_path_key(&state, &query.path).await {
+        Ok(rel) => serve_rendered(&state, &rel, RenderSpec::fit_within(width, height)).await,
+        Err(PathAccessError::Forbidden) => (StatusCode::FORBIDDEN, "Access outside ROOT_DIR is disabled").into_response(),
+        Err(PathAccessError::NotFound) => (StatusCode::NOT_FOUND, "File not found").into_response(),
+    }
+}
+
+#[cfg(feature = "cast")]
+#[derive(Debug, Default, Deserialize)]
+struct CastStartRequest {
+    interval_secs: Option<u64>,
+}
+
+/// 接口: GET /api/cast/devices，列出 mDNS 发现的 Cast 设备
+#[cfg(feature = "cast")]
+async fn list_cast_devices(State(state): State<AppState>) -> Json<Vec<cast::CastDevice>> {
+    Json(state.cast.devices().await)
+}
+
+/// 接口: POST /api/cast/{device}/start|next|stop
+///
+/// `start` 把调用方当前会话的播放列表推送到设备 (从其正在显示的图片开始)，
+/// 设备通过 `GALLERY_PUBLIC_URL` (缺省取请求的 Host) 回访 `/api/cast/frame`。
+#[cfg(feature = "cast")]
+async fn cast_control(
+    State(state): State<AppState>,
+    connect_info: ConnectInfo<SocketAddr>,
+    axum::extract::Path((device, action)): axum::extract::Path<(String, String)>,
+    headers: axum::http::HeaderMap,
+    body: Option<Json<CastStartRequest>>,
+) -> Result<Json<serde_json::Value>, (StatusCode, Json<serde_json::Value>)> {
+    let error = |status: StatusCode, detail: &str| (status, Json(serde_json::json!({ "detail": detail })));
+    let Some(device) = state.cast.find(&device).await else {
+        return Err(error(StatusCode::NOT_FOUND, "Cast device not found"));
+    };
+
+    match action.as_str() {
+        "start" => {
+            let ip = connect_info.0.ip().to_string();
+            let blocked = load_blocklist(&state.db, &ip).await;
+            let playlist: Vec<String> = match state.user_sessions.read().await.get(&ip) {
+                Some(session) => session.playlist.iter().filter(|p| !blocked.contains(*p)).cloned().collect(),
+                None => Vec::new(),
+            };
+            if playlist.is_empty() {
+                return Err(error(StatusCode::CONFLICT, "No active slideshow session to cast"));
+            }
+
+            let Some(base_url) = public_base_url(&headers) else {
+                return Err(error(
+                    StatusCode::BAD_REQUEST,
+                    "Cast devices cannot reach a loopback address; set GALLERY_PUBLIC_URL",
+                ));
+            };
+
+            let start = match state.now_showing.read().await.get(&ip) {
+                Some(showing) => playlist.iter().position(|p| *p == showing.path).unwrap_or(0),
+                None => 0,
+            };
+            let urls: Vec<String> = playlist
+                .iter()
+                .map(|p| format!("{}{}/api/v1/cast/frame?path={}", base_url, state.base_path, urlencoding::encode(p)))
+                .collect();
+            let interval = body
+                .and_then(|Json(req)| req.interval_secs)
+                .or_else(|| config_var("GALLERY_CAST_INTERVAL_SECS").ok().and_then(|v| v.parse().ok()))
+                .unwrap_or(10)
+                .max(2);
+
+            let count = urls.len();
+            state.cast.start(device.clone(), urls, start, Duration::from_secs(interval)).await;
+            Ok(Json(serde_json::json!({
+                "device": device.id,
+                "status": "starting",
+                "images": count,
+                "interval_secs": interval,
+            })))
+        }
+        "next" | "stop" => {
+            let command = if action == "next" { cast::CastCommand::Next } else { cast::CastCommand::Stop };
+            if !state.cast.command(&device.id, command).await {
+                return Err(error(StatusCode::CONFLICT, "No active cast session on this device"));
+            }
+            Ok(Json(serde_json::json!({ "device": device.id, "status": action })))
+        }
+        _ => Err(error(StatusCode::NOT_FOUND, "Unknown cast action (expected start, next or stop)")),
+    }
+}
+
+#[cfg(not(feature = "cast"))]
+async fn list_cast_devices() -> (StatusCode, Json<serde_json::Value>) {
+    cast_unavailable()
+}
+
+#[cfg(not(feature = "cast"))]
+async fn cast_control() -> (StatusCode, Json<serde_json::Value>) {
+    cast_unavailable()
+}
+
+#[cfg(not(feature = "cast"))]
+fn cast_unavailable() -> (StatusCode, Json<serde_json::Value>) {
+    (
+        StatusCode::NOT_IMPLEMENTED,
+        Json(serde_json::json!({ "detail": "Casting requires the `cast` build feature" })),
+    )
+}
+
+// --- 多相框编排 ---
+
+/// 服务器本地时间的每周时间窗口；`start` 晚于 `end` 表示跨越午夜
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct ScheduleWindow {
+    /// 星期几 (0=周日 … 6=周六)，为空表示每天；跨午夜窗口以开始那天为准
+    #[serde(default)]
+    days: Vec<u8>,
+    /// "HH:MM"
+    start: String,
+    end: String,
+}
+
+fn parse_hhmm(value: &str) -> Option<u32> {
+    let (h, m) = value.trim().split_once(':')?;
+    let (h, m): (u32, u32) = (h.parse().ok()?, m.parse().ok()?);
+    (h <= 24 && m < 60 && h * 60 + m <= 24 * 60).then_some(h * 60 + m)
+}
+
+impl ScheduleWindow {
+    fn validate(&self) -> Result<(), String> {
+        if parse_hhmm(&self.start).is_none() || parse_hhmm(&self.end).is_none() {
+            return Err(format!("Invalid time window {}-{} (expected HH:MM)", self.start, self.end));
+        }
+        if self.days.iter().any(|d| *d > 6) {
+            return Err("Schedule days must be 0 (Sunday) to 6 (Saturday)".to_string());
+        }
+        Ok(())
+    }
+
+    fn day_matches(&self, weekday: u32) -> bool {
+        self.days.is_empty() || self.days.iter().any(|d| *d as u32 == weekday)
+    }
+
+    fn contains(&self, now: &chrono::DateTime<chrono::Local>) -> bool {
+        use chrono::{Datelike, Timelike};
+        let (Some(start), Some(end)) = (parse_hhmm(&self.start), parse_hhmm(&self.end)) else {
+            return false;
+        };
+        let minute = now.hour() * 60 + now.minute();
+        let weekday = now.weekday().num_days_from_sunday();
+        if start <= end {
+            (start..end).contains(&minute) && self.day_matches(weekday)
+        } else if minute >= start {
+            self.day_matches(weekday)
+        } else {
+            minute < end && self.day_matches((weekday + 6) % 7)
+        }
+    }
+}
+
+/// 解析 `GALLERY_DARK_HOURS`，如 `22:00-07:00,12:30-13:30` (每天生效)
+fn parse_dark_hours(spec: &str) -> Vec<ScheduleWindow> {
+    spec.split(',')
+        .map(str::trim)
+        .filter(|part| !part.is_empty())
+        .filter_map(|part| {
+            let window = part.split_once('-').map(|(start, end)| ScheduleWindow {
+                days: Vec::new(),
+                start: start.trim().to_string(),
+                end: end.trim().to_string(),
+            });
+            match window.filter(|w| w.validate().is_ok()) {
+                Some(window) => Some(window),
+                None => {
+                    tracing::warn!("⚠️ Ignoring invalid dark hours window {:?}", part);
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+/// 找到从 `now` 起 `active` 的结果第一次变化的整分钟 (最多向后看一周)
+fn next_schedule_change<T: PartialEq>(
+    now: chrono::DateTime<chrono::Local>,
+    active: impl Fn(&chrono::DateTime<chrono::Local>) -> T,
+) -> Option<chrono::DateTime<chrono::Local>> {
+    use chrono::Timelike;
+    let current = active(&now);
+    let mut t = now.with_second(0)?.with_nanosecond(0)?;
+    for _ in 0..7 * 24 * 60 {
+        t += chrono::Duration::minutes(1);
+        if active(&t) != current {
+            return Some(t);
+        }
+    }
+    None
+}
+
+/// 时间表规则的过滤条件，覆盖客户端播放列表请求中的对应字段 (未设置的字段保持不变)
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+struct ScheduleFilters {
+    /// 文件夹 (相册)
+    paths: Option<Vec<String>>,
+    tags: Option<Vec<String>>,
+    people: Option<Vec<i64>>,
+    orientation: Option<String>,
+    sort: Option<String>,
+}
+
+impl ScheduleFilters {
+    fn apply(&self, req: &mut PlaylistRequest) {
+        if let Some(paths) = &self.paths {
+            req.paths = paths.clone();
+        }
+        if let Some(tags) = &self.tags {
+            req.tags = tags.clone();
+        }
+        if let Some(people) = &self.people {
+            req.people = people.clone();
+        }
+        if let Some(orientation) = &self.orientation {
+            req.orientation = Some(orientation.clone());
+        }
+        if let Some(sort) = &self.sort {
+            req.sort = Some(sort.clone());
+        }
+    }
+}
+
+/// 服务器端时间表规则：任一时间窗口命中时应用其过滤条件，按列表顺序第一个命中者生效
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct ScheduleRule {
+    name: String,
+    windows: Vec<ScheduleWindow>,
+    #[serde(default)]
+    filters: ScheduleFilters,
+}
+
+fn active_schedule_rule<'a>(rules: &'a [ScheduleRule], now: &chrono::DateTime<chrono::Local>) -> Option<&'a ScheduleRule> {
+    rules.iter().find(|rule| rule.windows.iter().any(|w| w.contains(now)))
+}
+
+async fn load_schedule_rules(pool: &Pool<Sqlite>) -> Vec<ScheduleRule> {
+    let rows: Vec<(String,)> = sqlx::query_as("SELECT rule_json FROM schedule_rules ORDER BY position")
+        .fetch_all(pool)
+        .await
+        .unwrap_or_default();
+    rows.iter().filter_map(|(json,)| serde_json::from_str(json).ok()).collect()
+}
+
+/// 对 `scheduled` 请求应用当前生效的规则，返回规则名
+async fn apply_schedule(state: &AppState, req: &mut PlaylistRequest) -> Option<String> {
+    let rules = load_schedule_rules(&state.db).await;
+    let rule = active_schedule_rule(&rules, &chrono::Local::now())?;
+    rule.filters.apply(req);
+    Some(rule.name.clone())
+}
+
+/// 生效规则已变化时，按会话原始条件重新生成 `scheduled` 会话的播放列表
+async fn refresh_scheduled_session(state: &AppState, ip: &str) {
+    let current = match state.user_sessions.read().await.get(ip) {
+        Some(session) if session.generation_status == GenerationStatus::Complete => {
+            session.criteria.clone().map(|c| (c, session.generation))
+        }
+        _ => None,
+    };
+    let Some((criteria, generation)) = current.filter(|(c, _)| c.scheduled) else {
+        return;
+    };
+
+    let mut req = PlaylistRequest {
+        paths: criteria.paths.clone(),
+        sort: Some(criteria.sort.clone()),
+        orientation: Some(criteria.orientation.clone()),
+        direction: criteria.direction.clone(),
+        current_path: None,
+        interleave: criteria.interleave,
+        max_per_folder: criteria.max_per_folder,
+        chunk_size: None,
+        collation: criteria.collation.clone(),
+        tags: criteria.tags.clone(),
+        people: criteria.people.clone(),
+        collapse_bursts: criteria.collapse_bursts,
+        scheduled: true,
+        mix: criteria.mix.clone(),
+        spread_by: criteria.spread_by.clone(),
+        spread_distance: criteria.spread_distance,
+        boost_recent_days: criteria.boost_recent_days,
+        panoramas_only: criteria.panoramas_only,
+        include_panoramas: criteria.include_panoramas,
+    };
+    let rule = apply_schedule(state, &mut req).await;
+    if rule == criteria.schedule_rule {
+        return;
+    }
+
+    tracing::info!(
+        "🕒 [Schedule] {} 切换到规则 {:?}，重新生成播放列表",
+        ip,
+        rule.as_deref().unwrap_or("<none>")
+    );
+    let valid_req_paths = prepare_request_paths(state, &req.paths).await;
+    let playlist = generate_playlist(state, &req, &valid_req_paths, ip).await;
+    let criteria = PlaylistCriteria { schedule_rule: rule, ..criteria };
+    store_session_playlist_if_current(state, ip, generation, playlist, Some(criteria), GenerationStatus::Complete).await;
+}
+
+#[derive(Debug, Deserialize)]
+struct SetSchedulesRequest {
+    rules: Vec<ScheduleRule>,
+}
+
+/// 接口: GET /api/schedules，列出时间表规则及当前生效的规则
+async fn get_schedules(State(state): State<AppState>) -> Json<serde_json::Value> {
+    let rules = load_schedule_rules(&state.db).await;
+    let now = chrono::Local::now();
+    let active = active_schedule_rule(&rules, &now).map(|r| r.name.clone());
+    let next_change_at =
+        next_schedule_change(now, |t| active_schedule_rule(&rules, t).map(|r| r.name.clone())).map(|t| t.timestamp());
+    Json(serde_json::json!({
+        "rules": rules,
+        "active": active,
+        "next_change_at": next_change_at,
+    }))
+}
+
+/// 接口: POST /api/schedules，整体替换时间表规则 (顺序即优先级)
+async fn set_schedules(
+    State(state): State<AppState>,
+    Json(req): Json<SetSchedulesRequest>,
+) -> Result<Json<serde_json::Value>, (StatusCode, Json<serde_json::Value>)> {
+    let bad_request = |detail: String| (StatusCode::BAD_REQUEST, Json(serde_json::json!({ "detail": detail })));
+    for rule in &req.rules {
+        if rule.name.trim().is_empty() || rule.windows.is_empty() {
+            return Err(bad_request("Each rule needs a name and at least one window".to_string()));
+        }
+        for window in &rule.windows {
+            window.validate().map_err(bad_request)?;
+        }
+    }
+
+    let db_error = |e: sqlx::Error| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(serde_json::json!({ "detail": format!("Failed to save schedules: {}", e) })),
+        )
+    };
+    let mut tx = state.db.begin().await.map_err(db_error)?;
+    sqlx::query("DELETE FROM schedule_rules").execute(&mut *tx).await.map_err(db_error)?;
+    for (position, rule) in req.rules.iter().enumerate() {
+        sqlx::query("INSERT INTO schedule_rules (position, name, rule_json) VALUES (?, ?, ?)")
+            .bind(position as i64)
+            .bind(&rule.name)
+            .bind(serde_json::to_string(rule).unwrap_or_default())
+            .execute(&mut *tx)
+            .await
+            .map_err(db_error)?;
+    }
+    tx.commit().await.map_err(db_error)?;
+
+    Ok(Json(serde_json::json!({ "rules": req.rules.len() })))
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct DeviceAssignment {
+    /// 便于管理界面显示，如 "recipes"
+    label: Option<String>,
+    /// 为空表示默认分配 (没有其他分配命中时使用)
+    schedule: Option<ScheduleWindow>,
+    playlist: PlaylistRequest,
+}
+
+/// 当前生效的分配：第一个命中时间窗口的，否则第一个无时间窗口的
+fn active_assignment(assignments: &[DeviceAssignment], now: &chrono::DateTime<chrono::Local>) -> Option<usize> {
+    assignments
+        .iter()
+        .position(|a| a.schedule.as_ref().is_some_and(|w| w.contains(now)))
+        .or_else(|| assignments.iter().position(|a| a.schedule.is_none()))
+}
+
+#[derive(Debug, Deserialize)]
+struct RegisterDeviceRequest {
+    id: String,
+    name: Option<String>,
+    #[serde(default)]
+    assignments: Vec<DeviceAssignment>,
+    /// 该设备的熄屏时段，覆盖全局 `GALLERY_DARK_HOURS`
+    dark_hours: Option<Vec<ScheduleWindow>>,
+    /// 预处理配置名 (见 `/api/profiles`)，设备应通过 `/api/resize?profile=` 取图
+    profile: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct DeviceInfo {
+    id: String,
+    name: Option<String>,
+    assignments: Vec<DeviceAssignment>,
+    active_assignment: Option<usize>,
+    dark_hours: Option<Vec<ScheduleWindow>>,
+    profile: Option<String>,
+    last_seen: Option<f64>,
+    last_ip: Option<String>,
+    /// 通过二维码配对过 (轮询需要设备密钥)
+    paired: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct DeviceQuery {
+    id: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct DeviceKeyQuery {
+    key: Option<String>,
+}
+
+const DEVICE_ID_RULE: &str = "Device id must be non-empty and contain only A-Z, a-z, 0-9, '-' or '_'";
+
+fn valid_device_id(id: &str) -> bool {
+    !id.is_empty() && id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// 配对过的设备 (有密钥) 必须出示密钥；手动注册、没有配对过的设备不校验
+fn device_key_matches(key_hash: Option<&str>, presented: Option<&str>) -> bool {
+    let Some(key_hash) = key_hash else { return true };
+    let (Ok(expected), Some(presented)) = (blake3::Hash::from_hex(key_hash), presented) else { return false };
+    blake3::hash(presented.trim().as_bytes()) == expected
+}
+
+fn parse_assignments(json: &str) -> Vec<DeviceAssignment> {
+    serde_json::from_str(json).unwrap_or_default()
+}
+
+fn parse_device_dark_hours(json: Option<String>) -> Option<Vec<ScheduleWindow>> {
+    json.and_then(|json| serde_json::from_str(&json).ok())
+}
+
+/// 接口: GET /api/devices，列出已注册的相框设备
+async fn list_devices(State(state): State<AppState>) -> Json<Vec<DeviceInfo>> {
+    let rows = sqlx::query(
+        "SELECT id, name, assignments_json, dark_hours_json, profile, last_seen, last_ip, key_hash IS NOT NULL AS paired
+         FROM devices ORDER BY id",
+    )
+        .fetch_all(&state.db)
+        .await
+        .unwrap_or_default();
+    let now = chrono::Local::now();
+    Json(
+        rows.iter()
+            .map(|row| {
+                let assignments = parse_assignments(row.get("assignments_json"));
+                DeviceInfo {
+                    id: row.get("id"),
+                    name: row.get("name"),
+                    active_assignment: active_assignment(&assignments, &now),
+                    assignments,
+                    dark_hours: parse_device_dark_hours(row.get("dark_hours_json")),
+                    profile: row.get("profile"),
+                    last_seen: row.get("last_seen"),
+                    last_ip: row.get("last_ip"),
+                    paired: row.get("paired"),
+                }
+            })
+            .collect(),
+    )
+}
+
+/// 接口: POST /api/devices，注册或更新设备及其分配 (按 id 覆盖)
+async fn register_device(
+    State(state): State<AppState>,
+    Json(req): Json<RegisterDeviceRequest>,
+) -> Result<Json<serde_json::Value>, (StatusCode, Json<serde_json::Value>)> {
+    let bad_request = |detail: String| (StatusCode::BAD_REQUEST, Json(serde_json::json!({ "detail": detail })));
+    let id = req.id.trim();
+    if !valid_device_id(id) {
+        return Err(bad_request(DEVICE_ID_RULE.to_string()));
+    }
+    let windows = req.assignments.iter().filter_map(|a| a.schedule.as_ref());
+    for window in windows.chain(req.dark_hours.iter().flatten()) {
+        window.validate().map_err(bad_request)?;
+    }
+
+    let assignments_json = serde_json::to_string(&req.assignments).unwrap_or_else(|_| "[]".to_string());
+    let dark_hours_json = req.dark_hours.as_ref().and_then(|d| serde_json::to_string(d).ok());
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs_f64();
+    sqlx::query(
+        "INSERT INTO devices (id, name, assignments_json, dark_hours_json, profile, created_at) VALUES (?, ?, ?, ?, ?, ?)
+         ON CONFLICT(id) DO UPDATE SET name = excluded.name, assignments_json = excluded.assignments_json,
+             dark_hours_json = excluded.dark_hours_json, profile = excluded.profile",
+    )
+    .bind(id)
+    .bind(&req.name)
+    .bind(&assignments_json)
+    .bind(&dark_hours_json)
+    .bind(&req.profile)
+    .bind(now)
+    .execute(&state.db)
+    .await
+    .map_err(|e| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(serde_json::json!({ "detail": format!("Failed to save device: {}", e) })),
+        )
+    })?;
+
+    tracing::info!("🖼️ Device registered: {} ({} assignments)", id, req.assignments.len());
+    Ok(Json(serde_json::json!({ "id": id, "assignments": req.assignments.len() })))
+}
+
+/// 接口: DELETE /api/devices?id=...
+async fn delete_device(State(state): State<AppState>, Query(query): Query<DeviceQuery>) -> Json<serde_json::Value> {
+    let removed = sqlx::query("DELETE FROM devices WHERE id = ?")
+        .bind(query.id.trim())
+        .execute(&state.db)
+        .await
+        .map(|r| r.rows_affected())
+        .unwrap_or(0);
+    Json(serde_json::json!({ "removed": removed }))
+}
+
+/// 接口: GET /api/device/{id}/assignment，相框轮询当前应展示的内容
+///
+/// 返回当前生效的分配及其生成的播放列表；`next_change_at` 为下次切换分配的时间 (Unix 秒)，便于设备安排下次轮询。
+/// 配对过的设备需携带配对时签发的密钥 (`Authorization: Bearer` 或 `?key=`)。
+async fn device_assignment(
+    State(state): State<AppState>,
+    connect_info: ConnectInfo<SocketAddr>,
+    axum::extract::Path(id): axum::extract::Path<String>,
+    Query(query): Query<DeviceKeyQuery>,
+    headers: axum::http::HeaderMap,
+) -> Result<Json<serde_json::Value>, (StatusCode, Json<serde_json::Value>)> {
+    let row = sqlx::query("SELECT name, assignments_json, dark_hours_json, profile, key_hash FROM devices WHERE id = ?")
+        .bind(&id)
+        .fetch_optional(&state.db)
+        .await
+        .unwrap_or(None);
+    let Some(row) = row else {
+        return Err((StatusCode::NOT_FOUND, Json(serde_json::json!({ "detail": "Device not registered" }))));
+    };
+    let presented = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .or(query.key.as_deref());
+    if !device_key_matches(row.get("key_hash"), presented) {
+        return Err((StatusCode::UNAUTHORIZED, Json(serde_json::json!({ "detail": "Invalid or missing device key" }))));
+    }
+
+    let seen_at = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs_f64();
+    let _ = sqlx::query("UPDATE devices SET last_seen = ?, last_ip = ? WHERE id = ?")
+        .bind(seen_at)
+        .bind(connect_info.0.ip().to_string())
+        .bind(&id)
+        .execute(&state.db)
+        .await;
+
+    let name: Option<String> = row.get("name");
+    let profile: Option<String> = row.get("profile");
+    let dark_hours = parse_device_dark_hours(row.get("dark_hours_json"))
+        .map(Arc::new)
+        .unwrap_or_else(|| state.dark_hours.read().unwrap().clone());
+    let sleep = SleepHint::new(dark_hours);
+
+    let assignments = parse_assignments(row.get("assignments_json"));
+    let now = chrono::Local::now();
+    let active = active_assignment(&assignments, &now);
+    let next_change_at = next_schedule_change(now, |t| active_assignment(&assignments, t)).map(|t| t.timestamp());
+
+    // 熄屏时段内不下发播放列表，设备应休眠到 sleep_until
+    let Some(index) = active.filter(|_| sleep.sleep_until.is_none()) else {
+        return Ok(Json(serde_json::json!({
+            "device": id,
+            "name": name,
+            "profile": profile,
+            "assignment": null,
+            "playlist": [],
+            "next_change_at": next_change_at,
+            "dark_hours": sleep.dark_hours,
+            "sleep_until": sleep.sleep_until,
+        })));
+    };
+
+    let assignment = &assignments[index];
+    let mut req = assignment.playlist.clone();
+    if req.collation.is_none() {
+        req.collation = state.default_collation.clone();
+    }
+    let schedule_rule = if req.scheduled { apply_schedule(&state, &mut req).await } else { None };
+    let valid_req_paths = prepare_request_paths(&state, &req.paths).await;
+    let playlist = generate_playlist(&state, &req, &valid_req_paths, &format!("device:{}", id)).await;
+
+    Ok(Json(serde_json::json!({
+        "device": id,
+        "name": name,
+        "profile": profile,
+        "assignment": index,
+        "label": assignment.label,
+        "criteria": assignment.playlist,
+        "schedule_rule": schedule_rule,
+        "playlist": playlist_for_client(&state, playlist).await,
+        "next_change_at": next_change_at,
+        "dark_hours": sleep.dark_hours,
+        "sleep_until": sleep.sleep_until,
+    })))
+}
+
+async fn trigger_scan(State(state): State<AppState>) -> Json<serde_json::Value> {
+    let scan_state = state.clone();
+    let job_id = spawn_cooperative_job(&state, "scan", move |ctx| async move {
+        Ok(serde_json::json!({ "added": rescan_library(&scan_state, &ctx.cancel).await }))
+    })
+    .await;
+    Json(serde_json::json!({ "status": "scanning_started", "job_id": job_id }))
+}
+
+/// 接口: POST /api/scan/cancel，取消本图库正在运行的全量扫描
+///
+/// 扫描在批次之间检查取消请求，未提交的批次回滚；任务记录最终变为 `cancelled`。
+async fn cancel_scan(
+    State(state): State<AppState>,
+) -> Result<(StatusCode, Json<serde_json::Value>), (StatusCode, Json<serde_json::Value>)> {
+    let running: Vec<(String,)> = sqlx::query_as("SELECT id FROM jobs WHERE kind = 'scan' AND status = 'running'")
+        .fetch_all(&state.db)
+        .await
+        .unwrap_or_default();
+    let job_ids: Vec<String> = {
+        let jobs = state.jobs.lock().unwrap();
+        running
+            .into_iter()
+            .filter_map(|(id,)| jobs.get(&id).map(|token| (id, token)))
+            .map(|(id, token)| {
+                token.cancel();
+                id
+            })
+            .collect()
+    };
+    if job_ids.is_empty() {
+        return Err((
+            StatusCode::CONFLICT,
+            Json(serde_json::json!({ "detail": "No scan is running" })),
+        ));
+    }
+    tracing::info!("🛑 [Scan] 请求取消扫描: {:?}", job_ids);
+    Ok((StatusCode::ACCEPTED, Json(serde_json::json!({ "status": "cancelling", "job_ids": job_ids }))))
+}
+
+/// 路径清洗 + 权限检查，并对外部路径/缺失路径做按需同步
+async fn prepare_request_paths(state: &AppState, paths: &[String]) -> Vec<String> {
+    let root_dir = state.root_dir.as_path();
+    let allow_parent = *state.allow_parent_dir_access.read().await;
+
+    let mut valid_req_paths = Vec::new();
+    for p in paths {
+        let rel = normalize_rel_path(p);
+
+        // 权限检查
+        if resolve_and_authorize(root_dir, &rel, allow_parent) == Err(PathAccessError::Forbidden) {
+            valid_req_paths.push(".".to_string()); // fallback to root
+        } else {
+            valid_req_paths.push(rel);
+        }
+    }
+    let mut seen_req = HashSet::new();
+    valid_req_paths.retain(|p| seen_req.insert(p.clone()));
+
+    let mut external_paths = Vec::new();
+    let mut external_seen = HashSet::new();
+    for p in &valid_req_paths {
+        if p.is_empty() || p == "." {
+            continue;
+        }
+        let full = resolve_full_path(root_dir, p);
+        if !is_under_root(root_dir, &full) && external_seen.insert(p.clone()) {
+            external_paths.push(p.clone());
+        }
+    }
+
+    for ext_path in external_paths {
+        let synced = state
+            .external_syncs
+            .run_if_stale(&ext_path, || async {
+                if let Err(err) = sync_external_path_to_db(&state.db, root_dir, &ext_path, state.follow_symlinks).await {
+                    tracing::error!("⚠️ External path sync failed for {}: {}", ext_path, err);
+                }
+            })
+            .await;
+        if synced {
+            invalidate_playlist_cache(state).await;
+        }
+    }
+
+    let mut missing_paths = Vec::new();
+    let mut indexed_folders = Vec::new();
+    for p in &valid_req_paths {
+        if p.is_empty() || p == "." {
+            continue;
+        }
+        let exists_row: Option<(i64,)> =
+            sqlx::query_as(&format!("SELECT 1 FROM images WHERE path LIKE ? AND {} LIMIT 1", PRESENT_SQL_FILTER))
+            .bind(format!("{}/%", p))
+            .fetch_optional(&state.db)
+            .await
+            .unwrap_or(None);
+        if exists_row.is_none() {
+            missing_paths.push(p.clone());
+        } else if !external_seen.contains(p) {
+            indexed_folders.push(p.clone());
+        }
+    }
+
+    // 已索引的文件夹超过新鲜度期限后重新同步，新加入的文件无需等待全量扫描
+    if state.folder_syncs.max_age.is_some() {
+        for folder in indexed_folders {
+            let synced = state
+                .folder_syncs
+                .run_if_stale(&folder, || async {
+                    if let Err(err) = sync_external_path_to_db(&state.db, root_dir, &folder, state.follow_symlinks).await {
+                        tracing::error!("⚠️ Folder re-sync failed for {}: {}", folder, err);
+                    }
+                })
+                .await;
+            if synced {
+                invalidate_playlist_cache(state).await;
+            }
+        }
+    }
+
+    for missing in missing_paths {
+        if let Err(err) = upsert_missing_path_to_db(&state.db, root_dir, &missing, state.follow_symlinks).await {
+            tracing::error!("⚠️ Missing-path upsert failed for {}: {}", missing, err);
+        }
+        invalidate_playlist_cache(state).await;
+    }
+
+    valid_req_paths
+}
+
+/// 构建单个来源的查询语句，返回 (SQL, 可选的 LIKE 前缀参数)
+/// 方向过滤如何对待全景图
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum PanoramaFilter {
+    /// `Landscape` / `Portrait` 排除全景图，`Both` 保留
+    Default,
+    /// 全景图按宽高照常参与方向过滤
+    Include,
+    /// 只要全景图，忽略方向过滤
+    Only,
+}
+
+impl PanoramaFilter {
+    fn new(only: bool, include: bool) -> Self {
+        match (only, include) {
+            (true, _) => Self::Only,
+            (false, true) => Self::Include,
+            (false, false) => Self::Default,
+        }
+    }
+}
+
+fn build_source_query(
+    path_prefix: &str,
+    allow_parent: bool,
+    orientation: &str,
+    panoramas: PanoramaFilter,
+) -> (String, Option<String>) {
+    let (mut query_builder, maybe_prefix_pattern): (String, Option<String>) = if path_prefix == "." || path_prefix.is_empty() {
+        (format!("SELECT * FROM images WHERE {} AND {}", PRESENT_SQL_FILTER, INTERNAL_PATH_SQL_FILTER), None)
+    } else {
+        (
+            format!("SELECT * FROM images WHERE {} AND path LIKE ?", PRESENT_SQL_FILTER),
+            Some(format!("{}/%", path_prefix)),
+        )
+    };
+
+    if !allow_parent && path_prefix != "." && !path_prefix.is_empty() {
+        query_builder.push_str(" AND ");
+        query_builder.push_str(INTERNAL_PATH_SQL_FILTER);
+    }
+    
+    if panoramas == PanoramaFilter::Only {
+        query_builder.push_str(" AND is_panorama = 1");
+    } else if orientation == "Landscape" {
+        query_builder.push_str(" AND is_landscape = 1");
+    } else if orientation == "Portrait" {
+        query_builder.push_str(" AND is_landscape = 0");
+    }
+    if panoramas == PanoramaFilter::Default && orientation != "Both" {
+        query_builder.push_str(" AND is_panorama = 0");
+    }
+
+    (query_builder, maybe_prefix_pattern)
+}
+
+/// 播放列表缓存键：规范化后的请求参数 + 访问权限 + 客户端屏蔽列表
+fn playlist_cache_key(
+    req: &PlaylistRequest,
+    valid_req_paths: &[String],
+    allow_parent: bool,
+    blocked: &HashSet<String>,
+) -> u64 {
+    let mut blocked_sorted: Vec<&String> = blocked.iter().collect();
+    blocked_sorted.sort();
+
+    let mut hasher = DefaultHasher::new();
+    req.sort_mode().hash(&mut hasher);
+    req.direction.hash(&mut hasher);
+    req.orientation_filter().hash(&mut hasher);
+    valid_req_paths.hash(&mut hasher);
+    req.interleave.hash(&mut hasher);
+    req.max_per_folder.hash(&mut hasher);
+    req.collation.hash(&mut hasher);
+    req.tags.hash(&mut hasher);
+    req.people.hash(&mut hasher);
+    req.collapse_bursts.hash(&mut hasher);
+    req.spread_by.hash(&mut hasher);
+    req.spread_distance.hash(&mut hasher);
+    req.boost_recent_days.hash(&mut hasher);
+    req.panoramas_only.hash(&mut hasher);
+    req.include_panoramas.hash(&mut hasher);
+    allow_parent.hash(&mut hasher);
+    blocked_sorted.hash(&mut hasher);
+    hasher.finish()
+}
+
+async fn cached_playlist(state: &AppState, key: u64) -> Option<Vec<String>> {
+    if state.playlist_cache_ttl.is_zero() {
+        return None;
+    }
+    let cache = state.playlist_cache.read().await;
+    cache
+        .get(&key)
+        .filter(|entry| entry.cached_at.elapsed() < state.playlist_cache_ttl)
+        .map(|entry| entry.paths.as_ref().clone())
+}
+
+async fn cache_playlist(state: &AppState, key: u64, paths: &[String]) {
+    if state.playlist_cache_ttl.is_zero() {
+        return;
+    }
+    let mut cache = state.playlist_cache.write().await;
+    cache.retain(|_, entry| entry.cached_at.elapsed() < state.playlist_cache_ttl);
+    cache.insert(
+        key,
+        CachedPlaylist {
+            paths: Arc::new(paths.to_vec()),
+            cached_at: Instant::now(),
+        },
+    );
+}
+
+/// 索引发生变化时清空播放列表缓存
+async fn invalidate_playlist_cache(state: &AppState) {
+    state.playlist_cache.write().await.clear();
+}
+
+/// 根据请求生成完整的有序播放列表 (相同请求在 TTL 内直接复用缓存)
+async fn generate_playlist(
+    state: &AppState,
+    req: &PlaylistRequest,
+    valid_req_paths: &[String],
+    client_ip: &str,
+) -> Vec<String> {
+    let allow_parent = *state.allow_parent_dir_access.read().await;
+    let blocked = load_blocklist(&state.db, client_ip).await;
+
+    let cache_key = playlist_cache_key(req, valid_req_paths, allow_parent, &blocked);
+    let mut final_paths = match cached_playlist(state, cache_key).await {
+        // 混合模式每次重新抽样，不使用缓存
+        _ if !req.mix.is_empty() => build_mixed_playlist(state, &req.mix, &blocked).await,
+        Some(paths) => paths,
+        None => {
+            let paths = build_ordered_playlist(state, req, valid_req_paths, allow_parent, &blocked).await;
+            cache_playlist(state, cache_key, &paths).await;
+            paths
+        }
+    };
+
+    // 4. 当前位置旋转
+    if let Some(curr) = &req.current_path {
+        let curr_norm = normalize_rel_path(curr);
+        if let Some(pos) = final_paths.iter().position(|x| x == &curr_norm) {
+            final_paths.rotate_left(pos);
+        }
+    }
+
+    final_paths
+}
+
+/// 查询 + 过滤 + 排序，得到未旋转的播放列表
+async fn build_ordered_playlist(
+    state: &AppState,
+    req: &PlaylistRequest,
+    valid_req_paths: &[String],
+    allow_parent: bool,
+    blocked: &HashSet<String>,
+) -> Vec<String> {
+    let root_dir = state.root_dir.as_path();
+
+    // 2. 数据库查询 (直接利用 SQL 筛选，速度极快)
+    // 注意：构建动态 LIKE 查询比较繁琐，这里简化为获取所有符合条件的然后内存过滤
+    // 或者针对每个路径前缀查一次
+    let mut source_groups: Vec<Vec<ImageMetadata>> = Vec::new();
+
+    for path_prefix in valid_req_paths {
+        let (mut query_builder, maybe_prefix_pattern) =
+            build_source_query(
+            path_prefix,
+            allow_parent,
+            &req.orientation_filter(),
+            PanoramaFilter::new(req.panoramas_only, req.include_panoramas),
+        );
+        let tag_binds = push_tag_filters(&mut query_builder, &req.tags);
+        push_person_filters(&mut query_builder, &req.people);
+
+        let mut query = sqlx::query_as::<_, ImageMetadata>(&query_builder);
+        if let Some(prefix_pattern) = maybe_prefix_pattern {
+            query = query.bind(prefix_pattern);
+        }
+        for value in tag_binds {
+            query = query.bind(value);
+        }
+        let rows = query.fetch_all(&state.db).await.unwrap_or_default();
+        
+        source_groups.push(rows);
+    }
+
+    // 去重 (同一图片只保留在第一个命中的来源中)，并过滤"不再显示"列表
+    let mut seen = HashSet::new();
+    for group in source_groups.iter_mut() {
+        group.retain(|i| !blocked.contains(&i.path) && seen.insert(i.path.clone()));
+    }
+
+    // 连拍/近似重复折叠 (抽样之前进行，避免名额被同一组连拍占满)
+    if req.collapse_bursts {
+        let window_secs = state.settings.parse::<f64>("GALLERY_BURST_WINDOW_SECS").unwrap_or(3.0);
+        source_groups = source_groups
+            .into_iter()
+            .map(|group| collapse_bursts(group, window_secs, BURST_PHASH_DISTANCE))
+            .collect();
+    }
+
+    // 按子文件夹限额抽样 (排序之前进行)
+    if let Some(max) = req.max_per_folder.filter(|m| *m > 0) {
+        source_groups = source_groups
+            .into_iter()
+            .map(|group| sample_per_folder(group, max))
+            .collect();
+    }
+
+    // 3. 排序 (interleave 模式下各来源分别排序后轮流合并)
+    // ICU 排序器不是 Send，必须在最后一个 await 之后再创建
+    let sort = req.sort_mode();
+    let positions = if sort == "manual" { load_manual_positions(state).await } else { HashMap::new() };
+    let collator = NameCollator::for_locale(req.collation.as_deref());
+    let all_images = if req.interleave && source_groups.len() > 1 {
+        let sorted_groups = source_groups
+            .into_iter()
+            .map(|group| sort_images(group, &sort, root_dir, &collator, &positions))
+            .collect();
+        interleave_round_robin(sorted_groups)
+    } else {
+        sort_images(source_groups.into_iter().flatten().collect(), &sort, root_dir, &collator, &positions)
+    };
+    let mut all_images = match req.spread_by.as_deref() {
+        Some(spread_by) if sort == "shuffle" => {
+            let distance = req.spread_distance.unwrap_or(SPREAD_DEFAULT_DISTANCE);
+            spread_shuffle(all_images, |image| spread_key(image, spread_by), distance, &mut rand::thread_rng())
+        }
+        _ => all_images,
+    };
+
+    if req.direction == "reverse" {
+        all_images.reverse();
+    }
+
+    // 新图优先 (方向反转之后进行，新图总在最前)
+    if let Some(days) = req.boost_recent_days.filter(|d| *d > 0) {
+        all_images = boost_recent(all_images, days);
+    }
+
+    all_images.into_iter().map(|i| i.path).collect()
+}
+
+/// 把最近 `days` 天内加入索引的图片稳定地移到最前
+fn boost_recent(images: Vec<ImageMetadata>, days: u32) -> Vec<ImageMetadata> {
+    let cutoff = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs_f64() - days as f64 * 86400.0;
+    let (mut fresh, rest): (Vec<_>, Vec<_>) =
+        images.into_iter().partition(|i| i.added_at.is_some_and(|t| t >= cutoff));
+    fresh.extend(rest);
+    fresh
+}
+
+static SESSION_GENERATION: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
+
+fn next_session_generation() -> u64 {
+    SESSION_GENERATION.fetch_add(1, std::sync::atomic::Ordering::Relaxed) + 1
+}
+
+/// 把播放列表写入内存会话与数据库，返回新会话的代号
+async fn store_session_playlist(
+    state: &AppState,
+    client_ip: &str,
+    playlist: Vec<String>,
+    criteria: Option<PlaylistCriteria>,
+    status: GenerationStatus,
+) -> u64 {
+    let mut sessions = state.user_sessions.write().await;
+    write_session_playlist(state, &mut sessions, client_ip, playlist, criteria, status).await
+}
+
+/// 后台任务写回结果：只有会话仍是 `generation` 那一代时才写入，
+/// 期间客户端若已重新请求 (或会话已结束)，则丢弃本次结果并返回 false
+async fn store_session_playlist_if_current(
+    state: &AppState,
+    client_ip: &str,
+    generation: u64,
+    playlist: Vec<String>,
+    criteria: Option<PlaylistCriteria>,
+    status: GenerationStatus,
+) -> bool {
+    let mut sessions = state.user_sessions.write().await;
+    if sessions.get(client_ip).map(|s| s.generation) != Some(generation) {
+        return false;
+    }
+    write_session_playlist(state, &mut sessions, client_ip, playlist, criteria, status).await;
+    true
+}
+
+/// 调用方持有会话写锁，保证数据库行与内存会话按同一顺序被替换
+async fn write_session_playlist(
+    state: &AppState,
+    sessions: &mut HashMap<String, UserSessionData>,
+    client_ip: &str,
+    playlist: Vec<String>,
+    criteria: Option<PlaylistCriteria>,
+    status: GenerationStatus,
+) -> u64 {
+    let criteria_json = criteria
+        .as_ref()
+        .and_then(|criteria| serde_json::to_string(criteria).ok());
+    if let Ok(json_playlist) = serde_json::to_string(&playlist) {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs_f64();
+        sqlx::query("INSERT OR REPLACE INTO playlists (client_ip, playlist, criteria_json, created_at) VALUES (?, ?, ?, ?)")
+            .bind(client_ip)
+            .bind(json_playlist)
+            .bind(criteria_json)
+            .bind(now)
+            .execute(&state.db)
+            .await
+            .ok();
+    }
+
+    let generation = next_session_generation();
+    sessions.insert(
+        client_ip.to_string(),
+        UserSessionData {
+            playlist,
+            criteria,
+            generation_status: status,
+            generation,
+            resume: None,
+        },
+    );
+    generation
+}
+
+/// 播放列表的稳定哈希：按顺序逐行拼接路径后取 blake3 的前 16 个十六进制字符。
+/// 客户端用它判断服务器端会话是否已被替换 (重新生成、时间表切换、另一个页面请求了新列表)
+fn playlist_hash(playlist: &[String]) -> String {
+    let mut hasher = blake3::Hasher::new();
+    for path in playlist {
+        hasher.update(path.as_bytes());
+        hasher.update(b"\n");
+    }
+    hasher.finalize().to_hex()[..16].to_string()
+}
+
+/// 本机当前会话播放列表 (内存中或数据库里的)，已去掉屏蔽的图片，与 `/api/session-playlist` 返回的一致
+async fn visible_session_playlist(state: &AppState, client_ip: &str, blocked: &HashSet<String>) -> Option<Vec<String>> {
+    let in_memory = state.user_sessions.read().await.get(client_ip).map(|s| s.playlist.clone());
+    let playlist = match in_memory {
+        Some(playlist) => playlist,
+        None => {
+            let row: Option<(String,)> = sqlx::query_as("SELECT playlist FROM playlists WHERE client_ip = ?")
+                .bind(client_ip)
+                .fetch_optional(&state.db)
+                .await
+                .unwrap_or(None);
+            serde_json::from_str::<Vec<String>>(&row?.0).ok()?
+        }
+    };
+    Some(playlist.into_iter().filter(|p| !blocked.contains(p)).collect())
+}
+
+/// 本机当前会话播放列表的哈希；没有会话时为 None
+async fn session_playlist_hash(state: &AppState, client_ip: &str, blocked: &HashSet<String>) -> Option<String> {
+    Some(playlist_hash(&visible_session_playlist(state, client_ip, blocked).await?))
+}
+
+/// 能否先用一次 LIMIT 查询快速给出首批结果 (仅限单来源的 shuffle / name 排序)
+fn can_generate_in_chunks(req: &PlaylistRequest, valid_req_paths: &[String]) -> bool {
+    valid_req_paths.len() == 1
+        && req.mix.is_empty()
+        && matches!(req.sort_mode().as_str(), "shuffle" | "name")
+        && req.max_per_folder.unwrap_or(0) == 0
+        && !req.collapse_bursts
+        && req.spread_by.is_none()
+        && req.boost_recent_days.unwrap_or(0) == 0
+        && NameCollator::is_natural(req.collation.as_deref())
+        && req.current_path.is_none()
+}
+
+/// 快速取出播放列表的首批条目
+async fn fetch_first_chunk(
+    state: &AppState,
+    req: &PlaylistRequest,
+    path_prefix: &str,
+    client_ip: &str,
+    chunk_size: usize,
+) -> Vec<String> {
+    let allow_parent = *state.allow_parent_dir_access.read().await;
+    let (mut query_builder, maybe_prefix_pattern) =
+        build_source_query(
+            path_prefix,
+            allow_parent,
+            &req.orientation_filter(),
+            PanoramaFilter::new(req.panoramas_only, req.include_panoramas),
+        );
+    let tag_binds = push_tag_filters(&mut query_builder, &req.tags);
+    push_person_filters(&mut query_builder, &req.people);
+    // "不再显示"列表必须在 LIMIT 之前排除，否则首批可能被屏蔽的图片占满
+    query_builder.push_str(" AND path NOT IN (SELECT path FROM blocklist WHERE client_ip = ?)");
+
+    if req.sort_mode() == "shuffle" {
+        query_builder.push_str(" ORDER BY RANDOM()");
+    } else if req.direction == "reverse" {
+        query_builder.push_str(&format!(" ORDER BY path COLLATE {} DESC", NATURAL_COLLATION));
+    } else {
+        query_builder.push_str(&format!(" ORDER BY path COLLATE {}", NATURAL_COLLATION));
+    }
+    query_builder.push_str(" LIMIT ?");
+
+    let mut query = sqlx::query_as::<_, ImageMetadata>(&query_builder);
+    if let Some(prefix_pattern) = maybe_prefix_pattern {
+        query = query.bind(prefix_pattern);
+    }
+    for value in tag_binds {
+        query = query.bind(value);
+    }
+    let rows = query
+        .bind(client_ip)
+        .bind(chunk_size as i64)
+        .fetch_all(&state.db)
+        .await
+        .unwrap_or_default();
+    rows.into_iter().map(|i| i.path).collect()
+}
+
+async fn get_playlist(
+    State(state): State<AppState>,
+    connect_info: ConnectInfo<SocketAddr>,
+    ValidJson(mut req): ValidJson<PlaylistRequest>,
+) -> Response {
+    if req.collation.is_none() {
+        req.collation = state.default_collation.clone();
+    }
+
+    // 1. 路径清洗
+    for path in &req.paths {
+        if let Err(rejection) = reject_double_encoded(&state.root_dir, &normalize_rel_path(path)) {
+            return rejection.into_response();
+        }
+    }
+    let valid_req_paths = prepare_request_paths(&state, &req.paths).await;
+    let ip = connect_info.0.ip().to_string();
+    let index_freshness = *state.index_freshness.read().unwrap();
+    let interval_secs = apply_folder_defaults(&state, &mut req, &valid_req_paths).await;
+
+    // 会话条件记录客户端的原始请求 (已补上文件夹默认值)，时间表规则只作用于本次生成
+    let mut criteria = PlaylistCriteria {
+        sort: req.sort_mode(),
+        direction: req.direction.clone(),
+        orientation: req.orientation_filter(),
+        paths: valid_req_paths.clone(),
+        interleave: req.interleave,
+        max_per_folder: req.max_per_folder,
+        collation: req.collation.clone(),
+        tags: req.tags.clone(),
+        people: req.people.clone(),
+        collapse_bursts: req.collapse_bursts,
+        scheduled: req.scheduled,
+        schedule_rule: None,
+        mix: req.mix.clone(),
+        spread_by: req.spread_by.clone(),
+        spread_distance: req.spread_distance,
+        boost_recent_days: req.boost_recent_days,
+        panoramas_only: req.panoramas_only,
+        include_panoramas: req.include_panoramas,
+    };
+    let valid_req_paths = if req.scheduled {
+        criteria.schedule_rule = apply_schedule(&state, &mut req).await;
+        if criteria.schedule_rule.is_some() {
+            prepare_request_paths(&state, &req.paths).await
+        } else {
+            valid_req_paths
+        }
+    } else {
+        valid_req_paths
+    };
+
+    // 分块模式：先返回首批结果，完整列表在后台生成后写入会话
+    if let Some(chunk_size) = req.chunk_size.filter(|c| *c > 0) {
+        if can_generate_in_chunks(&req, &valid_req_paths) {
+            let first_chunk = fetch_first_chunk(&state, &req, &valid_req_paths[0], &ip, chunk_size).await;
+
+            let generation = next_session_generation();
+            {
+                let mut sessions = state.user_sessions.write().await;
+                sessions.insert(
+                    ip.clone(),
+                    UserSessionData {
+                        playlist: first_chunk.clone(),
+                        criteria: Some(criteria.clone()),
+                        generation_status: GenerationStatus::Pending,
+                        generation,
+                        resume: None,
+                    },
+                );
+            }
+
+            let bg_state = state.clone();
+            let head = first_chunk.clone();
+            tokio::spawn(async move {
+                let full = generate_playlist(&bg_state, &req, &valid_req_paths, &ip).await;
+                let head_set: HashSet<&String> = head.iter().collect();
+                let mut merged = head.clone();
+                merged.extend(full.iter().filter(|p| !head_set.contains(p)).cloned());
+
+                // 期间客户端若已重新请求，则丢弃本次结果
+                let total = merged.len();
+                if store_session_playlist_if_current(
+                    &bg_state,
+                    &ip,
+                    generation,
+                    merged,
+                    Some(criteria),
+                    GenerationStatus::Complete,
+                )
+                .await
+                {
+                    tracing::info!("📜 [Playlist] {} 的完整播放列表已生成: {} 项", ip, total);
+                }
+            });
+
+            return Json(ChunkedPlaylistResponse {
+                playlist_hash: playlist_hash(&first_chunk),
+                playlist: playlist_for_client(&state, first_chunk).await,
+                generation_status: GenerationStatus::Pending,
+                index_freshness,
+                interval_secs,
+            })
+            .into_response();
+        }
+    }
+
+    let final_paths = generate_playlist(&state, &req, &valid_req_paths, &ip).await;
+
+    // 5. 持久化到数据库 (关键功能恢复)
+    store_session_playlist(&state, &ip, final_paths.clone(), Some(criteria), GenerationStatus::Complete).await;
+
+    if req.chunk_size.is_some() {
+        return Json(ChunkedPlaylistResponse {
+            playlist_hash: playlist_hash(&final_paths),
+            playlist: playlist_for_client(&state, final_paths).await,
+            generation_status: GenerationStatus::Complete,
+            index_freshness,
+            interval_secs,
+        })
+        .into_response();
+    }
+
+    // 纯数组响应没有位置放字段，改用响应头
+    let hash = playlist_hash(&final_paths);
+    let mut response = (
+        [("x-index-freshness", index_freshness.as_str()), ("x-playlist-hash", hash.as_str())],
+        Json(playlist_for_client(&state, final_paths).await),
+    )
+        .into_response();
+    if let Some(interval) = interval_secs {
+        response.headers_mut().insert("x-slideshow-interval", interval.into());
+    }
+    response
+}
+
+/// 只请求了一个文件夹时，客户端未指定的排序与方向过滤使用该文件夹的默认值 (见 `PATCH /api/folder`)；
+/// 返回文件夹的默认播放间隔
+async fn apply_folder_defaults(state: &AppState, req: &mut PlaylistRequest, paths: &[String]) -> Option<u32> {
+    let [path] = paths else {
+        return None;
+    };
+    let folder = if path == "." { "" } else { path.as_str() };
+    let meta = load_folder_meta(state, folder).await?;
+    if req.sort.is_none() {
+        req.sort = meta.sort;
+    }
+    if req.orientation.is_none() {
+        req.orientation = meta.orientation;
+    }
+    meta.interval_secs
+}
+
+async fn restore_playlist(
+    State(state): State<AppState>,
+    connect_info: ConnectInfo<SocketAddr>,
+    Json(req): Json<RestorePlaylistRequest>,
+) -> Result<Json<RestorePlaylistResponse>, (StatusCode, Json<serde_json::Value>)> {
+    let original_count = req.playlist.len();
+    tracing::info!("🔄 [Restore Playlist] 请求恢复播放列表，原始路径数量: {}", original_count);
+    if original_count == 0 {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            Json(serde_json::json!({ "detail": "Playlist cannot be empty" })),
+        ));
+    }
+
+    let root_dir = state.root_dir.clone();
+    let allow_parent = *state.allow_parent_dir_access.read().await;
+
+    // 验证路径有效性 (`id:` 引用展开为路径键，按 ID 模式下直接传来的外部路径被丢弃)
+    let mut normalized = Vec::with_capacity(req.playlist.len());
+    for path in &req.playlist {
+        if let Ok(rel) = client_path_key(&state, path).await {
+            normalized.push(rel);
+        }
+    }
+    let valid_paths = match req.validate {
+        // 使用 fs，确保文件确实还在 (放到阻塞线程池，避免大量 stat 阻塞运行时)
+        RestoreValidation::Fs => tokio::task::spawn_blocking(move || {
+            normalized
+                .into_iter()
+                .filter(|rel| {
+                    resolve_and_authorize(&root_dir, rel, allow_parent).is_ok_and(|full| full.is_file())
+                })
+                .collect()
+        })
+        .await
+        .unwrap_or_default(),
+        RestoreValidation::Index => {
+            let candidates: Vec<String> = normalized
+                .into_iter()
+                .filter(|rel| lexically_authorized(&root_dir, rel, allow_parent))
+                .collect();
+            let indexed = indexed_paths(&state.db, &candidates).await;
+            candidates.into_iter().filter(|rel| indexed.contains(rel)).collect()
+        }
+        RestoreValidation::None | RestoreValidation::Background => normalized
+            .into_iter()
+            .filter(|rel| lexically_authorized(&root_dir, rel, allow_parent))
+            .collect::<Vec<String>>(),
+    };
+
+    if valid_paths.is_empty() {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            Json(serde_json::json!({ "detail": "No valid paths in playlist" })),
+        ));
+    }
+
+    // 客户端手里的列表已经过时 (服务器端会话在它保存之后被替换)，不能拿旧列表覆盖
+    let ip = connect_info.0.ip().to_string();
+    let blocked = load_blocklist(&state.db, &ip).await;
+    if let Some(expected) = &req.playlist_hash {
+        if let Some(current) = session_playlist_hash(&state, &ip, &blocked).await.filter(|h| h != expected) {
+            tracing::info!("🔄 [Restore Playlist] {} 的会话已变化 ({} → {})，要求客户端刷新", ip, expected, current);
+            return Err((
+                StatusCode::CONFLICT,
+                Json(serde_json::json!({
+                    "detail": "Playlist changed on the server, fetch /api/session-playlist instead",
+                    "playlist_hash": current,
+                })),
+            ));
+        }
+    }
+
+    // 更新数据库会话
+    store_session_playlist(&state, &ip, valid_paths.clone(), req.criteria.clone(), GenerationStatus::Complete).await;
+
+    if req.validate == RestoreValidation::Background {
+        spawn_restore_validation(state.clone(), ip.clone(), valid_paths.clone(), allow_parent);
+    }
+
+    let current_index = req.current_index.min(valid_paths.len().saturating_sub(1));
+    let visible: Vec<String> = valid_paths.iter().filter(|p| !blocked.contains(*p)).cloned().collect();
+
+    Ok(Json(RestorePlaylistResponse {
+        status: "restored".to_string(),
+        valid_count: valid_paths.len(),
+        original_count,
+        current_index,
+        validation: req.validate,
+        playlist: playlist_for_client(&state, valid_paths).await,
+        playlist_hash: playlist_hash(&visible),
+    }))
+}
+
+/// 后台校验已恢复的播放列表：移除失效路径并通知该客户端
+fn spawn_restore_validation(state: AppState, client_ip: String, playlist: Vec<String>, allow_parent: bool) {
+    tokio::spawn(async move {
+        let root_dir = state.root_dir.clone();
+        let removed: HashSet<String> = tokio::task::spawn_blocking(move || {
+            playlist
+                .into_iter()
+                .filter(|rel| {
+                    !resolve_and_authorize(&root_dir, rel, allow_parent).is_ok_and(|full| full.is_file())
+                })
+                .collect()
+        })
+        .await
+        .unwrap_or_default();
+
+        if removed.is_empty() {
+            return;
+        }
+
+        // 从当前会话 (可能已被客户端替换) 中剔除失效路径
+        let current = {
+            let sessions = state.user_sessions.read().await;
+            sessions
+                .get(&client_ip)
+                .map(|s| (s.playlist.clone(), s.criteria.clone(), s.generation_status, s.generation))
+        };
+        let mut playlist_size = 0;
+        if let Some((mut playlist, criteria, status, generation)) = current {
+            playlist.retain(|p| !removed.contains(p));
+            playlist_size = playlist.len();
+            store_session_playlist_if_current(&state, &client_ip, generation, playlist, criteria, status).await;
+        }
+
+        tracing::info!("🧹 [Restore Validation] {} 的播放列表移除 {} 个失效路径", client_ip, removed.len());
+        let mut removed: Vec<String> = removed.into_iter().collect();
+        removed.sort();
+        publish_event(
+            &state,
+            Some(&client_ip),
+            "playlist_paths_removed",
+            serde_json::json!({ "removed": removed, "playlist_size": playlist_size }),
+        );
+    });
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct SessionEndRequest {
+    /// 最后展示的位置
+    current_index: Option<usize>,
+    /// 客户端自己的设置 (间隔、过渡效果等)，唤醒时原样返回
+    settings: Option<serde_json::Value>,
+}
+
+/// 客户端正常关机：保存最终进度并让会话休眠 (移出内存，数据库行保留并标记结束时间)。
+/// 下一次 `session-status` / `session-playlist` 请求会自动唤醒，响应中的 `resume` 给出保存的进度
+async fn end_session(
+    State(state): State<AppState>,
+    connect_info: ConnectInfo<SocketAddr>,
+    body: Option<Json<SessionEndRequest>>,
+) -> Result<Json<serde_json::Value>, (StatusCode, Json<serde_json::Value>)> {
+    let ip = connect_info.0.ip().to_string();
+    let req = body.map(|Json(req)| req).unwrap_or_default();
+
+    // 内存中的会话可能还没落库 (分块生成中)，先写入当前列表
+    let in_memory = state
+        .user_sessions
+        .read()
+        .await
+        .get(&ip)
+        .map(|s| (s.playlist.clone(), s.criteria.clone(), s.generation_status));
+    if let Some((playlist, criteria, status)) = in_memory {
+        store_session_playlist(&state, &ip, playlist, criteria, status).await;
+    }
+    state.user_sessions.write().await.remove(&ip);
+    state.now_showing.write().await.remove(&ip);
+
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs_f64();
+    let settings_json = req.settings.as_ref().and_then(|v| serde_json::to_string(v).ok());
+    let ended = sqlx::query(
+        "UPDATE playlists SET current_index = ?, client_settings = ?, ended_at = ? WHERE client_ip = ?",
+    )
+    .bind(req.current_index.map(|i| i as i64))
+    .bind(settings_json)
+    .bind(now)
+    .bind(&ip)
+    .execute(&state.db)
+    .await
+    .map_err(|err| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(serde_json::json!({ "detail": format!("Failed to end session: {}", err) })),
+        )
+    })?
+    .rows_affected();
+    if ended == 0 {
+        return Err((
+            StatusCode::NOT_FOUND,
+            Json(serde_json::json!({ "detail": "No session for this client" })),
+        ));
+    }
+
+    tracing::info!("💤 [Session] {} 结束会话 (位置 {:?})，进入休眠", ip, req.current_index);
+    Ok(Json(serde_json::json!({ "status": "dormant", "ended_at": now })))
+}
+
+#[derive(sqlx::FromRow)]
+struct DormantSessionRow {
+    playlist: String,
+    criteria_json: Option<String>,
+    current_index: Option<i64>,
+    client_settings: Option<String>,
+    ended_at: f64,
+}
+
+/// 唤醒休眠的会话：重新载入内存并清除结束标记，保存的进度随会话响应返回
+async fn wake_dormant_session(state: &AppState, client_ip: &str) {
+    if state.user_sessions.read().await.contains_key(client_ip) {
+        return;
+    }
+    let row: Option<DormantSessionRow> = sqlx::query_as(
+        "SELECT playlist, criteria_json, current_index, client_settings, ended_at FROM playlists
+         WHERE client_ip = ? AND ended_at IS NOT NULL",
+    )
+    .bind(client_ip)
+    .fetch_optional(&state.db)
+    .await
+    .unwrap_or(None);
+    let Some(row) = row else {
+        return;
+    };
+    let Ok(playlist) = serde_json::from_str::<Vec<String>>(&row.playlist) else {
+        return;
+    };
+
+    let resume = SessionResume {
+        current_index: row.current_index.map(|i| (i.max(0) as usize).min(playlist.len().saturating_sub(1))),
+        settings: row.client_settings.and_then(|raw| serde_json::from_str(&raw).ok()),
+        ended_at: row.ended_at,
+    };
+    sqlx::query("UPDATE playlists SET ended_at = NULL WHERE client_ip = ?")
+        .bind(client_ip)
+        .execute(&state.db)
+        .await
+        .ok();
+    tracing::info!("🌅 [Session] 唤醒 {} 的休眠会话 (位置 {:?})", client_ip, resume.current_index);
+    state.user_sessions.write().await.entry(client_ip.to_string()).or_insert(UserSessionData {
+        playlist,
+        criteria: row.criteria_json.as_deref().and_then(|raw| serde_json::from_str(raw).ok()),
+        generation_status: GenerationStatus::Complete,
+        generation: next_session_generation(),
+        resume: Some(resume),
+    });
+}
+
+async fn session_status(
+    State(state): State<AppState>,
+    connect_info: ConnectInfo<SocketAddr>,
+) -> Json<SessionStatusResponse> {
+    let ip = connect_info.0.ip().to_string();
+    let sleep = SleepHint::new(state.dark_hours.read().unwrap().clone());
+    let index_freshness = *state.index_freshness.read().unwrap();
+    wake_dormant_session(&state, &ip).await;
+    let blocked = load_blocklist(&state.db, &ip).await;
+    let playlist_hash = session_playlist_hash(&state, &ip, &blocked).await;
+
+    {
+        let sessions = state.user_sessions.read().await;
+        if let Some(session) = sessions.get(&ip) {
+            return Json(SessionStatusResponse {
+                has_session: true,
+                source: Some("memory".to_string()),
+                playlist_size: session.playlist.len(),
+                playlist_hash,
+                generation_status: session.generation_status,
+                index_freshness,
+                resume: session.resume.clone(),
+                sleep: sleep.clone(),
+            });
+        }
+    }
+    
+    // 从数据库查询
+    let row: Option<(String,)> = sqlx::query_as("SELECT playlist FROM playlists WHERE client_ip = ?")
+        .bind(&ip)
+        .fetch_optional(&state.db)
+        .await
+        .unwrap_or(None);
+
+    if let Some((playlist_json,)) = row {
+        if let Ok(list) = serde_json::from_str::<Vec<String>>(&playlist_json) {
+            return Json(SessionStatusResponse {
+                has_session: true,
+                source: Some("database".to_string()),
+                playlist_size: list.len(),
+                playlist_hash,
+                generation_status: GenerationStatus::Complete,
+                index_freshness,
+                resume: None,
+                sleep: sleep.clone(),
+            });
+        }
+    }
+
+    Json(SessionStatusResponse {
+        has_session: false,
+        source: None,
+        playlist_size: 0,
+        playlist_hash: None,
+        generation_status: GenerationStatus::Complete,
+        index_freshness,
+        resume: None,
+        sleep: sleep.clone(),
+    })
+}
+
+async fn session_playlist(
+    State(state): State<AppState>,
+    connect_info: ConnectInfo<SocketAddr>,
+) -> Json<SessionPlaylistResponse> {
+    let ip = connect_info.0.ip().to_string();
+    let sleep = SleepHint::new(state.dark_hours.read().unwrap().clone());
+    let index_freshness = *state.index_freshness.read().unwrap();
+    wake_dormant_session(&state, &ip).await;
+    refresh_scheduled_session(&state, &ip).await;
+    let blocked = load_blocklist(&state.db, &ip).await;
+
+    {
+        let sessions = state.user_sessions.read().await;
+        if let Some(session) = sessions.get(&ip) {
+            let playlist: Vec<String> = session
+                .playlist
+                .iter()
+                .filter(|p| !blocked.contains(*p))
+                .cloned()
+                .collect();
+            return Json(SessionPlaylistResponse {
+                has_session: true,
+                source: Some("memory".to_string()),
+                playlist_size: playlist.len(),
+                playlist_hash: Some(playlist_hash(&playlist)),
+                playlist: playlist_for_client(&state, playlist).await,
+                criteria: session.criteria.clone(),
+                generation_status: session.generation_status,
+                index_freshness,
+                resume: session.resume.clone(),
+                sleep: sleep.clone(),
+            });
+        }
+    }
+
+    let row: Option<(String, Option<String>)> = sqlx::query_as("SELECT playlist, criteria_json FROM playlists WHERE client_ip = ?")
+        .bind(&ip)
+        .fetch_optional(&state.db)
+        .await
+        .unwrap_or(None);
+
+    if let Some((playlist_json, criteria_json)) = row {
+        if let Ok(mut list) = serde_json::from_str::<Vec<String>>(&playlist_json) {
+            list.retain(|p| !blocked.contains(p));
+            let criteria = criteria_json
+                .as_deref()
+                .and_then(|raw| serde_json::from_str::<PlaylistCriteria>(raw).ok());
+            return Json(SessionPlaylistResponse {
+                has_session: true,
+                source: Some("database".to_string()),
+                playlist_size: list.len(),
+                playlist_hash: Some(playlist_hash(&list)),
+                playlist: playlist_for_client(&state, list).await,
+                criteria,
+                generation_status: GenerationStatus::Complete,
+                index_freshness,
+                resume: None,
+                sleep: sleep.clone(),
+            });
+        }
+    }
+
+    Json(SessionPlaylistResponse {
+        has_session: false,
+        source: None,
+        playlist_size: 0,
+        playlist: Vec::new(),
+        playlist_hash: None,
+        criteria: None,
+        generation_status: GenerationStatus::Complete,
+        index_freshness,
+        resume: None,
+        sleep: sleep.clone(),
+    })
+}
+
+async fn get_blocklist(
+    State(state): State<AppState>,
+    connect_info: ConnectInfo<SocketAddr>,
+) -> Json<BlocklistResponse> {
+    let ip = connect_info.0.ip().to_string();
+    let rows: Vec<(String,)> = sqlx::query_as("SELECT path FROM blocklist WHERE client_ip = ? ORDER BY created_at")
+        .bind(&ip)
+        .fetch_all(&state.db)
+        .await
+        .unwrap_or_default();
+    Json(BlocklistResponse {
+        paths: rows.into_iter().map(|(path,)| path).collect(),
+    })
+}
+
+async fn add_to_blocklist(
+    State(state): State<AppState>,
+    connect_info: ConnectInfo<SocketAddr>,
+    Json(req): Json<BlocklistRequest>,
+) -> Result<Json<serde_json::Value>, (StatusCode, Json<serde_json::Value>)> {
+    let ip = connect_info.0.ip().to_string();
+    let paths: Vec<String> = req
+        .paths
+        .iter()
+        .map(|p| normalize_rel_path(p))
+        .filter(|p| !p.is_empty() && p != ".")
+        .collect();
+    if paths.is_empty() {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            Json(serde_json::json!({ "detail": "No valid paths to block" })),
+        ));
+    }
+
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs_f64();
+    let db_error = |err: sqlx::Error| {
+        tracing::error!("❌ 写入 {} 的不再显示列表失败: {}", ip, err);
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(serde_json::json!({ "detail": "Failed to update blocklist" })),
+        )
+    };
+    let mut tx = state.db.begin().await.map_err(db_error)?;
+    for path in &paths {
+        sqlx::query("INSERT OR IGNORE INTO blocklist (client_ip, path, created_at) VALUES (?, ?, ?)")
+            .bind(&ip)
+            .bind(path)
+            .bind(now)
+            .execute(&mut *tx)
+            .await
+            .map_err(db_error)?;
+    }
+    tx.commit().await.map_err(db_error)?;
+
+    Ok(Json(serde_json::json!({ "status": "ok", "blocked": paths })))
+}
+
+/// 接口: POST /api/tags/bulk，按文件夹前缀或路径列表批量添加/移除标签 (单个事务)
+async fn bulk_tag(
+    State(state): State<AppState>,
+    Json(req): Json<BulkTagRequest>,
+) -> Result<Json<BulkTagResponse>, (StatusCode, Json<serde_json::Value>)> {
+    let bad_request = |detail: &str| (StatusCode::BAD_REQUEST, Json(serde_json::json!({ "detail": detail })));
+    let db_error = || {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(serde_json::json!({ "detail": "Failed to update tags" })),
+        )
+    };
+
+    let Some(tag) = normalize_tag(&req.tag) else {
+        return Err(bad_request("Tag must not be empty"));
+    };
+    if req.folder.is_none() && req.paths.is_empty() {
+        return Err(bad_request("Either folder or paths is required"));
+    }
+
+    let allow_parent = *state.allow_parent_dir_access.read().await;
+    let folder = match req.folder.as_deref().map(normalize_rel_path) {
+        Some(folder) => {
+            if let Err(PathAccessError::Forbidden) = resolve_and_authorize(&state.root_dir, &folder, allow_parent) {
+                return Err((
+                    StatusCode::FORBIDDEN,
+                    Json(serde_json::json!({ "detail": "Access outside ROOT_DIR is disabled" })),
+                ));
+            }
+            Some(folder)
+        }
+        None => None,
+    };
+    let paths: Vec<String> = req
+        .paths
+        .iter()
+        .map(|p| normalize_rel_path(p))
+        .filter(|p| !p.is_empty() && p != ".")
+        .filter(|p| allow_parent || !is_external_key(p))
+        .collect();
+
+    // 文件夹条件：根目录只包含内部路径，其它目录按前缀匹配
+    let folder_filter = folder.as_ref().map(|folder| {
+        if folder == "." {
+            (INTERNAL_PATH_SQL_FILTER.to_string(), None)
+        } else {
+            (
+                "path LIKE ? ESCAPE '\\'".to_string(),
+                Some(format!("{}/%", escape_like_pattern(folder))),
+            )
+        }
+    });
+
+    let mut tx = state.db.begin().await.map_err(|_| db_error())?;
+    let mut matched = 0u64;
+    let mut affected = 0u64;
+
+    if let Some((clause, pattern)) = &folder_filter {
+        let count_sql = format!("SELECT COUNT(*) FROM images WHERE {}", clause);
+        let mut count_query = sqlx::query_as::<_, (i64,)>(&count_sql);
+        if let Some(pattern) = pattern {
+            count_query = count_query.bind(pattern);
+        }
+        matched += count_query.fetch_one(&mut *tx).await.map_err(|_| db_error())?.0 as u64;
+
+        let sql = match req.action {
+            TagAction::Add => format!(
+                "INSERT OR IGNORE INTO image_tags (path, tag) SELECT path, ? FROM images WHERE {}",
+                clause
+            ),
+            TagAction::Remove => format!(
+                "DELETE FROM image_tags WHERE tag = ? AND path IN (SELECT path FROM images WHERE {})",
+                clause
+            ),
+        };
+        let mut query = sqlx::query(&sql).bind(&tag);
+        if let Some(pattern) = pattern {
+            query = query.bind(pattern);
+        }
+        affected += query.execute(&mut *tx).await.map_err(|_| db_error())?.rows_affected();
+    }
+
+    if !paths.is_empty() {
+        // 只给已索引的图片打标签；移除时不做限制，便于清理残留记录
+        let targets: Vec<String> = match req.action {
+            TagAction::Add => {
+                let known = indexed_paths(&state.db, &paths).await;
+                paths.into_iter().filter(|p| known.contains(p)).collect()
+            }
+            TagAction::Remove => paths,
+        };
+        matched += targets.len() as u64;
+        for path in &targets {
+            let query = match req.action {
+                TagAction::Add => sqlx::query("INSERT OR IGNORE INTO image_tags (path, tag) VALUES (?, ?)"),
+                TagAction::Remove => sqlx::query("DELETE FROM image_tags WHERE path = ? AND tag = ?"),
+            };
+            affected += query
+                .bind(path)
+                .bind(&tag)
+                .execute(&mut *tx)
+                .await
+                .map_err(|_| db_error())?
+                .rows_affected();
+        }
+    }
+
+    tx.commit().await.map_err(|_| db_error())?;
+    invalidate_playlist_cache(&state).await;
+
+    Ok(Json(BulkTagResponse {
+        tag,
+        action: req.action,
+        matched,
+        affected,
+    }))
+}
+
+async fn remove_from_blocklist(
+    State(state): State<AppState>,
+    connect_info: ConnectInfo<SocketAddr>,
+    Json(req): Json<BlocklistRequest>,
+) -> Json<serde_json::Value> {
+    let ip = connect_info.0.ip().to_string();
+    let mut removed = 0;
+    for p in &req.paths {
+        let path = normalize_rel_path(p);
+        if let Ok(result) = sqlx::query("DELETE FROM blocklist WHERE client_ip = ? AND path = ?")
+            .bind(&ip)
+            .bind(&path)
+            .execute(&state.db)
+            .await
+        {
+            removed += result.rows_affected();
+        }
+    }
+    Json(serde_json::json!({ "status": "ok", "removed": removed }))
+}
+
+// 文件服务交给 tower-http 的 ServeFile：带 Content-Length / Accept-Ranges / Last-Modified，
+// 支持 Range 与条件请求，依靠 OS Page Cache
+// --- 文件服务逻辑 ---
+
+/// 核心文件读取逻辑
+async fn serve_file_core(state: AppState, raw_path: String, request: Request) -> Response {
+    // 1. Query 提取器已完成唯一一次解码 (见 reject_double_encoded 的编码约定)
+    if let Err(rejection) = reject_double_encoded(&state.root_dir, &normalize_rel_path(&raw_path)) {
+        return rejection.into_response();
+    }
+    let rel = match client_path_key(&state, &raw_path).await {
+        Ok(rel) => rel,
+        Err(PathAccessError::Forbidden) => {
+            return (
+                StatusCode::FORBIDDEN,
+                Json(serde_json::json!({ "message": "Files outside ROOT_DIR must be requested by ID (/api/file/{id})" })),
+            )
+                .into_response();
+        }
+        Err(PathAccessError::NotFound) => return StatusCode::NOT_FOUND.into_response(),
+    };
+    serve_rel_file(state, rel, request).await
+}
+
+/// 按已规范化的路径键输出文件，并记录访问日志
+async fn serve_rel_file(state: AppState, rel: String, request: Request) -> Response {
+    let started = Instant::now();
+    let method = request.method().to_string();
+    let client_ip = request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip().to_string())
+        .unwrap_or_default();
+    let response = throttle_response(&state, &client_ip, send_rel_file(state.clone(), rel.clone(), request).await);
+    track_file_access(&state, rel, method, client_ip, started, response)
+}
+
+async fn send_rel_file(state: AppState, rel: String, request: Request) -> Response {
+    let root_dir = state.root_dir.as_path();
+    let allow_parent = *state.allow_parent_dir_access.read().await;
+
+    // 2. 权限检查
+    let full = match resolve_and_authorize(root_dir, &rel, allow_parent) {
+        Ok(full) => full,
+        Err(PathAccessError::Forbidden) => {
+            return (
+                StatusCode::FORBIDDEN, 
+                Json(serde_json::json!({ "message": "Access outside ROOT_DIR is disabled" }))
+            ).into_response();
+        }
+        Err(PathAccessError::NotFound) => {
+            return match archived_offline_response(&state, &rel).await {
+                Some(response) => response,
+                None => StatusCode::NOT_FOUND.into_response(),
+            };
+        }
+    };
+    if !state.follow_symlinks && path_has_symlink(root_dir, &full) {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+
+    // 3. 检查文件是否存在
+    let file_meta = match tokio::fs::metadata(&full).await {
+        Ok(m) if m.is_file() => m,
+        _ => return StatusCode::NOT_FOUND.into_response(),
+    };
+
+    // 4. ETag / If-None-Match (HEAD 预取时也能拿到)
+    let etag = file_etag(&file_meta);
+    let not_modified = request
+        .headers()
+        .get(header::IF_NONE_MATCH)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.split(',').any(|tag| tag.trim() == etag || tag.trim() == "*"));
+    // 展示统计只计实际发送了内容的响应 (200/206)，304 与 HEAD 不计
+    let counts_as_serve = request.method() == axum::http::Method::GET && is_image_ext(&full);
+    if counts_as_serve {
+        if let Some(ConnectInfo(addr)) = request.extensions().get::<ConnectInfo<SocketAddr>>() {
+            let at = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs_f64();
+            state
+                .now_showing
+                .write()
+                .await
+                .insert(addr.ip().to_string(), NowShowing { path: rel.clone(), at });
+        }
+    }
+    if not_modified {
+        return (
+            StatusCode::NOT_MODIFIED,
+            [(header::ETAG, etag), (header::CACHE_CONTROL, "public, max-age=3600".to_string())],
+        )
+            .into_response();
+    }
+
+    // 同步客户端通过 Want-Repr-Digest / Want-Digest 要求校验和 (或 `GALLERY_FILE_DIGEST` 总是附带)，
+    // 只用索引里已有的哈希，不在请求时重新计算
+    let digest = if state.settings.flag("GALLERY_FILE_DIGEST") || wants_blake3_digest(request.headers()) {
+        stored_file_digest(&state.db, &rel, &file_meta).await
+    } else {
+        None
+    };
+
+    // 5. 高效流式传输 (原样输出，不做内容编码；HEAD 请求只返回头部)
+    let mime = from_path(&full).first_or_octet_stream();
+    match ServeFile::new_with_mime(&full, &mime).oneshot(request).await {
+        Ok(res) => {
+            let mut res = res.map(Body::new);
+            if counts_as_serve && matches!(res.status(), StatusCode::OK | StatusCode::PARTIAL_CONTENT) {
+                record_image_served(&state, &rel);
+            }
+            // 缓存控制：让浏览器缓存图片 1 小时，减少服务器压力
+            res.headers_mut()
+                .insert(header::CACHE_CONTROL, "public, max-age=3600".parse().unwrap());
+            if let Ok(value) = etag.parse() {
+                res.headers_mut().insert(header::ETAG, value);
+            }
+            // 摘要描述的是完整文件，Range 请求 (206) 同样适用
+            if let Some(digest) = digest.filter(|_| res.status().is_success()) {
+                if let Ok(value) = format!("blake3=:{}:", digest).parse() {
+                    res.headers_mut().insert(HeaderName::from_static("repr-digest"), value);
+                }
+                if let Ok(value) = format!("blake3={}", digest).parse() {
+                    res.headers_mut().insert(HeaderName::from_static("digest"), value);
+                }
+            }
+            res
+        }
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+}
+
+/// 请求头是否要了 blake3 摘要 (RFC 9530 的 `Want-Repr-Digest`，或旧式 `Want-Digest`；权重 0 表示不要)
+fn wants_blake3_digest(headers: &axum::http::HeaderMap) -> bool {
+    ["want-repr-digest", "want-digest"]
+        .iter()
+        .flat_map(|name| headers.get_all(*name))
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|item| {
+            let (algorithm, weight) = item.split_once(['=', ';']).unwrap_or((item, ""));
+            let weight = weight.trim().trim_start_matches("q=");
+            algorithm.trim().eq_ignore_ascii_case("blake3") && weight.parse::<f64>().map_or(true, |w| w > 0.0)
+        })
+}
+
+/// 索引中的 blake3 哈希 (base64)；文件在上次索引后改过 (大小或 mtime 不一致) 时不返回，避免给出过期的校验和
+async fn stored_file_digest(pool: &Pool<Sqlite>, rel_path: &str, file_meta: &std::fs::Metadata) -> Option<String> {
+    use base64::Engine;
+    let (hash, mtime, size): (String, f64, i64) =
+        sqlx::query_as("SELECT hash, mtime, size FROM images WHERE path = ? AND hash IS NOT NULL")
+            .bind(rel_path)
+            .fetch_optional(pool)
+            .await
+            .ok()
+            .flatten()?;
+    let current_mtime = file_meta.modified().ok()?.duration_since(UNIX_EPOCH).ok()?.as_secs_f64();
+    if size != file_meta.len() as i64 || (mtime - current_mtime).abs() > 0.001 {
+        return None;
+    }
+    let bytes = blake3::Hash::from_hex(hash).ok()?;
+    Some(base64::engine::general_purpose::STANDARD.encode(bytes.as_bytes()))
+}
+
+/// 记录一次图片展示 (按采样率抽样，只写内存缓冲)
+fn record_image_served(state: &AppState, rel_path: &str) {
+    let rate = state.analytics_sample_rate;
+    if rate <= 0.0 || (rate < 1.0 && rand::random::<f64>() >= rate) {
+        return;
+    }
+    let weight = (1.0 / rate).round().max(1.0) as i64;
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs_f64();
+    if let Ok(mut stats) = state.serve_stats.lock() {
+        let entry = stats.entry(rel_path.to_string()).or_insert((0, now));
+        entry.0 += weight;
+        entry.1 = now;
+    }
+}
+
+/// 把内存中的展示统计批量写入数据库 (失败时放回缓冲，下次重试)
+async fn flush_serve_stats(state: &AppState) {
+    let pending: Vec<(String, (i64, f64))> = match state.serve_stats.lock() {
+        Ok(mut stats) => stats.drain().collect(),
+        Err(_) => return,
+    };
+    if pending.is_empty() {
+        return;
+    }
+    let written = async {
+        let mut tx = state.db.begin().await?;
+        for (path, (count, last_served)) in &pending {
+            sqlx::query(
+                "INSERT INTO image_stats (path, serve_count, last_served) VALUES (?, ?, ?)
+                 ON CONFLICT(path) DO UPDATE SET
+                    serve_count = serve_count + excluded.serve_count,
+                    last_served = MAX(last_served, excluded.last_served)",
+            )
+            .bind(path)
+            .bind(count)
+            .bind(last_served)
+            .execute(&mut *tx)
+            .await?;
+        }
+        tx.commit().await
+    }
+    .await;
+    if let Err(e) = written {
+        tracing::warn!("⚠️ [Analytics] 写入 {} 条展示统计失败，保留到下次写入: {}", pending.len(), e);
+        if let Ok(mut stats) = state.serve_stats.lock() {
+            for (path, (count, last_served)) in pending {
+                let entry = stats.entry(path).or_insert((0, last_served));
+                entry.0 += count;
+                entry.1 = entry.1.max(last_served);
+            }
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct AnalyticsQuery {
+    limit: Option<usize>,
+    /// top 的排序: "most" (默认) 或 "least"
+    order: Option<String>,
+    /// never-shown 的目录前缀
+    path: Option<String>,
+}
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+struct ImageServeStat {
+    path: String,
+    serve_count: i64,
+    last_served: f64,
+}
+
+#[derive(Debug, Serialize)]
+struct NeverShownResponse {
+    total: i64,
+    paths: Vec<String>,
+}
+
+/// 接口: GET /api/analytics/top?order=most|least&limit=...，展示次数排行
+async fn analytics_top(
+    State(state): State<AppState>,
+    Query(query): Query<AnalyticsQuery>,
+) -> Json<Vec<ImageServeStat>> {
+    flush_serve_stats(&state).await;
+    let direction = if query.order.as_deref() == Some("least") { "ASC" } else { "DESC" };
+    let sql = format!(
+        "SELECT s.path, s.serve_count, s.last_served FROM image_stats s JOIN images i ON i.path = s.path
+         ORDER BY s.serve_count {}, s.last_served {} LIMIT ?",
+        direction, direction
+    );
+    let rows = sqlx::query_as::<_, ImageServeStat>(&sql)
+        .bind(query.limit.unwrap_or(50).clamp(1, 1000) as i64)
+        .fetch_all(&state.db)
+        .await
+        .unwrap_or_default();
+    Json(rows)
+}
+
+/// 接口: GET /api/analytics/never-shown?path=...&limit=...，从未展示过的已索引图片
+async fn analytics_never_shown(
+    State(state): State<AppState>,
+    Query(query): Query<AnalyticsQuery>,
+) -> Json<NeverShownResponse> {
+    flush_serve_stats(&state).await;
+    let base = normalize_rel_path(query.path.as_deref().unwrap_or("."));
+    let (filter, pattern) = if base == "." {
+        (INTERNAL_PATH_SQL_FILTER.to_string(), None)
+    } else {
+        (
+            "path LIKE ? ESCAPE '\\'".to_string(),
+            Some(format!("{}/%", escape_like_pattern(&base))),
+        )
+    };
+    let where_clause = format!(
+        "WHERE {} AND {} AND path NOT IN (SELECT path FROM image_stats WHERE serve_count > 0)",
+        filter, PRESENT_SQL_FILTER
+    );
+
+    let count_sql = format!("SELECT COUNT(*) FROM images {}", where_clause);
+    let mut count_query = sqlx::query_as::<_, (i64,)>(&count_sql);
+    let list_sql = format!(
+        "SELECT path FROM images {} ORDER BY path COLLATE {} LIMIT ?",
+        where_clause, NATURAL_COLLATION
+    );
+    let mut list_query = sqlx::query_as::<_, (String,)>(&list_sql);
+    if let Some(pattern) = &pattern {
+        count_query = count_query.bind(pattern);
+        list_query = list_query.bind(pattern);
+    }
+
+    let total = count_query.fetch_one(&state.db).await.map(|r| r.0).unwrap_or(0);
+    let paths = list_query
+        .bind(query.limit.unwrap_or(200).clamp(1, 5000) as i64)
+        .fetch_all(&state.db)
+        .await
+        .unwrap_or_default()
+        .into_iter()
+        .map(|(p,)| p)
+        .collect();
+    Json(NeverShownResponse { total, paths })
+}
+
+/// 由文件大小与修改时间生成弱 ETag，供文件/缩略图等接口共用
+fn file_etag(meta: &std::fs::Metadata) -> String {
+    let mtime_nanos = meta
+        .modified()
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_nanos())
+        .unwrap_or(0);
+    format!("W/\"{:x}-{:x}\"", meta.len(), mtime_nanos)
+}
+
+/// 接口 1: 处理 /api/file?path=...
+async fn serve_file_by_query(
+    State(state): State<AppState>,
+    Query(query): Query<FileQuery>,
+    request: Request,
+) -> Response {
+    serve_file_core(state, query.path, request).await
+}
+
+/// 接口: GET /api/file/:id，按稳定 ID 获取 ROOT_DIR 之外的文件 (ID 见 info / browse / query 响应中的 `file_id`)
+async fn serve_file_by_id(
+    State(state): State<AppState>,
+    axum::extract::Path(id): axum::extract::Path<String>,
+    request: Request,
+) -> Response {
+    let path: Option<String> = sqlx::query_scalar("SELECT path FROM external_files WHERE id = ?")
+        .bind(&id)
+        .fetch_optional(&state.db)
+        .await
+        .ok()
+        .flatten();
+    let Some(path) = path else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    serve_rel_file(state, path, request).await
+}
+
+// 接口 2: 处理直接路径 /folder/image.jpg
+// async fn serve_file_by_path(
+//     State(state): State<AppState>,
+//     AxumPath(path_str): AxumPath<String>,
+// ) -> Response {
+//     serve_file_core(state, path_str).await
+// }
+
+/// 流式计算文件内容哈希 (blake3，十六进制)
+fn compute_file_hash(full_path: &Path) -> Option<String> {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update_reader(std::fs::File::open(full_path).ok()?).ok()?;
+    Some(hasher.finalize().to_hex().to_string())
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq, Eq)]
+enum RetentionPolicy {
+    #[serde(rename = "keep_largest")]
+    Largest,
+    #[serde(rename = "keep_oldest")]
+    Oldest,
+    #[serde(rename = "keep_preferred_folder")]
+    PreferredFolder,
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum DuplicateMatch {
+    /// 文件内容完全相同 (blake3)
+    #[default]
+    Exact,
+    /// 感知哈希相同且方向一致 (例如不同分辨率的导出副本)
+    Perceptual,
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum RemovalAction {
+    #[default]
+    Trash,
+    Delete,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+#[derive(Debug, Deserialize)]
+struct ResolveDuplicatesRequest {
+    policy: RetentionPolicy,
+    /// keep_preferred_folder 时优先保留该目录 (递归) 下的副本
+    #[serde(default)]
+    preferred_folder: Option<String>,
+    #[serde(default, rename = "match")]
+    match_mode: DuplicateMatch,
+    #[serde(default)]
+    action: RemovalAction,
+    /// 默认只返回计划；实际执行需 dry_run=false 并带上试运行返回的 confirm_token
+    #[serde(default = "default_true")]
+    dry_run: bool,
+    #[serde(default)]
+    confirm_token: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct DuplicateGroupPlan {
+    keep: String,
+    remove: Vec<String>,
+    reclaimable_bytes: i64,
+}
+
+#[derive(Debug, Serialize)]
+struct DuplicateRemovalFailure {
+    path: String,
+    error: String,
+}
+
+#[derive(Debug, Serialize)]
+struct ResolveDuplicatesResponse {
+    dry_run: bool,
+    policy: RetentionPolicy,
+    action: RemovalAction,
+    groups: Vec<DuplicateGroupPlan>,
+    files_to_remove: usize,
+    reclaimable_bytes: i64,
+    /// 计划内容的指纹；执行时必须原样回传，计划变化 (文件增删) 时拒绝执行
+    confirm_token: String,
+    removed: Vec<String>,
+    failed: Vec<DuplicateRemovalFailure>,
+}
+
+/// 从索引中删除一张图片的所有相关记录
+async fn delete_image_rows(conn: &mut sqlx::SqliteConnection, path: &str) -> sqlx::Result<()> {
+    for table in [
+        "images",
+        "image_tags",
+        "suggested_tags",
+        "faces",
+        "image_companions",
+        "image_stats",
+        "image_positions",
+        "image_captions",
+        "image_edits",
+    ] {
+        sqlx::query(&format!("DELETE FROM {} WHERE path = ?", table))
+            .bind(path)
+            .execute(&mut *conn)
+            .await?;
+    }
+    Ok(())
+}
+
+/// 把文件移入回收站目录并记录原位置
+async fn move_to_trash(state: &AppState, rel_path: &str, full_path: &Path) -> anyhow::Result<()> {
+    let trash_dir = state.root_dir.join(TRASH_DIR_NAME);
+    tokio::fs::create_dir_all(&trash_dir).await?;
+
+    let size = tokio::fs::metadata(full_path).await.map(|m| m.len() as i64).unwrap_or(0);
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    let file_name = full_path
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let target = trash_dir.join(truncate_file_name(&format!("{}-{}", now.as_nanos(), file_name)));
+    tokio::fs::rename(full_path, &target).await?;
+
+    let mut tx = state.db.begin().await?;
+    sqlx::query("INSERT INTO trash (original_path, trash_path, size, deleted_at) VALUES (?, ?, ?, ?)")
+        .bind(rel_path)
+        .bind(target.to_string_lossy().as_ref())
+        .bind(size)
+        .bind(now.as_secs_f64())
+        .execute(&mut *tx)
+        .await?;
+    delete_image_rows(&mut tx, rel_path).await?;
+    tx.commit().await?;
+    Ok(())
+}
+
+/// 回收站保留天数 (`GALLERY_TRASH_RETENTION_DAYS`，默认 30；0 表示永不自动清理)
+fn trash_retention_days(settings: &Settings) -> u64 {
+    settings.parse("GALLERY_TRASH_RETENTION_DAYS").unwrap_or(30)
+}
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+struct TrashEntry {
+    id: i64,
+    original_path: String,
+    size: i64,
+    deleted_at: f64,
+    /// 预计被自动清理的时间 (未启用自动清理时为空)
+    #[sqlx(skip)]
+    purge_at: Option<f64>,
+}
+
+/// 接口: GET /api/trash，按删除时间倒序列出回收站内容
+async fn list_trash(State(state): State<AppState>) -> Json<serde_json::Value> {
+    let retention = trash_retention_days(&state.settings);
+    let mut entries: Vec<TrashEntry> =
+        sqlx::query_as("SELECT id, original_path, size, deleted_at FROM trash ORDER BY deleted_at DESC")
+            .fetch_all(&state.db)
+            .await
+            .unwrap_or_default();
+    for entry in &mut entries {
+        entry.purge_at = (retention > 0).then_some(entry.deleted_at + retention as f64 * 86400.0);
+    }
+    Json(serde_json::json!({
+        "retention_days": retention,
+        "total_bytes": entries.iter().map(|e| e.size).sum::<i64>(),
+        "entries": entries,
+    }))
+}
+
+/// 永久删除超过保留期的回收站文件
+async fn purge_expired_trash(state: &AppState, retention_days: u64) {
+    let cutoff = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs_f64() - retention_days as f64 * 86400.0;
+    let expired: Vec<(i64, String, i64)> = sqlx::query_as("SELECT id, trash_path, size FROM trash WHERE deleted_at < ?")
+        .bind(cutoff)
+        .fetch_all(&state.db)
+        .await
+        .unwrap_or_default();
+    if expired.is_empty() {
+        return;
+    }
+
+    let (mut purged, mut bytes) = (0usize, 0i64);
+    for (id, trash_path, size) in expired {
+        match tokio::fs::remove_file(&trash_path).await {
+            Ok(()) => {}
+            // 已被手动清掉的文件只需删除记录
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+            Err(err) => {
+                tracing::warn!("⚠️ Failed to purge {} from trash: {}", trash_path, err);
+                continue;
+            }
         }
-        _ => all_images.sort_by(|a, b| natord::compare_ignore_case(&a.path, &b.path)),
+        sqlx::query("DELETE FROM trash WHERE id = ?").bind(id).execute(&state.db).await.ok();
+        purged += 1;
+        bytes += size;
     }
+    tracing::info!("🗑️ [Background] 回收站清理 {} 个过期文件，释放 {} MB", purged, bytes / 1024 / 1024);
+}
 
-    let mut final_paths: Vec<String> = all_images.into_iter().map(|i| i.path).collect();
+/// 找出重复图片组 (每组至少两张)
+async fn find_duplicate_groups(
+    state: &AppState,
+    match_mode: DuplicateMatch,
+    allow_parent: bool,
+) -> Vec<Vec<ImageMetadata>> {
+    let images: Vec<ImageMetadata> = sqlx::query_as("SELECT * FROM images WHERE size > 0 AND missing_since IS NULL")
+        .fetch_all(&state.db)
+        .await
+        .unwrap_or_default();
+    let images: Vec<ImageMetadata> = images
+        .into_iter()
+        .filter(|i| allow_parent || !is_external_key(&i.path))
+        .collect();
 
-    if req.direction == "reverse" {
-        final_paths.reverse();
+    let mut grouped: HashMap<String, Vec<ImageMetadata>> = HashMap::new();
+    match match_mode {
+        DuplicateMatch::Perceptual => {
+            for image in images {
+                if let Some(phash) = image.phash.clone().filter(|h| !h.is_empty()) {
+                    grouped
+                        .entry(format!("{}-{}", phash, image.is_landscape))
+                        .or_default()
+                        .push(image);
+                }
+            }
+        }
+        DuplicateMatch::Exact => {
+            // 先按大小分组，只对大小相同的文件计算内容哈希
+            let mut by_size: HashMap<i64, Vec<ImageMetadata>> = HashMap::new();
+            for image in images {
+                by_size.entry(image.size).or_default().push(image);
+            }
+            for candidates in by_size.into_values().filter(|g| g.len() > 1) {
+                for image in candidates {
+                    let full = resolve_full_path(&state.root_dir, &image.path);
+                    if let Some(hash) = ensure_image_hash(&state.db, &image.path, &full).await {
+                        grouped.entry(hash).or_default().push(image);
+                    }
+                }
+            }
+        }
     }
 
-    // 4. 当前位置旋转
-    if let Some(curr) = req.current_path {
-        let curr_norm = normalize_rel_path(&curr);
-        if let Some(pos) = final_paths.iter().position(|x| x == &curr_norm) {
-            final_paths.rotate_left(pos);
-        }
+    let mut groups: Vec<Vec<ImageMetadata>> = grouped.into_values().filter(|g| g.len() > 1).collect();
+    for group in groups.iter_mut() {
+        group.sort_by(|a, b| natord::compare_ignore_case(&a.path, &b.path));
     }
+    groups.sort_by(|a, b| natord::compare_ignore_case(&a[0].path, &b[0].path));
+    groups
+}
 
-    // 5. 持久化到数据库 (关键功能恢复)
-    let ip = connect_info.0.ip().to_string();
-    let criteria = PlaylistCriteria {
-        sort: req.sort.clone(),
-        direction: req.direction.clone(),
-        orientation: req.orientation.clone(),
-        paths: valid_req_paths.clone(),
+/// 按保留策略选出每组要保留的一张 (返回下标)
+fn pick_keeper(group: &[ImageMetadata], policy: RetentionPolicy, preferred_folder: Option<&str>) -> usize {
+    let largest = |candidates: &mut dyn Iterator<Item = (usize, &ImageMetadata)>| {
+        candidates
+            .max_by(|(ia, a), (ib, b)| {
+                megapixels(a)
+                    .total_cmp(&megapixels(b))
+                    .then(a.size.cmp(&b.size))
+                    .then(ib.cmp(ia))
+            })
+            .map(|(i, _)| i)
     };
-    let criteria_json = serde_json::to_string(&criteria).ok();
-    if let Ok(json_playlist) = serde_json::to_string(&final_paths) {
-        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs_f64();
-        sqlx::query("INSERT OR REPLACE INTO playlists (client_ip, playlist, criteria_json, created_at) VALUES (?, ?, ?, ?)")
-            .bind(&ip)
-            .bind(json_playlist)
-            .bind(criteria_json)
-            .bind(now)
-            .execute(&state.db)
-            .await
-            .ok();
+    match policy {
+        RetentionPolicy::Largest => largest(&mut group.iter().enumerate()).unwrap_or(0),
+        RetentionPolicy::Oldest => group
+            .iter()
+            .enumerate()
+            .min_by(|(ia, a), (ib, b)| {
+                a.taken_at
+                    .unwrap_or(a.mtime)
+                    .total_cmp(&b.taken_at.unwrap_or(b.mtime))
+                    .then(ia.cmp(ib))
+            })
+            .map(|(i, _)| i)
+            .unwrap_or(0),
+        RetentionPolicy::PreferredFolder => {
+            let in_preferred = |path: &str| match preferred_folder {
+                Some(".") | Some("") => !is_external_key(path),
+                Some(folder) => path.starts_with(&format!("{}/", folder)),
+                None => false,
+            };
+            largest(&mut group.iter().enumerate().filter(|(_, i)| in_preferred(&i.path)))
+                .or_else(|| largest(&mut group.iter().enumerate()))
+                .unwrap_or(0)
+        }
     }
+}
 
-    {
-        let mut sessions = state.user_sessions.write().await;
-        sessions.insert(
-            ip.clone(),
-            UserSessionData {
-                playlist: final_paths.clone(),
-                criteria: Some(criteria),
-            },
-        );
+/// 接口: POST /api/duplicates/resolve，按保留策略清理重复图片 (默认试运行)
+/// (带 `Prefer: respond-async` 时在后台执行，返回任务 ID)
+async fn resolve_duplicates(
+    State(state): State<AppState>,
+    headers: axum::http::HeaderMap,
+    Json(req): Json<ResolveDuplicatesRequest>,
+) -> Result<Response, (StatusCode, Json<serde_json::Value>)> {
+    if prefers_async(&headers) {
+        let job_state = state.clone();
+        let id = spawn_job(&state, "duplicates", move |job| async move {
+            let Json(response) = run_resolve_duplicates(job_state, req, Some(job)).await.map_err(job_error)?;
+            serde_json::to_value(response).map_err(|e| e.to_string())
+        })
+        .await;
+        return Ok(job_accepted(&state, &id));
     }
-
-    Json(final_paths)
+    run_resolve_duplicates(state, req, None).await.map(IntoResponse::into_response)
 }
 
-async fn restore_playlist(
-    State(state): State<AppState>,
-    connect_info: ConnectInfo<SocketAddr>,
-    Json(req): Json<RestorePlaylistRequest>,
-) -> Result<Json<serde_json::Value>, (StatusCode, Json<serde_json::Value>)> {
-    let original_count = req.playlist.len();
-    tracing::info!("🔄 [Restore Playlist] 请求恢复播放列表，原始路径数量: {}", original_count);
-    if original_count == 0 {
+async fn run_resolve_duplicates(
+    state: AppState,
+    req: ResolveDuplicatesRequest,
+    job: Option<JobContext>,
+) -> Result<Json<ResolveDuplicatesResponse>, (StatusCode, Json<serde_json::Value>)> {
+    let preferred_folder = req.preferred_folder.as_deref().map(normalize_rel_path);
+    if req.policy == RetentionPolicy::PreferredFolder && preferred_folder.is_none() {
         return Err((
             StatusCode::BAD_REQUEST,
-            Json(serde_json::json!({ "detail": "Playlist cannot be empty" })),
+            Json(serde_json::json!({ "detail": "preferred_folder is required for keep_preferred_folder" })),
         ));
     }
 
-    let root_dir = state.root_dir.as_path();
     let allow_parent = *state.allow_parent_dir_access.read().await;
+    let groups = find_duplicate_groups(&state, req.match_mode, allow_parent).await;
+
+    let mut plans = Vec::new();
+    for group in &groups {
+        let keep = pick_keeper(group, req.policy, preferred_folder.as_deref());
+        let remove: Vec<&ImageMetadata> = group.iter().enumerate().filter(|(i, _)| *i != keep).map(|(_, m)| m).collect();
+        plans.push(DuplicateGroupPlan {
+            keep: group[keep].path.clone(),
+            reclaimable_bytes: remove.iter().map(|m| m.size).sum(),
+            remove: remove.into_iter().map(|m| m.path.clone()).collect(),
+        });
+    }
 
-    // 验证路径有效性 (使用 fs 非 DB，确保文件确实还在)
-    let mut valid_paths = Vec::new();
-    for p in req.playlist {
-        let rel = normalize_rel_path(&p);
-        let full = resolve_full_path(root_dir, &rel);
-        if full.is_file() {
-            if allow_parent || is_under_root(root_dir, &full) {
-                valid_paths.push(rel);
-            }
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(format!("{:?}{:?}", req.action, req.policy).as_bytes());
+    for plan in &plans {
+        hasher.update(plan.keep.as_bytes());
+        for path in &plan.remove {
+            hasher.update(b"\0");
+            hasher.update(path.as_bytes());
         }
+        hasher.update(b"\n");
+    }
+    let confirm_token = hasher.finalize().to_hex()[..16].to_string();
+
+    let mut response = ResolveDuplicatesResponse {
+        dry_run: req.dry_run,
+        policy: req.policy,
+        action: req.action,
+        files_to_remove: plans.iter().map(|p| p.remove.len()).sum(),
+        reclaimable_bytes: plans.iter().map(|p| p.reclaimable_bytes).sum(),
+        groups: plans,
+        confirm_token,
+        removed: Vec::new(),
+        failed: Vec::new(),
+    };
+    if req.dry_run {
+        return Ok(Json(response));
     }
 
-    if valid_paths.is_empty() {
+    if req.confirm_token.as_deref() != Some(response.confirm_token.as_str()) {
         return Err((
-            StatusCode::BAD_REQUEST,
-            Json(serde_json::json!({ "detail": "No valid paths in playlist" })),
+            StatusCode::CONFLICT,
+            Json(serde_json::json!({ "detail": "Plan changed or confirm_token missing; run a dry run first" })),
         ));
     }
 
-    // 更新数据库会话
-    let ip = connect_info.0.ip().to_string();
-    let criteria_json = req
-        .criteria
-        .as_ref()
-        .and_then(|criteria| serde_json::to_string(criteria).ok());
-    if let Ok(json_playlist) = serde_json::to_string(&valid_paths) {
-        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs_f64();
-        sqlx::query("INSERT OR REPLACE INTO playlists (client_ip, playlist, criteria_json, created_at) VALUES (?, ?, ?, ?)")
-            .bind(&ip)
-            .bind(json_playlist)
-            .bind(criteria_json)
-            .bind(now)
-            .execute(&state.db)
-            .await
-            .ok();
-    }
-
-    {
-        let mut sessions = state.user_sessions.write().await;
-        sessions.insert(
-            ip.clone(),
-            UserSessionData {
-                playlist: valid_paths.clone(),
-                criteria: req.criteria.clone(),
+    let to_remove: Vec<String> = response.groups.iter().flat_map(|p| p.remove.clone()).collect();
+    let total = to_remove.len();
+    for (done, path) in to_remove.into_iter().enumerate() {
+        if let Some(job) = &job {
+            job.set_progress(done, total).await;
+        }
+        let result = match resolve_and_authorize(&state.root_dir, &path, allow_parent) {
+            Err(_) => Err(anyhow::anyhow!("file not accessible")),
+            Ok(full) => match req.action {
+                RemovalAction::Trash => move_to_trash(&state, &path, &full).await,
+                RemovalAction::Delete => match tokio::fs::remove_file(&full).await {
+                    Ok(()) => match state.db.acquire().await {
+                        Ok(mut conn) => delete_image_rows(&mut conn, &path).await.map_err(anyhow::Error::from),
+                        Err(err) => Err(err.into()),
+                    },
+                    Err(err) => Err(err.into()),
+                },
             },
-        );
+        };
+        match result {
+            Ok(()) => response.removed.push(path),
+            Err(err) => response.failed.push(DuplicateRemovalFailure { path, error: err.to_string() }),
+        }
     }
 
-    let current_index = req.current_index.min(valid_paths.len().saturating_sub(1));
+    if !response.removed.is_empty() {
+        invalidate_playlist_cache(&state).await;
+    }
+    tracing::info!(
+        "🧹 Duplicate cleanup: {} removed ({:?}), {} failed",
+        response.removed.len(),
+        req.action,
+        response.failed.len()
+    );
+    Ok(Json(response))
+}
 
-    Ok(Json(serde_json::json!({
-        "status": "restored",
-        "valid_count": valid_paths.len(),
-        "original_count": original_count,
-        "current_index": current_index,
-        "playlist": valid_paths
-    })))
+#[derive(Debug, Deserialize)]
+struct UsageQuery {
+    #[serde(default)]
+    path: Option<String>,
+    /// 展开的目录层数，更深的目录计入其祖先
+    depth: Option<usize>,
 }
 
-async fn session_status(
-    State(state): State<AppState>,
-    connect_info: ConnectInfo<SocketAddr>,
-) -> Json<SessionStatusResponse> {
-    let ip = connect_info.0.ip().to_string();
+/// 目录占用树节点 (children 按字节数降序，适合直接绘制 treemap)
+#[derive(Debug, Default, Serialize)]
+struct UsageNode {
+    name: String,
+    path: String,
+    bytes: i64,
+    files: i64,
+    children: Vec<UsageNode>,
+}
 
-    {
-        let sessions = state.user_sessions.read().await;
-        if let Some(session) = sessions.get(&ip) {
-            return Json(SessionStatusResponse {
-                has_session: true,
-                source: Some("memory".to_string()),
-                playlist_size: session.playlist.len(),
-            });
-        }
-    }
-    
-    // 从数据库查询
-    let row: Option<(String,)> = sqlx::query_as("SELECT playlist FROM playlists WHERE client_ip = ?")
-        .bind(&ip)
-        .fetch_optional(&state.db)
-        .await
-        .unwrap_or(None);
+#[derive(Default)]
+struct UsageBuilder {
+    bytes: i64,
+    files: i64,
+    children: BTreeMap<String, UsageBuilder>,
+}
 
-    if let Some((playlist_json,)) = row {
-        if let Ok(list) = serde_json::from_str::<Vec<String>>(&playlist_json) {
-            return Json(SessionStatusResponse {
-                has_session: true,
-                source: Some("database".to_string()),
-                playlist_size: list.len(),
-            });
+impl UsageBuilder {
+    fn into_node(self, name: String, path: String) -> UsageNode {
+        let mut children: Vec<UsageNode> = self
+            .children
+            .into_iter()
+            .map(|(child, builder)| {
+                let child_path = if path == "." { child.clone() } else { format!("{}/{}", path, child) };
+                builder.into_node(child, child_path)
+            })
+            .collect();
+        children.sort_by(|a, b| b.bytes.cmp(&a.bytes).then_with(|| a.name.cmp(&b.name)));
+        UsageNode {
+            name,
+            path,
+            bytes: self.bytes,
+            files: self.files,
+            children,
         }
     }
-
-    Json(SessionStatusResponse { has_session: false, source: None, playlist_size: 0 })
 }
 
-async fn session_playlist(
+/// 接口: GET /api/usage?path=...&depth=...，按目录子树汇总索引中的文件大小
+async fn disk_usage(
     State(state): State<AppState>,
-    connect_info: ConnectInfo<SocketAddr>,
-) -> Json<SessionPlaylistResponse> {
-    let ip = connect_info.0.ip().to_string();
+    Query(query): Query<UsageQuery>,
+) -> Result<Json<UsageNode>, (StatusCode, Json<serde_json::Value>)> {
+    let allow_parent = *state.allow_parent_dir_access.read().await;
+    let base = normalize_rel_path(query.path.as_deref().unwrap_or("."));
+    if !allow_parent && is_external_key(&base) {
+        return Err((
+            StatusCode::FORBIDDEN,
+            Json(serde_json::json!({ "detail": "Access outside ROOT_DIR is disabled" })),
+        ));
+    }
+    let depth = query.depth.unwrap_or(3).clamp(1, 32);
 
-    {
-        let sessions = state.user_sessions.read().await;
-        if let Some(session) = sessions.get(&ip) {
-            return Json(SessionPlaylistResponse {
-                has_session: true,
-                source: Some("memory".to_string()),
-                playlist_size: session.playlist.len(),
-                playlist: session.playlist.clone(),
-                criteria: session.criteria.clone(),
-            });
+    let rows: Vec<(String, i64)> = if base == "." {
+        sqlx::query_as(&format!("SELECT path, size FROM images WHERE {} AND {}", PRESENT_SQL_FILTER, INTERNAL_PATH_SQL_FILTER))
+            .fetch_all(&state.db)
+            .await
+    } else {
+        sqlx::query_as("SELECT path, size FROM images WHERE path LIKE ? ESCAPE '\\' AND missing_since IS NULL")
+            .bind(format!("{}/%", escape_like_pattern(&base)))
+            .fetch_all(&state.db)
+            .await
+    }
+    .unwrap_or_default();
+
+    let mut root = UsageBuilder::default();
+    for (path, size) in rows {
+        let rel = if base == "." { path.as_str() } else { &path[base.len() + 1..] };
+        let mut node = &mut root;
+        node.bytes += size;
+        node.files += 1;
+        // 最后一段是文件名，只沿目录部分下降
+        let folders: Vec<&str> = rel.split('/').collect();
+        for folder in folders[..folders.len() - 1].iter().take(depth) {
+            node = node.children.entry(folder.to_string()).or_default();
+            node.bytes += size;
+            node.files += 1;
         }
     }
 
-    let row: Option<(String, Option<String>)> = sqlx::query_as("SELECT playlist, criteria_json FROM playlists WHERE client_ip = ?")
-        .bind(&ip)
-        .fetch_optional(&state.db)
+    let name = if base == "." {
+        state
+            .root_dir
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_default()
+    } else {
+        base.rsplit('/').next().unwrap_or(&base).to_string()
+    };
+    Ok(Json(root.into_node(name, base)))
+}
+
+/// 读取索引中的哈希，缺失时计算并回写
+async fn ensure_image_hash(pool: &Pool<Sqlite>, rel_path: &str, full_path: &Path) -> Option<String> {
+    let stored: Option<(Option<String>,)> = sqlx::query_as("SELECT hash FROM images WHERE path = ?")
+        .bind(rel_path)
+        .fetch_optional(pool)
         .await
         .unwrap_or(None);
-
-    if let Some((playlist_json, criteria_json)) = row {
-        if let Ok(list) = serde_json::from_str::<Vec<String>>(&playlist_json) {
-            let criteria = criteria_json
-                .as_deref()
-                .and_then(|raw| serde_json::from_str::<PlaylistCriteria>(raw).ok());
-            return Json(SessionPlaylistResponse {
-                has_session: true,
-                source: Some("database".to_string()),
-                playlist_size: list.len(),
-                playlist: list,
-                criteria,
-            });
-        }
+    if let Some((Some(hash),)) = stored {
+        return Some(hash);
     }
 
-    Json(SessionPlaylistResponse {
-        has_session: false,
-        source: None,
-        playlist_size: 0,
-        playlist: Vec::new(),
-        criteria: None,
-    })
+    let full = full_path.to_path_buf();
+    let hash = tokio::task::spawn_blocking(move || compute_file_hash(&full))
+        .await
+        .ok()
+        .flatten()?;
+    sqlx::query("UPDATE images SET hash = ? WHERE path = ?")
+        .bind(&hash)
+        .bind(rel_path)
+        .execute(pool)
+        .await
+        .ok();
+    Some(hash)
 }
 
-// 简单的文件服务，不带缓存逻辑，依靠 OS Page Cache
-// --- 文件服务逻辑 ---
+/// 后台检测还没轮到的图片在查询时补上色彩信息
+async fn ensure_color_info(pool: &Pool<Sqlite>, meta: &ImageMetadata, full_path: &Path) -> (Option<String>, bool) {
+    if let Some(hdr_gain_map) = meta.hdr_gain_map {
+        return (meta.color_profile.clone(), hdr_gain_map);
+    }
+    let full = full_path.to_path_buf();
+    let Ok(info) = tokio::task::spawn_blocking(move || color::inspect(&full)).await else {
+        return (None, false);
+    };
+    sqlx::query("UPDATE images SET color_profile = ?, hdr_gain_map = ? WHERE path = ?")
+        .bind(&info.profile)
+        .bind(info.hdr_gain_map)
+        .bind(&meta.path)
+        .execute(pool)
+        .await
+        .ok();
+    (info.profile, info.hdr_gain_map)
+}
 
-/// 核心文件读取逻辑
-async fn serve_file_core(state: AppState, raw_path: String) -> Response {
-    let root_dir = state.root_dir.as_path();
-    let allow_parent = *state.allow_parent_dir_access.read().await;
-    
-    // 1. URL 解码 (非常重要！前端传过来的可能是 "foo%20bar.jpg")
-    // axum::extract::Path 会自动解码，但 Query 需要手动处理或者依赖 serde
-    // 这里做一次从百分号编码的解码，防止 raw_path 依然包含 %20
-    let decoded_path = urlencoding::decode(&raw_path)
-        .map(|s| s.into_owned())
-        .unwrap_or_else(|_| raw_path.clone());
+async fn load_image_tags(pool: &Pool<Sqlite>, rel_path: &str) -> Vec<String> {
+    let rows: Vec<(String,)> = sqlx::query_as("SELECT tag FROM image_tags WHERE path = ? ORDER BY tag")
+        .bind(rel_path)
+        .fetch_all(pool)
+        .await
+        .unwrap_or_default();
+    rows.into_iter().map(|(tag,)| tag).collect()
+}
 
-    let rel = normalize_rel_path(&decoded_path);
-    let full = resolve_full_path(root_dir, &rel);
+/// 接口: GET /api/tags/suggest?q=...，层级标签前缀补全
+/// 既匹配完整路径前缀 ("places/ja")，也匹配任意一级的前缀 ("kyo" -> "places/japan/kyoto")；
+/// 中间层级即使没有直接使用也会作为候选返回
+async fn suggest_tags(
+    State(state): State<AppState>,
+    Query(query): Query<TagSuggestQuery>,
+) -> Json<Vec<TagSuggestion>> {
+    let limit = query.limit.unwrap_or(20).clamp(1, 200);
+    let needle = query.q.trim().trim_start_matches('/').to_lowercase();
 
-    // 2. 权限检查
-    if !allow_parent && !is_under_root(root_dir, &full) {
-        return (
-            StatusCode::FORBIDDEN, 
-            Json(serde_json::json!({ "message": "Access outside ROOT_DIR is disabled" }))
-        ).into_response();
-    }
+    let rows: Vec<(String, i64)> = sqlx::query_as("SELECT tag, COUNT(*) FROM image_tags GROUP BY tag")
+        .fetch_all(&state.db)
+        .await
+        .unwrap_or_default();
 
-    // 3. 检查文件是否存在
-    if !full.exists() || !full.is_file() {
-        return StatusCode::NOT_FOUND.into_response();
+    // 展开为完整的标签树：每个节点记录直接计数与是否有子节点
+    let mut nodes: BTreeMap<String, (i64, bool)> = BTreeMap::new();
+    for (tag, count) in rows {
+        nodes.entry(tag.clone()).or_insert((0, false)).0 += count;
+        let mut end = tag.len();
+        while let Some(pos) = tag[..end].rfind('/') {
+            nodes.entry(tag[..pos].to_string()).or_insert((0, false)).1 = true;
+            end = pos;
+        }
     }
 
-    // 4. 高效流式传输
-    match tokio::fs::File::open(&full).await {
-        Ok(file) => {
-            let stream = tokio_util::io::ReaderStream::new(file);
-            let body = axum::body::Body::from_stream(stream);
-
-            let mime = from_path(&full).first_or_octet_stream();
-            let mut headers = HeaderMap::new();
-            headers.insert(header::CONTENT_TYPE, mime.as_ref().parse().unwrap());
-            // 缓存控制：让浏览器缓存图片 1 小时，减少服务器压力
-            headers.insert(header::CACHE_CONTROL, "public, max-age=3600".parse().unwrap());
+    // 排序：完整前缀匹配优先，其次层级前缀匹配；同类中按使用次数、再按字母
+    let mut matches: Vec<(u8, TagSuggestion)> = nodes
+        .into_iter()
+        .filter_map(|(tag, (count, has_children))| {
+            let lower = tag.to_lowercase();
+            let rank = if lower.starts_with(&needle) {
+                0
+            } else if lower.split('/').any(|segment| segment.starts_with(&needle)) {
+                1
+            } else {
+                return None;
+            };
+            Some((rank, TagSuggestion { tag, count, has_children }))
+        })
+        .collect();
+    matches.sort_by(|(ra, a), (rb, b)| {
+        ra.cmp(rb)
+            .then_with(|| b.count.cmp(&a.count))
+            .then_with(|| a.tag.cmp(&b.tag))
+    });
 
-            (headers, body).into_response()
-        },
-        Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
-    }
+    Json(matches.into_iter().take(limit).map(|(_, s)| s).collect())
 }
 
-/// 接口 1: 处理 /api/file?path=...
-async fn serve_file_by_query(
+/// 接口: GET /api/info?path=...，单张图片的详细元数据
+async fn image_info(
     State(state): State<AppState>,
     Query(query): Query<FileQuery>,
-) -> Response {
-    if state.log_api_file_requests {
-        tracing::info!("📷 [API /api/file] path={}", query.path);
-    }
-    serve_file_core(state, query.path).await
+) -> Result<Json<ImageInfoResponse>, (StatusCode, Json<serde_json::Value>)> {
+    collect_image_info(&state, &query.path).await.map(Json)
 }
 
-/// 接口 2: 处理直接路径 /folder/image.jpg
-// async fn serve_file_by_path(
-//     State(state): State<AppState>,
-//     AxumPath(path_str): AxumPath<String>,
-// ) -> Response {
-//     serve_file_core(state, path_str).await
-// }
+async fn collect_image_info(
+    state: &AppState,
+    raw_path: &str,
+) -> Result<ImageInfoResponse, (StatusCode, Json<serde_json::Value>)> {
+    let root_dir = state.root_dir.as_path();
+    let (rel, full) = match authorize_client_path(state, raw_path).await {
+        Ok((rel, full)) if full.is_file() => (rel, full),
+        Err(PathAccessError::Forbidden) => {
+            return Err((
+                StatusCode::FORBIDDEN,
+                Json(serde_json::json!({ "detail": "Access outside ROOT_DIR is disabled" })),
+            ));
+        }
+        _ => {
+            return Err((
+                StatusCode::NOT_FOUND,
+                Json(serde_json::json!({ "detail": "Image not found" })),
+            ));
+        }
+    };
+
+    let rel = rel.as_str();
+    let indexed = sqlx::query_as::<_, ImageMetadata>("SELECT * FROM images WHERE path = ?")
+        .bind(rel)
+        .fetch_optional(&state.db)
+        .await
+        .unwrap_or(None);
+
+    // 索引里还没有 (例如扫描未完成)：现场读取并补录
+    let meta = match indexed {
+        Some(meta) => meta,
+        None => {
+            let root_clone = root_dir.to_path_buf();
+            let full_clone = full.clone();
+            let meta = tokio::task::spawn_blocking(move || process_image_metadata_sync(&full_clone, &root_clone))
+                .await
+                .ok()
+                .flatten()
+                .ok_or((
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                    Json(serde_json::json!({ "detail": "Unreadable image" })),
+                ))?;
+            if let Ok(mut conn) = state.db.acquire().await {
+                upsert_image_row(&mut conn, &meta).await.ok();
+            }
+            meta
+        }
+    };
+
+    let hash = ensure_image_hash(&state.db, &meta.path, &full).await;
+    let (color_profile, hdr_gain_map) = ensure_color_info(&state.db, &meta, &full).await;
+    let tags = load_image_tags(&state.db, &meta.path).await;
+    let suggested: Vec<(String, f64)> =
+        sqlx::query_as("SELECT tag, score FROM suggested_tags WHERE path = ? ORDER BY score DESC")
+            .bind(&meta.path)
+            .fetch_all(&state.db)
+            .await
+            .unwrap_or_default();
+    let companion_rows: Vec<(String, String)> =
+        sqlx::query_as("SELECT companion, kind FROM image_companions WHERE path = ? ORDER BY kind, companion")
+            .bind(&meta.path)
+            .fetch_all(&state.db)
+            .await
+            .unwrap_or_default();
+    let caption = load_image_caption(&state.db, &meta.path).await;
+    let edit = load_image_edit(&state.db, &meta.path).await;
+    let file_id = external_file_id(&state.db, &meta.path).await;
+    let backup = backup::image_status(state, &meta.path, meta.size, meta.mtime).await;
+
+    Ok(ImageInfoResponse {
+        file_id,
+        mime: from_path(&full).first_or_octet_stream().to_string(),
+        orientation: if meta.is_landscape { "landscape" } else { "portrait" }.to_string(),
+        panorama: meta.is_panorama,
+        path: meta.path,
+        width: meta.width,
+        height: meta.height,
+        mtime: meta.mtime,
+        added_at: meta.added_at,
+        color_profile,
+        hdr_gain_map,
+        size: meta.size,
+        hash,
+        tags,
+        suggested_tags: suggested
+            .into_iter()
+            .map(|(tag, score)| SuggestedTag { tag, score })
+            .collect(),
+        companions: companion_rows
+            .into_iter()
+            .map(|(path, kind)| Companion {
+                mime: from_path(&path).first_or_octet_stream().to_string(),
+                path,
+                kind,
+            })
+            .collect(),
+        caption,
+        edit,
+        backup,
+    })
+}
 
 async fn browse_folder(
     State(state): State<AppState>,
@@ -1015,13 +12560,10 @@ async fn browse_folder(
     let allow_parent = *state.allow_parent_dir_access.read().await;
 
     let mut rel_path = normalize_rel_path(&query.path);
-    let mut target_path = if rel_path.is_empty() || rel_path == "." {
-        root_dir.to_path_buf()
-    } else {
-        resolve_full_path(root_dir, &rel_path)
-    };
+    reject_double_encoded(root_dir, &rel_path)?;
+    let mut target_path = resolve_full_path(root_dir, &rel_path);
 
-    if !allow_parent && !is_under_root(root_dir, &target_path) {
+    if resolve_and_authorize(root_dir, &rel_path, allow_parent) == Err(PathAccessError::Forbidden) {
         target_path = root_dir.to_path_buf();
         rel_path.clear();
     } else {
@@ -1031,22 +12573,38 @@ async fn browse_folder(
         }
     }
 
-    if !target_path.exists() || !target_path.is_dir() {
+    // 离线的归档文件夹按索引列出，而不是 404
+    let archived = archived_folders(&state.db).await;
+    let offline = !target_path.exists()
+        && archived_folder_of(&archived, &rel_path).is_some()
+        && has_indexed_images(&state, &rel_path).await;
+    if !offline
+        && (!target_path.exists()
+            || !target_path.is_dir()
+            || (!state.follow_symlinks && path_has_symlink(root_dir, &target_path)))
+    {
         return Err((
             StatusCode::NOT_FOUND,
             Json(serde_json::json!({ "detail": "Folder not found" })),
         ));
     }
 
-    let mut items = Vec::new();
-    let entries = std::fs::read_dir(&target_path).map_err(|_| {
-        (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            Json(serde_json::json!({ "detail": "Failed to read folder" })),
-        )
-    })?;
+    let mut items = if offline { browse_offline_folder(&state, &rel_path).await } else { Vec::new() };
+    let entries = if offline {
+        Vec::new()
+    } else {
+        std::fs::read_dir(&target_path)
+            .map_err(|_| {
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    Json(serde_json::json!({ "detail": "Failed to read folder" })),
+                )
+            })?
+            .flatten()
+            .collect()
+    };
 
-    for entry in entries.flatten() {
+    for entry in entries {
         let entry_path = entry.path();
         let name = entry.file_name().to_string_lossy().to_string();
         if name.starts_with('.') {
@@ -1057,6 +12615,23 @@ async fn browse_folder(
             continue;
         };
 
+        // 符号链接：按策略跳过，或跟随后再确认目标仍在允许范围内
+        let ft = if ft.is_symlink() {
+            if !state.follow_symlinks {
+                continue;
+            }
+            let entry_rel = path_to_rel_string(root_dir, &entry_path);
+            if resolve_and_authorize(root_dir, &entry_rel, allow_parent).is_err() {
+                continue;
+            }
+            match std::fs::metadata(&entry_path) {
+                Ok(m) => m.file_type(),
+                Err(_) => continue,
+            }
+        } else {
+            ft
+        };
+
         let is_dir = ft.is_dir();
         if !is_dir && !is_image_ext(&entry_path) {
             continue;
@@ -1066,9 +12641,45 @@ async fn browse_folder(
             name,
             path: path_to_rel_string(root_dir, &entry_path),
             item_type: if is_dir { "folder" } else { "file" }.to_string(),
+            meta: None,
+            file_id: None,
         });
     }
 
+    let folder_rows: Vec<FolderRow> = sqlx::query_as(&format!(
+        "SELECT path, {} FROM folders WHERE path = ? OR path LIKE ? ESCAPE '\\'",
+        FOLDER_META_COLUMNS
+    ))
+    .bind(&rel_path)
+    .bind(folder_like_pattern(&rel_path))
+    .fetch_all(&state.db)
+    .await
+    .unwrap_or_default();
+    let mut folder_meta: HashMap<String, FolderMeta> = folder_rows.into_iter().map(|row| (row.path, row.meta)).collect();
+    // 磁盘离线的直接子归档文件夹也要列出来
+    for (folder, _) in &archived {
+        let is_child = match folder.rsplit_once('/') {
+            Some((parent, _)) => parent == rel_path,
+            None => !folder.is_empty() && rel_path.is_empty(),
+        };
+        if is_child && !items.iter().any(|item| &item.path == folder) && has_indexed_images(&state, folder).await {
+            items.push(BrowseItem {
+                name: folder.rsplit('/').next().unwrap_or_default().to_string(),
+                path: folder.clone(),
+                item_type: "folder".to_string(),
+                meta: None,
+                file_id: None,
+            });
+        }
+    }
+    for item in items.iter_mut() {
+        if item.item_type == "folder" {
+            item.meta = folder_meta.remove(&item.path);
+        } else {
+            item.file_id = external_file_id(&state.db, &item.path).await;
+        }
+    }
+
     items.sort_by(|a, b| {
         let rank_a = if a.item_type == "folder" { 0 } else { 1 };
         let rank_b = if b.item_type == "folder" { 0 } else { 1 };
@@ -1078,8 +12689,11 @@ async fn browse_folder(
     });
 
     Ok(Json(BrowseResponse {
+        meta: folder_meta.remove(&rel_path),
         current_path: rel_path,
         items,
+        index_freshness: *state.index_freshness.read().unwrap(),
+        offline,
     }))
 }
 
@@ -1087,6 +12701,7 @@ async fn get_runtime_config(State(state): State<AppState>) -> Json<serde_json::V
     let v = *state.allow_parent_dir_access.read().await;
     Json(serde_json::json!({
         "allow_parent_dir_access": v,
+        "follow_symlinks": state.follow_symlinks,
         "env_value": env::var("GALLERY_ALLOW_PARENT_DIR_ACCESS").unwrap_or_else(|_| "<unset>".to_string())
     }))
 }
@@ -1130,78 +12745,552 @@ async fn toggle_runtime_config(State(state): State<AppState>) -> Json<serde_json
     }))
 }
 
+// --- 事件推送 (SSE) ---
+
+/// 推送给客户端的服务器事件
+#[derive(Clone, Debug)]
+struct ServerEvent {
+    /// 仅推送给该客户端 IP；None 表示广播
+    target_ip: Option<String>,
+    event: String,
+    data: serde_json::Value,
+}
+
+/// 发布事件 (没有订阅者时直接丢弃)
+fn publish_event(state: &AppState, target_ip: Option<&str>, event: &str, data: serde_json::Value) {
+    let _ = state.events.send(ServerEvent {
+        target_ip: target_ip.map(str::to_string),
+        event: event.to_string(),
+        data,
+    });
+}
+
+/// 图库内容变化事件：`{"added": [...], "removed": [...]}`，按客户端的文件夹订阅过滤
+const LIBRARY_CHANGED_EVENT: &str = "library_changed";
+
+fn publish_library_changes(state: &AppState, added: &[String], removed: &[String]) {
+    if added.is_empty() && removed.is_empty() {
+        return;
+    }
+    publish_event(
+        state,
+        None,
+        LIBRARY_CHANGED_EVENT,
+        serde_json::json!({ "added": added, "removed": removed }),
+    );
+}
+
+/// 只保留订阅文件夹下的路径；没有订阅时原样推送，过滤后为空时返回 None
+fn scope_library_changes(data: &serde_json::Value, prefixes: &[String]) -> Option<serde_json::Value> {
+    if prefixes.is_empty() {
+        return Some(data.clone());
+    }
+    let in_scope = |path: &str| {
+        prefixes
+            .iter()
+            .any(|prefix| path.strip_prefix(prefix.as_str()).is_some_and(|rest| rest.starts_with('/')))
+    };
+    let scoped = |key: &str| -> Vec<serde_json::Value> {
+        data[key]
+            .as_array()
+            .map(|paths| paths.iter().filter(|p| p.as_str().is_some_and(in_scope)).cloned().collect())
+            .unwrap_or_default()
+    };
+    let (added, removed) = (scoped("added"), scoped("removed"));
+    if added.is_empty() && removed.is_empty() {
+        return None;
+    }
+    Some(serde_json::json!({ "added": added, "removed": removed }))
+}
+
+/// 每个客户端最多订阅的文件夹数
+const MAX_SUBSCRIPTIONS: usize = 64;
+
+#[derive(Debug, Deserialize)]
+struct SubscriptionsRequest {
+    prefixes: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct SubscriptionsResponse {
+    /// 为空表示接收整个图库的变化
+    prefixes: Vec<String>,
+}
+
+async fn load_subscriptions(pool: &Pool<Sqlite>, client_ip: &str) -> Vec<String> {
+    let rows: Vec<(String,)> = sqlx::query_as("SELECT prefix FROM folder_subscriptions WHERE client_ip = ? ORDER BY prefix")
+        .bind(client_ip)
+        .fetch_all(pool)
+        .await
+        .unwrap_or_default();
+    rows.into_iter().map(|(prefix,)| prefix).collect()
+}
+
+/// 接口: GET /api/subscriptions，本机订阅的文件夹
+async fn get_subscriptions(
+    State(state): State<AppState>,
+    connect_info: ConnectInfo<SocketAddr>,
+) -> Json<SubscriptionsResponse> {
+    let ip = connect_info.0.ip().to_string();
+    Json(SubscriptionsResponse { prefixes: load_subscriptions(&state.db, &ip).await })
+}
+
+/// 接口: POST /api/subscriptions，替换本机订阅的文件夹：`/api/events` 只推送这些文件夹下的图库变化。
+/// 包含 `.` (整个图库) 时等同于取消订阅
+async fn set_subscriptions(
+    State(state): State<AppState>,
+    connect_info: ConnectInfo<SocketAddr>,
+    Json(req): Json<SubscriptionsRequest>,
+) -> Result<Json<SubscriptionsResponse>, (StatusCode, Json<serde_json::Value>)> {
+    let ip = connect_info.0.ip().to_string();
+    let mut prefixes: Vec<String> = Vec::new();
+    for raw in &req.prefixes {
+        let prefix = normalize_rel_path(raw);
+        reject_double_encoded(&state.root_dir, &prefix)?;
+        if prefix.split('/').any(|part| part == "..") {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                Json(serde_json::json!({ "detail": format!("Invalid folder {:?}", raw) })),
+            ));
+        }
+        if prefix.is_empty() || prefix == "." {
+            prefixes.clear();
+            break;
+        }
+        if !prefixes.contains(&prefix) {
+            prefixes.push(prefix);
+        }
+    }
+    if prefixes.len() > MAX_SUBSCRIPTIONS {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            Json(serde_json::json!({ "detail": format!("At most {} folders", MAX_SUBSCRIPTIONS) })),
+        ));
+    }
+
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs_f64();
+    let db_error = |_| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(serde_json::json!({ "detail": "Failed to update subscriptions" })),
+        )
+    };
+    let mut tx = state.db.begin().await.map_err(db_error)?;
+    sqlx::query("DELETE FROM folder_subscriptions WHERE client_ip = ?")
+        .bind(&ip)
+        .execute(&mut *tx)
+        .await
+        .map_err(db_error)?;
+    for prefix in &prefixes {
+        sqlx::query("INSERT INTO folder_subscriptions (client_ip, prefix, created_at) VALUES (?, ?, ?)")
+            .bind(&ip)
+            .bind(prefix)
+            .bind(now)
+            .execute(&mut *tx)
+            .await
+            .map_err(db_error)?;
+    }
+    tx.commit().await.map_err(db_error)?;
+
+    tracing::info!("🔔 [Subscriptions] {} 订阅 {:?}", ip, prefixes);
+    prefixes.sort();
+    Ok(Json(SubscriptionsResponse { prefixes }))
+}
+
+/// 接口: DELETE /api/subscriptions，恢复接收整个图库的变化
+async fn clear_subscriptions(
+    State(state): State<AppState>,
+    connect_info: ConnectInfo<SocketAddr>,
+) -> Json<SubscriptionsResponse> {
+    let ip = connect_info.0.ip().to_string();
+    sqlx::query("DELETE FROM folder_subscriptions WHERE client_ip = ?")
+        .bind(&ip)
+        .execute(&state.db)
+        .await
+        .ok();
+    Json(SubscriptionsResponse { prefixes: Vec::new() })
+}
+
+/// 接口: GET /api/events，Server-Sent Events 长连接
+async fn event_stream(
+    State(state): State<AppState>,
+    connect_info: ConnectInfo<SocketAddr>,
+) -> Sse<impl futures::Stream<Item = Result<Event, Infallible>>> {
+    let ip = connect_info.0.ip().to_string();
+    let rx = state.events.subscribe();
+
+    let stream = futures::stream::unfold(rx, move |mut rx| {
+        let ip = ip.clone();
+        let state = state.clone();
+        async move {
+            loop {
+                match rx.recv().await {
+                    Ok(mut ev) if ev.target_ip.as_deref().is_none_or(|t| t == ip) => {
+                        // 图库变化只推送订阅范围内的部分，全部无关时不唤醒客户端
+                        if ev.event == LIBRARY_CHANGED_EVENT {
+                            let prefixes = load_subscriptions(&state.db, &ip).await;
+                            match scope_library_changes(&ev.data, &prefixes) {
+                                Some(data) => ev.data = data,
+                                None => continue,
+                            }
+                        }
+                        let event = Event::default()
+                            .event(ev.event)
+                            .json_data(ev.data)
+                            .unwrap_or_default();
+                        return Some((Ok(event), rx));
+                    }
+                    Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => return None,
+                }
+            }
+        }
+    });
+
+    Sse::new(stream).keep_alive(KeepAlive::default())
+}
+
+// --- Middleware ---
+
+/// 为所有响应补上安全加固头 (处理函数已自行设置的不覆盖)
+async fn security_headers_middleware(
+    State(config): State<Arc<SecurityHeaders>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let mut response = next.run(request).await;
+    let headers = response.headers_mut();
+    let pairs = [
+        (header::X_CONTENT_TYPE_OPTIONS, &config.content_type_options),
+        (header::CONTENT_SECURITY_POLICY, &config.content_security_policy),
+        (header::REFERRER_POLICY, &config.referrer_policy),
+        (HeaderName::from_static("cross-origin-resource-policy"), &config.cross_origin_resource_policy),
+    ];
+    for (name, value) in pairs {
+        if let Some(value) = value {
+            if !headers.contains_key(&name) {
+                headers.insert(name, value.clone());
+            }
+        }
+    }
+    response
+}
+
+/// 旧版 `/api/...` 路由：附加弃用响应头，停用后返回 410 并指向 `/api/v1` 上的对应地址
+async fn legacy_api_middleware(
+    State(config): State<Arc<ApiDeprecation>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    // 挂载在 /api (或 profile 的 /g/{name}/api) 之下，这里的路径已去掉前缀
+    let path = request.uri().path();
+    let mount = request
+        .extensions()
+        .get::<axum::extract::OriginalUri>()
+        .and_then(|original| original.path().strip_suffix(path).map(str::to_string))
+        .unwrap_or_else(|| "/api".to_string());
+    let successor = match request.uri().query() {
+        Some(query) => format!("{}/v1{}?{}", mount, path, query),
+        None => format!("{}/v1{}", mount, path),
+    };
+    let client = request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip().to_string())
+        .unwrap_or_default();
+    if config.should_warn(&client) {
+        tracing::warn!("⚠️ 客户端 {} 仍在使用未带版本号的 API (/api{})，请迁移到 /api/v1", client, request.uri().path());
+    }
+
+    let mut response = if config.is_sunset() {
+        (
+            StatusCode::GONE,
+            Json(serde_json::json!({"detail": format!("Unversioned API routes have been retired; use {}", successor)})),
+        )
+            .into_response()
+    } else {
+        next.run(request).await
+    };
+    let headers = response.headers_mut();
+    headers.insert(HeaderName::from_static("deprecation"), config.deprecation.clone());
+    if let Some((_, sunset)) = &config.sunset {
+        headers.insert(HeaderName::from_static("sunset"), sunset.clone());
+    }
+    if let Ok(link) = HeaderValue::from_str(&format!("<{}>; rel=\"successor-version\"", successor)) {
+        headers.append(header::LINK, link);
+    }
+    headers.insert(
+        header::WARNING,
+        HeaderValue::from_static("299 - \"Deprecated API: unversioned /api routes will be removed, use /api/v1\""),
+    );
+    response
+}
+
 // --- Main ---
 
+/// 一个图库的全部路由 (默认图库挂在根路径，profile 挂在 `/g/{name}` 下)
+fn library_router(state: AppState, deprecation: Arc<ApiDeprecation>) -> Router {
+    // 同一组接口挂载两次：/api/v1 为正式地址，未带版本号的 /api 为旧地址 (附带弃用响应头)
+    let api = Router::new()
+        .route("/capabilities", get(get_capabilities))
+        .route("/scan", post(trigger_scan))
+        .route("/scan/cancel", post(cancel_scan))
+        .route("/browse", get(browse_folder))
+        .route("/folder", patch(patch_folder))
+        .route("/folder/order", get(get_folder_order).put(set_folder_order))
+        .route("/info", get(image_info).patch(patch_image_caption))
+        .route("/info/batch", post(image_info_batch))
+        .route("/edits", get(get_image_edit).put(save_image_edit).delete(delete_image_edit))
+        .route("/query", post(query_images))
+        .route("/graphql", get(graphiql).post(execute_graphql))
+        .route("/tags/bulk", post(bulk_tag))
+        .route("/tags/suggest", get(suggest_tags))
+        .route("/search/semantic", get(semantic_search))
+        .route("/duplicates/resolve", post(resolve_duplicates))
+        .route("/relink", post(relink_metadata))
+        .route(
+            "/replication/delta",
+            post(replication::receive_delta).layer(DefaultBodyLimit::max(replication::DELTA_MAX_BYTES)),
+        )
+        .route("/replication/file", put(replication::receive_file))
+        .route("/backup", get(backup::backup_summary))
+        .route("/trash", get(list_trash))
+        .route("/usage", get(disk_usage))
+        .route("/analytics/top", get(analytics_top))
+        .route("/analytics/never-shown", get(analytics_never_shown))
+        .route("/admin/access-log", get(access_log))
+        .route("/admin/diagnostics", get(diagnostics::diagnostics))
+        .route("/admin/cache", get(thumbnail_cache::cache_stats))
+        .route("/admin/cache/prune", post(thumbnail_cache::prune_cache))
+        .route("/people", get(list_people))
+        .route("/people/name", post(name_person))
+        .route("/faces", get(list_faces))
+        .route("/faces/crop", get(face_crop))
+        .route("/kenburns", get(ken_burns).post(ken_burns_batch))
+        .route("/resize", get(resize_image))
+        .route("/transition", get(transition_frames))
+        .route("/srcset", get(srcset_manifest))
+        .route("/collage", post(create_collage))
+        .route("/contact-sheet", post(contact_sheet))
+        .route("/import", post(import_images))
+        .route("/jobs", get(list_jobs))
+        .route("/jobs/:id", get(job_status).delete(cancel_job))
+        .route("/watermarks", get(list_watermarks).post(save_watermark).delete(delete_watermark))
+        .route("/profiles", get(list_device_profiles).post(save_device_profile).delete(delete_device_profile))
+        .route("/schedules", get(get_schedules).post(set_schedules))
+        .route("/digests", get(list_digests).post(save_digest).delete(delete_digest))
+        .route("/digests/send", post(send_digest_now))
+        .route("/devices", get(list_devices).post(register_device).delete(delete_device))
+        .route("/device/:id/assignment", get(device_assignment))
+        .route("/pairing/qr", get(pairing_qr))
+        .route("/pairing/redeem", post(redeem_pairing))
+        .route("/cast/devices", get(list_cast_devices))
+        .route("/cast/frame", get(cast_frame))
+        .route("/cast/:device/:action", post(cast_control))
+        .route("/calendar.ics", get(calendar_feed))
+        .route("/tiles", get(tile_descriptor))
+        .route("/tiles/*rest", get(tile_image))
+        .route("/share", post(create_share))
+        .route("/shares", get(list_shares))
+        .route("/share/:token", get(share_details).delete(revoke_share))
+        .route("/share/:token/comments", get(list_share_comments))
+        .route("/playlist", post(get_playlist))
+        .route("/restore-playlist", post(restore_playlist))
+        .route("/session-status", get(session_status))
+        .route("/session-playlist", get(session_playlist))
+        .route("/session/end", post(end_session))
+        .route("/events", get(event_stream))
+        .route(
+            "/subscriptions",
+            get(get_subscriptions).post(set_subscriptions).delete(clear_subscriptions),
+        )
+        .route(
+            "/blocklist",
+            get(get_blocklist).post(add_to_blocklist).delete(remove_from_blocklist),
+        )
+        .route("/runtime-config", get(get_runtime_config).post(set_runtime_config))
+        .route("/runtime-config/toggle", post(toggle_runtime_config))
+        // --- 修复点开始 ---
+        .route("/file", get(serve_file_by_query)) // 必须放在通配符之前 (GET 路由同时处理 HEAD)
+        .route("/file/:id", get(serve_file_by_id))
+        // --- 修复点结束 ---
+        .layer(middleware::from_fn_with_state(state.db.clone(), idempotency_middleware));
+    let legacy_api = api.clone().layer(middleware::from_fn_with_state(deprecation, legacy_api_middleware));
+    Router::new()
+        .nest("/api", legacy_api.nest("/v1", api))
+        .route("/iiif/:id", get(iiif_base))
+        .route("/iiif/:id/info.json", get(iiif_info))
+        .route("/iiif/:id/:region/:size/:rotation/:file", get(iiif_image))
+        .route("/share/:token", get(view_share))
+        .route("/share/:token/unlock", post(unlock_share))
+        .route("/share/:token/:index", get(share_file))
+        .route("/share/:token/:index/comments", get(list_share_item_comments).post(add_share_comment))
+        .with_state(state)
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
-    tracing_subscriber::registry()
-        .with(
-            tracing_subscriber::EnvFilter::try_from_default_env()
-                .unwrap_or_else(|_| "gallery_server=debug,tower_http=info,axum::rejection=trace".into()),
-        )
-        .with(tracing_subscriber::fmt::layer())
-        .init();
+    let env_filter = tracing_subscriber::EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| "gallery_server=debug,tower_http=info,axum::rejection=trace".into());
+    let registry = tracing_subscriber::registry().with(tracing_subscriber::fmt::layer().with_filter(env_filter));
+    // 管理页的错误日志不受 RUST_LOG 过滤影响，因此过滤条件只挂在输出层上
+    #[cfg(feature = "admin-ui")]
+    let registry = registry.with(admin::error_log_layer());
+    registry.init();
 
     // 把原来的 tracing::info! 替换为 tracing 的宏更好，比如：
     tracing::info!("Starting server setup...");
 
-    let host = env::var("GALLERY_HOST").unwrap_or_else(|_| "0.0.0.0".to_string());
-    let port = env::var("GALLERY_PORT")
+    // 配置文件 (`GALLERY_CONFIG`) 优先于进程环境变量，须在读取其它配置之前加载
+    let config_file = ConfigFile::from_env();
+    if let Some(config) = &config_file {
+        match config.load(false) {
+            Ok(_) => tracing::info!("📄 已加载配置文件 {}", config.path.display()),
+            Err(err) => tracing::error!("❌ 配置文件 {} 加载失败: {}", config.path.display(), err),
+        }
+    }
+
+    let host = config_var("GALLERY_HOST").unwrap_or_else(|_| "0.0.0.0".to_string());
+    let port = config_var("GALLERY_PORT")
         .ok()
         .and_then(|v| v.parse::<u16>().ok())
         .unwrap_or(4860);
 
     // 1. 环境配置
-    let root_dir = env::var("GALLERY_ROOT_DIR").map(PathBuf::from).unwrap_or(env::current_dir()?);
-    let db_path = root_dir.join("gallery_metadata.db");
-    
-    // 2. 数据库连接池
-    let db_url = format!("sqlite://{}?mode=rwc", db_path.to_string_lossy());
-    let pool = SqlitePoolOptions::new()
-        .max_connections(10)
-        .connect(&db_url)
-        .await
-        .expect("Failed to connect to SQLite");
-    
-    init_db(&pool).await?;
+    let root_dir = config_var("GALLERY_ROOT_DIR").map(PathBuf::from).unwrap_or(env::current_dir()?);
+
+    #[cfg(feature = "notify")]
+    let (notifier, notifier_worker) = match notify::Notifier::from_env() {
+        Ok(Some((notifier, worker))) => (Some(Arc::new(notifier)), Some(worker)),
+        Ok(None) => (None, None),
+        Err(err) => {
+            tracing::error!("⚠️ New-image notifications disabled: {:#}", err);
+            (None, None)
+        }
+    };
 
-    let app_state = AppState {
-        db: pool.clone(),
-        root_dir: Arc::new(root_dir.clone()),
-        allow_parent_dir_access: Arc::new(RwLock::new(env::var("GALLERY_ALLOW_PARENT_DIR_ACCESS").unwrap_or_default() == "1")),
-        external_synced_paths_this_boot: Arc::new(RwLock::new(HashSet::new())),
-        user_sessions: Arc::new(RwLock::new(HashMap::new())),
-        log_api_file_requests: env_flag_enabled("GALLERY_LOG_API_FILE_REQUESTS"),
+    let shared = SharedServices {
+        #[cfg(feature = "onnx")]
+        autotagger: match autotag::AutoTagger::from_env() {
+            Ok(tagger) => tagger.map(Arc::new),
+            Err(err) => {
+                tracing::error!("⚠️ ONNX auto-tagging disabled: {:#}", err);
+                None
+            }
+        },
+        #[cfg(feature = "onnx")]
+        text_encoder: match autotag::TextEncoder::from_env() {
+            Ok(encoder) => encoder.map(Arc::new),
+            Err(err) => {
+                tracing::error!("⚠️ ONNX semantic search disabled: {:#}", err);
+                None
+            }
+        },
+        #[cfg(feature = "onnx")]
+        face_analyzer: match faces::FaceAnalyzer::from_env() {
+            Ok(analyzer) => analyzer.map(Arc::new),
+            Err(err) => {
+                tracing::error!("⚠️ Face detection disabled: {:#}", err);
+                None
+            }
+        },
+        #[cfg(feature = "cast")]
+        cast: Arc::new(cast::CastManager::default()),
+        #[cfg(feature = "smtp")]
+        mailer: match digest::Mailer::from_env() {
+            Ok(mailer) => mailer.map(Arc::new),
+            Err(err) => {
+                tracing::error!("⚠️ Email digests disabled: {:#}", err);
+                None
+            }
+        },
+        #[cfg(feature = "notify")]
+        notifier,
     };
 
+    // 2. 数据库连接池 (每个图库一个数据库)
+    let app_state = open_library(root_dir, Settings::default(), &shared)
+        .await
+        .expect("Failed to connect to SQLite");
+
+    let access_log_days = app_state.access_log_retention_days.load(std::sync::atomic::Ordering::Relaxed);
+    if access_log_days > 0 {
+        tracing::info!("📝 /api/file access log: ON (kept {} days)", access_log_days);
+    } else {
+        tracing::info!("📝 /api/file access log: OFF");
+    }
     tracing::info!(
-        "📝 API /api/file request logging: {}",
-        if app_state.log_api_file_requests { "ON" } else { "OFF" }
+        "🔗 Follow symlinks: {}",
+        if app_state.follow_symlinks { "ON" } else { "OFF" }
     );
 
-    // 启动时触发一次扫描
-    let state_clone = app_state.clone();
-    tokio::spawn(async move {
-        scan_library_task(state_clone.db, state_clone.root_dir).await;
-    });
+    // 其它图库 (`GALLERY_PROFILES`)，各自独立的 ROOT_DIR、数据库、会话与缓存，挂载在 /g/{name}
+    let mut profile_states = Vec::new();
+    for (name, root) in parse_profiles(&config_var("GALLERY_PROFILES").unwrap_or_default()) {
+        match open_library(root.clone(), Settings::for_profile(&name), &shared).await {
+            Ok(state) => {
+                tracing::info!("📚 Profile {}: {} (/g/{}/api/v1)", name, root.display(), name);
+                profile_states.push(state);
+            }
+            Err(err) => tracing::error!("❌ Profile {} ({}) disabled: {:#}", name, root.display(), err),
+        }
+    }
+
+    // 启动时触发一次扫描，并启动各图库的维护任务
+    start_library(&app_state).await;
+    for state in &profile_states {
+        start_library(state).await;
+    }
+
+    // 以下集成只服务默认图库
+    #[cfg(feature = "mqtt")]
+    mqtt::spawn(app_state.clone());
+
+    #[cfg(feature = "cast")]
+    app_state.cast.spawn_discovery();
+
+    #[cfg(feature = "smtp")]
+    digest::spawn(app_state.clone());
+
+    #[cfg(feature = "notify")]
+    if let Some(worker) = notifier_worker {
+        worker.spawn(app_state.clone());
+    }
+
+    #[cfg(feature = "replication")]
+    replication::spawn(app_state.clone());
+
+    #[cfg(feature = "backup")]
+    backup::spawn(app_state.clone());
+
+    if let Some(config) = config_file {
+        let mut states = vec![app_state.clone()];
+        states.extend(profile_states.iter().cloned());
+        spawn_config_reloader(states, config);
+    }
 
     // 3. 路由
-    let app = Router::new()
-        .route("/api/scan", post(trigger_scan))
-        .route("/api/browse", get(browse_folder))
-        .route("/api/playlist", post(get_playlist))
-        .route("/api/restore-playlist", post(restore_playlist))
-        .route("/api/session-status", get(session_status))
-        .route("/api/session-playlist", get(session_playlist))
-        .route("/api/runtime-config", get(get_runtime_config).post(set_runtime_config))
-        .route("/api/runtime-config/toggle", post(toggle_runtime_config))
-        // --- 修复点开始 ---
-        .route("/api/file", get(serve_file_by_query)) // 必须放在通配符之前
+    #[cfg(feature = "grpc")]
+    let grpc_routes = grpc::router(app_state.clone());
+    #[cfg(feature = "admin-ui")]
+    let admin_routes = admin::router(app_state.clone());
+    let deprecation = Arc::new(ApiDeprecation::from_env());
+    let mut app = library_router(app_state.clone(), deprecation.clone());
+    for profile in &profile_states {
+        app = app.nest(&profile.base_path, library_router(profile.clone(), deprecation.clone()));
+    }
+    let app = app
         // .route("/*file_path", get(serve_file_by_path))
-        // --- 修复点结束 ---
+        .layer(middleware::from_fn_with_state(
+            Arc::new(SecurityHeaders::from_env()),
+            security_headers_middleware,
+        ))
         .layer(CorsLayer::permissive())
-        .layer(TraceLayer::new_for_http())
-        .with_state(app_state);
+        .layer(TraceLayer::new_for_http());
+    #[cfg(feature = "grpc")]
+    let app = app.merge(grpc_routes);
+    #[cfg(feature = "admin-ui")]
+    let app = app.merge(admin_routes);
 
     // 4. 服务器启动 (Rustls)
     let addr: SocketAddr = format!("{}:{}", host, port)
@@ -1209,18 +13298,552 @@ async fn main() -> Result<()> {
         .unwrap_or_else(|_| SocketAddr::from(([0, 0, 0, 0], 4860)));
     tracing::info!("🚀 Rust Gallery Server running on https://{}", addr);
     
+    let ssl = (config_var("GALLERY_SSL_CERT"), config_var("GALLERY_SSL_KEY"));
+
+    #[cfg(feature = "mdns")]
+    let _mdns = mdns::advertise(&host, addr.port(), ssl.0.is_ok() && ssl.1.is_ok());
+
+    let http_tuning = HttpTuning::from_env();
+    tracing::info!("🔧 HTTP 连接参数: {:?}", http_tuning);
     // 加载证书部分省略，逻辑同上... 假设证书存在
-    if let (Ok(cert), Ok(key)) = (env::var("GALLERY_SSL_CERT"), env::var("GALLERY_SSL_KEY")) {
+    if let (Ok(cert), Ok(key)) = ssl {
          let tls_config = RustlsConfig::from_pem_file(cert, key).await?;
-         axum_server::bind_rustls(addr, tls_config)
+         if !http_tuning.http2 {
+             // 默认 ALPN 为 h2 + http/1.1
+             let mut inner = (*tls_config.get_inner()).clone();
+             inner.alpn_protocols = vec![b"http/1.1".to_vec()];
+             tls_config.reload_from_config(Arc::new(inner));
+         }
+         #[cfg(feature = "http3")]
+         let app = if env_flag_enabled("GALLERY_HTTP3") {
+             let quic_addr = SocketAddr::new(
+                 addr.ip(),
+                 config_var("GALLERY_HTTP3_PORT").ok().and_then(|p| p.trim().parse().ok()).unwrap_or(addr.port()),
+             );
+             match http3::spawn(quic_addr, &tls_config, app.clone()) {
+                 Ok(()) => {
+                     let alt_svc = HeaderValue::from_str(&format!("h3=\":{}\"; ma=86400", quic_addr.port()))?;
+                     app.layer(middleware::map_response(move |mut response: Response| {
+                         let alt_svc = alt_svc.clone();
+                         async move {
+                             response.headers_mut().insert(header::ALT_SVC, alt_svc);
+                             response
+                         }
+                     }))
+                 }
+                 Err(err) => {
+                     tracing::error!("❌ HTTP/3 监听启动失败，仅提供 TCP: {}", err);
+                     app
+                 }
+             }
+         } else {
+             app
+         };
+         let mut server = axum_server::bind_rustls(addr, tls_config);
+         http_tuning.apply(server.http_builder());
+         server
             .serve(app.into_make_service_with_connect_info::<SocketAddr>())
             .await?;
     } else {
         tracing::info!("⚠️  SSL未配置，运行在 HTTP 模式");
-        axum_server::bind(addr)
+        #[cfg(feature = "http3")]
+        if env_flag_enabled("GALLERY_HTTP3") {
+            tracing::warn!("⚠️ HTTP/3 需要 TLS 证书 (GALLERY_SSL_CERT / GALLERY_SSL_KEY)，已忽略 GALLERY_HTTP3");
+        }
+        let mut server = axum_server::bind(addr);
+        http_tuning.apply(server.http_builder());
+        server
             .serve(app.into_make_service_with_connect_info::<SocketAddr>())
             .await?;
     }
 
     Ok(())
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 构造测试用目录：root/album/a.jpg，以及位于 root 之外的 outside/secret.jpg
+    fn traversal_fixture() -> (tempfile::TempDir, PathBuf) {
+        let tmp = tempfile::tempdir().unwrap();
+        let base = std::fs::canonicalize(tmp.path()).unwrap();
+        let root = base.join("root");
+        std::fs::create_dir_all(root.join("album")).unwrap();
+        std::fs::write(root.join("album/a.jpg"), b"x").unwrap();
+        std::fs::create_dir_all(base.join("outside")).unwrap();
+        std::fs::write(base.join("outside/secret.jpg"), b"x").unwrap();
+        (tmp, root)
+    }
+
+    fn authorize(root: &Path, raw: &str) -> Result<PathBuf, PathAccessError> {
+        resolve_and_authorize(root, &normalize_rel_path(raw), false)
+    }
+
+    /// 单连接的内存数据库 (多连接时每个连接各是一个独立的库)
+    async fn memory_db() -> Pool<Sqlite> {
+        let options = "sqlite::memory:".parse::<SqliteConnectOptions>().unwrap()
+            .collation(NATURAL_COLLATION, |a: &str, b: &str| natord::compare_ignore_case(a, b));
+        let pool = SqlitePoolOptions::new().max_connections(1).connect_with(options).await.unwrap();
+        init_db(&pool).await.unwrap();
+        pool
+    }
+
+    /// Display P3 的矩阵/TRC 配置文件 (sRGB 曲线，D50 适配后的原色)
+    fn display_p3_profile() -> Vec<u8> {
+        let fixed = |v: f64| ((v * 65536.0).round() as i32).to_be_bytes();
+        let xyz = |c: [f64; 3]| [&b"XYZ \0\0\0\0"[..], &fixed(c[0]), &fixed(c[1]), &fixed(c[2])].concat();
+        let mut para = vec![b'p', b'a', b'r', b'a', 0, 0, 0, 0, 0, 3, 0, 0];
+        for v in [2.4, 1.0 / 1.055, 0.055 / 1.055, 1.0 / 12.92, 0.04045] {
+            para.extend_from_slice(&fixed(v));
+        }
+        let tags: [(&[u8; 4], Vec<u8>); 6] = [
+            (b"rXYZ", xyz([0.515102, 0.241196, -0.001053])),
+            (b"gXYZ", xyz([0.291965, 0.692235, 0.041885])),
+            (b"bXYZ", xyz([0.157153, 0.066569, 0.784072])),
+            (b"rTRC", para.clone()),
+            (b"gTRC", para.clone()),
+            (b"bTRC", para),
+        ];
+        let mut profile = vec![0u8; 128];
+        profile[16..20].copy_from_slice(b"RGB ");
+        profile.extend_from_slice(&(tags.len() as u32).to_be_bytes());
+        let mut data = Vec::new();
+        let data_start = 128 + 4 + tags.len() * 12;
+        for (signature, body) in &tags {
+            profile.extend_from_slice(*signature);
+            profile.extend_from_slice(&((data_start + data.len()) as u32).to_be_bytes());
+            profile.extend_from_slice(&(body.len() as u32).to_be_bytes());
+            data.extend_from_slice(body);
+        }
+        profile.extend(data);
+        profile
+    }
+
+    #[test]
+    fn wide_gamut_profiles_convert_to_srgb() {
+        let profile = display_p3_profile();
+        let transform = color::SrgbTransform::from_icc(&profile).expect("P3 is not sRGB");
+        let mut image = image::RgbImage::from_pixel(1, 1, image::Rgb([200, 150, 100]));
+        transform.apply(&mut image);
+        let [r, g, b] = image.get_pixel(0, 0).0;
+        assert!(r.abs_diff(209) <= 2 && g.abs_diff(147) <= 2 && b.abs_diff(91) <= 2, "{:?}", (r, g, b));
+
+        // 保留模式：配置文件 (跨多个 APP2 段) 原样嵌入
+        let mut jpeg = std::io::Cursor::new(Vec::new());
+        image.write_to(&mut jpeg, image::ImageOutputFormat::Jpeg(90)).unwrap();
+        let large: Vec<u8> = profile.iter().cycle().take(70_000).copied().collect();
+        let embedded = color::embed_jpeg_metadata(jpeg.into_inner(), None, Some(&large));
+        let mut decoder = image::codecs::jpeg::JpegDecoder::new(std::io::Cursor::new(embedded)).unwrap();
+        assert_eq!(image::ImageDecoder::icc_profile(&mut decoder), Some(large));
+    }
+
+    #[test]
+    fn image_edits_rotate_then_crop() {
+        let image = image::DynamicImage::ImageRgb8(image::RgbImage::from_pixel(200, 100, image::Rgb([128; 3])));
+        let edit = |crop, rotation, exposure| ImageEdit { crop, rotation, exposure };
+
+        assert_eq!(apply_image_edit(image.clone(), &edit(None, 90.0, 0.0)).dimensions(), (100, 200));
+        // 微调角度时裁掉空角，宽高比不变
+        let (w, h) = apply_image_edit(image.clone(), &edit(None, 3.0, 0.0)).dimensions();
+        assert!(w < 200 && h < 100 && (w as f32 / h as f32 - 2.0).abs() < 0.05, "{}x{}", w, h);
+        assert_eq!(edited_size(200, 100, &edit(None, 3.0, 0.0)), (w, h));
+        // 裁剪坐标相对旋转后的图片
+        let crop = gallery_client::types::CropRect { x: 0.5, y: 0.0, w: 0.5, h: 0.25 };
+        let edited = apply_image_edit(image.clone(), &edit(Some(crop), -90.0, 1.0));
+        assert_eq!(edited.dimensions(), (50, 50));
+        assert_eq!(edited_size(200, 100, &edit(Some(crop), -90.0, 1.0)), (50, 50));
+        assert!(edited.get_pixel(0, 0)[0] > 160);
+
+        assert!(validate_image_edit(&edit(Some(gallery_client::types::CropRect { x: 0.6, ..crop }), 0.0, 0.0)).is_err());
+    }
+
+    #[test]
+    fn hdr_base_gain_maps_render_as_sdr() {
+        let jpeg = |value: u8, xmp: &str| {
+            let mut buf = std::io::Cursor::new(Vec::new());
+            image::RgbImage::from_pixel(8, 8, image::Rgb([value; 3]))
+                .write_to(&mut buf, image::ImageOutputFormat::Jpeg(95))
+                .unwrap();
+            let encoded = buf.into_inner();
+            let payload = [&b"http://ns.adobe.com/xap/1.0/\0"[..], xmp.as_bytes()].concat();
+            let len = ((payload.len() + 2) as u16).to_be_bytes();
+            [&[0xFF, 0xD8, 0xFF, 0xE1][..], &len, &payload, &encoded[2..]].concat()
+        };
+        let primary = jpeg(200, r#"<rdf:Description xmlns:hdrgm="http://ns.adobe.com/hdr-gain-map/1.0/" hdrgm:Version="1.0"/>"#);
+        // 增益图全为 0：G = GainMapMin = -1，即线性光减半
+        let gain_map = jpeg(
+            0,
+            r#"<rdf:Description hdrgm:GainMapMin="-1" hdrgm:OffsetSDR="0" hdrgm:OffsetHDR="0">
+                 <hdrgm:GainMapMax><rdf:Seq><rdf:li>-1</rdf:li></rdf:Seq></hdrgm:GainMapMax>
+                 <hdrgm:BaseRenditionIsHDR>True</hdrgm:BaseRenditionIsHDR></rdf:Description>"#,
+        );
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("hdr.jpg");
+        std::fs::write(&path, [primary, gain_map].concat()).unwrap();
+
+        assert!(color::inspect(&path).hdr_gain_map);
+        let hdr = color::HdrBase::read(&path).expect("HDR base with gain map");
+        let sdr = hdr.to_sdr(image::open(&path).unwrap().to_rgb8());
+        let value = sdr.get_pixel(4, 4)[0];
+        assert!(value.abs_diff(146) <= 3, "{}", value);
+    }
+
+    #[test]
+    fn weighted_interleave_keeps_proportions() {
+        let favorites: Vec<String> = (0..7).map(|i| format!("fav{}", i)).collect();
+        let recent: Vec<String> = (0..100).map(|i| format!("new{}", i)).collect();
+        let mixed = weighted_interleave(vec![(70.0, favorites), (30.0, recent), (50.0, Vec::new())]);
+        // 收藏按 7:3 交错在前 10 张里，用完后剩下的最近图片继续播放
+        assert_eq!(mixed.len(), 107);
+        assert_eq!(mixed[..10].iter().filter(|p| p.starts_with("fav")).count(), 7);
+        assert!(mixed[..5].iter().any(|p| p.starts_with("new")));
+        assert!(mixed[10..].iter().all(|p| p.starts_with("new")));
+        // 三个来源时，其中一个用完后另外两个仍按权重比例交错
+        let a: Vec<String> = (0..2).map(|i| format!("a{}", i)).collect();
+        let b: Vec<String> = (0..40).map(|i| format!("b{}", i)).collect();
+        let c: Vec<String> = (0..40).map(|i| format!("c{}", i)).collect();
+        let mixed = weighted_interleave(vec![(50.0, a), (30.0, b), (10.0, c)]);
+        assert_eq!(mixed.len(), 82);
+        let tail_b = mixed[4..44].iter().filter(|p| p.starts_with('b')).count();
+        assert!((29..=31).contains(&tail_b), "{}", tail_b);
+        assert!(weighted_interleave::<String>(Vec::new()).is_empty());
+    }
+
+    #[test]
+    fn spread_shuffle_keeps_groups_apart() {
+        let items: Vec<(char, usize)> = "abc".chars().flat_map(|c| (0..10).map(move |i| (c, i))).collect();
+        let spread = spread_shuffle(items, |item| item.0, 2, &mut rand::thread_rng());
+        assert_eq!(spread.len(), 30);
+        assert!(spread.windows(3).all(|w| w[0].0 != w[1].0 && w[0].0 != w[2].0 && w[1].0 != w[2].0));
+        // 组内顺序不变
+        assert!(spread.iter().filter(|item| item.0 == 'a').map(|item| item.1).eq(0..10));
+        // 无法满足时尽力而为，不丢图
+        assert_eq!(spread_shuffle(vec![1, 1, 1, 2], |n| *n, 2, &mut rand::thread_rng()).len(), 4);
+    }
+
+    #[test]
+    fn allows_plain_paths_inside_root() {
+        let (_tmp, root) = traversal_fixture();
+        assert_eq!(authorize(&root, "album/a.jpg"), Ok(root.join("album/a.jpg")));
+        assert_eq!(authorize(&root, "/album/a.jpg"), Ok(root.join("album/a.jpg")));
+        assert_eq!(authorize(&root, "album/./a.jpg"), Ok(root.join("album/a.jpg")));
+        assert_eq!(authorize(&root, "album/../album/a.jpg"), Ok(root.join("album/a.jpg")));
+    }
+
+    #[test]
+    fn rejects_parent_traversal_payloads() {
+        let (_tmp, root) = traversal_fixture();
+        let payloads = [
+            "../outside/secret.jpg",
+            "album/../../outside/secret.jpg",
+            "..\\outside\\secret.jpg",
+            "album\\..\\..\\outside\\secret.jpg",
+            "./../outside/secret.jpg",
+            "album/./../../outside/secret.jpg",
+            "album/../../../../../../etc/passwd",
+            "..",
+            "../",
+        ];
+        for payload in payloads {
+            assert_eq!(
+                authorize(&root, payload),
+                Err(PathAccessError::Forbidden),
+                "payload should be rejected: {payload}"
+            );
+        }
+    }
+
+    #[test]
+    fn missing_or_malformed_paths_are_not_found() {
+        let (_tmp, root) = traversal_fixture();
+        assert_eq!(authorize(&root, "album/missing.jpg"), Err(PathAccessError::NotFound));
+        assert_eq!(authorize(&root, "etc/passwd"), Err(PathAccessError::NotFound));
+        assert_eq!(authorize(&root, "album/a.jpg\0.png"), Err(PathAccessError::NotFound));
+        assert_eq!(authorize(&root, "album/%2e%2e/%2e%2e/outside"), Err(PathAccessError::NotFound));
+    }
+
+    #[test]
+    fn decomposed_filenames_resolve_from_nfc_keys() {
+        let (_tmp, root) = traversal_fixture();
+        // "café" 以 NFD (e + U+0301) 存储在磁盘上
+        std::fs::write(root.join("album/cafe\u{301}.jpg"), b"x").unwrap();
+        let resolved = authorize(&root, "album/caf\u{e9}.jpg").unwrap();
+        assert!(resolved.ends_with("album/cafe\u{301}.jpg"));
+        assert_eq!(normalize_rel_path("album/cafe\u{301}.jpg"), "album/caf\u{e9}.jpg");
+    }
+
+    /// 经 Query 提取器解码查询字符串中的 `path`
+    fn decode_query(query: &str) -> String {
+        let uri: axum::http::Uri = format!("/api/file?{}", query).parse().unwrap();
+        Query::<FileQuery>::try_from_uri(&uri).unwrap().0.path
+    }
+
+    #[test]
+    fn query_paths_are_decoded_exactly_once() {
+        let (_tmp, root) = traversal_fixture();
+        for name in ["a#b.jpg", "why?.jpg", "1+1.jpg", "50% off.jpg", "100%25.jpg", "日本 写真.jpg"] {
+            std::fs::write(root.join("album").join(name), b"x").unwrap();
+            let rel = format!("album/{}", name);
+            // 与 encodeURIComponent / gallery-client 的编码一致
+            let decoded = normalize_rel_path(&decode_query(&format!("path={}", urlencoding::encode(&rel))));
+            assert_eq!(decoded, rel);
+            assert_eq!(authorize(&root, &decoded), Ok(root.join(&rel)));
+            assert!(reject_double_encoded(&root, &decoded).is_ok(), "literal name rejected: {name}");
+        }
+        // 表单编码：未编码的 `+` 是空格
+        assert_eq!(decode_query("path=album/1+1.jpg"), "album/1 1.jpg");
+        assert_eq!(decode_query("path=album/1%2B1.jpg"), "album/1+1.jpg");
+    }
+
+    #[test]
+    fn double_encoded_paths_are_rejected() {
+        let (_tmp, root) = traversal_fixture();
+        let twice = urlencoding::encode(&urlencoding::encode("album/a b.jpg")).into_owned();
+        let decoded = normalize_rel_path(&decode_query(&format!("path={}", twice)));
+        assert_eq!(decoded, "album%2Fa%20b.jpg");
+        assert_eq!(reject_double_encoded(&root, &decoded).unwrap_err().0, StatusCode::BAD_REQUEST);
+        assert!(looks_percent_encoded("album/%E6%97%A5.jpg"));
+        assert!(!looks_percent_encoded("album/50% off.jpg"));
+        assert!(!looks_percent_encoded("album/%zz.jpg"));
+    }
+
+    #[test]
+    fn written_file_names_are_portable() {
+        assert_eq!(sanitize_file_name("sunset.jpg", false).as_deref(), Ok("sunset.jpg"));
+        assert_eq!(sanitize_file_name("a:b*c?.jpg", false).as_deref(), Ok("a_b_c_.jpg"));
+        assert_eq!(sanitize_file_name("tab\tname.jpg", false).as_deref(), Ok("tab_name.jpg"));
+        assert_eq!(sanitize_file_name(" draft. ", false).as_deref(), Ok("draft"));
+        assert_eq!(sanitize_file_name("con.jpg", false).as_deref(), Ok("con_.jpg"));
+        assert_eq!(sanitize_file_name("cafe\u{301}.jpg", false).as_deref(), Ok("caf\u{e9}.jpg"));
+        let long = sanitize_file_name(&format!("{}.jpeg", "日".repeat(100)), false).unwrap();
+        assert!(long.len() <= FILE_NAME_MAX_BYTES && long.ends_with("日.jpeg"));
+        assert!(sanitize_file_name("..", false).is_err());
+        assert!(sanitize_file_name("draft.", true).unwrap_err().contains("ends with a dot"));
+        assert_eq!(sanitize_rel_path("2024/trip. /day 1", false).as_deref(), Ok("2024/trip/day 1"));
+    }
+
+    #[test]
+    fn parent_access_flag_permits_outside_paths() {
+        let (_tmp, root) = traversal_fixture();
+        let resolved = resolve_and_authorize(&root, "../outside/secret.jpg", true).unwrap();
+        assert!(resolved.ends_with("outside/secret.jpg"));
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn windows_drive_paths_become_stable_keys() {
+        assert_eq!(absolute_path_key(r"D:\Wallpapers\sea.jpg").as_deref(), Some("D:/Wallpapers/sea.jpg"));
+        assert_eq!(absolute_path_key(r"d:\Wallpapers\").as_deref(), Some("D:/Wallpapers"));
+        assert_eq!(absolute_path_key(r"\\?\D:\Wallpapers").as_deref(), Some("D:/Wallpapers"));
+        assert!(is_external_key("D:/Wallpapers/a.jpg"));
+    }
+
+    #[cfg(not(windows))]
+    #[test]
+    fn drive_letters_are_plain_folders_off_windows() {
+        assert_eq!(absolute_path_key("x:/album/a.jpg"), None);
+        assert!(!is_external_key("x:/album/a.jpg"));
+    }
+
+    #[test]
+    fn unc_paths_become_stable_keys() {
+        assert_eq!(absolute_path_key(r"\\nas\photos\2024").as_deref(), Some("//nas/photos/2024"));
+        assert_eq!(absolute_path_key(r"\\?\UNC\nas\photos").as_deref(), Some("//nas/photos"));
+        assert_eq!(absolute_path_key("album/a.jpg"), None);
+        assert_eq!(absolute_path_key("../outside"), None);
+    }
+
+    #[test]
+    fn external_keys_are_recognized() {
+        assert!(is_external_key("../outside/a.jpg"));
+        assert!(is_external_key("//nas/photos/a.jpg"));
+        assert!(!is_external_key("album/a.jpg"));
+        assert!(!is_external_key("..album/a.jpg"));
+    }
+
+    #[test]
+    fn verbatim_prefixes_are_stripped() {
+        assert_eq!(strip_verbatim_prefix(Path::new(r"\\?\C:\Photos")), PathBuf::from(r"C:\Photos"));
+        assert_eq!(strip_verbatim_prefix(Path::new(r"\\?\UNC\nas\share")), PathBuf::from(r"\\nas\share"));
+        assert_eq!(strip_verbatim_prefix(Path::new("/srv/photos")), PathBuf::from("/srv/photos"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn rejects_symlinks_escaping_root() {
+        let (_tmp, root) = traversal_fixture();
+        let outside = root.parent().unwrap().join("outside");
+        std::os::unix::fs::symlink(&outside, root.join("escape")).unwrap();
+        std::os::unix::fs::symlink(outside.join("secret.jpg"), root.join("album/link.jpg")).unwrap();
+
+        assert_eq!(authorize(&root, "escape/secret.jpg"), Err(PathAccessError::Forbidden));
+        assert_eq!(authorize(&root, "escape"), Err(PathAccessError::Forbidden));
+        assert_eq!(authorize(&root, "album/link.jpg"), Err(PathAccessError::Forbidden));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn allows_symlinks_that_stay_inside_root() {
+        let (_tmp, root) = traversal_fixture();
+        std::os::unix::fs::symlink(root.join("album"), root.join("alias")).unwrap();
+        assert_eq!(authorize(&root, "alias/a.jpg"), Ok(root.join("alias/a.jpg")));
+    }
+
+    #[tokio::test]
+    async fn reindexing_unchanged_file_keeps_derived_data() {
+        let pool = memory_db().await;
+        let meta = ImageMetadata {
+            path: "album/a.jpg".into(),
+            mtime: 100.0,
+            width: 40,
+            height: 30,
+            is_landscape: true,
+            size: 1234,
+            taken_at: None,
+            phash: None,
+            added_at: None,
+            color_profile: None,
+            hdr_gain_map: None,
+            is_panorama: false,
+        };
+        let mut conn = pool.acquire().await.unwrap();
+        upsert_image_row(&mut conn, &meta).await.unwrap();
+        sqlx::query("UPDATE images SET phash = 'abcd', embedding = x'0102' WHERE path = ?")
+            .bind(&meta.path).execute(&mut *conn).await.unwrap();
+
+        let derived = "SELECT phash, embedding FROM images WHERE path = 'album/a.jpg'";
+        upsert_image_row(&mut conn, &meta).await.unwrap();
+        let row: (Option<String>, Option<Vec<u8>>) = sqlx::query_as(derived).fetch_one(&mut *conn).await.unwrap();
+        assert_eq!(row, (Some("abcd".into()), Some(vec![1, 2])));
+
+        upsert_image_row(&mut conn, &ImageMetadata { mtime: 200.0, ..meta }).await.unwrap();
+        let row: (Option<String>, Option<Vec<u8>>) = sqlx::query_as(derived).fetch_one(&mut *conn).await.unwrap();
+        assert_eq!(row, (None, None));
+    }
+
+    /// 挂上幂等中间件的测试路由：第一次调用一直挂起直到 `first_call` 放行 (未放行就丢弃时永远不返回)，之后的调用立即返回
+    fn idempotent_app(db: Pool<Sqlite>, calls: Arc<std::sync::atomic::AtomicUsize>, first_call: Arc<tokio::sync::Notify>) -> Router {
+        let handler = move || async move {
+            let n = calls.fetch_add(1, std::sync::atomic::Ordering::SeqCst) + 1;
+            if n == 1 {
+                first_call.notified().await;
+            }
+            (StatusCode::CREATED, format!("created {}", n))
+        };
+        Router::new()
+            .route("/items", post(handler))
+            .layer(middleware::from_fn_with_state(db, idempotency_middleware))
+    }
+
+    fn keyed_post(key: &str, body: &'static str) -> Request {
+        Request::post("/items").header("idempotency-key", key).body(Body::from(body)).unwrap()
+    }
+
+    async fn body_text(response: Response) -> String {
+        String::from_utf8(axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap().to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn idempotent_requests_replay_and_reject_concurrent_duplicates() {
+        let calls = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let first_call = Arc::new(tokio::sync::Notify::new());
+        let app = idempotent_app(memory_db().await, calls.clone(), first_call.clone());
+
+        let first = tokio::spawn(app.clone().oneshot(keyed_post("k1", "a")));
+        while calls.load(std::sync::atomic::Ordering::SeqCst) == 0 {
+            tokio::task::yield_now().await;
+        }
+        let duplicate = app.clone().oneshot(keyed_post("k1", "a")).await.unwrap();
+        assert_eq!(duplicate.status(), StatusCode::CONFLICT);
+
+        first_call.notify_one();
+        let first = first.await.unwrap().unwrap();
+        assert_eq!(first.status(), StatusCode::CREATED);
+        assert_eq!(body_text(first).await, "created 1");
+
+        let replay = app.clone().oneshot(keyed_post("k1", "a")).await.unwrap();
+        assert_eq!(replay.status(), StatusCode::CREATED);
+        assert_eq!(replay.headers().get("idempotent-replayed").unwrap(), "true");
+        assert_eq!(body_text(replay).await, "created 1");
+        assert_eq!(calls.load(std::sync::atomic::Ordering::SeqCst), 1);
+
+        let mismatched = app.oneshot(keyed_post("k1", "b")).await.unwrap();
+        assert_eq!(mismatched.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    }
+
+    #[tokio::test]
+    async fn abandoned_idempotent_requests_can_be_retried() {
+        let db = memory_db().await;
+        let calls = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let app = idempotent_app(db.clone(), calls.clone(), Arc::new(tokio::sync::Notify::new()));
+
+        // 客户端断开：请求 future 被丢弃，处理中的记录随之删除
+        let dropped = tokio::time::timeout(Duration::from_millis(50), app.clone().oneshot(keyed_post("k1", "a"))).await;
+        assert!(dropped.is_err());
+        for _ in 0..100 {
+            let pending: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM idempotency_keys").fetch_one(&db).await.unwrap();
+            if pending == 0 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let retried = app.clone().oneshot(keyed_post("k1", "a")).await.unwrap();
+        assert_eq!(body_text(retried).await, "created 2");
+
+        // 进程被杀时遗留的过期处理中记录同样视为放弃
+        let stale = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs_f64() - IDEMPOTENCY_PENDING_TIMEOUT_SECS - 1.0;
+        sqlx::query("INSERT INTO idempotency_keys (key, fingerprint, created_at) VALUES ('k2', 'x', ?)")
+            .bind(stale)
+            .execute(&db)
+            .await
+            .unwrap();
+        let retried = app.oneshot(keyed_post("k2", "a")).await.unwrap();
+        assert_eq!(body_text(retried).await, "created 3");
+    }
+
+    #[test]
+    fn share_unlock_locks_out_after_free_attempts() {
+        let mut throttle = UnlockThrottle::default();
+        for _ in 0..UNLOCK_FREE_ATTEMPTS {
+            assert!(throttle.begin_attempt("10.0.0.1").is_ok());
+        }
+        assert!(throttle.begin_attempt("10.0.0.1").is_err());
+        assert!(throttle.begin_attempt("10.0.0.2").is_ok());
+        throttle.clear("10.0.0.1");
+        assert!(throttle.begin_attempt("10.0.0.1").is_ok());
+    }
+
+    #[test]
+    fn concurrent_share_unlocks_count_before_verifying() {
+        let throttle = Arc::new(std::sync::Mutex::new(UnlockThrottle::default()));
+        let admitted: usize = std::thread::scope(|scope| {
+            let workers: Vec<_> = (0..32)
+                .map(|_| scope.spawn(|| throttle.lock().unwrap().begin_attempt("10.0.0.1").is_ok()))
+                .collect();
+            workers.into_iter().map(|w| w.join().unwrap() as usize).sum()
+        });
+        assert_eq!(admitted, UNLOCK_FREE_ATTEMPTS as usize);
+    }
+
+    #[test]
+    fn pairing_tokens_are_single_use_and_expire() {
+        let ttl = Duration::from_secs(600);
+        let mut tokens = HashMap::from([
+            ("fresh".to_string(), Instant::now()),
+            ("stale".to_string(), Instant::now() - Duration::from_secs(601)),
+        ]);
+        assert!(take_pairing_token(&mut tokens, "fresh", ttl));
+        assert!(!take_pairing_token(&mut tokens, "fresh", ttl));
+        assert!(!take_pairing_token(&mut tokens, "stale", ttl));
+        assert!(!take_pairing_token(&mut tokens, "unknown", ttl));
+    }
+
+    #[test]
+    fn paired_devices_require_their_key() {
+        let hash = blake3::hash(b"secret").to_hex();
+        assert!(device_key_matches(Some(hash.as_str()), Some("secret")));
+        assert!(!device_key_matches(Some(hash.as_str()), Some("guess")));
+        assert!(!device_key_matches(Some(hash.as_str()), None));
+        assert!(device_key_matches(None, None));
+    }
+}
@@ -0,0 +1,68 @@
+//! 可选的 mDNS / Bonjour 服务广播 (cargo feature `mdns`)
+//!
+//! 以 `_gallery._tcp` 与 `_http._tcp` 广播本服务，便于手机 / 电视客户端在局域网内自动发现。
+//! - `GALLERY_MDNS`: 设为 `0` / `false` 时不广播 (启用该 feature 后默认广播)
+//! - `GALLERY_MDNS_NAME`: 实例名，默认 `Gravity Gallery (<主机名>)`
+//!
+//! TXT 记录：`tls` (`1`/`0`)、`path` (API 前缀)、`version`。
+
+use std::{env, net::IpAddr};
+
+use mdns_sd::{ServiceDaemon, ServiceInfo};
+
+const SERVICE_TYPES: &[&str] = &["_gallery._tcp.local.", "_http._tcp.local."];
+
+fn host_name() -> String {
+    env::var("HOSTNAME")
+        .or_else(|_| env::var("COMPUTERNAME"))
+        .ok()
+        .or_else(|| std::fs::read_to_string("/etc/hostname").ok())
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| "gravity-gallery".to_string())
+}
+
+/// 注册广播；返回的守护进程需在服务运行期间保持存活
+pub fn advertise(host: &str, port: u16, tls: bool) -> Option<ServiceDaemon> {
+    if crate::config_var("GALLERY_MDNS")
+        .map(|v| matches!(v.trim().to_ascii_lowercase().as_str(), "0" | "false" | "no" | "off"))
+        .unwrap_or(false)
+    {
+        return None;
+    }
+
+    let daemon = match ServiceDaemon::new() {
+        Ok(daemon) => daemon,
+        Err(err) => {
+            tracing::error!("⚠️ mDNS advertisement disabled: {}", err);
+            return None;
+        }
+    };
+
+    let host_name = host_name();
+    let instance = crate::config_var("GALLERY_MDNS_NAME")
+        .ok()
+        .filter(|v| !v.trim().is_empty())
+        .unwrap_or_else(|| format!("Gravity Gallery ({})", host_name));
+    let mdns_host = format!("{}.local.", host_name.replace([' ', '.'], "-"));
+    let properties = [
+        ("tls", if tls { "1" } else { "0" }),
+        ("path", "/api"),
+        ("version", env!("CARGO_PKG_VERSION")),
+    ];
+    // 绑定到具体地址时只广播该地址，否则广播所有网卡地址
+    let bound_ip = host.parse::<IpAddr>().ok().filter(|ip| !ip.is_unspecified());
+
+    for service_type in SERVICE_TYPES {
+        let info = match bound_ip {
+            Some(ip) => ServiceInfo::new(service_type, &instance, &mdns_host, ip, port, &properties[..]),
+            None => ServiceInfo::new(service_type, &instance, &mdns_host, (), port, &properties[..])
+                .map(ServiceInfo::enable_addr_auto),
+        };
+        match info.and_then(|info| daemon.register(info)) {
+            Ok(()) => tracing::info!("📣 mDNS: advertising \"{}\" as {}", instance, service_type),
+            Err(err) => tracing::warn!("⚠️ mDNS registration for {} failed: {}", service_type, err),
+        }
+    }
+    Some(daemon)
+}
@@ -0,0 +1,166 @@
+//! 可选的 MQTT 集成 (cargo feature `mqtt`)，便于接入 Home Assistant
+//!
+//! - `GALLERY_MQTT_URL`: 如 `mqtt://192.168.1.10:1883` (未设置时不启用)
+//! - `GALLERY_MQTT_USERNAME` / `GALLERY_MQTT_PASSWORD`: 可选认证
+//! - `GALLERY_MQTT_TOPIC_PREFIX`: 主题前缀，默认 `gravity_gallery`
+//! - `GALLERY_MQTT_STATS_INTERVAL_SECS`: 图库统计发布间隔，默认 60
+//!
+//! 发布 (retained)：
+//! - `{prefix}/status`: `online` / `offline` (遗嘱消息)
+//! - `{prefix}/stats`: `{"images", "bytes", "sessions"}`
+//! - `{prefix}/rooms/{room}/now_showing`: 该客户端当前展示的图片
+//!
+//! 订阅：`{prefix}/rooms/{room}/command` 与 `{prefix}/command` (广播)，
+//! 负载为 `next` / `previous` / `pause` / `resume`，通过 SSE `remote_command` 事件转发给客户端。
+//! 房间名即客户端 IP (`.` 与 `:` 替换为 `_`)。
+
+use std::{collections::HashMap, time::Duration};
+
+use rumqttc::{AsyncClient, Event, LastWill, MqttOptions, Packet, QoS};
+
+use crate::{publish_event, AppState, NowShowing, REMOTE_COMMANDS};
+
+pub fn room_id(client_ip: &str) -> String {
+    client_ip.replace(['.', ':'], "_")
+}
+
+/// 解析 `mqtt://host:port`，缺省端口 1883
+fn parse_url(url: &str) -> Option<(String, u16)> {
+    let rest = url.trim().strip_prefix("mqtt://").unwrap_or(url.trim());
+    let rest = rest.trim_end_matches('/');
+    match rest.rsplit_once(':') {
+        Some((host, port)) => Some((host.to_string(), port.parse().ok()?)),
+        None if !rest.is_empty() => Some((rest.to_string(), 1883)),
+        None => None,
+    }
+}
+
+/// 按环境变量启动 MQTT 客户端；未配置时什么也不做
+pub fn spawn(state: AppState) {
+    let Some(url) = crate::config_var("GALLERY_MQTT_URL").ok().filter(|v| !v.trim().is_empty()) else {
+        return;
+    };
+    let Some((host, port)) = parse_url(&url) else {
+        tracing::error!("⚠️ Invalid GALLERY_MQTT_URL: {}", url);
+        return;
+    };
+    let prefix = crate::config_var("GALLERY_MQTT_TOPIC_PREFIX").unwrap_or_else(|_| "gravity_gallery".to_string());
+    let stats_interval = crate::config_var("GALLERY_MQTT_STATS_INTERVAL_SECS")
+        .ok()
+        .and_then(|v| v.parse::<u64>().ok())
+        .unwrap_or(60)
+        .max(5);
+
+    let mut options = MqttOptions::new(format!("gravity-gallery-{}", std::process::id()), host, port);
+    options.set_keep_alive(Duration::from_secs(30));
+    options.set_last_will(LastWill::new(format!("{}/status", prefix), "offline", QoS::AtLeastOnce, true));
+    if let (Ok(user), Ok(pass)) = (crate::config_var("GALLERY_MQTT_USERNAME"), crate::config_var("GALLERY_MQTT_PASSWORD")) {
+        options.set_credentials(user, pass);
+    }
+
+    let (client, mut eventloop) = AsyncClient::new(options, 64);
+    tracing::info!("📡 MQTT enabled: {} (prefix {})", url, prefix);
+
+    // 事件循环：连接 (含重连) 后重新订阅，收到命令时转发给客户端
+    let loop_client = client.clone();
+    let loop_state = state.clone();
+    let loop_prefix = prefix.clone();
+    tokio::spawn(async move {
+        loop {
+            match eventloop.poll().await {
+                Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                    let _ = loop_client
+                        .publish(format!("{}/status", loop_prefix), QoS::AtLeastOnce, true, "online")
+                        .await;
+                    for topic in [format!("{}/command", loop_prefix), format!("{}/rooms/+/command", loop_prefix)] {
+                        let _ = loop_client.subscribe(topic, QoS::AtLeastOnce).await;
+                    }
+                }
+                Ok(Event::Incoming(Packet::Publish(message))) => {
+                    handle_command(&loop_state, &loop_prefix, &message.topic, &message.payload).await;
+                }
+                Ok(_) => {}
+                Err(err) => {
+                    tracing::warn!("⚠️ MQTT connection error: {}", err);
+                    tokio::time::sleep(Duration::from_secs(5)).await;
+                }
+            }
+        }
+    });
+
+    // 定期发布图库统计
+    let stats_client = client.clone();
+    let stats_state = state.clone();
+    let stats_prefix = prefix.clone();
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(Duration::from_secs(stats_interval));
+        loop {
+            ticker.tick().await;
+            publish_stats(&stats_state, &stats_client, &stats_prefix).await;
+        }
+    });
+
+    // 各房间的当前图片 (仅在变化时发布)
+    tokio::spawn(async move {
+        let mut last_published: HashMap<String, NowShowing> = HashMap::new();
+        let mut ticker = tokio::time::interval(Duration::from_secs(2));
+        loop {
+            ticker.tick().await;
+            let current = state.now_showing.read().await.clone();
+            for (ip, showing) in &current {
+                if last_published.get(ip).map(|s| &s.path) == Some(&showing.path) {
+                    continue;
+                }
+                if let Ok(payload) = serde_json::to_vec(showing) {
+                    let topic = format!("{}/rooms/{}/now_showing", prefix, room_id(ip));
+                    let _ = client.publish(topic, QoS::AtLeastOnce, true, payload).await;
+                }
+            }
+            last_published = current;
+        }
+    });
+}
+
+async fn publish_stats(state: &AppState, client: &AsyncClient, prefix: &str) {
+    let (images, bytes): (i64, i64) = sqlx::query_as("SELECT COUNT(*), COALESCE(SUM(size), 0) FROM images WHERE missing_since IS NULL")
+        .fetch_one(&state.db)
+        .await
+        .unwrap_or((0, 0));
+    let sessions = state.user_sessions.read().await.len();
+    let payload = serde_json::json!({ "images": images, "bytes": bytes, "sessions": sessions });
+    let _ = client
+        .publish(format!("{}/stats", prefix), QoS::AtLeastOnce, true, payload.to_string())
+        .await;
+}
+
+async fn handle_command(state: &AppState, prefix: &str, topic: &str, payload: &[u8]) {
+    let command = String::from_utf8_lossy(payload).trim().to_lowercase();
+    if !REMOTE_COMMANDS.contains(&command.as_str()) {
+        tracing::warn!("⚠️ Ignoring unknown MQTT command {:?} on {}", command, topic);
+        return;
+    }
+
+    if topic == format!("{}/command", prefix) {
+        publish_event(state, None, "remote_command", serde_json::json!({ "command": command }));
+        return;
+    }
+
+    // {prefix}/rooms/{room}/command -> 找回对应的客户端 IP
+    let Some(room) = topic
+        .strip_prefix(&format!("{}/rooms/", prefix))
+        .and_then(|rest| rest.strip_suffix("/command"))
+    else {
+        return;
+    };
+    let sessions = state.user_sessions.read().await;
+    let showing = state.now_showing.read().await;
+    let target = sessions
+        .keys()
+        .chain(showing.keys())
+        .find(|ip| room_id(ip) == room)
+        .cloned();
+    match target {
+        Some(ip) => publish_event(state, Some(&ip), "remote_command", serde_json::json!({ "command": command })),
+        None => tracing::warn!("⚠️ MQTT command for unknown room {}", room),
+    }
+}
@@ -0,0 +1,245 @@
+//! 可选的新图片通知 (cargo feature `notify`)，支持 Telegram 机器人与 Discord Webhook
+//!
+//! - `GALLERY_TELEGRAM_BOT_TOKEN` / `GALLERY_TELEGRAM_CHAT_ID`: 发送到 Telegram 聊天
+//! - `GALLERY_DISCORD_WEBHOOK_URL`: 发送到 Discord 频道
+//! - `GALLERY_NOTIFY_FOLDERS`: 逗号分隔的监视文件夹 (含子文件夹)，为空表示整个图库
+//! - `GALLERY_NOTIFY_BATCH_SECS`: 首批新图片到达后等待合并的时间，默认 300 秒
+//! - `GALLERY_NOTIFY_THUMBNAILS`: 每条通知附带的缩略图数量，默认 4 (最多 10)
+//!
+//! 扫描发现的新图片先按文件夹合并，批次结束时每个文件夹发送一条消息；
+//! 设置了 `GALLERY_PUBLIC_URL` 时附带 7 天有效的分享链接。
+
+use std::{collections::BTreeMap, time::Duration};
+
+use anyhow::{bail, Context, Result};
+use futures::future::BoxFuture;
+use reqwest::multipart::{Form, Part};
+use tokio::sync::mpsc;
+
+use crate::{create_playlist_share_link, render_image, resolve_and_authorize, AppState, RenderSpec};
+
+const THUMBNAIL_SIZE: u32 = 1280;
+const SHARE_TTL_SECS: f64 = 7.0 * 86400.0;
+
+/// 一条待发送的通知
+pub struct Notification {
+    pub text: String,
+    pub link: Option<String>,
+    /// JPEG 缩略图
+    pub thumbnails: Vec<Vec<u8>>,
+}
+
+/// 通知渠道；新渠道只需实现该 trait 并在 `sinks_from_env` 中注册
+pub trait NotificationSink: Send + Sync {
+    fn name(&self) -> &'static str;
+    fn send<'a>(&'a self, client: &'a reqwest::Client, notification: &'a Notification) -> BoxFuture<'a, Result<()>>;
+}
+
+struct Telegram {
+    token: String,
+    chat_id: String,
+}
+
+impl NotificationSink for Telegram {
+    fn name(&self) -> &'static str {
+        "telegram"
+    }
+
+    fn send<'a>(&'a self, client: &'a reqwest::Client, notification: &'a Notification) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let caption = match &notification.link {
+                Some(link) => format!("{}\n{}", notification.text, link),
+                None => notification.text.clone(),
+            };
+            let api = |method: &str| format!("https://api.telegram.org/bot{}/{}", self.token, method);
+            let form = Form::new().text("chat_id", self.chat_id.clone());
+            let request = match notification.thumbnails.as_slice() {
+                [] => client.post(api("sendMessage")).multipart(form.text("text", caption)),
+                [photo] => client
+                    .post(api("sendPhoto"))
+                    .multipart(form.text("caption", caption).part("photo", jpeg_part(photo, 0)?)),
+                photos => {
+                    // 相册：说明文字挂在第一张上
+                    let media: Vec<serde_json::Value> = (0..photos.len())
+                        .map(|i| {
+                            let mut item = serde_json::json!({ "type": "photo", "media": format!("attach://photo{}", i) });
+                            if i == 0 {
+                                item["caption"] = caption.clone().into();
+                            }
+                            item
+                        })
+                        .collect();
+                    let mut form = form.text("media", serde_json::to_string(&media)?);
+                    for (i, photo) in photos.iter().enumerate() {
+                        form = form.part(format!("photo{}", i), jpeg_part(photo, i)?);
+                    }
+                    client.post(api("sendMediaGroup")).multipart(form)
+                }
+            };
+            check_response(request.send().await?).await
+        })
+    }
+}
+
+struct Discord {
+    webhook_url: String,
+}
+
+impl NotificationSink for Discord {
+    fn name(&self) -> &'static str {
+        "discord"
+    }
+
+    fn send<'a>(&'a self, client: &'a reqwest::Client, notification: &'a Notification) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let content = match &notification.link {
+                Some(link) => format!("{}\n{}", notification.text, link),
+                None => notification.text.clone(),
+            };
+            let mut form = Form::new().text("payload_json", serde_json::json!({ "content": content }).to_string());
+            for (i, photo) in notification.thumbnails.iter().enumerate() {
+                form = form.part(format!("files[{}]", i), jpeg_part(photo, i)?);
+            }
+            check_response(client.post(&self.webhook_url).multipart(form).send().await?).await
+        })
+    }
+}
+
+fn jpeg_part(bytes: &[u8], index: usize) -> Result<Part> {
+    Ok(Part::bytes(bytes.to_vec())
+        .file_name(format!("photo{}.jpg", index))
+        .mime_str("image/jpeg")?)
+}
+
+async fn check_response(response: reqwest::Response) -> Result<()> {
+    let status = response.status();
+    if !status.is_success() {
+        let body = response.text().await.unwrap_or_default();
+        bail!("HTTP {}: {}", status, body.chars().take(200).collect::<String>());
+    }
+    Ok(())
+}
+
+fn sinks_from_env() -> Result<Vec<Box<dyn NotificationSink>>> {
+    let non_empty = |key: &str| crate::config_var(key).ok().map(|v| v.trim().to_string()).filter(|v| !v.is_empty());
+    let mut sinks: Vec<Box<dyn NotificationSink>> = Vec::new();
+    if let Some(token) = non_empty("GALLERY_TELEGRAM_BOT_TOKEN") {
+        let chat_id = non_empty("GALLERY_TELEGRAM_CHAT_ID")
+            .context("GALLERY_TELEGRAM_CHAT_ID is required together with GALLERY_TELEGRAM_BOT_TOKEN")?;
+        sinks.push(Box::new(Telegram { token, chat_id }));
+    }
+    if let Some(webhook_url) = non_empty("GALLERY_DISCORD_WEBHOOK_URL") {
+        sinks.push(Box::new(Discord { webhook_url }));
+    }
+    Ok(sinks)
+}
+
+/// 新图片的投递入口；扫描任务只需把新增路径送进来
+pub struct Notifier {
+    sender: mpsc::UnboundedSender<Vec<String>>,
+    folders: Vec<String>,
+}
+
+impl Notifier {
+    /// 未配置任何渠道时返回 `Ok(None)`；批处理任务在 `spawn` 中启动
+    pub fn from_env() -> Result<Option<(Self, NotifierWorker)>> {
+        let sinks = sinks_from_env()?;
+        if sinks.is_empty() {
+            return Ok(None);
+        }
+        let folders = crate::config_var("GALLERY_NOTIFY_FOLDERS")
+            .unwrap_or_default()
+            .split(',')
+            .map(|f| crate::normalize_rel_path(f.trim()))
+            .filter(|f| !f.is_empty() && f != ".")
+            .collect();
+        let (sender, receiver) = mpsc::unbounded_channel();
+        Ok(Some((Self { sender, folders }, NotifierWorker { sinks, receiver })))
+    }
+
+    /// 过滤出监视范围内的路径并排队
+    pub fn images_added(&self, paths: &[String]) {
+        let watched: Vec<String> = paths
+            .iter()
+            .filter(|p| {
+                self.folders.is_empty()
+                    || self
+                        .folders
+                        .iter()
+                        .any(|f| p.strip_prefix(f.as_str()).is_some_and(|rest| rest.starts_with('/')))
+            })
+            .cloned()
+            .collect();
+        if !watched.is_empty() {
+            let _ = self.sender.send(watched);
+        }
+    }
+}
+
+pub struct NotifierWorker {
+    sinks: Vec<Box<dyn NotificationSink>>,
+    receiver: mpsc::UnboundedReceiver<Vec<String>>,
+}
+
+impl NotifierWorker {
+    pub fn spawn(mut self, state: AppState) {
+        let batch_secs = crate::config_var("GALLERY_NOTIFY_BATCH_SECS").ok().and_then(|v| v.parse().ok()).unwrap_or(300);
+        let names: Vec<&str> = self.sinks.iter().map(|s| s.name()).collect();
+        tracing::info!("🔔 New-image notifications enabled: {} (batch {}s)", names.join(", "), batch_secs);
+        tokio::spawn(async move {
+            let client = reqwest::Client::new();
+            while let Some(first) = self.receiver.recv().await {
+                // 第一批到达后继续收集，直到窗口结束
+                let mut batch = first;
+                let deadline = tokio::time::Instant::now() + Duration::from_secs(batch_secs);
+                while let Ok(Some(more)) = tokio::time::timeout_at(deadline, self.receiver.recv()).await {
+                    batch.extend(more);
+                }
+                batch.sort();
+                batch.dedup();
+
+                let mut by_folder: BTreeMap<String, Vec<String>> = BTreeMap::new();
+                for path in batch {
+                    let folder = path.rsplit_once('/').map_or(".", |(dir, _)| dir).to_string();
+                    by_folder.entry(folder).or_default().push(path);
+                }
+                for (folder, paths) in by_folder {
+                    let notification = match build_notification(&state, &folder, &paths).await {
+                        Ok(notification) => notification,
+                        Err(err) => {
+                            tracing::warn!("⚠️ Failed to prepare notification for {}: {:#}", folder, err);
+                            continue;
+                        }
+                    };
+                    for sink in &self.sinks {
+                        match sink.send(&client, &notification).await {
+                            Ok(()) => tracing::info!("🔔 Notified {} about {} new images in {}", sink.name(), paths.len(), folder),
+                            Err(err) => tracing::warn!("⚠️ {} notification failed: {:#}", sink.name(), err),
+                        }
+                    }
+                }
+            }
+        });
+    }
+}
+
+async fn build_notification(state: &AppState, folder: &str, paths: &[String]) -> Result<Notification> {
+    let count = paths.len();
+    let text = format!("📷 {} new image{} in {}", count, if count == 1 { "" } else { "s" }, folder);
+
+    let link = create_playlist_share_link(state, &format!("New in {}", folder), paths, SHARE_TTL_SECS).await?;
+
+    let limit = crate::config_var("GALLERY_NOTIFY_THUMBNAILS").ok().and_then(|v| v.parse::<usize>().ok()).unwrap_or(4).min(10);
+    let allow_parent = *state.allow_parent_dir_access.read().await;
+    let mut thumbnails = Vec::new();
+    for path in paths.iter().take(limit) {
+        let Ok(full) = resolve_and_authorize(&state.root_dir, path, allow_parent) else {
+            continue;
+        };
+        let spec = RenderSpec::fit_within(THUMBNAIL_SIZE, THUMBNAIL_SIZE);
+        if let Ok(Some((bytes, _))) = tokio::task::spawn_blocking(move || render_image(&full, &spec)).await {
+            thumbnails.push(bytes);
+        }
+    }
+    Ok(Notification { text, link, thumbnails })
+}
@@ -0,0 +1,581 @@
+//! 复制到另一台图库实例 (如放在亲友家里的异地备份相框)
+//!
+//! 接收端 (所有构建都可用)：
+//! - `GALLERY_REPLICATION_TOKEN`: 接收复制所需的共享密钥 (`Authorization: Bearer`)，未设置时接口返回 404
+//! - `POST /api/replication/delta`: 应用索引变更 (标签、标题/说明/评分、缺失与删除)
+//! - `PUT /api/replication/file?path=&mtime=`: 写入一个文件 (请求体为文件内容，上限 2 GiB) 并立即索引
+//!
+//! 推送端 (cargo feature `replication`，只服务默认图库)：
+//! - `GALLERY_REPLICA_URL`: 接收端地址，如 `https://frame.example.net:4860` (接收端的 profile 以 `/g/{name}` 结尾)
+//! - `GALLERY_REPLICA_TOKEN`: 与接收端的 `GALLERY_REPLICATION_TOKEN` 相同
+//! - `GALLERY_REPLICA_FILES`: 同时复制文件；缺失或删除的图片在接收端移入回收站 (默认只同步索引，文件另行同步)
+//! - `GALLERY_REPLICA_INTERVAL_SECS`: 推送间隔，默认 600
+//!
+//! 推送端记录每条记录上次送达时的指纹，只发送变化的记录；接收端缺少或不一致的文件在复制文件时补传。
+
+use std::{
+    path::PathBuf,
+    sync::atomic::{AtomicU64, Ordering},
+    time::UNIX_EPOCH,
+};
+
+use axum::{
+    body::Body,
+    extract::{Query, State},
+    http::{header, HeaderMap, StatusCode},
+    Json,
+};
+use futures::StreamExt;
+use gallery_client::types::ImageCaption;
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+
+use crate::{
+    indexed_paths, invalidate_playlist_cache, is_external_key, is_image_ext, is_under_root, move_to_trash,
+    normalize_rel_path, process_image_metadata_sync, resolve_and_authorize, resolve_full_path, upsert_image_row,
+    upsert_missing_path_to_db, AppState, PathAccessError, CACHE_DIR_NAME, TRASH_DIR_NAME,
+};
+
+/// 接收端单次变更请求体的上限 (说明文字较长时一批可能超过默认的 2 MB)
+pub const DELTA_MAX_BYTES: usize = 32 * 1024 * 1024;
+/// 接收端单个文件的上限 (请求体直接写入临时文件，不经过默认的请求体限制)
+const FILE_MAX_BYTES: u64 = 2 * 1024 * 1024 * 1024;
+/// 修改时间相差超过该秒数视为不同的文件 (部分文件系统只保留到秒)
+const MTIME_TOLERANCE_SECS: f64 = 1.0;
+
+type ApiError = (StatusCode, Json<serde_json::Value>);
+
+fn api_error(status: StatusCode, detail: &str) -> ApiError {
+    (status, Json(serde_json::json!({ "detail": detail })))
+}
+
+/// 一张图片的索引记录 (不含接收端可自行计算的尺寸、哈希等)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReplicaImage {
+    pub path: String,
+    pub mtime: f64,
+    pub size: i64,
+    /// 推送端已找不到该文件
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub missing_since: Option<f64>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(flatten)]
+    pub caption: ImageCaption,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct ReplicationDelta {
+    /// 推送端同时复制文件：缺失与删除的图片在接收端移入回收站
+    #[serde(default)]
+    pub mirror_files: bool,
+    #[serde(default)]
+    pub upserts: Vec<ReplicaImage>,
+    /// 推送端已清除的记录
+    #[serde(default)]
+    pub deletes: Vec<String>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct ReplicationResult {
+    pub applied: usize,
+    pub deleted: usize,
+    /// 接收端没有该文件，记录未应用
+    pub missing: Vec<String>,
+    /// 文件存在但大小或修改时间不同 (元数据已应用)
+    pub stale: Vec<String>,
+    /// 不是 ROOT_DIR 内的图片路径，已忽略
+    pub rejected: Vec<String>,
+}
+
+/// 校验共享密钥；比较哈希而不是原文，耗时与密钥内容无关
+fn authorize(state: &AppState, headers: &HeaderMap) -> Result<(), ApiError> {
+    let Some(token) = state.settings.get("GALLERY_REPLICATION_TOKEN").filter(|t| !t.trim().is_empty()) else {
+        return Err(api_error(StatusCode::NOT_FOUND, "Replication is not enabled on this server"));
+    };
+    let presented = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .map(|v| blake3::hash(v.trim().as_bytes()));
+    if presented != Some(blake3::hash(token.trim().as_bytes())) {
+        return Err(api_error(StatusCode::UNAUTHORIZED, "Invalid replication token"));
+    }
+    Ok(())
+}
+
+/// 复制只接受 ROOT_DIR 内的图片 (不含回收站与缓存)。
+/// 尚不存在的文件要求最近的已存在上级目录真实位于 ROOT_DIR 内，防止经符号链接写到外面
+fn replica_target(state: &AppState, path: &str) -> Option<(String, PathBuf)> {
+    let rel = normalize_rel_path(path);
+    let top = rel.split('/').next().unwrap_or_default();
+    if rel.is_empty() || is_external_key(&rel) || top == TRASH_DIR_NAME || top == CACHE_DIR_NAME {
+        return None;
+    }
+    let root_dir = state.root_dir.as_path();
+    let full = match resolve_and_authorize(root_dir, &rel, false) {
+        Ok(full) => full,
+        Err(PathAccessError::Forbidden) => return None,
+        Err(PathAccessError::NotFound) => {
+            let full = resolve_full_path(root_dir, &rel);
+            let canonical_root = std::fs::canonicalize(root_dir).ok()?;
+            let existing = full.ancestors().skip(1).find(|a| a.exists())?;
+            if !is_under_root(root_dir, &full) || !std::fs::canonicalize(existing).ok()?.starts_with(&canonical_root) {
+                return None;
+            }
+            full
+        }
+    };
+    is_image_ext(&full).then_some((rel, full))
+}
+
+/// 接口: POST /api/replication/delta
+pub async fn receive_delta(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(delta): Json<ReplicationDelta>,
+) -> Result<Json<ReplicationResult>, ApiError> {
+    authorize(&state, &headers)?;
+    let mut result = ReplicationResult::default();
+    for item in delta.upserts {
+        apply_upsert(&state, item, delta.mirror_files, &mut result).await;
+    }
+    for path in delta.deletes {
+        let Some((rel, full)) = replica_target(&state, &path) else {
+            result.rejected.push(path);
+            continue;
+        };
+        // 只同步索引时文件由其他方式同步，接收端自己的扫描会发现删除
+        if delta.mirror_files && full.is_file() {
+            match move_to_trash(&state, &rel, &full).await {
+                Ok(()) => result.deleted += 1,
+                Err(err) => tracing::warn!("⚠️ Replicated delete of {} failed: {:#}", rel, err),
+            }
+        }
+    }
+    if result.applied + result.deleted > 0 {
+        invalidate_playlist_cache(&state).await;
+    }
+    Ok(Json(result))
+}
+
+async fn apply_upsert(state: &AppState, item: ReplicaImage, mirror_files: bool, result: &mut ReplicationResult) {
+    let Some((rel, full)) = replica_target(state, &item.path) else {
+        result.rejected.push(item.path);
+        return;
+    };
+    if item.missing_since.is_some() {
+        if mirror_files && full.is_file() {
+            match move_to_trash(state, &rel, &full).await {
+                Ok(()) => result.deleted += 1,
+                Err(err) => tracing::warn!("⚠️ Replicated delete of {} failed: {:#}", rel, err),
+            }
+        }
+        return;
+    }
+    let Some(meta) = tokio::fs::metadata(&full).await.ok().filter(|m| m.is_file()) else {
+        result.missing.push(item.path);
+        return;
+    };
+    let local_mtime = meta
+        .modified()
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map_or(0.0, |d| d.as_secs_f64());
+    if meta.len() as i64 != item.size || (local_mtime - item.mtime).abs() > MTIME_TOLERANCE_SECS {
+        result.stale.push(item.path.clone());
+    }
+
+    // 标签与说明挂在索引记录上 (外键)，尚未扫描到的文件先补建索引
+    if indexed_paths(&state.db, std::slice::from_ref(&rel)).await.is_empty() {
+        if let Err(err) = upsert_missing_path_to_db(&state.db, &state.root_dir, &rel, state.follow_symlinks).await {
+            tracing::warn!("⚠️ Failed to index replicated image {}: {}", rel, err);
+        }
+    }
+    match apply_metadata(state, &rel, &item).await {
+        Ok(()) => result.applied += 1,
+        Err(err) => {
+            tracing::warn!("⚠️ Failed to apply replicated metadata for {}: {}", rel, err);
+            result.rejected.push(item.path);
+        }
+    }
+}
+
+/// 用推送端的标签与说明整体替换本地记录
+async fn apply_metadata(state: &AppState, rel: &str, item: &ReplicaImage) -> sqlx::Result<()> {
+    let mut tx = state.db.begin().await?;
+    sqlx::query("DELETE FROM image_tags WHERE path = ?").bind(rel).execute(&mut *tx).await?;
+    for tag in &item.tags {
+        sqlx::query("INSERT OR IGNORE INTO image_tags (path, tag) VALUES (?, ?)")
+            .bind(rel)
+            .bind(tag)
+            .execute(&mut *tx)
+            .await?;
+    }
+    if item.caption.is_empty() {
+        sqlx::query("DELETE FROM image_captions WHERE path = ?").bind(rel).execute(&mut *tx).await?;
+    } else {
+        sqlx::query(
+            "INSERT INTO image_captions (path, title, caption, rating, updated_at) VALUES (?, ?, ?, ?, ?)
+             ON CONFLICT(path) DO UPDATE SET title = excluded.title, caption = excluded.caption,
+                 rating = excluded.rating, updated_at = excluded.updated_at",
+        )
+        .bind(rel)
+        .bind(&item.caption.title)
+        .bind(&item.caption.caption)
+        .bind(item.caption.rating)
+        .bind(std::time::SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs_f64())
+        .execute(&mut *tx)
+        .await?;
+    }
+    tx.commit().await
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ReplicaFileQuery {
+    path: String,
+    /// 推送端的修改时间 (Unix 秒)，写入后设置到文件上
+    mtime: Option<f64>,
+}
+
+/// 接口: PUT /api/replication/file?path=&mtime=
+pub async fn receive_file(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query): Query<ReplicaFileQuery>,
+    body: Body,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    authorize(&state, &headers)?;
+    let Some((rel, full)) = replica_target(&state, &query.path) else {
+        return Err(api_error(StatusCode::BAD_REQUEST, "path must be an image inside ROOT_DIR"));
+    };
+    let too_large = || api_error(StatusCode::PAYLOAD_TOO_LARGE, &format!("File exceeds {} bytes", FILE_MAX_BYTES));
+    let declared = headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok());
+    if declared.is_some_and(|len| len > FILE_MAX_BYTES) {
+        return Err(too_large());
+    }
+    let write_error = |err: std::io::Error| {
+        tracing::warn!("⚠️ Failed to write replicated file {}: {}", rel, err);
+        api_error(StatusCode::INTERNAL_SERVER_ERROR, "Failed to write file")
+    };
+
+    // 先写临时文件 (扩展名不是图片，扫描不会收录)，完整收到后再替换
+    let parent = full.parent().map(PathBuf::from).unwrap_or_else(|| state.root_dir.to_path_buf());
+    tokio::fs::create_dir_all(&parent).await.map_err(write_error)?;
+    let file_name = full.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+    // 同一文件可能同时收到多个上传，临时文件名按请求区分
+    static TMP_SEQ: AtomicU64 = AtomicU64::new(0);
+    let seq = TMP_SEQ.fetch_add(1, Ordering::Relaxed);
+    let tmp = parent.join(format!(".{}.replica-{}-{}", file_name, std::process::id(), seq));
+    let mut file = tokio::fs::File::create(&tmp).await.map_err(write_error)?;
+    let mut stream = body.into_data_stream();
+    let mut size = 0u64;
+    while let Some(chunk) = stream.next().await {
+        let written = match chunk {
+            Ok(chunk) => file.write_all(&chunk).await.map(|_| chunk.len() as u64),
+            Err(err) => Err(std::io::Error::other(err)),
+        };
+        match written {
+            Ok(len) => size += len,
+            Err(err) => {
+                drop(file);
+                let _ = tokio::fs::remove_file(&tmp).await;
+                return Err(write_error(err));
+            }
+        }
+        if size > FILE_MAX_BYTES {
+            drop(file);
+            let _ = tokio::fs::remove_file(&tmp).await;
+            return Err(too_large());
+        }
+    }
+    if let Err(err) = file.flush().await {
+        drop(file);
+        let _ = tokio::fs::remove_file(&tmp).await;
+        return Err(write_error(err));
+    }
+    let file = file.into_std().await;
+    if let Some(mtime) = query.mtime.filter(|t| t.is_finite() && *t >= 0.0) {
+        let _ = file.set_modified(UNIX_EPOCH + std::time::Duration::from_secs_f64(mtime));
+    }
+    drop(file);
+    if let Err(err) = tokio::fs::rename(&tmp, &full).await {
+        let _ = tokio::fs::remove_file(&tmp).await;
+        return Err(write_error(err));
+    }
+
+    let (indexed, root) = (full.clone(), state.root_dir.clone());
+    let meta = tokio::task::spawn_blocking(move || process_image_metadata_sync(&indexed, &root)).await.ok().flatten();
+    if let Some(meta) = meta {
+        if let Ok(mut conn) = state.db.acquire().await {
+            upsert_image_row(&mut conn, &meta).await.ok();
+        }
+    }
+    invalidate_playlist_cache(&state).await;
+    tracing::info!("🔁 Received replicated file {} ({} bytes)", rel, size);
+    Ok(Json(serde_json::json!({ "path": rel, "size": size })))
+}
+
+#[cfg(feature = "replication")]
+pub use push::spawn;
+
+#[cfg(feature = "replication")]
+mod push {
+    use std::{collections::HashMap, time::Duration};
+
+    use anyhow::{bail, Result};
+    use gallery_client::types::{ImageCaption, IndexFreshness};
+
+    use super::{ReplicaImage, ReplicationDelta, ReplicationResult};
+    use crate::{resolve_full_path, AppState, INTERNAL_PATH_SQL_FILTER};
+
+    /// 每次请求携带的记录数
+    const BATCH_SIZE: usize = 200;
+
+    struct ReplicaTarget {
+        base_url: String,
+        token: String,
+        mirror_files: bool,
+        interval: Duration,
+    }
+
+    impl ReplicaTarget {
+        fn from_state(state: &AppState) -> Option<Self> {
+            let settings = &state.settings;
+            let base_url = settings.get("GALLERY_REPLICA_URL").filter(|v| !v.trim().is_empty())?;
+            let Some(token) = settings.get("GALLERY_REPLICA_TOKEN").filter(|v| !v.trim().is_empty()) else {
+                tracing::error!("⚠️ GALLERY_REPLICA_URL is set but GALLERY_REPLICA_TOKEN is missing; replication disabled");
+                return None;
+            };
+            Some(Self {
+                base_url: base_url.trim().trim_end_matches('/').to_string(),
+                token: token.trim().to_string(),
+                mirror_files: settings.flag("GALLERY_REPLICA_FILES"),
+                interval: settings.duration_secs("GALLERY_REPLICA_INTERVAL_SECS").unwrap_or(Duration::from_secs(600)),
+            })
+        }
+
+        fn endpoint(&self, path: &str) -> String {
+            format!("{}/api/v1/replication/{}", self.base_url, path)
+        }
+    }
+
+    #[derive(sqlx::FromRow)]
+    struct CaptionRow {
+        path: String,
+        #[sqlx(flatten)]
+        caption: ImageCaption,
+    }
+
+    #[derive(Default)]
+    struct PushStats {
+        upserts: usize,
+        deletes: usize,
+        files: usize,
+        pending: usize,
+    }
+
+    pub fn spawn(state: AppState) {
+        let Some(target) = ReplicaTarget::from_state(&state) else {
+            return;
+        };
+        tracing::info!(
+            "🔁 Replicating {} to {} every {}s",
+            if target.mirror_files { "index and files" } else { "index" },
+            target.base_url,
+            target.interval.as_secs()
+        );
+        tokio::spawn(async move {
+            let client = reqwest::Client::new();
+            let mut ticker = tokio::time::interval(target.interval);
+            loop {
+                ticker.tick().await;
+                // 扫描期间索引不完整，此时推送会把尚未扫描到的图片当作已删除
+                if *state.index_freshness.read().unwrap() != IndexFreshness::Fresh {
+                    continue;
+                }
+                match push(&state, &client, &target).await {
+                    Ok(stats) if stats.upserts + stats.deletes + stats.files + stats.pending > 0 => tracing::info!(
+                        "🔁 Replicated {} records, {} deletions and {} files to {} ({} waiting for files)",
+                        stats.upserts,
+                        stats.deletes,
+                        stats.files,
+                        target.base_url,
+                        stats.pending
+                    ),
+                    Ok(_) => {}
+                    Err(err) => tracing::warn!("⚠️ Replication to {} failed: {:#}", target.base_url, err),
+                }
+            }
+        });
+    }
+
+    /// 当前索引中的全部记录 (ROOT_DIR 之外的路径不复制)
+    async fn current_records(state: &AppState) -> Result<Vec<ReplicaImage>> {
+        let rows: Vec<(String, Option<f64>, i64, Option<f64>)> = sqlx::query_as(&format!(
+            "SELECT path, mtime, size, missing_since FROM images WHERE {} ORDER BY path",
+            INTERNAL_PATH_SQL_FILTER
+        ))
+        .fetch_all(&state.db)
+        .await?;
+        let mut tags: HashMap<String, Vec<String>> = HashMap::new();
+        let tag_rows: Vec<(String, String)> =
+            sqlx::query_as("SELECT path, tag FROM image_tags ORDER BY path, tag").fetch_all(&state.db).await?;
+        for (path, tag) in tag_rows {
+            tags.entry(path).or_default().push(tag);
+        }
+        let caption_rows: Vec<CaptionRow> =
+            sqlx::query_as("SELECT path, title, caption, rating FROM image_captions").fetch_all(&state.db).await?;
+        let mut captions: HashMap<String, ImageCaption> =
+            caption_rows.into_iter().map(|row| (row.path, row.caption)).collect();
+        Ok(rows
+            .into_iter()
+            .map(|(path, mtime, size, missing_since)| ReplicaImage {
+                tags: tags.remove(&path).unwrap_or_default(),
+                caption: captions.remove(&path).unwrap_or_default(),
+                path,
+                mtime: mtime.unwrap_or(0.0),
+                size,
+                missing_since,
+            })
+            .collect())
+    }
+
+    fn fingerprint(record: &ReplicaImage) -> String {
+        let json = serde_json::to_vec(record).unwrap_or_default();
+        blake3::hash(&json).to_hex().to_string()
+    }
+
+    async fn push(state: &AppState, client: &reqwest::Client, target: &ReplicaTarget) -> Result<PushStats> {
+        let sent: HashMap<String, String> =
+            sqlx::query_as::<_, (String, String)>("SELECT path, fingerprint FROM replica_sent WHERE target = ?")
+                .bind(&target.base_url)
+                .fetch_all(&state.db)
+                .await?
+                .into_iter()
+                .collect();
+        let records = current_records(state).await?;
+        let deletes: Vec<String> = {
+            let current: std::collections::HashSet<&str> = records.iter().map(|r| r.path.as_str()).collect();
+            sent.keys().filter(|p| !current.contains(p.as_str())).cloned().collect()
+        };
+        let changed: Vec<(ReplicaImage, String)> = records
+            .into_iter()
+            .map(|record| {
+                let print = fingerprint(&record);
+                (record, print)
+            })
+            .filter(|(record, print)| sent.get(&record.path) != Some(print))
+            .collect();
+
+        let mut stats = PushStats::default();
+        for batch in changed.chunks(BATCH_SIZE) {
+            let upserts: Vec<ReplicaImage> = batch.iter().map(|(record, _)| record.clone()).collect();
+            let mut result = post_delta(client, target, upserts.clone(), Vec::new()).await?;
+
+            if target.mirror_files {
+                let wanted: Vec<&ReplicaImage> = upserts
+                    .iter()
+                    .filter(|r| result.missing.contains(&r.path) || result.stale.contains(&r.path))
+                    .collect();
+                let mut uploaded = Vec::new();
+                for record in wanted {
+                    match upload_file(state, client, target, record).await {
+                        Ok(()) => uploaded.push(record.clone()),
+                        Err(err) => tracing::warn!("⚠️ Failed to replicate file {}: {:#}", record.path, err),
+                    }
+                }
+                stats.files += uploaded.len();
+                if !uploaded.is_empty() {
+                    // 文件到达后重新应用这些记录的元数据
+                    let retry: Vec<String> = uploaded.iter().map(|r| r.path.clone()).collect();
+                    let second = post_delta(client, target, uploaded, Vec::new()).await?;
+                    result.missing.retain(|p| !retry.contains(p) || second.missing.contains(p));
+                    result.stale.retain(|p| !retry.contains(p) || second.stale.contains(p));
+                }
+            }
+
+            // 接收端暂时缺文件的记录下次重发；复制文件时不一致的文件也要重传
+            let mut tx = state.db.begin().await?;
+            for (record, print) in batch {
+                let pending = result.missing.contains(&record.path)
+                    || (target.mirror_files && result.stale.contains(&record.path));
+                if pending {
+                    stats.pending += 1;
+                    continue;
+                }
+                sqlx::query(
+                    "INSERT INTO replica_sent (target, path, fingerprint) VALUES (?, ?, ?)
+                     ON CONFLICT(target, path) DO UPDATE SET fingerprint = excluded.fingerprint",
+                )
+                .bind(&target.base_url)
+                .bind(&record.path)
+                .bind(print)
+                .execute(&mut *tx)
+                .await?;
+                stats.upserts += 1;
+            }
+            tx.commit().await?;
+            for path in &result.rejected {
+                tracing::warn!("⚠️ Replica rejected {}", path);
+            }
+        }
+
+        for batch in deletes.chunks(BATCH_SIZE) {
+            post_delta(client, target, Vec::new(), batch.to_vec()).await?;
+            let mut tx = state.db.begin().await?;
+            for path in batch {
+                sqlx::query("DELETE FROM replica_sent WHERE target = ? AND path = ?")
+                    .bind(&target.base_url)
+                    .bind(path)
+                    .execute(&mut *tx)
+                    .await?;
+            }
+            tx.commit().await?;
+            stats.deletes += batch.len();
+        }
+        Ok(stats)
+    }
+
+    async fn post_delta(
+        client: &reqwest::Client,
+        target: &ReplicaTarget,
+        upserts: Vec<ReplicaImage>,
+        deletes: Vec<String>,
+    ) -> Result<ReplicationResult> {
+        let delta = ReplicationDelta { mirror_files: target.mirror_files, upserts, deletes };
+        let response = client.post(target.endpoint("delta")).bearer_auth(&target.token).json(&delta).send().await?;
+        let status = response.status();
+        if !status.is_success() {
+            bail!("{}: {}", status, response.text().await.unwrap_or_default());
+        }
+        Ok(response.json().await?)
+    }
+
+    async fn upload_file(
+        state: &AppState,
+        client: &reqwest::Client,
+        target: &ReplicaTarget,
+        record: &ReplicaImage,
+    ) -> Result<()> {
+        // 流式上传，不把整个文件读进内存
+        let file = tokio::fs::File::open(resolve_full_path(&state.root_dir, &record.path)).await?;
+        let len = file.metadata().await?.len();
+        let response = client
+            .put(target.endpoint("file"))
+            .query(&[("path", record.path.as_str()), ("mtime", &record.mtime.to_string())])
+            .bearer_auth(&target.token)
+            .header(reqwest::header::CONTENT_LENGTH, len)
+            .body(reqwest::Body::wrap_stream(tokio_util::io::ReaderStream::new(file)))
+            .send()
+            .await?;
+        let status = response.status();
+        if !status.is_success() {
+            bail!("{}: {}", status, response.text().await.unwrap_or_default());
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,398 @@
+//! 缩略图磁盘缓存 (`{ROOT_DIR}/.gallery-cache/thumbs`) 及其管理接口
+//!
+//! `/api/resize`、分享缩略图、投屏帧、过渡动画等不带水印的渲染结果按 (路径, 文件版本, 渲染参数) 缓存，
+//! 原图修改后自动使用新条目，旧条目由淘汰策略清理：
+//! - `GALLERY_THUMBNAIL_CACHE_MAX_MB`: 总大小上限，超出时先删最久未使用的条目
+//! - `GALLERY_THUMBNAIL_CACHE_MAX_AGE_DAYS`: 超过这么多天未使用的条目删除
+//!
+//! 配置了任一项时每小时自动清理一次；`POST /api/admin/cache/prune` 可随时按指定策略清理，
+//! `GET /api/admin/cache` 查看大小、条目数与命中率。大图切片目录 (`GALLERY_TILE_CACHE_DIR`) 按同一策略
+//! 单独计算大小并一起清理。
+//!
+//! 扫描发现新图片后可在后台逐张预生成缩略图 (`prewarm` 任务，见 `/api/jobs`)，避免首次浏览新相册时集中渲染：
+//! - `GALLERY_PREWARM_SIZES`: 逗号分隔的宽度，与 `/api/resize?width=` 的请求一致，如 `320,1280`
+//! - `GALLERY_PREWARM_PROFILES`: 逗号分隔的设备配置名 (墨水屏等)，`*` 为全部
+
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, SystemTime},
+};
+
+use axum::{extract::State, http::StatusCode, Json};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    file_version, load_device_profile, render_cache_key, render_image, resolve_full_path, spawn_cooperative_job, AppState,
+    RenderSpec, ResizeFit, Settings, CACHE_DIR_NAME, RENDER_MAX_SIDE,
+};
+
+/// 自动清理间隔
+const PRUNE_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// 预生成时每次渲染后的停顿，给在线请求让出 CPU
+const PREWARM_PAUSE: Duration = Duration::from_millis(20);
+
+/// 按需预热 (`/api/srcset`) 同时渲染的上限，以及排队等待的键数上限 (超出的直接跳过，等真正请求时再渲染)
+const WARM_CONCURRENCY: usize = 2;
+const WARM_MAX_QUEUED: usize = 256;
+
+/// 缓存的运行时状态：命中与未命中计数 (进程启动以来)、按需预热的并发限制与排队中的键
+#[derive(Debug)]
+pub struct CacheStats {
+    hits: AtomicU64,
+    misses: AtomicU64,
+    warm_slots: tokio::sync::Semaphore,
+    warming: Mutex<HashSet<String>>,
+}
+
+impl Default for CacheStats {
+    fn default() -> Self {
+        Self {
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            warm_slots: tokio::sync::Semaphore::new(WARM_CONCURRENCY),
+            warming: Mutex::new(HashSet::new()),
+        }
+    }
+}
+
+pub fn cache_dir(root_dir: &Path) -> PathBuf {
+    root_dir.join(CACHE_DIR_NAME).join("thumbs")
+}
+
+/// 缓存条目可能的格式
+const CACHED_MIMES: [&str; 3] = ["image/jpeg", "image/png", "image/gif"];
+
+/// 缓存键对应的文件，按键的前两位分目录
+fn entry_path(root_dir: &Path, key: &str, mime: &str) -> PathBuf {
+    let ext = match mime {
+        "image/png" => "png",
+        "image/gif" => "gif",
+        _ => "jpg",
+    };
+    cache_dir(root_dir).join(&key[..2]).join(format!("{}.{}", key, ext))
+}
+
+/// 读取缓存条目，命中时刷新修改时间 (作为"最近使用"时间供淘汰使用)
+pub async fn lookup(state: &AppState, key: &str) -> Option<(Vec<u8>, &'static str)> {
+    for mime in CACHED_MIMES {
+        let path = entry_path(&state.root_dir, key, mime);
+        if let Ok(bytes) = tokio::fs::read(&path).await {
+            state.thumbnail_cache.hits.fetch_add(1, Ordering::Relaxed);
+            touch(path);
+            return Some((bytes, mime));
+        }
+    }
+    state.thumbnail_cache.misses.fetch_add(1, Ordering::Relaxed);
+    None
+}
+
+/// 刷新缓存文件的修改时间，淘汰时按它判断最近使用
+pub fn touch(path: PathBuf) {
+    tokio::task::spawn_blocking(move || {
+        if let Ok(file) = std::fs::File::options().append(true).open(&path) {
+            file.set_modified(SystemTime::now()).ok();
+        }
+    });
+}
+
+pub fn contains(root_dir: &Path, key: &str) -> bool {
+    CACHED_MIMES.iter().any(|mime| entry_path(root_dir, key, mime).exists())
+}
+
+/// 写入缓存条目 (先写临时文件再改名，并发请求不会读到半个文件)。
+/// 同一个键可能被多个请求同时渲染，临时文件名按进程与序号区分，改名是原子的，谁最后完成都一样
+pub async fn store(state: &AppState, key: &str, bytes: &[u8], mime: &str) {
+    static TMP_SEQ: AtomicU64 = AtomicU64::new(0);
+    let path = entry_path(&state.root_dir, key, mime);
+    let tmp = path.with_extension(format!("{}-{}.tmp", std::process::id(), TMP_SEQ.fetch_add(1, Ordering::Relaxed)));
+    let result = async {
+        tokio::fs::create_dir_all(path.parent().unwrap()).await?;
+        tokio::fs::write(&tmp, bytes).await?;
+        tokio::fs::rename(&tmp, &path).await
+    }
+    .await;
+    if let Err(err) = result {
+        tracing::warn!("⚠️ Failed to cache thumbnail {}: {}", path.display(), err);
+        tokio::fs::remove_file(&tmp).await.ok();
+    }
+}
+
+/// 在后台渲染尚未缓存的条目：全局最多 `WARM_CONCURRENCY` 个同时渲染，已在排队的键不重复排队，
+/// 队列满时直接跳过 (一个页面同时请求上百张图的 srcset 不会一起解码上百张原图)
+pub fn warm(state: &AppState, full: &Path, entries: Vec<(String, RenderSpec)>) {
+    let cache = Arc::clone(&state.thumbnail_cache);
+    for (key, spec) in entries {
+        {
+            let mut warming = cache.warming.lock().unwrap();
+            if warming.len() >= WARM_MAX_QUEUED || !warming.insert(key.clone()) {
+                continue;
+            }
+        }
+        let (state, cache, full) = (state.clone(), Arc::clone(&cache), full.to_path_buf());
+        tokio::spawn(async move {
+            if let Ok(_permit) = cache.warm_slots.acquire().await {
+                // 排队期间可能已被在线请求渲染好
+                if !contains(&state.root_dir, &key) {
+                    let rendered = tokio::task::spawn_blocking(move || render_image(&full, &spec)).await.ok().flatten();
+                    if let Some((bytes, mime)) = rendered {
+                        store(&state, &key, &bytes, mime).await;
+                    }
+                }
+            }
+            cache.warming.lock().unwrap().remove(&key);
+        });
+    }
+}
+
+/// 淘汰策略；两项都为 None 时不清理
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize)]
+pub struct PrunePolicy {
+    pub max_bytes: Option<u64>,
+    pub max_age_days: Option<u64>,
+}
+
+impl PrunePolicy {
+    pub fn from_settings(settings: &Settings) -> Self {
+        Self {
+            max_bytes: settings.parse::<u64>("GALLERY_THUMBNAIL_CACHE_MAX_MB").and_then(|mb| mb.checked_mul(1024 * 1024)),
+            max_age_days: settings.parse("GALLERY_THUMBNAIL_CACHE_MAX_AGE_DAYS"),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.max_bytes.is_none() && self.max_age_days.is_none()
+    }
+}
+
+struct Entry {
+    path: PathBuf,
+    size: u64,
+    used_at: SystemTime,
+}
+
+fn list_entries(dir: &Path) -> Vec<Entry> {
+    walkdir::WalkDir::new(dir)
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_type().is_file())
+        .filter_map(|e| {
+            let meta = e.metadata().ok()?;
+            Some(Entry { path: e.into_path(), size: meta.len(), used_at: meta.modified().ok()? })
+        })
+        .collect()
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct PruneResult {
+    removed: usize,
+    freed_bytes: u64,
+    entries: usize,
+    size_bytes: u64,
+}
+
+/// 按同一策略清理的目录：缩略图缓存与大图切片缓存 (两者分别计算大小上限)
+fn prune_dirs(state: &AppState) -> Vec<PathBuf> {
+    vec![cache_dir(&state.root_dir), crate::tile_cache_dir(state)]
+}
+
+fn prune_all(dirs: Vec<PathBuf>, policy: PrunePolicy) -> PruneResult {
+    let mut result = PruneResult::default();
+    for dir in dirs {
+        let pruned = prune(&dir, policy);
+        result.removed += pruned.removed;
+        result.freed_bytes += pruned.freed_bytes;
+        result.entries += pruned.entries;
+        result.size_bytes += pruned.size_bytes;
+    }
+    result
+}
+
+/// 先删超龄条目，再按最近使用时间从旧到新删除，直到不超过大小上限
+fn prune(dir: &Path, policy: PrunePolicy) -> PruneResult {
+    let mut entries = list_entries(dir);
+    entries.sort_by_key(|e| e.used_at);
+    let cutoff = policy
+        .max_age_days
+        .and_then(|days| SystemTime::now().checked_sub(Duration::from_secs(days.checked_mul(86400)?)));
+    let mut total: u64 = entries.iter().map(|e| e.size).sum();
+    let mut result = PruneResult::default();
+    for entry in &entries {
+        let expired = cutoff.is_some_and(|cutoff| entry.used_at < cutoff);
+        let over_size = policy.max_bytes.is_some_and(|max| total > max);
+        if !expired && !over_size {
+            continue;
+        }
+        if std::fs::remove_file(&entry.path).is_ok() {
+            total -= entry.size;
+            result.removed += 1;
+            result.freed_bytes += entry.size;
+            // 切片按 版本/层级 分目录，删空的目录一并移除 (非空时 remove_dir 失败即停止)
+            for parent in entry.path.ancestors().skip(1).take_while(|p| *p != dir) {
+                if std::fs::remove_dir(parent).is_err() {
+                    break;
+                }
+            }
+        }
+    }
+    result.entries = entries.len() - result.removed;
+    result.size_bytes = total;
+    result
+}
+
+/// 配置了淘汰策略时，每小时清理一次
+pub fn spawn_pruner(state: AppState) {
+    let policy = PrunePolicy::from_settings(&state.settings);
+    if policy.is_empty() {
+        return;
+    }
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(PRUNE_INTERVAL);
+        loop {
+            ticker.tick().await;
+            let dirs = prune_dirs(&state);
+            let result = tokio::task::spawn_blocking(move || prune_all(dirs, policy)).await.unwrap_or_default();
+            if result.removed > 0 {
+                tracing::info!(
+                    "🧹 [Background] 缩略图与切片缓存清理 {} 个条目，释放 {} MB",
+                    result.removed,
+                    result.freed_bytes / 1024 / 1024
+                );
+            }
+        }
+    });
+}
+
+/// 接口: GET /api/admin/cache，缩略图缓存的大小、条目数、命中率与淘汰策略
+pub async fn cache_stats(State(state): State<AppState>) -> Json<serde_json::Value> {
+    let dir = cache_dir(&state.root_dir);
+    let scan_dir = dir.clone();
+    let (entries, size_bytes) = tokio::task::spawn_blocking(move || {
+        let entries = list_entries(&scan_dir);
+        (entries.len(), entries.iter().map(|e| e.size).sum::<u64>())
+    })
+    .await
+    .unwrap_or_default();
+    let hits = state.thumbnail_cache.hits.load(Ordering::Relaxed);
+    let misses = state.thumbnail_cache.misses.load(Ordering::Relaxed);
+    Json(serde_json::json!({
+        "dir": dir.display().to_string(),
+        "entries": entries,
+        "size_bytes": size_bytes,
+        "hits": hits,
+        "misses": misses,
+        "hit_rate": (hits + misses > 0).then(|| hits as f64 / (hits + misses) as f64),
+        "policy": PrunePolicy::from_settings(&state.settings),
+    }))
+}
+
+/// 接口: POST /api/admin/cache/prune，按请求中的策略清理 (省略的字段使用配置的策略；`max_bytes: 0` 清空缓存)
+pub async fn prune_cache(
+    State(state): State<AppState>,
+    body: Option<Json<PrunePolicy>>,
+) -> Result<Json<PruneResult>, (StatusCode, Json<serde_json::Value>)> {
+    let configured = PrunePolicy::from_settings(&state.settings);
+    let requested = body.map(|Json(policy)| policy).unwrap_or_default();
+    let policy = PrunePolicy {
+        max_bytes: requested.max_bytes.or(configured.max_bytes),
+        max_age_days: requested.max_age_days.or(configured.max_age_days),
+    };
+    if policy.is_empty() {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            Json(serde_json::json!({ "detail": "max_bytes or max_age_days is required when no cache policy is configured" })),
+        ));
+    }
+    let dirs = prune_dirs(&state);
+    let result = tokio::task::spawn_blocking(move || prune_all(dirs, policy)).await.unwrap_or_default();
+    tracing::info!("🧹 缩略图与切片缓存清理 {} 个条目，释放 {} MB", result.removed, result.freed_bytes / 1024 / 1024);
+    Ok(Json(result))
+}
+
+/// 预生成的渲染参数：配置的宽度与设备配置
+async fn prewarm_specs(state: &AppState) -> Vec<RenderSpec> {
+    let mut specs: Vec<RenderSpec> = state
+        .settings
+        .get("GALLERY_PREWARM_SIZES")
+        .unwrap_or_default()
+        .split(',')
+        .filter_map(|w| w.trim().parse::<u32>().ok())
+        .filter(|w| (1..=RENDER_MAX_SIDE).contains(w))
+        // 与 /api/resize 只给 width 时的参数相同，才能命中同一个缓存键
+        .map(|width| RenderSpec { width, height: RENDER_MAX_SIDE, fit: ResizeFit::Contain, ..Default::default() })
+        .collect();
+
+    let profiles = state.settings.get("GALLERY_PREWARM_PROFILES").unwrap_or_default();
+    let names: Vec<String> = if profiles.trim() == "*" {
+        sqlx::query_scalar("SELECT name FROM device_profiles ORDER BY name")
+            .fetch_all(&state.db)
+            .await
+            .unwrap_or_default()
+    } else {
+        profiles.split(',').map(|n| n.trim().to_string()).filter(|n| !n.is_empty()).collect()
+    };
+    for name in names {
+        match load_device_profile(&state.db, &name).await.map(|p| RenderSpec::from_profile(&p)) {
+            Some(Ok(spec)) => specs.push(spec),
+            Some(Err(err)) => tracing::warn!("⚠️ Skipping prewarm profile {:?}: {}", name, err),
+            None => tracing::warn!("⚠️ Prewarm profile {:?} not found", name),
+        }
+    }
+    specs
+}
+
+/// 扫描后为新图片启动预生成任务 (未配置或没有新图片时什么也不做)
+pub async fn prewarm(state: &AppState, added: &[String]) {
+    if added.is_empty() {
+        return;
+    }
+    let specs = prewarm_specs(state).await;
+    if specs.is_empty() {
+        return;
+    }
+    let (job_state, paths) = (state.clone(), added.to_vec());
+    let id = spawn_cooperative_job(state, "prewarm", move |ctx| async move {
+        let (mut generated, mut skipped, mut failed) = (0usize, 0usize, 0usize);
+        for (done, path) in paths.iter().enumerate() {
+            if ctx.cancel.is_cancelled() {
+                break;
+            }
+            let full = resolve_full_path(&job_state.root_dir, path);
+            let Ok(meta) = tokio::fs::metadata(&full).await else {
+                failed += specs.len();
+                continue;
+            };
+            for spec in &specs {
+                let Some(key) = render_cache_key(path, file_version(&meta), spec) else { continue };
+                if contains(&job_state.root_dir, &key) {
+                    skipped += 1;
+                    continue;
+                }
+                // 一次只渲染一张，低优先级
+                let (full, spec) = (full.clone(), spec.clone());
+                match tokio::task::spawn_blocking(move || render_image(&full, &spec)).await.ok().flatten() {
+                    Some((bytes, mime)) => {
+                        store(&job_state, &key, &bytes, mime).await;
+                        generated += 1;
+                    }
+                    None => failed += 1,
+                }
+                tokio::time::sleep(PREWARM_PAUSE).await;
+            }
+            ctx.set_progress(done + 1, paths.len()).await;
+        }
+        Ok(serde_json::json!({
+            "images": paths.len(),
+            "generated": generated,
+            "skipped": skipped,
+            "failed": failed,
+        }))
+    })
+    .await;
+    tracing::info!("🔥 [Background] 为 {} 张新图片预生成缩略图 (任务 {})", added.len(), id);
+}